@@ -0,0 +1,209 @@
+/*
+ * Handling of the alpha channel of RGBA and BGRA input
+ */
+
+/// # Handling of the alpha channel of RGBA and BGRA input
+///
+/// JPEG images can't store an alpha channel, so it has to be removed before encoding.
+///
+/// The color values of premultiplied color types are already multiplied by the alpha
+/// value, which is taken into account when compositing them onto a background.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AlphaPolicy {
+    /// Ignores the alpha channel and encodes the color values as they are
+    ///
+    /// Transparent pixels keep whatever color they hold.
+    Ignore,
+
+    /// Composites the pixels onto a background with the given RGB color
+    Composite([u8; 3]),
+
+    /// Divides the color values of premultiplied input by the alpha value
+    ///
+    /// Fully transparent pixels are encoded as black.
+    Unpremultiply,
+}
+
+impl Default for AlphaPolicy {
+    fn default() -> Self {
+        AlphaPolicy::Ignore
+    }
+}
+
+impl AlphaPolicy {
+    /// Returns the color of a pixel with the given color values and alpha
+    #[inline(always)]
+    pub(crate) fn apply(self, r: u8, g: u8, b: u8, a: u8) -> (u8, u8, u8) {
+        match self {
+            AlphaPolicy::Ignore => (r, g, b),
+            AlphaPolicy::Composite(background) => (
+                composite(r, background[0], a),
+                composite(g, background[1], a),
+                composite(b, background[2], a),
+            ),
+            AlphaPolicy::Unpremultiply => (
+                unpremultiply(r, a),
+                unpremultiply(g, a),
+                unpremultiply(b, a),
+            ),
+        }
+    }
+
+    /// Returns the color of a pixel with premultiplied color values and alpha
+    #[inline(always)]
+    pub(crate) fn apply_premultiplied(self, r: u8, g: u8, b: u8, a: u8) -> (u8, u8, u8) {
+        match self {
+            AlphaPolicy::Composite(background) => (
+                composite_premultiplied(r, background[0], a),
+                composite_premultiplied(g, background[1], a),
+                composite_premultiplied(b, background[2], a),
+            ),
+            _ => self.apply(r, g, b, a),
+        }
+    }
+
+    /// Returns the color of a pixel with 16 bit color values and alpha
+    #[inline(always)]
+    pub(crate) fn apply_16(self, r: u16, g: u16, b: u16, a: u16) -> (u16, u16, u16) {
+        match self {
+            AlphaPolicy::Ignore => (r, g, b),
+            AlphaPolicy::Composite(background) => (
+                composite_16(r, background[0], a),
+                composite_16(g, background[1], a),
+                composite_16(b, background[2], a),
+            ),
+            AlphaPolicy::Unpremultiply => (
+                unpremultiply_16(r, a),
+                unpremultiply_16(g, a),
+                unpremultiply_16(b, a),
+            ),
+        }
+    }
+}
+
+/// Divides a value in the range of 0..=255*255 by 255 with rounding
+#[inline(always)]
+pub(crate) fn div_255(value: u32) -> u32 {
+    let value = value + 128;
+    (value + (value >> 8)) >> 8
+}
+
+#[inline(always)]
+fn composite(value: u8, background: u8, alpha: u8) -> u8 {
+    let alpha = u32::from(alpha);
+    div_255(u32::from(value) * alpha + u32::from(background) * (255 - alpha)) as u8
+}
+
+#[inline(always)]
+fn composite_premultiplied(value: u8, background: u8, alpha: u8) -> u8 {
+    let background = div_255(u32::from(background) * (255 - u32::from(alpha)));
+
+    // Invalid input with values larger than alpha can exceed the range of samples
+    (u32::from(value) + background).min(255) as u8
+}
+
+#[inline(always)]
+fn unpremultiply(value: u8, alpha: u8) -> u8 {
+    if alpha == 0 {
+        0
+    } else {
+        // Computed in floating point to match the AVX2 implementation
+        let value = f32::from(value) * 255.0 / f32::from(alpha);
+        (value + 0.5).min(255.0) as u8
+    }
+}
+
+#[inline(always)]
+fn composite_16(value: u16, background: u8, alpha: u16) -> u16 {
+    let alpha = u64::from(alpha);
+    let background = u64::from(background) * 257;
+
+    ((u64::from(value) * alpha + background * (65535 - alpha) + 32767) / 65535) as u16
+}
+
+#[inline(always)]
+fn unpremultiply_16(value: u16, alpha: u16) -> u16 {
+    if alpha == 0 {
+        0
+    } else {
+        let alpha = u64::from(alpha);
+        ((u64::from(value) * 65535 + alpha / 2) / alpha).min(65535) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{div_255, AlphaPolicy};
+
+    #[test]
+    fn test_div_255() {
+        for value in 0..=255 * 255 {
+            assert_eq!(div_255(value), (value as f64 / 255.0).round() as u32);
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let composite = AlphaPolicy::Composite([255, 255, 255]);
+
+        assert_eq!(composite.apply(10, 20, 30, 255), (10, 20, 30));
+        assert_eq!(composite.apply(10, 20, 30, 0), (255, 255, 255));
+        assert_eq!(composite.apply(0, 100, 255, 128), (127, 177, 255));
+
+        let unpremultiply = AlphaPolicy::Unpremultiply;
+
+        assert_eq!(unpremultiply.apply(10, 20, 30, 255), (10, 20, 30));
+        assert_eq!(unpremultiply.apply(10, 20, 30, 0), (0, 0, 0));
+        assert_eq!(unpremultiply.apply(64, 128, 0, 128), (128, 255, 0));
+        assert_eq!(unpremultiply.apply(200, 20, 30, 100), (255, 51, 77));
+
+        assert_eq!(AlphaPolicy::Ignore.apply(10, 20, 30, 0), (10, 20, 30));
+    }
+
+    #[test]
+    fn test_apply_16() {
+        let composite = AlphaPolicy::Composite([255, 255, 255]);
+
+        assert_eq!(composite.apply_16(10, 20, 30, 65535), (10, 20, 30));
+        assert_eq!(composite.apply_16(10, 20, 30, 0), (65535, 65535, 65535));
+        assert_eq!(
+            composite.apply_16(0, 0, 65535, 32768),
+            (32767, 32767, 65535)
+        );
+
+        let unpremultiply = AlphaPolicy::Unpremultiply;
+
+        assert_eq!(unpremultiply.apply_16(10, 20, 30, 0), (0, 0, 0));
+        assert_eq!(
+            unpremultiply.apply_16(1000, 40000, 0, 32768),
+            (2000, 65535, 0)
+        );
+
+        assert_eq!(AlphaPolicy::Ignore.apply_16(10, 20, 30, 0), (10, 20, 30));
+    }
+
+    #[test]
+    fn test_apply_premultiplied() {
+        let composite = AlphaPolicy::Composite([255, 255, 255]);
+
+        assert_eq!(composite.apply_premultiplied(10, 20, 30, 255), (10, 20, 30));
+        assert_eq!(composite.apply_premultiplied(0, 0, 0, 0), (255, 255, 255));
+        assert_eq!(
+            composite.apply_premultiplied(0, 50, 128, 128),
+            (127, 177, 255)
+        );
+        assert_eq!(
+            composite.apply_premultiplied(200, 0, 0, 128),
+            (255, 127, 127)
+        );
+
+        assert_eq!(
+            AlphaPolicy::Unpremultiply.apply_premultiplied(64, 128, 0, 128),
+            (128, 255, 0)
+        );
+        assert_eq!(
+            AlphaPolicy::Ignore.apply_premultiplied(10, 20, 30, 0),
+            (10, 20, 30)
+        );
+    }
+}
@@ -0,0 +1,1030 @@
+/*
+ * QM arithmetic coder as described in Annex D of the JPEG specification.
+ *
+ * The encoding procedures are based on the implementation in jcarith.c of the IJG libjpeg.
+ */
+
+#[cfg(test)]
+use crate::coefficient_decoder::EntropyDecoder;
+use crate::marker::Marker;
+use crate::writer::{JfifWrite, JfifWriter};
+use crate::EncodingError;
+
+/// Qe values and probability estimation state machine
+///
+/// Table D.2: (Qe_Value, Next_Index_LPS, Next_Index_MPS, Switch_MPS)
+///
+/// The last entry isn't part of the table in the specification and is used for
+/// the fixed probability estimate of 0.5 as suggested in T.851.
+static QE_TABLE: [(u16, u8, u8, bool); 114] = [
+    (0x5a1d, 1, 1, true),
+    (0x2586, 14, 2, false),
+    (0x1114, 16, 3, false),
+    (0x080b, 18, 4, false),
+    (0x03d8, 20, 5, false),
+    (0x01da, 23, 6, false),
+    (0x00e5, 25, 7, false),
+    (0x006f, 28, 8, false),
+    (0x0036, 30, 9, false),
+    (0x001a, 33, 10, false),
+    (0x000d, 35, 11, false),
+    (0x0006, 9, 12, false),
+    (0x0003, 10, 13, false),
+    (0x0001, 12, 13, false),
+    (0x5a7f, 15, 15, true),
+    (0x3f25, 36, 16, false),
+    (0x2cf2, 38, 17, false),
+    (0x207c, 39, 18, false),
+    (0x17b9, 40, 19, false),
+    (0x1182, 42, 20, false),
+    (0x0cef, 43, 21, false),
+    (0x09a1, 45, 22, false),
+    (0x072f, 46, 23, false),
+    (0x055c, 48, 24, false),
+    (0x0406, 49, 25, false),
+    (0x0303, 51, 26, false),
+    (0x0240, 52, 27, false),
+    (0x01b1, 54, 28, false),
+    (0x0144, 56, 29, false),
+    (0x00f5, 57, 30, false),
+    (0x00b7, 59, 31, false),
+    (0x008a, 60, 32, false),
+    (0x0068, 62, 33, false),
+    (0x004e, 63, 34, false),
+    (0x003b, 32, 35, false),
+    (0x002c, 33, 9, false),
+    (0x5ae1, 37, 37, true),
+    (0x484c, 64, 38, false),
+    (0x3a0d, 65, 39, false),
+    (0x2ef1, 67, 40, false),
+    (0x261f, 68, 41, false),
+    (0x1f33, 69, 42, false),
+    (0x19a8, 70, 43, false),
+    (0x1518, 72, 44, false),
+    (0x1177, 73, 45, false),
+    (0x0e74, 74, 46, false),
+    (0x0bfb, 75, 47, false),
+    (0x09f8, 77, 48, false),
+    (0x0861, 78, 49, false),
+    (0x0706, 79, 50, false),
+    (0x05cd, 48, 51, false),
+    (0x04de, 50, 52, false),
+    (0x040f, 50, 53, false),
+    (0x0363, 51, 54, false),
+    (0x02d4, 52, 55, false),
+    (0x025c, 53, 56, false),
+    (0x01f8, 54, 57, false),
+    (0x01a4, 55, 58, false),
+    (0x0160, 56, 59, false),
+    (0x0125, 57, 60, false),
+    (0x00f6, 58, 61, false),
+    (0x00cb, 59, 62, false),
+    (0x00ab, 61, 63, false),
+    (0x008f, 61, 32, false),
+    (0x5b12, 65, 65, true),
+    (0x4d04, 80, 66, false),
+    (0x412c, 81, 67, false),
+    (0x37d8, 82, 68, false),
+    (0x2fe8, 83, 69, false),
+    (0x293c, 84, 70, false),
+    (0x2379, 86, 71, false),
+    (0x1edf, 87, 72, false),
+    (0x1aa9, 87, 73, false),
+    (0x174e, 72, 74, false),
+    (0x1424, 72, 75, false),
+    (0x119c, 74, 76, false),
+    (0x0f6b, 74, 77, false),
+    (0x0d51, 75, 78, false),
+    (0x0bb6, 77, 79, false),
+    (0x0a40, 77, 48, false),
+    (0x5832, 80, 81, true),
+    (0x4d1c, 88, 82, false),
+    (0x438e, 89, 83, false),
+    (0x3bdd, 90, 84, false),
+    (0x34ee, 91, 85, false),
+    (0x2eae, 92, 86, false),
+    (0x299a, 93, 87, false),
+    (0x2516, 86, 71, false),
+    (0x5570, 88, 89, true),
+    (0x4ca9, 95, 90, false),
+    (0x44d9, 96, 91, false),
+    (0x3e22, 97, 92, false),
+    (0x3824, 99, 93, false),
+    (0x32b4, 99, 94, false),
+    (0x2e17, 93, 86, false),
+    (0x56a8, 95, 96, true),
+    (0x4f46, 101, 97, false),
+    (0x47e5, 102, 98, false),
+    (0x41cf, 103, 99, false),
+    (0x3c3d, 104, 100, false),
+    (0x375e, 99, 93, false),
+    (0x5231, 105, 102, false),
+    (0x4c0f, 106, 103, false),
+    (0x4639, 107, 104, false),
+    (0x415e, 103, 99, false),
+    (0x5627, 105, 106, true),
+    (0x50e7, 108, 107, false),
+    (0x4b85, 109, 103, false),
+    (0x5597, 110, 109, false),
+    (0x504f, 111, 107, false),
+    (0x5a10, 110, 111, true),
+    (0x5522, 112, 109, false),
+    (0x59eb, 112, 111, true),
+    (0x5a1d, 113, 113, false),
+];
+
+/// Index of the fixed probability estimate in the Qe table
+const FIXED_BIN: u8 = 113;
+
+const DC_STAT_BINS: usize = 64;
+const AC_STAT_BINS: usize = 256;
+
+/// Number of conditioning tables supported by the encoder
+pub(crate) const NUM_CONDITIONING_TABLES: usize = 2;
+
+/// Conditioning parameters of the arithmetic coder
+///
+/// The DC bounds select the conditioning of the DC difference categories (F.1.4.4.1.2) and
+/// `ac_kx` the conditioning of the AC magnitude categories (F.1.4.4.2).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ArithmeticConditioning {
+    dc_lower: u8,
+    dc_upper: u8,
+    ac_kx: u8,
+}
+
+impl ArithmeticConditioning {
+    /// Create new conditioning parameters
+    ///
+    /// Returns `None` if the bounds don't satisfy `dc_lower <= dc_upper <= 15`
+    /// or `ac_kx` is not between 1 and 63.
+    pub fn new(dc_lower: u8, dc_upper: u8, ac_kx: u8) -> Option<ArithmeticConditioning> {
+        if dc_lower > dc_upper || dc_upper > 15 || !(1..=63).contains(&ac_kx) {
+            return None;
+        }
+
+        Some(ArithmeticConditioning {
+            dc_lower,
+            dc_upper,
+            ac_kx,
+        })
+    }
+
+    /// Returns the lower and upper bound of the DC conditioning
+    pub fn dc_bounds(&self) -> (u8, u8) {
+        (self.dc_lower, self.dc_upper)
+    }
+
+    /// Returns the AC conditioning value
+    pub fn ac_kx(&self) -> u8 {
+        self.ac_kx
+    }
+
+    /// Returns the value of the DC conditioning table in the DAC segment
+    pub(crate) fn dc_value(&self) -> u8 {
+        self.dc_lower | (self.dc_upper << 4)
+    }
+}
+
+impl Default for ArithmeticConditioning {
+    /// The default conditioning as defined in F.1.4.4 (L = 0, U = 1, Kx = 5)
+    fn default() -> Self {
+        ArithmeticConditioning {
+            dc_lower: 0,
+            dc_upper: 1,
+            ac_kx: 5,
+        }
+    }
+}
+
+pub(crate) struct ArithmeticEncoder<'a, W: JfifWrite> {
+    writer: &'a mut JfifWriter<W>,
+
+    // Base of the coding interval (C register)
+    c: u32,
+    // Size of the coding interval (A register)
+    a: u32,
+    // Number of stacked 0xFF bytes which might overflow
+    sc: u32,
+    // Number of pending 0x00 bytes which might be discarded at the end
+    zc: u32,
+    // Bit shift counter until the next byte is written
+    ct: i32,
+    // Most recent output byte != 0xFF or -1 if empty
+    buffer: i32,
+
+    last_dc: [i32; 4],
+    dc_context: [usize; 4],
+
+    dc_stats: [[u8; DC_STAT_BINS]; NUM_CONDITIONING_TABLES],
+    ac_stats: [[u8; AC_STAT_BINS]; NUM_CONDITIONING_TABLES],
+    fixed_bin: u8,
+}
+
+impl<'a, W: JfifWrite> ArithmeticEncoder<'a, W> {
+    pub fn new(writer: &'a mut JfifWriter<W>) -> Self {
+        ArithmeticEncoder {
+            writer,
+            c: 0,
+            a: 0x10000,
+            sc: 0,
+            zc: 0,
+            ct: 11,
+            buffer: -1,
+            last_dc: [0; 4],
+            dc_context: [0; 4],
+            dc_stats: [[0; DC_STAT_BINS]; NUM_CONDITIONING_TABLES],
+            ac_stats: [[0; AC_STAT_BINS]; NUM_CONDITIONING_TABLES],
+            fixed_bin: FIXED_BIN,
+        }
+    }
+
+    /// Terminates the current interval and writes a restart marker
+    ///
+    /// The statistics and DC predictions are reset afterwards.
+    pub fn restart(&mut self, restart_num: u8) -> Result<(), EncodingError> {
+        self.finish()?;
+        self.writer.write_marker(Marker::RST(restart_num))?;
+
+        self.c = 0;
+        self.a = 0x10000;
+        self.sc = 0;
+        self.zc = 0;
+        self.ct = 11;
+        self.buffer = -1;
+        self.last_dc = [0; 4];
+        self.dc_context = [0; 4];
+        self.dc_stats = [[0; DC_STAT_BINS]; NUM_CONDITIONING_TABLES];
+        self.ac_stats = [[0; AC_STAT_BINS]; NUM_CONDITIONING_TABLES];
+
+        Ok(())
+    }
+
+    #[inline]
+    fn emit_byte(&mut self, value: u8) -> Result<(), EncodingError> {
+        self.writer.write_u8(value)
+    }
+
+    fn emit_zeros(&mut self) -> Result<(), EncodingError> {
+        while self.zc > 0 {
+            self.emit_byte(0x00)?;
+            self.zc -= 1;
+        }
+        Ok(())
+    }
+
+    // Write a byte which can't overflow anymore with byte stuffing
+    fn emit_stuffed(&mut self, value: u8) -> Result<(), EncodingError> {
+        self.emit_zeros()?;
+        self.emit_byte(value)?;
+        if value == 0xFF {
+            self.emit_byte(0x00)?;
+        }
+        Ok(())
+    }
+
+    // Output the buffered byte and all stacked 0xFF bytes
+    fn emit_stacked(&mut self) -> Result<(), EncodingError> {
+        if self.buffer == 0 {
+            self.zc += 1;
+        } else if self.buffer > 0 {
+            self.emit_stuffed(self.buffer as u8)?;
+        }
+
+        if self.sc > 0 {
+            self.emit_zeros()?;
+            while self.sc > 0 {
+                self.emit_byte(0xFF)?;
+                self.emit_byte(0x00)?;
+                self.sc -= 1;
+            }
+        }
+        Ok(())
+    }
+
+    // Propagate a carry over all stacked 0xFF bytes
+    fn emit_overflow(&mut self) -> Result<(), EncodingError> {
+        if self.buffer >= 0 {
+            self.emit_stuffed((self.buffer + 1) as u8)?;
+        }
+
+        // The carry converts the stacked 0xFF bytes to 0x00
+        self.zc += self.sc;
+        self.sc = 0;
+        Ok(())
+    }
+
+    /// Terminates the encoding of the current interval (D.1.8)
+    pub fn finish(&mut self) -> Result<(), EncodingError> {
+        // Find the value in the coding interval with the largest number of trailing zero bits
+        let temp = (self.a - 1 + self.c) & 0xFFFF0000;
+        if temp < self.c {
+            self.c = temp + 0x8000;
+        } else {
+            self.c = temp;
+        }
+
+        self.c <<= self.ct;
+
+        if self.c & 0xF8000000 != 0 {
+            self.emit_overflow()?;
+        } else {
+            self.emit_stacked()?;
+        }
+
+        // Output final bytes only if they are not 0x00
+        if self.c & 0x7FFF800 != 0 {
+            self.emit_zeros()?;
+
+            self.emit_stuffed(((self.c >> 19) & 0xFF) as u8)?;
+
+            if self.c & 0x7F800 != 0 {
+                self.emit_stuffed(((self.c >> 11) & 0xFF) as u8)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Encode a binary decision with the given statistics bin (D.1.4 - D.1.6)
+    fn encode(&mut self, st: &mut u8, value: bool) -> Result<(), EncodingError> {
+        let sv = *st;
+        let (qe, next_lps, next_mps, switch_mps) = QE_TABLE[usize::from(sv & 0x7F)];
+        let qe = u32::from(qe);
+        let mps = sv >> 7 == 1;
+
+        self.a -= qe;
+
+        if value != mps {
+            // Encode the less probable symbol
+            if self.a >= qe {
+                self.c += self.a;
+                self.a = qe;
+            }
+
+            let mps = if switch_mps {
+                (sv & 0x80) ^ 0x80
+            } else {
+                sv & 0x80
+            };
+            *st = mps | next_lps;
+        } else {
+            // Encode the more probable symbol
+            if self.a >= 0x8000 {
+                return Ok(());
+            }
+
+            // Conditional exchange if the interval size of the LPS is larger
+            if self.a < qe {
+                self.c += self.a;
+                self.a = qe;
+            }
+
+            *st = (sv & 0x80) | next_mps;
+        }
+
+        // Renormalization and data output
+        while self.a < 0x8000 {
+            self.a <<= 1;
+            self.c <<= 1;
+            self.ct -= 1;
+
+            if self.ct == 0 {
+                let temp = self.c >> 19;
+
+                if temp > 0xFF {
+                    self.emit_overflow()?;
+                    // The spacer bits in the C register guarantee that this can't be 0xFF
+                    self.buffer = (temp & 0xFF) as i32;
+                } else if temp == 0xFF {
+                    self.sc += 1;
+                } else {
+                    self.emit_stacked()?;
+                    self.buffer = temp as i32;
+                }
+
+                self.c &= 0x7FFFF;
+                self.ct += 8;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn encode_dc_stat(
+        &mut self,
+        table: usize,
+        bin: usize,
+        value: bool,
+    ) -> Result<(), EncodingError> {
+        let mut st = self.dc_stats[table][bin];
+        self.encode(&mut st, value)?;
+        self.dc_stats[table][bin] = st;
+        Ok(())
+    }
+
+    fn encode_ac_stat(
+        &mut self,
+        table: usize,
+        bin: usize,
+        value: bool,
+    ) -> Result<(), EncodingError> {
+        let mut st = self.ac_stats[table][bin];
+        self.encode(&mut st, value)?;
+        self.ac_stats[table][bin] = st;
+        Ok(())
+    }
+
+    fn encode_fixed(&mut self, value: bool) -> Result<(), EncodingError> {
+        let mut st = self.fixed_bin;
+        self.encode(&mut st, value)?;
+        self.fixed_bin = st;
+        Ok(())
+    }
+
+    /// Encode the DC coefficient of a block of the given component (F.1.4.1)
+    ///
+    /// The DC value is point transformed by `al`.
+    pub fn encode_dc(
+        &mut self,
+        block: &[i16; 64],
+        component: usize,
+        al: u8,
+        table: usize,
+        conditioning: &ArithmeticConditioning,
+    ) -> Result<(), EncodingError> {
+        let value = i32::from(block[0] >> al);
+
+        // Statistics bin S0 for DC coefficient coding (Table F.4)
+        let s0 = self.dc_context[component];
+
+        let diff = value - self.last_dc[component];
+
+        if diff == 0 {
+            self.encode_dc_stat(table, s0, false)?;
+            self.dc_context[component] = 0;
+            return Ok(());
+        }
+
+        self.last_dc[component] = value;
+        self.encode_dc_stat(table, s0, true)?;
+
+        // Sign of the difference
+        let mut st = if diff > 0 {
+            self.encode_dc_stat(table, s0 + 1, false)?;
+            self.dc_context[component] = 4;
+            s0 + 2
+        } else {
+            self.encode_dc_stat(table, s0 + 1, true)?;
+            self.dc_context[component] = 8;
+            s0 + 3
+        };
+
+        // Magnitude category
+        let v = diff.unsigned_abs() - 1;
+        let mut m = 0;
+
+        if v > 0 {
+            self.encode_dc_stat(table, st, true)?;
+            m = 1;
+            st = 20;
+
+            let mut v2 = v >> 1;
+            while v2 > 0 {
+                self.encode_dc_stat(table, st, true)?;
+                m <<= 1;
+                st += 1;
+                v2 >>= 1;
+            }
+        }
+        self.encode_dc_stat(table, st, false)?;
+
+        // Conditioning category for the next difference (F.1.4.4.1.2)
+        if m < (1 << conditioning.dc_lower) >> 1 {
+            self.dc_context[component] = 0;
+        } else if m > (1 << conditioning.dc_upper) >> 1 {
+            self.dc_context[component] += 8;
+        }
+
+        // Magnitude bit pattern
+        st += 14;
+        m >>= 1;
+        while m > 0 {
+            self.encode_dc_stat(table, st, m & v != 0)?;
+            m >>= 1;
+        }
+
+        Ok(())
+    }
+
+    /// Encode the bit `al` of the DC coefficient in a refinement scan (G.1.3.1)
+    pub fn encode_dc_refinement(&mut self, block: &[i16; 64], al: u8) -> Result<(), EncodingError> {
+        self.encode_fixed((block[0] >> al) & 1 == 1)
+    }
+
+    /// Encode the AC coefficients `start..end` of a block (F.1.4.2)
+    ///
+    /// The coefficients are point transformed by `al`.
+    pub fn encode_ac_first(
+        &mut self,
+        block: &[i16; 64],
+        start: usize,
+        end: usize,
+        al: u8,
+        table: usize,
+        conditioning: &ArithmeticConditioning,
+    ) -> Result<(), EncodingError> {
+        let transformed = |k: usize| (block[k].unsigned_abs() >> al) as u32;
+
+        // Position of the last non zero coefficient
+        let last = (start..end).rev().find(|&k| transformed(k) != 0);
+
+        let mut k = start;
+
+        if let Some(last) = last {
+            while k <= last {
+                let mut st = 3 * (k - 1);
+
+                // Not the end of block
+                self.encode_ac_stat(table, st, false)?;
+
+                while transformed(k) == 0 {
+                    self.encode_ac_stat(table, st + 1, false)?;
+                    st += 3;
+                    k += 1;
+                }
+
+                self.encode_ac_stat(table, st + 1, true)?;
+                self.encode_fixed(block[k] < 0)?;
+
+                st += 2;
+
+                // Magnitude category
+                let v = transformed(k) - 1;
+                let mut m = 0;
+
+                if v > 0 {
+                    self.encode_ac_stat(table, st, true)?;
+                    m = 1;
+
+                    let mut v2 = v >> 1;
+                    if v2 > 0 {
+                        self.encode_ac_stat(table, st, true)?;
+                        m <<= 1;
+                        st = if k <= usize::from(conditioning.ac_kx) {
+                            189
+                        } else {
+                            217
+                        };
+
+                        v2 >>= 1;
+                        while v2 > 0 {
+                            self.encode_ac_stat(table, st, true)?;
+                            m <<= 1;
+                            st += 1;
+                            v2 >>= 1;
+                        }
+                    }
+                }
+                self.encode_ac_stat(table, st, false)?;
+
+                // Magnitude bit pattern
+                st += 14;
+                m >>= 1;
+                while m > 0 {
+                    self.encode_ac_stat(table, st, m & v != 0)?;
+                    m >>= 1;
+                }
+
+                k += 1;
+            }
+        }
+
+        // End of block
+        if k < end {
+            self.encode_ac_stat(table, 3 * (k - 1), true)?;
+        }
+
+        Ok(())
+    }
+
+    /// Encode the bit `al` of the AC coefficients `start..end` in a refinement scan (G.1.3.3)
+    pub fn encode_ac_refinement(
+        &mut self,
+        block: &[i16; 64],
+        start: usize,
+        end: usize,
+        al: u8,
+        table: usize,
+    ) -> Result<(), EncodingError> {
+        let transformed = |k: usize| (block[k].unsigned_abs() >> al) as u32;
+
+        // Position of the last non zero coefficient
+        let last = (start..end).rev().find(|&k| transformed(k) != 0);
+
+        // Position of the last coefficient which was non zero in the previous stage
+        let last_previous = (start..end).rev().find(|&k| transformed(k) > 1);
+
+        let mut k = start;
+
+        if let Some(last) = last {
+            while k <= last {
+                let mut st = 3 * (k - 1);
+
+                if last_previous.map_or(true, |last_previous| k > last_previous) {
+                    // Not the end of block
+                    self.encode_ac_stat(table, st, false)?;
+                }
+
+                loop {
+                    let v = transformed(k);
+
+                    if v > 1 {
+                        // Correction bit of a previously non zero coefficient
+                        self.encode_ac_stat(table, st + 2, v & 1 == 1)?;
+                        break;
+                    } else if v == 1 {
+                        // Newly non zero coefficient
+                        self.encode_ac_stat(table, st + 1, true)?;
+                        self.encode_fixed(block[k] < 0)?;
+                        break;
+                    }
+
+                    self.encode_ac_stat(table, st + 1, false)?;
+                    st += 3;
+                    k += 1;
+                }
+
+                k += 1;
+            }
+        }
+
+        // End of block
+        if k < end {
+            self.encode_ac_stat(table, 3 * (k - 1), true)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Decoder for the arithmetic coded data of a restart interval
+///
+/// Only used to verify the encoder in tests. The decoding procedures are based on
+/// jdarith.c of the IJG libjpeg.
+#[cfg(test)]
+pub(crate) struct ArithmeticDecoder<'a> {
+    data: &'a [u8],
+    position: usize,
+
+    c: u32,
+    a: u32,
+    ct: i32,
+
+    last_dc: [i32; 4],
+    dc_context: [usize; 4],
+
+    dc_stats: [[u8; DC_STAT_BINS]; NUM_CONDITIONING_TABLES],
+    ac_stats: [[u8; AC_STAT_BINS]; NUM_CONDITIONING_TABLES],
+    fixed_bin: u8,
+
+    conditioning: [ArithmeticConditioning; NUM_CONDITIONING_TABLES],
+}
+
+#[cfg(test)]
+impl<'a> ArithmeticDecoder<'a> {
+    /// Creates a decoder for the entropy coded data of a restart interval without markers
+    pub fn new(
+        data: &'a [u8],
+        conditioning: [ArithmeticConditioning; NUM_CONDITIONING_TABLES],
+    ) -> Self {
+        ArithmeticDecoder {
+            data,
+            position: 0,
+            c: 0,
+            a: 0,
+            // Forces reading of 2 initial bytes
+            ct: -16,
+            last_dc: [0; 4],
+            dc_context: [0; 4],
+            dc_stats: [[0; DC_STAT_BINS]; NUM_CONDITIONING_TABLES],
+            ac_stats: [[0; AC_STAT_BINS]; NUM_CONDITIONING_TABLES],
+            fixed_bin: FIXED_BIN,
+            conditioning,
+        }
+    }
+
+    /// Reads the next byte without stuffed zero bytes
+    ///
+    /// Zeros are supplied after the end of the data.
+    fn read_byte(&mut self) -> u32 {
+        let value = match self.data.get(self.position) {
+            Some(&value) => value,
+            None => return 0,
+        };
+
+        self.position += 1;
+
+        if value == 0xFF {
+            assert_eq!(self.data.get(self.position), Some(&0), "Unexpected marker");
+            self.position += 1;
+        }
+
+        u32::from(value)
+    }
+
+    /// Decode a binary decision with the given statistics bin (D.2.4 - D.2.6)
+    fn decode(&mut self, st: &mut u8) -> bool {
+        // Renormalization and data input
+        while self.a < 0x8000 {
+            self.ct -= 1;
+
+            if self.ct < 0 {
+                self.c = (self.c << 8) | self.read_byte();
+                self.ct += 8;
+
+                if self.ct < 0 {
+                    self.ct += 1;
+
+                    if self.ct == 0 {
+                        // Got the 2 initial bytes
+                        self.a = 0x8000;
+                    }
+                }
+            }
+
+            self.a <<= 1;
+        }
+
+        let sv = *st;
+        let (qe, next_lps, next_mps, switch_mps) = QE_TABLE[usize::from(sv & 0x7F)];
+        let qe = u32::from(qe);
+
+        let mps = sv & 0x80;
+        let after_mps = mps | next_mps;
+        let after_lps = if switch_mps { mps ^ 0x80 } else { mps } | next_lps;
+
+        self.a -= qe;
+        let temp = self.a << self.ct;
+
+        let value = if self.c >= temp {
+            self.c -= temp;
+
+            // Conditional exchange of the less probable symbol
+            let exchange = self.a < qe;
+            self.a = qe;
+
+            if exchange {
+                *st = after_mps;
+                mps
+            } else {
+                *st = after_lps;
+                mps ^ 0x80
+            }
+        } else if self.a < 0x8000 {
+            // Conditional exchange of the more probable symbol
+            if self.a < qe {
+                *st = after_lps;
+                mps ^ 0x80
+            } else {
+                *st = after_mps;
+                mps
+            }
+        } else {
+            mps
+        };
+
+        value != 0
+    }
+
+    fn decode_dc_stat(&mut self, table: usize, bin: usize) -> bool {
+        let mut st = self.dc_stats[table][bin];
+        let value = self.decode(&mut st);
+        self.dc_stats[table][bin] = st;
+        value
+    }
+
+    fn decode_ac_stat(&mut self, table: usize, bin: usize) -> bool {
+        let mut st = self.ac_stats[table][bin];
+        let value = self.decode(&mut st);
+        self.ac_stats[table][bin] = st;
+        value
+    }
+
+    fn decode_fixed(&mut self) -> bool {
+        let mut st = self.fixed_bin;
+        let value = self.decode(&mut st);
+        self.fixed_bin = st;
+        value
+    }
+}
+
+#[cfg(test)]
+impl EntropyDecoder for ArithmeticDecoder<'_> {
+    /// Decode the DC coefficient of a block of the given component (F.2.4.1)
+    ///
+    /// The coefficient is point transformed by `al`.
+    fn decode_dc(&mut self, component: usize, table: usize, al: u8) -> i32 {
+        let s0 = self.dc_context[component];
+
+        if !self.decode_dc_stat(table, s0) {
+            self.dc_context[component] = 0;
+            return self.last_dc[component] << al;
+        }
+
+        let negative = self.decode_dc_stat(table, s0 + 1);
+        let mut st = s0 + 2 + usize::from(negative);
+
+        // Magnitude category
+        let mut m = 0;
+
+        if self.decode_dc_stat(table, st) {
+            m = 1;
+            st = 20;
+
+            while self.decode_dc_stat(table, st) {
+                m <<= 1;
+                assert!(m < 0x8000, "Magnitude overflow");
+                st += 1;
+            }
+        }
+
+        // Conditioning category for the next difference (F.1.4.4.1.2)
+        let (lower, upper) = self.conditioning[table].dc_bounds();
+        let sign_context = 4 * usize::from(negative);
+
+        self.dc_context[component] = if m < (1 << lower) >> 1 {
+            0
+        } else if m > (1 << upper) >> 1 {
+            12 + sign_context
+        } else {
+            4 + sign_context
+        };
+
+        // Magnitude bit pattern
+        let mut v = m;
+        st += 14;
+        m >>= 1;
+        while m > 0 {
+            if self.decode_dc_stat(table, st) {
+                v |= m;
+            }
+            m >>= 1;
+        }
+
+        let diff = v + 1;
+        self.last_dc[component] += if negative { -diff } else { diff };
+
+        self.last_dc[component] << al
+    }
+
+    /// Decode the bit `al` of the DC coefficient in a refinement scan (G.2.3)
+    fn decode_dc_refinement(&mut self, coefficient: &mut i32, al: u8) {
+        if self.decode_fixed() {
+            *coefficient |= 1 << al;
+        }
+    }
+
+    /// Decode the AC coefficients `start..end` of a block in zigzag order (F.2.4.2)
+    ///
+    /// The coefficients are point transformed by `al`.
+    fn decode_ac_first(
+        &mut self,
+        block: &mut [i32; 64],
+        start: usize,
+        end: usize,
+        al: u8,
+        table: usize,
+    ) {
+        let kx = usize::from(self.conditioning[table].ac_kx());
+
+        let mut k = start;
+
+        while k < end {
+            let mut st = 3 * (k - 1);
+
+            // End of block
+            if self.decode_ac_stat(table, st) {
+                break;
+            }
+
+            while !self.decode_ac_stat(table, st + 1) {
+                st += 3;
+                k += 1;
+                assert!(k < end, "Spectral overflow");
+            }
+
+            let negative = self.decode_fixed();
+            st += 2;
+
+            // Magnitude category
+            let mut m = 0;
+
+            if self.decode_ac_stat(table, st) {
+                m = 1;
+
+                if self.decode_ac_stat(table, st) {
+                    m <<= 1;
+                    st = if k <= kx { 189 } else { 217 };
+
+                    while self.decode_ac_stat(table, st) {
+                        m <<= 1;
+                        assert!(m < 0x8000, "Magnitude overflow");
+                        st += 1;
+                    }
+                }
+            }
+
+            // Magnitude bit pattern
+            let mut v = m;
+            st += 14;
+            m >>= 1;
+            while m > 0 {
+                if self.decode_ac_stat(table, st) {
+                    v |= m;
+                }
+                m >>= 1;
+            }
+
+            let value = if negative { -(v + 1) } else { v + 1 };
+            block[k] = value << al;
+
+            k += 1;
+        }
+    }
+
+    /// Decode the bit `al` of the AC coefficients `start..end` in a refinement scan (G.2.3)
+    fn decode_ac_refinement(
+        &mut self,
+        block: &mut [i32; 64],
+        start: usize,
+        end: usize,
+        al: u8,
+        table: usize,
+    ) {
+        // End of block position of the previous stage
+        let last_previous = (1..end).rev().find(|&k| block[k] != 0).unwrap_or(0);
+
+        let mut k = start;
+
+        while k < end {
+            let mut st = 3 * (k - 1);
+
+            if k > last_previous && self.decode_ac_stat(table, st) {
+                break;
+            }
+
+            loop {
+                let coefficient = &mut block[k];
+
+                if *coefficient != 0 {
+                    // Correction bit of a previously non zero coefficient
+                    if self.decode_ac_stat(table, st + 2) {
+                        if *coefficient < 0 {
+                            *coefficient -= 1 << al;
+                        } else {
+                            *coefficient += 1 << al;
+                        }
+                    }
+                    break;
+                }
+
+                if self.decode_ac_stat(table, st + 1) {
+                    // Newly non zero coefficient
+                    let value = if self.decode_fixed() { -1 } else { 1 };
+                    *coefficient = value << al;
+                    break;
+                }
+
+                st += 3;
+                k += 1;
+                assert!(k < end, "Spectral overflow");
+            }
+
+            k += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ArithmeticConditioning;
+
+    #[test]
+    fn test_conditioning() {
+        assert_eq!(
+            ArithmeticConditioning::new(0, 1, 5),
+            Some(ArithmeticConditioning::default())
+        );
+
+        let conditioning = ArithmeticConditioning::new(2, 15, 63).unwrap();
+        assert_eq!(conditioning.dc_bounds(), (2, 15));
+        assert_eq!(conditioning.ac_kx(), 63);
+        assert_eq!(conditioning.dc_value(), 0xF2);
+
+        assert_eq!(ArithmeticConditioning::new(3, 2, 5), None);
+        assert_eq!(ArithmeticConditioning::new(0, 16, 5), None);
+        assert_eq!(ArithmeticConditioning::new(0, 1, 0), None);
+        assert_eq!(ArithmeticConditioning::new(0, 1, 64), None);
+    }
+}
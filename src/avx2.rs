@@ -1,8 +1,10 @@
 mod fdct;
+mod idct;
 mod ycbcr;
 
 use crate::encoder::Operations;
 pub use fdct::fdct_avx2;
+pub use idct::idct_avx2;
 pub(crate) use ycbcr::*;
 
 pub(crate) struct AVX2Operations;
@@ -12,4 +14,9 @@ impl Operations for AVX2Operations {
     fn fdct(data: &mut [i16; 64]) {
         fdct_avx2(data);
     }
+
+    #[inline(always)]
+    fn idct(data: &mut [i16; 64]) {
+        idct_avx2(data);
+    }
 }
@@ -0,0 +1,207 @@
+/*
+ * Inverse DCT with AVX2 instructions
+ *
+ * This computes the same integer algorithm as the scalar port of libjpeg's jidctint.c
+ * in idct.rs. The eight columns (or rows) of a block are processed at once in 32 bit
+ * lanes, so the results are identical as long as the coefficients are in the range
+ * produced by the quantization of 8 bit images.
+ */
+
+#[cfg(target_arch = "x86")]
+use core::arch::x86::{
+    __m128i, __m256i, _mm256_add_epi32, _mm256_cvtepi16_epi32, _mm256_mullo_epi32,
+    _mm256_packs_epi32, _mm256_permute2x128_si256, _mm256_permute4x64_epi64, _mm256_set1_epi32,
+    _mm256_slli_epi32, _mm256_srai_epi32, _mm256_storeu_si256, _mm256_sub_epi32,
+    _mm256_unpackhi_epi32, _mm256_unpackhi_epi64, _mm256_unpacklo_epi32, _mm256_unpacklo_epi64,
+    _mm_loadu_si128,
+};
+
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::{
+    __m128i, __m256i, _mm256_add_epi32, _mm256_cvtepi16_epi32, _mm256_mullo_epi32,
+    _mm256_packs_epi32, _mm256_permute2x128_si256, _mm256_permute4x64_epi64, _mm256_set1_epi32,
+    _mm256_slli_epi32, _mm256_srai_epi32, _mm256_storeu_si256, _mm256_sub_epi32,
+    _mm256_unpackhi_epi32, _mm256_unpackhi_epi64, _mm256_unpacklo_epi32, _mm256_unpacklo_epi64,
+    _mm_loadu_si128,
+};
+
+const CONST_BITS: i32 = 13;
+const PASS1_BITS: i32 = 2;
+
+// FIX(0.298631336)
+const F_0_298: i32 = 2446;
+// FIX(0.390180644)
+const F_0_390: i32 = 3196;
+// FIX(0.541196100)
+const F_0_541: i32 = 4433;
+// FIX(0.765366865)
+const F_0_765: i32 = 6270;
+// FIX(0.899976223)
+const F_0_899: i32 = 7373;
+// FIX(1.175875602)
+const F_1_175: i32 = 9633;
+// FIX(1.501321110)
+const F_1_501: i32 = 12299;
+// FIX(1.847759065)
+const F_1_847: i32 = 15137;
+// FIX(1.961570560)
+const F_1_961: i32 = 16069;
+// FIX(2.053119869)
+const F_2_053: i32 = 16819;
+// FIX(2.562915447)
+const F_2_562: i32 = 20995;
+// FIX(3.072711026)
+const F_3_072: i32 = 25172;
+
+const DESCALE_P1: i32 = CONST_BITS - PASS1_BITS;
+const DESCALE_P2: i32 = CONST_BITS + PASS1_BITS + 3;
+
+#[inline(always)]
+pub fn idct_avx2(data: &mut [i16; 64]) {
+    unsafe {
+        idct_avx2_internal(data);
+    }
+}
+
+#[target_feature(enable = "avx2")]
+unsafe fn idct_avx2_internal(data: &mut [i16; 64]) {
+    #[inline(always)]
+    unsafe fn mul(value: __m256i, constant: i32) -> __m256i {
+        _mm256_mullo_epi32(value, _mm256_set1_epi32(constant))
+    }
+
+    // Transposes the 8x8 block of 32 bit values in the rows
+    #[inline(always)]
+    unsafe fn do_transpose(rows: [__m256i; 8]) -> [__m256i; 8] {
+        // t0=(00 10 01 11  04 14 05 15)
+        // t1=(02 12 03 13  06 16 07 17)
+        let t0 = _mm256_unpacklo_epi32(rows[0], rows[1]);
+        let t1 = _mm256_unpackhi_epi32(rows[0], rows[1]);
+        let t2 = _mm256_unpacklo_epi32(rows[2], rows[3]);
+        let t3 = _mm256_unpackhi_epi32(rows[2], rows[3]);
+        let t4 = _mm256_unpacklo_epi32(rows[4], rows[5]);
+        let t5 = _mm256_unpackhi_epi32(rows[4], rows[5]);
+        let t6 = _mm256_unpacklo_epi32(rows[6], rows[7]);
+        let t7 = _mm256_unpackhi_epi32(rows[6], rows[7]);
+
+        // u0=(00 10 20 30  04 14 24 34)
+        // u1=(01 11 21 31  05 15 25 35)
+        let u0 = _mm256_unpacklo_epi64(t0, t2);
+        let u1 = _mm256_unpackhi_epi64(t0, t2);
+        let u2 = _mm256_unpacklo_epi64(t1, t3);
+        let u3 = _mm256_unpackhi_epi64(t1, t3);
+        let u4 = _mm256_unpacklo_epi64(t4, t6);
+        let u5 = _mm256_unpackhi_epi64(t4, t6);
+        let u6 = _mm256_unpacklo_epi64(t5, t7);
+        let u7 = _mm256_unpackhi_epi64(t5, t7);
+
+        [
+            _mm256_permute2x128_si256(u0, u4, 0x20),
+            _mm256_permute2x128_si256(u1, u5, 0x20),
+            _mm256_permute2x128_si256(u2, u6, 0x20),
+            _mm256_permute2x128_si256(u3, u7, 0x20),
+            _mm256_permute2x128_si256(u0, u4, 0x31),
+            _mm256_permute2x128_si256(u1, u5, 0x31),
+            _mm256_permute2x128_si256(u2, u6, 0x31),
+            _mm256_permute2x128_si256(u3, u7, 0x31),
+        ]
+    }
+
+    // One dimensional inverse DCT of the values in the lanes of the inputs
+    #[inline(always)]
+    unsafe fn do_idct(first_pass: bool, input: [__m256i; 8]) -> [__m256i; 8] {
+        // Even part
+        let z2 = input[2];
+        let z3 = input[6];
+
+        let z1 = mul(_mm256_add_epi32(z2, z3), F_0_541);
+        let tmp2 = _mm256_add_epi32(z1, mul(z3, -F_1_847));
+        let tmp3 = _mm256_add_epi32(z1, mul(z2, F_0_765));
+
+        let tmp0 = _mm256_slli_epi32(_mm256_add_epi32(input[0], input[4]), CONST_BITS);
+        let tmp1 = _mm256_slli_epi32(_mm256_sub_epi32(input[0], input[4]), CONST_BITS);
+
+        let even = [
+            _mm256_add_epi32(tmp0, tmp3),
+            _mm256_add_epi32(tmp1, tmp2),
+            _mm256_sub_epi32(tmp1, tmp2),
+            _mm256_sub_epi32(tmp0, tmp3),
+        ];
+
+        // Odd part
+        let tmp0 = input[7];
+        let tmp1 = input[5];
+        let tmp2 = input[3];
+        let tmp3 = input[1];
+
+        let z1 = _mm256_add_epi32(tmp0, tmp3);
+        let z2 = _mm256_add_epi32(tmp1, tmp2);
+        let z3 = _mm256_add_epi32(tmp0, tmp2);
+        let z4 = _mm256_add_epi32(tmp1, tmp3);
+        let z5 = mul(_mm256_add_epi32(z3, z4), F_1_175);
+
+        let tmp0 = mul(tmp0, F_0_298);
+        let tmp1 = mul(tmp1, F_2_053);
+        let tmp2 = mul(tmp2, F_3_072);
+        let tmp3 = mul(tmp3, F_1_501);
+        let z1 = mul(z1, -F_0_899);
+        let z2 = mul(z2, -F_2_562);
+        let z3 = _mm256_add_epi32(mul(z3, -F_1_961), z5);
+        let z4 = _mm256_add_epi32(mul(z4, -F_0_390), z5);
+
+        let odd = [
+            _mm256_add_epi32(_mm256_add_epi32(tmp3, z1), z4),
+            _mm256_add_epi32(_mm256_add_epi32(tmp2, z2), z3),
+            _mm256_add_epi32(_mm256_add_epi32(tmp1, z2), z4),
+            _mm256_add_epi32(_mm256_add_epi32(tmp0, z1), z3),
+        ];
+
+        // Final butterfly with rounding right shift
+        let descale = |value: __m256i| {
+            if first_pass {
+                _mm256_srai_epi32(
+                    _mm256_add_epi32(value, _mm256_set1_epi32(1 << (DESCALE_P1 - 1))),
+                    DESCALE_P1,
+                )
+            } else {
+                _mm256_srai_epi32(
+                    _mm256_add_epi32(value, _mm256_set1_epi32(1 << (DESCALE_P2 - 1))),
+                    DESCALE_P2,
+                )
+            }
+        };
+
+        [
+            descale(_mm256_add_epi32(even[0], odd[0])),
+            descale(_mm256_add_epi32(even[1], odd[1])),
+            descale(_mm256_add_epi32(even[2], odd[2])),
+            descale(_mm256_add_epi32(even[3], odd[3])),
+            descale(_mm256_sub_epi32(even[3], odd[3])),
+            descale(_mm256_sub_epi32(even[2], odd[2])),
+            descale(_mm256_sub_epi32(even[1], odd[1])),
+            descale(_mm256_sub_epi32(even[0], odd[0])),
+        ]
+    }
+
+    let in_data = data.as_ptr() as *const __m128i;
+
+    let mut rows = [_mm256_set1_epi32(0); 8];
+    for (i, row) in rows.iter_mut().enumerate() {
+        *row = _mm256_cvtepi16_epi32(_mm_loadu_si128(in_data.add(i)));
+    }
+
+    // ---- Pass 1: process columns, the lanes of the rows.
+    let rows = do_idct(true, rows);
+
+    // ---- Pass 2: process rows, the lanes of the transposed rows.
+    let columns = do_idct(false, do_transpose(rows));
+    let rows = do_transpose(columns);
+
+    let out_data = data.as_mut_ptr() as *mut __m256i;
+
+    for i in 0..4 {
+        // packs interleaves the 128 bit lanes of both rows, which the permute reverts
+        let packed = _mm256_packs_epi32(rows[2 * i], rows[2 * i + 1]);
+        _mm256_storeu_si256(out_data.add(i), _mm256_permute4x64_epi64(packed, 0xD8));
+    }
+}
@@ -1,37 +1,167 @@
 #[cfg(target_arch = "x86")]
 use core::arch::x86::{
-    __m256i, _mm256_add_epi32, _mm256_mullo_epi32, _mm256_set1_epi32, _mm256_set_epi32,
-    _mm256_srli_epi32, _mm256_sub_epi32,
+    __m256i, _mm256_add_epi32, _mm256_add_ps, _mm256_andnot_si256, _mm256_cmpeq_epi32,
+    _mm256_cvtepi32_ps, _mm256_cvttps_epi32, _mm256_div_ps, _mm256_min_epi32, _mm256_min_ps,
+    _mm256_mul_ps, _mm256_mullo_epi32, _mm256_set1_epi32, _mm256_set1_ps, _mm256_set_epi32,
+    _mm256_setzero_si256, _mm256_srli_epi32, _mm256_sub_epi32,
 };
 
 #[cfg(target_arch = "x86_64")]
 use core::arch::x86_64::{
-    __m256i, _mm256_add_epi32, _mm256_mullo_epi32, _mm256_set1_epi32, _mm256_set_epi32,
-    _mm256_srli_epi32, _mm256_sub_epi32,
+    __m256i, _mm256_add_epi32, _mm256_add_ps, _mm256_andnot_si256, _mm256_cmpeq_epi32,
+    _mm256_cvtepi32_ps, _mm256_cvttps_epi32, _mm256_div_ps, _mm256_min_epi32, _mm256_min_ps,
+    _mm256_mul_ps, _mm256_mullo_epi32, _mm256_set1_epi32, _mm256_set1_ps, _mm256_set_epi32,
+    _mm256_setzero_si256, _mm256_srli_epi32, _mm256_sub_epi32,
 };
 
 use alloc::vec::Vec;
 
-use crate::{rgb_to_ycbcr, ImageBuffer, JpegColorType};
+use crate::{AlphaPolicy, ColorMatrix, ImageBuffer, JpegColorType};
+
+/// Loads a value of 8 pixels with `num_colors` values each in reverse order
+#[inline(always)]
+unsafe fn load8(data: *const u8, num_colors: usize) -> __m256i {
+    _mm256_set_epi32(
+        *data as i32,
+        *data.add(num_colors) as i32,
+        *data.add(2 * num_colors) as i32,
+        *data.add(3 * num_colors) as i32,
+        *data.add(4 * num_colors) as i32,
+        *data.add(5 * num_colors) as i32,
+        *data.add(6 * num_colors) as i32,
+        *data.add(7 * num_colors) as i32,
+    )
+}
+
+/// Divides values in the range of 0..=255*255 by 255 with rounding
+#[inline(always)]
+unsafe fn div_255(value: __m256i) -> __m256i {
+    let value = _mm256_add_epi32(value, _mm256_set1_epi32(128));
+    _mm256_srli_epi32(_mm256_add_epi32(value, _mm256_srli_epi32(value, 8)), 8)
+}
+
+/// Applies the alpha policy to the color values of 8 pixels
+///
+/// This computes the same results as [AlphaPolicy::apply] or, for premultiplied color
+/// values, [AlphaPolicy::apply_premultiplied].
+#[target_feature(enable = "avx2")]
+unsafe fn apply_alpha(
+    policy: AlphaPolicy,
+    premultiplied: bool,
+    rgb: [__m256i; 3],
+    alpha: __m256i,
+) -> [__m256i; 3] {
+    match policy {
+        AlphaPolicy::Ignore => rgb,
+        AlphaPolicy::Composite(background) => {
+            let inverse = _mm256_sub_epi32(_mm256_set1_epi32(255), alpha);
+
+            let mut result = rgb;
+            for (value, background) in result.iter_mut().zip(background) {
+                let background =
+                    _mm256_mullo_epi32(_mm256_set1_epi32(i32::from(background)), inverse);
+
+                *value = if premultiplied {
+                    _mm256_min_epi32(
+                        _mm256_add_epi32(*value, div_255(background)),
+                        _mm256_set1_epi32(255),
+                    )
+                } else {
+                    div_255(_mm256_add_epi32(
+                        _mm256_mullo_epi32(*value, alpha),
+                        background,
+                    ))
+                };
+            }
+            result
+        }
+        AlphaPolicy::Unpremultiply => {
+            let transparent = _mm256_cmpeq_epi32(alpha, _mm256_setzero_si256());
+            let alpha = _mm256_cvtepi32_ps(alpha);
+
+            let mut result = rgb;
+            for value in result.iter_mut() {
+                let scaled = _mm256_mul_ps(_mm256_cvtepi32_ps(*value), _mm256_set1_ps(255.0));
+                let scaled = _mm256_div_ps(scaled, alpha);
+                let scaled = _mm256_min_ps(
+                    _mm256_add_ps(scaled, _mm256_set1_ps(0.5)),
+                    _mm256_set1_ps(255.0),
+                );
+                *value = _mm256_andnot_si256(transparent, _mm256_cvttps_epi32(scaled));
+            }
+            result
+        }
+    }
+}
 
 macro_rules! ycbcr_image_avx2 {
-    ($name:ident, $num_colors:expr, $o1:expr, $o2:expr, $o3:expr) => {
-        pub(crate) struct $name<'a>(pub &'a [u8], pub u16, pub u16);
+    ($name:ident, 3, $o1:expr, $o2:expr, $o3:expr) => {
+        pub(crate) struct $name<'a>(pub &'a [u8], pub u16, pub u16, pub usize, pub ColorMatrix);
+
+        impl<'a> $name<'a> {
+            #[inline(always)]
+            unsafe fn load_rgb(&self, data: *const u8) -> [__m256i; 3] {
+                [
+                    load8(data.add($o1), 3),
+                    load8(data.add($o2), 3),
+                    load8(data.add($o3), 3),
+                ]
+            }
+
+            #[inline(always)]
+            fn get_rgb(&self, pixel: &[u8]) -> (u8, u8, u8) {
+                (pixel[$o1], pixel[$o2], pixel[$o3])
+            }
+        }
+
+        ycbcr_image_avx2!(@impl $name, 3);
+    };
+    ($name:ident, 4, $o1:expr, $o2:expr, $o3:expr, $oa:expr, $premultiplied:expr) => {
+        pub(crate) struct $name<'a>(
+            pub &'a [u8],
+            pub u16,
+            pub u16,
+            pub usize,
+            pub ColorMatrix,
+            pub AlphaPolicy,
+        );
+
+        impl<'a> $name<'a> {
+            #[inline(always)]
+            unsafe fn load_rgb(&self, data: *const u8) -> [__m256i; 3] {
+                let rgb = [
+                    load8(data.add($o1), 4),
+                    load8(data.add($o2), 4),
+                    load8(data.add($o3), 4),
+                ];
+
+                if self.5 == AlphaPolicy::Ignore {
+                    rgb
+                } else {
+                    apply_alpha(self.5, $premultiplied, rgb, load8(data.add($oa), 4))
+                }
+            }
 
+            #[inline(always)]
+            fn get_rgb(&self, pixel: &[u8]) -> (u8, u8, u8) {
+                let (r, g, b, a) = (pixel[$o1], pixel[$o2], pixel[$o3], pixel[$oa]);
+
+                if $premultiplied {
+                    self.5.apply_premultiplied(r, g, b, a)
+                } else {
+                    self.5.apply(r, g, b, a)
+                }
+            }
+        }
+
+        ycbcr_image_avx2!(@impl $name, 4);
+    };
+    (@impl $name:ident, $num_colors:expr) => {
         impl<'a> $name<'a> {
             #[target_feature(enable = "avx2")]
             unsafe fn fill_buffers_avx2(&self, y: u16, buffers: &mut [Vec<u8>; 4]) {
-                unsafe fn load3(data: *const u8) -> __m256i {
-                    _mm256_set_epi32(
-                        *data as i32,
-                        *data.offset(1 * $num_colors) as i32,
-                        *data.offset(2 * $num_colors) as i32,
-                        *data.offset(3 * $num_colors) as i32,
-                        *data.offset(4 * $num_colors) as i32,
-                        *data.offset(5 * $num_colors) as i32,
-                        *data.offset(6 * $num_colors) as i32,
-                        *data.offset(7 * $num_colors) as i32,
-                    )
+                for buffer in buffers.iter_mut().take(3) {
+                    buffer.reserve(self.width() as usize);
                 }
 
                 let mut y_buffer = buffers[0].as_mut_ptr().add(buffers[0].len());
@@ -41,27 +171,27 @@ macro_rules! ycbcr_image_avx2 {
                 let mut cr_buffer = buffers[2].as_mut_ptr().add(buffers[2].len());
                 buffers[2].set_len(buffers[2].len() + self.width() as usize);
 
-                let ymulr = _mm256_set1_epi32(19595);
-                let ymulg = _mm256_set1_epi32(38470);
-                let ymulb = _mm256_set1_epi32(7471);
+                let coefficients = self.4.coefficients();
+
+                let ymulr = _mm256_set1_epi32(coefficients.y[0]);
+                let ymulg = _mm256_set1_epi32(coefficients.y[1]);
+                let ymulb = _mm256_set1_epi32(coefficients.y[2]);
+
+                let cbmulr = _mm256_set1_epi32(coefficients.cb[0]);
+                let cbmulg = _mm256_set1_epi32(coefficients.cb[1]);
+                let cbmulb = _mm256_set1_epi32(coefficients.cb[2]);
 
-                let cbmulr = _mm256_set1_epi32(-11059);
-                let cbmulg = _mm256_set1_epi32(21709);
-                let cbmulb = _mm256_set1_epi32(32768);
+                let crmulr = _mm256_set1_epi32(coefficients.cr[0]);
+                let crmulg = _mm256_set1_epi32(coefficients.cr[1]);
+                let crmulb = _mm256_set1_epi32(coefficients.cr[2]);
 
-                let crmulr = _mm256_set1_epi32(32768);
-                let crmulg = _mm256_set1_epi32(27439);
-                let crmulb = _mm256_set1_epi32(5329);
+                let y_offset = _mm256_set1_epi32((coefficients.y_offset << 16) + 0x7FFF);
+                let c_offset = _mm256_set1_epi32((128 << 16) + 0x7FFF);
 
-                let mut data = self
-                    .0
-                    .as_ptr()
-                    .offset((y as isize * self.1 as isize * $num_colors));
+                let mut data = self.0.as_ptr().add(usize::from(y) * self.3);
 
                 for _ in 0..self.width() / 8 {
-                    let r = load3(data.offset($o1));
-                    let g = load3(data.offset($o2));
-                    let b = load3(data.offset($o3));
+                    let [r, g, b] = self.load_rgb(data);
 
                     data = data.add($num_colors * 8);
 
@@ -70,7 +200,7 @@ macro_rules! ycbcr_image_avx2 {
                     let yb = _mm256_mullo_epi32(ymulb, b);
 
                     let y = _mm256_add_epi32(_mm256_add_epi32(yr, yg), yb);
-                    let y = _mm256_add_epi32(y, _mm256_set1_epi32(0x7FFF));
+                    let y = _mm256_add_epi32(y, y_offset);
                     let y = _mm256_srli_epi32(y, 16);
                     let y: [i32; 8] = core::mem::transmute(y);
 
@@ -78,9 +208,8 @@ macro_rules! ycbcr_image_avx2 {
                     let cbg = _mm256_mullo_epi32(cbmulg, g);
                     let cbb = _mm256_mullo_epi32(cbmulb, b);
 
-                    let cb = _mm256_add_epi32(_mm256_sub_epi32(cbr, cbg), cbb);
-                    let cb = _mm256_add_epi32(cb, _mm256_set1_epi32(128 << 16));
-                    let cb = _mm256_add_epi32(cb, _mm256_set1_epi32(0x7FFF));
+                    let cb = _mm256_add_epi32(_mm256_add_epi32(cbr, cbg), cbb);
+                    let cb = _mm256_add_epi32(cb, c_offset);
                     let cb = _mm256_srli_epi32(cb, 16);
                     let cb: [i32; 8] = core::mem::transmute(cb);
 
@@ -88,9 +217,8 @@ macro_rules! ycbcr_image_avx2 {
                     let crg = _mm256_mullo_epi32(crmulg, g);
                     let crb = _mm256_mullo_epi32(crmulb, b);
 
-                    let cr = _mm256_sub_epi32(_mm256_sub_epi32(crr, crg), crb);
-                    let cr = _mm256_add_epi32(cr, _mm256_set1_epi32(128 << 16));
-                    let cr = _mm256_add_epi32(cr, _mm256_set1_epi32(0x7FFF));
+                    let cr = _mm256_add_epi32(_mm256_add_epi32(crr, crg), crb);
+                    let cr = _mm256_add_epi32(cr, c_offset);
                     let cr = _mm256_srli_epi32(cr, 16);
                     let cr: [i32; 8] = core::mem::transmute(cr);
 
@@ -111,8 +239,8 @@ macro_rules! ycbcr_image_avx2 {
                 }
 
                 for _ in 0..self.width() % 8 {
-                    let (y, cb, cr) =
-                        rgb_to_ycbcr(*data.offset($o1), *data.offset($o2), *data.offset($o3));
+                    let (r, g, b) = self.get_rgb(core::slice::from_raw_parts(data, $num_colors));
+                    let (y, cb, cr) = coefficients.rgb_to_ycbcr(r, g, b);
 
                     data = data.add($num_colors);
 
@@ -152,6 +280,12 @@ macro_rules! ycbcr_image_avx2 {
 }
 
 ycbcr_image_avx2!(RgbImageAVX2, 3, 0, 1, 2);
-ycbcr_image_avx2!(RgbaImageAVX2, 4, 0, 1, 2);
+ycbcr_image_avx2!(RgbaImageAVX2, 4, 0, 1, 2, 3, false);
 ycbcr_image_avx2!(BgrImageAVX2, 3, 2, 1, 0);
-ycbcr_image_avx2!(BgraImageAVX2, 4, 2, 1, 0);
+ycbcr_image_avx2!(BgraImageAVX2, 4, 2, 1, 0, 3, false);
+ycbcr_image_avx2!(ArgbImageAVX2, 4, 1, 2, 3, 0, false);
+ycbcr_image_avx2!(AbgrImageAVX2, 4, 3, 2, 1, 0, false);
+ycbcr_image_avx2!(RgbaPremultipliedImageAVX2, 4, 0, 1, 2, 3, true);
+ycbcr_image_avx2!(BgraPremultipliedImageAVX2, 4, 2, 1, 0, 3, true);
+ycbcr_image_avx2!(ArgbPremultipliedImageAVX2, 4, 1, 2, 3, 0, true);
+ycbcr_image_avx2!(AbgrPremultipliedImageAVX2, 4, 3, 2, 1, 0, true);
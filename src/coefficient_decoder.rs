@@ -0,0 +1,604 @@
+/*
+ * Decoder for the quantized coefficients of encoded images
+ *
+ * Only used in tests to verify the entropy coded data of encoding processes which aren't
+ * supported by the decoder used in the other tests. The decoder follows the procedures of
+ * Annex F, G and J independently of the encoder, e.g. for the order of the blocks in a scan.
+ */
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+#[cfg(feature = "arithmetic")]
+use crate::arithmetic::{ArithmeticDecoder, NUM_CONDITIONING_TABLES};
+use crate::hierarchical::Plane;
+use crate::huffman::{HuffmanDecoder, HuffmanTable};
+use crate::idct::idct_wide;
+use crate::writer::ZIGZAG;
+#[cfg(feature = "arithmetic")]
+use crate::ArithmeticConditioning;
+
+/// Decoding procedures of an entropy coder for the restart intervals of a scan
+///
+/// Blocks are passed with the coefficients in zigzag order.
+pub(crate) trait EntropyDecoder {
+    /// Returns the DC coefficient of the next block of the component
+    fn decode_dc(&mut self, component: usize, table: usize, al: u8) -> i32;
+
+    fn decode_dc_refinement(&mut self, coefficient: &mut i32, al: u8);
+
+    fn decode_ac_first(
+        &mut self,
+        block: &mut [i32; 64],
+        start: usize,
+        end: usize,
+        al: u8,
+        table: usize,
+    );
+
+    fn decode_ac_refinement(
+        &mut self,
+        block: &mut [i32; 64],
+        start: usize,
+        end: usize,
+        al: u8,
+        table: usize,
+    );
+}
+
+/// A component of a decoded frame
+pub(crate) struct DecodedComponent {
+    pub id: u8,
+    pub horizontal_sampling_factor: u8,
+    pub vertical_sampling_factor: u8,
+
+    /// Quantization table in natural order
+    pub quantization_table: [u16; 64],
+
+    /// Number of block columns of the component without the padding to complete MCUs
+    pub cols: usize,
+
+    /// Number of block rows of the component without the padding to complete MCUs
+    pub rows: usize,
+
+    /// Blocks of the component in row major order with the coefficients in natural order
+    pub blocks: Vec<[i32; 64]>,
+}
+
+impl DecodedComponent {
+    /// Returns the samples of the component like a decoder would reconstruct them
+    ///
+    /// The samples are cropped to `width` x `height`.
+    pub fn reconstruct(&self, precision: u8, width: usize, height: usize) -> Plane {
+        let level_shift = 1 << (precision - 1);
+        let max_value = (1 << precision) - 1;
+
+        let mut samples = vec![0i16; width * height];
+
+        for (index, block) in self.blocks.iter().enumerate() {
+            let start_x = (index % self.cols) * 8;
+            let start_y = (index / self.cols) * 8;
+
+            let mut coefficients = [0i32; 64];
+            for (i, coefficient) in coefficients.iter_mut().enumerate() {
+                *coefficient = block[i] * i32::from(self.quantization_table[i]);
+            }
+
+            let values = idct_wide(&coefficients);
+
+            for y in start_y..(start_y + 8).min(height) {
+                for x in start_x..(start_x + 8).min(width) {
+                    let value = values[(y - start_y) * 8 + x - start_x] + level_shift;
+                    samples[y * width + x] = value.clamp(0, max_value) as i16;
+                }
+            }
+        }
+
+        Plane::new(width, height, samples)
+    }
+}
+
+/// A decoded frame
+pub(crate) struct DecodedFrame {
+    /// The SOF marker of the frame
+    pub marker: u8,
+    pub precision: u8,
+    pub width: u16,
+    pub height: u16,
+    pub components: Vec<DecodedComponent>,
+}
+
+/// State of a frame while its scans are decoded
+struct Frame {
+    marker: u8,
+    precision: u8,
+    width: u16,
+    height: u16,
+    mcu_cols: usize,
+    mcu_rows: usize,
+    components: Vec<FrameComponent>,
+}
+
+struct FrameComponent {
+    id: u8,
+    h: usize,
+    v: usize,
+    quantization_table_index: usize,
+    quantization_table: Option<[u16; 64]>,
+    cols: usize,
+    rows: usize,
+
+    /// Blocks including the padding of interleaved scans with the coefficients in zigzag order
+    blocks: Vec<[i32; 64]>,
+    stride: usize,
+}
+
+impl Frame {
+    fn is_arithmetic(&self) -> bool {
+        self.marker >= 0xC8
+    }
+
+    fn is_progressive(&self) -> bool {
+        matches!(self.marker & 0x03, 2)
+    }
+
+    fn is_differential(&self) -> bool {
+        self.marker & 0x04 != 0
+    }
+
+    fn finish(self) -> DecodedFrame {
+        let components = self
+            .components
+            .into_iter()
+            .map(|component| {
+                let mut blocks = Vec::with_capacity(component.cols * component.rows);
+
+                for y in 0..component.rows {
+                    for x in 0..component.cols {
+                        let zigzag = &component.blocks[y * component.stride + x];
+
+                        let mut block = [0i32; 64];
+                        for (k, &value) in zigzag.iter().enumerate() {
+                            block[usize::from(ZIGZAG[k])] = value;
+                        }
+
+                        blocks.push(block);
+                    }
+                }
+
+                DecodedComponent {
+                    id: component.id,
+                    horizontal_sampling_factor: component.h as u8,
+                    vertical_sampling_factor: component.v as u8,
+                    quantization_table: component
+                        .quantization_table
+                        .expect("Component without scan"),
+                    cols: component.cols,
+                    rows: component.rows,
+                    blocks,
+                }
+            })
+            .collect();
+
+        DecodedFrame {
+            marker: self.marker,
+            precision: self.precision,
+            width: self.width,
+            height: self.height,
+            components,
+        }
+    }
+}
+
+/// A scan with the indices of its components in the frame
+struct Scan {
+    components: Vec<(usize, usize, usize)>,
+    start: usize,
+    end: usize,
+    ah: u8,
+    al: u8,
+}
+
+/// Tables and settings defined by the segments before a scan
+struct Tables {
+    quantization: [[u16; 64]; 4],
+    dc_huffman: [Option<HuffmanTable>; 4],
+    ac_huffman: [Option<HuffmanTable>; 4],
+    #[cfg_attr(not(feature = "arithmetic"), allow(dead_code))]
+    dc_conditioning: [(u8, u8); 4],
+    #[cfg_attr(not(feature = "arithmetic"), allow(dead_code))]
+    ac_conditioning: [u8; 4],
+    restart_interval: usize,
+}
+
+/// Decodes the coefficients of all frames of an image
+///
+/// Panics if the image isn't valid or uses features which aren't supported, like
+/// lossless coding or arithmetic coded differential frames.
+pub(crate) fn decode_coefficients(data: &[u8]) -> Vec<DecodedFrame> {
+    assert_eq!(&data[..2], &[0xFF, 0xD8], "Missing SOI marker");
+
+    let mut tables = Tables {
+        quantization: [[0; 64]; 4],
+        dc_huffman: [None, None, None, None],
+        ac_huffman: [None, None, None, None],
+        dc_conditioning: [(0, 1); 4],
+        ac_conditioning: [5; 4],
+        restart_interval: 0,
+    };
+
+    let mut frames = Vec::new();
+    let mut frame: Option<Frame> = None;
+
+    let mut position = 2;
+
+    loop {
+        assert_eq!(data[position], 0xFF, "Missing marker at {}", position);
+
+        // Fill bytes
+        while data[position + 1] == 0xFF {
+            position += 1;
+        }
+
+        let marker = data[position + 1];
+        position += 2;
+
+        if marker == 0xD9 {
+            break;
+        }
+
+        let length = usize::from(u16::from_be_bytes([data[position], data[position + 1]]));
+        let segment = &data[position + 2..position + length];
+        position += length;
+
+        match marker {
+            0xC0..=0xC2 | 0xC5 | 0xC6 | 0xC9 | 0xCA => {
+                if let Some(frame) = frame.take() {
+                    frames.push(frame.finish());
+                }
+
+                frame = Some(read_frame_header(marker, segment));
+            }
+            0xC3 | 0xC7 | 0xCB | 0xCD..=0xCF => {
+                panic!("Unsupported frame type: {:X}", marker)
+            }
+            0xC4 => {
+                let mut segment = segment;
+
+                while !segment.is_empty() {
+                    let index = usize::from(segment[0] & 0x0F);
+
+                    let mut lengths = [0u8; 16];
+                    lengths.copy_from_slice(&segment[1..17]);
+
+                    let num_values: usize = lengths.iter().map(|&l| usize::from(l)).sum();
+                    let table = Some(HuffmanTable::new(&lengths, &segment[17..17 + num_values]));
+
+                    if segment[0] >> 4 == 0 {
+                        tables.dc_huffman[index] = table;
+                    } else {
+                        tables.ac_huffman[index] = table;
+                    }
+
+                    segment = &segment[17 + num_values..];
+                }
+            }
+            0xCC => {
+                for table in segment.chunks_exact(2) {
+                    let index = usize::from(table[0] & 0x0F);
+
+                    if table[0] >> 4 == 0 {
+                        tables.dc_conditioning[index] = (table[1] & 0x0F, table[1] >> 4);
+                    } else {
+                        tables.ac_conditioning[index] = table[1];
+                    }
+                }
+            }
+            0xDB => {
+                let mut segment = segment;
+
+                while !segment.is_empty() {
+                    let wide = segment[0] >> 4 == 1;
+                    let index = usize::from(segment[0] & 0x0F);
+                    let size = if wide { 128 } else { 64 };
+
+                    let table = &mut tables.quantization[index];
+
+                    for (k, &z) in ZIGZAG.iter().enumerate() {
+                        table[usize::from(z)] = if wide {
+                            u16::from_be_bytes([segment[1 + 2 * k], segment[2 + 2 * k]])
+                        } else {
+                            u16::from(segment[1 + k])
+                        };
+                    }
+
+                    segment = &segment[1 + size..];
+                }
+            }
+            0xDD => {
+                tables.restart_interval = usize::from(u16::from_be_bytes([segment[0], segment[1]]));
+            }
+            0xDA => {
+                let frame = frame.as_mut().expect("Scan without frame");
+                let scan = read_scan_header(frame, segment);
+
+                let intervals = split_restart_intervals(data, &mut position);
+                decode_scan(frame, &scan, &tables, &intervals);
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(frame) = frame.take() {
+        frames.push(frame.finish());
+    }
+
+    frames
+}
+
+fn read_frame_header(marker: u8, segment: &[u8]) -> Frame {
+    let precision = segment[0];
+    let height = u16::from_be_bytes([segment[1], segment[2]]);
+    let width = u16::from_be_bytes([segment[3], segment[4]]);
+    let num_components = usize::from(segment[5]);
+
+    assert_eq!(segment.len(), 6 + 3 * num_components);
+
+    let components: Vec<_> = segment[6..]
+        .chunks_exact(3)
+        .map(|c| (c[0], usize::from(c[1] >> 4), usize::from(c[1] & 0x0F), c[2]))
+        .collect();
+
+    let max_h_sampling = components.iter().map(|c| c.1).max().unwrap();
+    let max_v_sampling = components.iter().map(|c| c.2).max().unwrap();
+
+    let mcu_cols = ceil_div(usize::from(width), 8 * max_h_sampling);
+    let mcu_rows = ceil_div(usize::from(height), 8 * max_v_sampling);
+
+    let components = components
+        .into_iter()
+        .map(|(id, h, v, quantization_table_index)| {
+            // Size of the component (A.1.1)
+            let component_width = ceil_div(usize::from(width) * h, max_h_sampling);
+            let component_height = ceil_div(usize::from(height) * v, max_v_sampling);
+
+            let stride = mcu_cols * h;
+
+            FrameComponent {
+                id,
+                h,
+                v,
+                quantization_table_index: usize::from(quantization_table_index),
+                quantization_table: None,
+                cols: ceil_div(component_width, 8),
+                rows: ceil_div(component_height, 8),
+                blocks: vec![[0; 64]; stride * mcu_rows * v],
+                stride,
+            }
+        })
+        .collect();
+
+    Frame {
+        marker,
+        precision,
+        width,
+        height,
+        mcu_cols,
+        mcu_rows,
+        components,
+    }
+}
+
+fn read_scan_header(frame: &Frame, segment: &[u8]) -> Scan {
+    let num_components = usize::from(segment[0]);
+
+    let components = segment[1..1 + 2 * num_components]
+        .chunks_exact(2)
+        .map(|c| {
+            let index = frame
+                .components
+                .iter()
+                .position(|component| component.id == c[0])
+                .expect("Unknown component in scan");
+
+            (index, usize::from(c[1] >> 4), usize::from(c[1] & 0x0F))
+        })
+        .collect();
+
+    let parameters = &segment[1 + 2 * num_components..];
+
+    let (start, end) = if frame.is_progressive() {
+        (usize::from(parameters[0]), usize::from(parameters[1]))
+    } else {
+        (0, 63)
+    };
+
+    Scan {
+        components,
+        start,
+        end: end + 1,
+        ah: parameters[2] >> 4,
+        al: parameters[2] & 0x0F,
+    }
+}
+
+/// Returns the entropy coded data of the restart intervals of a scan starting at `position`
+///
+/// The restart markers must be numbered in order. `position` is moved to the marker
+/// following the scan.
+fn split_restart_intervals<'a>(data: &'a [u8], position: &mut usize) -> Vec<&'a [u8]> {
+    let mut intervals = Vec::new();
+    let mut start = *position;
+    let mut i = *position;
+
+    loop {
+        if data[i] != 0xFF || data[i + 1] == 0x00 {
+            i += 1;
+            continue;
+        }
+
+        let marker = data[i + 1];
+
+        match marker {
+            0xD0..=0xD7 => {
+                assert_eq!(
+                    usize::from(marker - 0xD0),
+                    intervals.len() % 8,
+                    "Restart markers out of order"
+                );
+
+                intervals.push(&data[start..i]);
+                i += 2;
+                start = i;
+            }
+            0xFF => i += 1,
+            _ => {
+                intervals.push(&data[start..i]);
+                *position = i;
+                return intervals;
+            }
+        }
+    }
+}
+
+/// Returns the blocks of a scan in coding order as (component, block index) pairs (A.2)
+///
+/// The blocks are grouped by MCUs, which contain a single block in non interleaved scans.
+fn get_mcus(frame: &Frame, scan: &Scan) -> Vec<Vec<(usize, usize)>> {
+    if let [(index, _, _)] = scan.components[..] {
+        let component = &frame.components[index];
+
+        return (0..component.rows)
+            .flat_map(|y| (0..component.cols).map(move |x| vec![(index, y * component.stride + x)]))
+            .collect();
+    }
+
+    let mut mcus = Vec::with_capacity(frame.mcu_cols * frame.mcu_rows);
+
+    for mcu_y in 0..frame.mcu_rows {
+        for mcu_x in 0..frame.mcu_cols {
+            let mut mcu = Vec::new();
+
+            for &(index, _, _) in &scan.components {
+                let component = &frame.components[index];
+
+                for v in 0..component.v {
+                    for h in 0..component.h {
+                        let y = mcu_y * component.v + v;
+                        let x = mcu_x * component.h + h;
+                        mcu.push((index, y * component.stride + x));
+                    }
+                }
+            }
+
+            mcus.push(mcu);
+        }
+    }
+
+    mcus
+}
+
+fn decode_scan(frame: &mut Frame, scan: &Scan, tables: &Tables, intervals: &[&[u8]]) {
+    for &(index, _, _) in &scan.components {
+        let component = &mut frame.components[index];
+        component.quantization_table =
+            Some(tables.quantization[component.quantization_table_index]);
+    }
+
+    let mcus = get_mcus(frame, scan);
+
+    let mcus_per_interval = if tables.restart_interval > 0 {
+        tables.restart_interval
+    } else {
+        mcus.len()
+    };
+
+    assert_eq!(
+        intervals.len(),
+        ceil_div(mcus.len(), mcus_per_interval),
+        "Wrong number of restart intervals"
+    );
+
+    for (interval, mcus) in intervals.iter().zip(mcus.chunks(mcus_per_interval)) {
+        if frame.is_arithmetic() {
+            #[cfg(feature = "arithmetic")]
+            decode_interval(
+                frame,
+                scan,
+                mcus,
+                &mut ArithmeticDecoder::new(interval, get_conditioning(tables)),
+            );
+
+            #[cfg(not(feature = "arithmetic"))]
+            panic!("Arithmetic coding isn't supported");
+        } else {
+            decode_interval(
+                frame,
+                scan,
+                mcus,
+                &mut HuffmanDecoder::new(interval, &tables.dc_huffman, &tables.ac_huffman),
+            );
+        }
+    }
+}
+
+#[cfg(feature = "arithmetic")]
+fn get_conditioning(tables: &Tables) -> [ArithmeticConditioning; NUM_CONDITIONING_TABLES] {
+    let mut conditioning = [ArithmeticConditioning::default(); NUM_CONDITIONING_TABLES];
+
+    for (i, conditioning) in conditioning.iter_mut().enumerate() {
+        let (lower, upper) = tables.dc_conditioning[i];
+        *conditioning = ArithmeticConditioning::new(lower, upper, tables.ac_conditioning[i])
+            .expect("Invalid conditioning");
+    }
+
+    conditioning
+}
+
+fn decode_interval<D: EntropyDecoder>(
+    frame: &mut Frame,
+    scan: &Scan,
+    mcus: &[Vec<(usize, usize)>],
+    decoder: &mut D,
+) {
+    let progressive = frame.is_progressive();
+    let differential = frame.is_differential();
+
+    // The decoders add the DC differences to the previous DC coefficient, but the DC
+    // coefficients of differential frames aren't predicted
+    let mut previous_dc = [0i32; 4];
+
+    for mcu in mcus {
+        for &(index, block_index) in mcu {
+            let &(_, dc_table, ac_table) = scan.components.iter().find(|c| c.0 == index).unwrap();
+
+            let block = &mut frame.components[index].blocks[block_index];
+
+            if scan.start == 0 {
+                if scan.ah > 0 {
+                    decoder.decode_dc_refinement(&mut block[0], scan.al);
+                } else {
+                    let dc = decoder.decode_dc(index, dc_table, scan.al);
+
+                    block[0] = if differential {
+                        dc - core::mem::replace(&mut previous_dc[index], dc)
+                    } else {
+                        dc
+                    };
+                }
+
+                if !progressive {
+                    decoder.decode_ac_first(block, 1, 64, 0, ac_table);
+                }
+            } else if scan.ah > 0 {
+                decoder.decode_ac_refinement(block, scan.start, scan.end, scan.al, ac_table);
+            } else {
+                decoder.decode_ac_first(block, scan.start, scan.end, scan.al, ac_table);
+            }
+        }
+    }
+}
+
+fn ceil_div(value: usize, div: usize) -> usize {
+    value / div + usize::from(value % div != 0)
+}
@@ -0,0 +1,293 @@
+/*
+ * Matrices for the conversion from RGB to YCbCr
+ */
+
+/// # Matrices for the conversion from RGB to YCbCr
+///
+/// JPEG decoders assume [ColorMatrix::Bt601Full], which is defined by JFIF. The other
+/// matrices are useful to match the conversion used by the source of an image, e.g. a
+/// frame of a video, if the decoder of the image uses the same conversion.
+///
+/// Limited range matrices scale the luma to 16..=235 and the chroma to 16..=240.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ColorMatrix {
+    /// BT.601 with full range, as defined by JFIF
+    Bt601Full,
+
+    /// BT.601 with limited range
+    Bt601Limited,
+
+    /// BT.709 with full range
+    Bt709Full,
+
+    /// BT.709 with limited range
+    Bt709Limited,
+
+    /// BT.2020 (non-constant luminance) with full range
+    Bt2020Full,
+
+    /// BT.2020 (non-constant luminance) with limited range
+    Bt2020Limited,
+}
+
+/// Coefficients of a conversion from RGB to YCbCr scaled by 2^16
+pub(crate) struct Coefficients {
+    pub y: [i32; 3],
+    pub cb: [i32; 3],
+    pub cr: [i32; 3],
+
+    /// Offset of the luma for 8 bit samples
+    pub y_offset: i32,
+}
+
+impl Coefficients {
+    /// Converts an RGB pixel to YCbCr
+    #[inline(always)]
+    pub fn rgb_to_ycbcr(&self, r: u8, g: u8, b: u8) -> (u8, u8, u8) {
+        let r = r as i32;
+        let g = g as i32;
+        let b = b as i32;
+
+        let y = self.y[0] * r + self.y[1] * g + self.y[2] * b + (self.y_offset << 16);
+        let cb = self.cb[0] * r + self.cb[1] * g + self.cb[2] * b + (128 << 16);
+        let cr = self.cr[0] * r + self.cr[1] * g + self.cr[2] * b + (128 << 16);
+
+        let y = (y + 0x7FFF) >> 16;
+        let cb = (cb + 0x7FFF) >> 16;
+        let cr = (cr + 0x7FFF) >> 16;
+
+        (y as u8, cb as u8, cr as u8)
+    }
+
+    /// Converts an RGB pixel with a precision of up to 16 bits to YCbCr
+    #[inline(always)]
+    pub fn rgb_to_ycbcr_with_precision(
+        &self,
+        r: u16,
+        g: u16,
+        b: u16,
+        precision: u8,
+    ) -> (u16, u16, u16) {
+        let r = r as i64;
+        let g = g as i64;
+        let b = b as i64;
+
+        let y_offset = i64::from(self.y_offset) << (precision + 8);
+        let center = 1i64 << (precision - 1);
+
+        let y = i64::from(self.y[0]) * r
+            + i64::from(self.y[1]) * g
+            + i64::from(self.y[2]) * b
+            + y_offset;
+        let cb = i64::from(self.cb[0]) * r
+            + i64::from(self.cb[1]) * g
+            + i64::from(self.cb[2]) * b
+            + (center << 16);
+        let cr = i64::from(self.cr[0]) * r
+            + i64::from(self.cr[1]) * g
+            + i64::from(self.cr[2]) * b
+            + (center << 16);
+
+        let y = (y + 0x7FFF) >> 16;
+        let cb = (cb + 0x7FFF) >> 16;
+        let cr = (cr + 0x7FFF) >> 16;
+
+        (y as u16, cb as u16, cr as u16)
+    }
+}
+
+// The green coefficients are adjusted so that gray has a chroma of exactly 128
+// and white the maximum luma.
+
+static BT_601_FULL: Coefficients = Coefficients {
+    y: [19595, 38470, 7471],
+    cb: [-11059, -21709, 32768],
+    cr: [32768, -27439, -5329],
+    y_offset: 0,
+};
+
+static BT_601_LIMITED: Coefficients = Coefficients {
+    y: [16829, 33039, 6416],
+    cb: [-9714, -19070, 28784],
+    cr: [28784, -24103, -4681],
+    y_offset: 16,
+};
+
+static BT_709_FULL: Coefficients = Coefficients {
+    y: [13933, 46871, 4732],
+    cb: [-7509, -25259, 32768],
+    cr: [32768, -29763, -3005],
+    y_offset: 0,
+};
+
+static BT_709_LIMITED: Coefficients = Coefficients {
+    y: [11966, 40254, 4064],
+    cb: [-6596, -22188, 28784],
+    cr: [28784, -26145, -2639],
+    y_offset: 16,
+};
+
+static BT_2020_FULL: Coefficients = Coefficients {
+    y: [17216, 44434, 3886],
+    cb: [-9151, -23617, 32768],
+    cr: [32768, -30133, -2635],
+    y_offset: 0,
+};
+
+static BT_2020_LIMITED: Coefficients = Coefficients {
+    y: [14786, 38160, 3338],
+    cb: [-8038, -20746, 28784],
+    cr: [28784, -26469, -2315],
+    y_offset: 16,
+};
+
+impl Default for ColorMatrix {
+    fn default() -> Self {
+        ColorMatrix::Bt601Full
+    }
+}
+
+impl ColorMatrix {
+    pub(crate) fn coefficients(self) -> &'static Coefficients {
+        use ColorMatrix::*;
+
+        match self {
+            Bt601Full => &BT_601_FULL,
+            Bt601Limited => &BT_601_LIMITED,
+            Bt709Full => &BT_709_FULL,
+            Bt709Limited => &BT_709_LIMITED,
+            Bt2020Full => &BT_2020_FULL,
+            Bt2020Limited => &BT_2020_LIMITED,
+        }
+    }
+
+    /// Converts an RGB pixel to YCbCr with this matrix
+    pub fn rgb_to_ycbcr(self, r: u8, g: u8, b: u8) -> (u8, u8, u8) {
+        self.coefficients().rgb_to_ycbcr(r, g, b)
+    }
+
+    /// Returns the weights of red and blue for the luma
+    fn get_weights(self) -> (f32, f32) {
+        use ColorMatrix::*;
+
+        match self {
+            Bt601Full | Bt601Limited => (0.299, 0.114),
+            Bt709Full | Bt709Limited => (0.2126, 0.0722),
+            Bt2020Full | Bt2020Limited => (0.2627, 0.0593),
+        }
+    }
+
+    /// Returns the scale of the luma and the chroma and the offset of the luma
+    fn get_range(self) -> (f32, f32, f32) {
+        use ColorMatrix::*;
+
+        match self {
+            Bt601Full | Bt709Full | Bt2020Full => (1.0, 1.0, 0.0),
+            Bt601Limited | Bt709Limited | Bt2020Limited => (219.0 / 255.0, 224.0 / 255.0, 16.0),
+        }
+    }
+
+    /// Converts an RGB pixel to YCbCr without rounding
+    pub(crate) fn rgb_to_ycbcr_f32(self, rgb: [f32; 3]) -> [f32; 3] {
+        let (kr, kb) = self.get_weights();
+        let (y_scale, c_scale, y_offset) = self.get_range();
+
+        let [r, g, b] = rgb;
+
+        let y = kr * r + (1.0 - kr - kb) * g + kb * b;
+        let cb = (b - y) / (2.0 * (1.0 - kb));
+        let cr = (r - y) / (2.0 * (1.0 - kr));
+
+        [
+            y * y_scale + y_offset,
+            cb * c_scale + 128.0,
+            cr * c_scale + 128.0,
+        ]
+    }
+
+    /// Converts YCbCr to RGB like a decoder, clamping the results to the range of samples
+    pub(crate) fn ycbcr_to_rgb_f32(self, y: f32, cb: f32, cr: f32) -> [f32; 3] {
+        let (kr, kb) = self.get_weights();
+        let (y_scale, c_scale, y_offset) = self.get_range();
+
+        let y = (y - y_offset) / y_scale;
+        let cb = (cb - 128.0) / c_scale;
+        let cr = (cr - 128.0) / c_scale;
+
+        let r = y + 2.0 * (1.0 - kr) * cr;
+        let b = y + 2.0 * (1.0 - kb) * cb;
+        let g = (y - kr * r - kb * b) / (1.0 - kr - kb);
+
+        [
+            r.clamp(0.0, 255.0),
+            g.clamp(0.0, 255.0),
+            b.clamp(0.0, 255.0),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ColorMatrix;
+    use crate::rgb_to_ycbcr;
+
+    const MATRICES: [ColorMatrix; 6] = [
+        ColorMatrix::Bt601Full,
+        ColorMatrix::Bt601Limited,
+        ColorMatrix::Bt709Full,
+        ColorMatrix::Bt709Limited,
+        ColorMatrix::Bt2020Full,
+        ColorMatrix::Bt2020Limited,
+    ];
+
+    #[test]
+    fn test_bt601_full() {
+        for v in (0..=255).step_by(5) {
+            let (r, g, b) = (v as u8, (255 - v) as u8, ((v * 7) % 256) as u8);
+
+            assert_eq!(
+                ColorMatrix::Bt601Full.rgb_to_ycbcr(r, g, b),
+                rgb_to_ycbcr(r, g, b)
+            );
+        }
+    }
+
+    #[test]
+    fn test_ranges() {
+        for matrix in MATRICES {
+            // Ranges of the luma and the chroma
+            let (y_range, c_range) = if matrix.get_range().2 == 0.0 {
+                ((0, 255), (0, 255))
+            } else {
+                ((16, 235), (16, 240))
+            };
+
+            assert_eq!(matrix.rgb_to_ycbcr(0, 0, 0), (y_range.0, 128, 128));
+            assert_eq!(matrix.rgb_to_ycbcr(255, 255, 255), (y_range.1, 128, 128));
+            assert_eq!(matrix.rgb_to_ycbcr(0, 0, 255).1, c_range.1);
+            assert_eq!(matrix.rgb_to_ycbcr(255, 0, 0).2, c_range.1);
+            assert_eq!(matrix.rgb_to_ycbcr(255, 255, 0).1, c_range.0);
+            assert_eq!(matrix.rgb_to_ycbcr(0, 255, 255).2, c_range.0);
+        }
+    }
+
+    #[test]
+    fn test_coefficients() {
+        for matrix in MATRICES {
+            for rgb in [[255, 0, 0], [0, 255, 0], [0, 0, 255], [40, 120, 200]] {
+                let (y, cb, cr) = matrix.rgb_to_ycbcr(rgb[0], rgb[1], rgb[2]);
+                let expected = matrix.rgb_to_ycbcr_f32(rgb.map(f32::from));
+
+                for (value, expected) in [y, cb, cr].iter().zip(expected) {
+                    assert!((f32::from(*value) - expected).abs() <= 0.51);
+                }
+
+                let reconstructed = matrix.ycbcr_to_rgb_f32(expected[0], expected[1], expected[2]);
+
+                for (value, expected) in reconstructed.iter().zip(rgb) {
+                    assert!((value - f32::from(expected)).abs() < 0.01);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,254 @@
+/*
+ * Reduction of samples with more than 8 bits to 8 bits
+ *
+ * The samples are read with 16 bits and scaled by 255, so that a difference of 65535
+ * equals one step of an 8 bit sample.
+ */
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::ImageBuffer;
+
+/// # Reduction of samples with more than 8 bits to 8 bits
+///
+/// Used for images with more than 8 bits per sample, like [ColorType::Rgb16](crate::ColorType::Rgb16)
+/// or [ColorType::RgbF32](crate::ColorType::RgbF32), if the image is encoded with a
+/// sample precision of 8 bits.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Dithering {
+    /// Rounds every sample to the nearest 8 bit value
+    Round,
+
+    /// Adds the thresholds of an 8x8 Bayer matrix before the samples are truncated
+    Ordered,
+
+    /// Distributes the rounding error of every sample to its neighbors (Floyd-Steinberg)
+    ///
+    /// This needs all rows of the image and isn't supported by the
+    /// [StreamingEncoder](crate::StreamingEncoder).
+    ErrorDiffusion,
+}
+
+impl Default for Dithering {
+    fn default() -> Self {
+        Dithering::Round
+    }
+}
+
+/// One step of an 8 bit sample in 16 bit samples scaled by 255
+const STEP: u32 = 65535;
+
+#[rustfmt::skip]
+static BAYER: [[u8; 8]; 8] = [
+    [ 0, 32,  8, 40,  2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44,  4, 36, 14, 46,  6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [ 3, 35, 11, 43,  1, 33,  9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47,  7, 39, 13, 45,  5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+/// Rounds a 16 bit sample to the nearest 8 bit sample
+#[inline]
+pub(crate) fn round_sample(value: u16) -> u8 {
+    ((u32::from(value) * 255 + STEP / 2) / STEP) as u8
+}
+
+/// Converts a 16 bit sample to an 8 bit sample with 4 fractional bits
+#[inline]
+pub(crate) fn fixed_point_sample(value: u16) -> u16 {
+    ((u32::from(value) * 255 * 16 + STEP / 2) / STEP) as u16
+}
+
+/// Reduces a 16 bit sample with the threshold of the Bayer matrix at the given position
+#[inline]
+fn dither_sample(value: u16, x: usize, y: usize) -> u8 {
+    // Thresholds are centered in their interval, so that the average is half a step
+    let threshold = (2 * u32::from(BAYER[y % 8][x % 8]) + 1) * STEP / 128;
+
+    ((u32::from(value) * 255 + threshold) / STEP) as u8
+}
+
+/// Adds the samples of row `y` reduced to 8 bits to the buffers
+///
+/// The samples of images with up to 8 bits per sample are added as they are. `row` is the
+/// index of the row in the whole image, which selects the row of the dither pattern.
+/// Error diffusion needs all rows and falls back to rounding.
+pub(crate) fn fill_buffers_reduced<I: ImageBuffer + ?Sized>(
+    image: &I,
+    y: u16,
+    row: usize,
+    dithering: Dithering,
+    buffers: &mut [Vec<u8>; 4],
+) {
+    if image.sample_precision() <= 8 {
+        image.fill_buffers(y, buffers);
+        return;
+    }
+
+    let mut samples: [Vec<u16>; 4] = Default::default();
+    image.fill_buffers_with_precision(y, 16, &mut samples);
+
+    for (buffer, samples) in buffers.iter_mut().zip(&samples) {
+        if dithering == Dithering::Ordered {
+            buffer.extend(
+                samples
+                    .iter()
+                    .enumerate()
+                    .map(|(x, &value)| dither_sample(value, x, row)),
+            );
+        } else {
+            buffer.extend(samples.iter().map(|&value| round_sample(value)));
+        }
+    }
+}
+
+/// Reduces the 16 bit samples of all rows to 8 bits with error diffusion
+///
+/// `rows` contains the samples of all components with rows of `buffer_width` samples, of
+/// which the first `width` samples of the first `height` rows belong to the image. The
+/// padding is replicated from the reduced samples at the edges of the image.
+pub(crate) fn diffuse_errors(
+    rows: &[Vec<u16>; 4],
+    buffer_width: usize,
+    width: usize,
+    height: usize,
+) -> [Vec<u8>; 4] {
+    let mut reduced: [Vec<u8>; 4] = Default::default();
+
+    for (samples, reduced) in rows.iter().zip(reduced.iter_mut()) {
+        if samples.is_empty() {
+            continue;
+        }
+
+        *reduced = vec![0; samples.len()];
+
+        // Errors of the current and the next row multiplied by 16 and offset by one sample
+        let mut errors = vec![0i32; width + 2];
+        let mut next_errors = vec![0i32; width + 2];
+
+        for y in 0..height {
+            let row = &samples[y * buffer_width..][..width];
+            let out = &mut reduced[y * buffer_width..][..width];
+
+            for (x, (&value, out)) in row.iter().zip(out.iter_mut()).enumerate() {
+                let value = (i32::from(value) * 255) + errors[x + 1] / 16;
+                let sample = ((value.max(0) as u32 + STEP / 2) / STEP).min(255);
+                let error = value - (sample * STEP) as i32;
+
+                *out = sample as u8;
+
+                errors[x + 2] += error * 7;
+                next_errors[x] += error * 3;
+                next_errors[x + 1] += error * 5;
+                next_errors[x + 2] += error;
+            }
+
+            core::mem::swap(&mut errors, &mut next_errors);
+            next_errors.fill(0);
+        }
+
+        for row in reduced.chunks_exact_mut(buffer_width).take(height) {
+            let last = row[width - 1];
+            row[width..].fill(last);
+        }
+
+        let last_row = (height - 1) * buffer_width;
+
+        for y in height..samples.len() / buffer_width {
+            reduced.copy_within(last_row..last_row + buffer_width, y * buffer_width);
+        }
+    }
+
+    reduced
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use super::{diffuse_errors, dither_sample, fixed_point_sample, round_sample};
+
+    #[test]
+    fn test_round_sample() {
+        for value in 0..=255u8 {
+            assert_eq!(round_sample(u16::from(value) * 257), value);
+        }
+
+        assert_eq!(round_sample(128), 0);
+        assert_eq!(round_sample(129), 1);
+        assert_eq!(round_sample(u16::MAX), 255);
+    }
+
+    #[test]
+    fn test_fixed_point_sample() {
+        for value in 0..=255u8 {
+            assert_eq!(
+                fixed_point_sample(u16::from(value) * 257),
+                u16::from(value) << 4
+            );
+        }
+
+        assert_eq!(fixed_point_sample(100 * 257 + 128), 100 * 16 + 8);
+    }
+
+    #[test]
+    fn test_dither_sample() {
+        for value in 0..=255u8 {
+            let value = u16::from(value) * 257;
+
+            for (x, y) in [(0, 0), (3, 5), (7, 7)] {
+                assert_eq!(dither_sample(value, x, y), round_sample(value));
+            }
+        }
+
+        // The average of a block equals the value between two 8 bit samples
+        let value = 100 * 257 + 64;
+        let sum: u32 = (0..64)
+            .map(|i| u32::from(dither_sample(value, i % 8, i / 8)))
+            .sum();
+
+        assert_eq!(sum, 100 * 64 + 16);
+    }
+
+    #[test]
+    fn test_diffuse_errors() {
+        // A quarter step above 100 with 2 rows of padding
+        let width = 16;
+        let height = 6;
+        let buffer_width = 24;
+
+        let rows = [
+            vec![100 * 257 + 64; buffer_width * (height + 2)],
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+        ];
+
+        let reduced = diffuse_errors(&rows, buffer_width, width, height);
+
+        assert_eq!(reduced[0].len(), rows[0].len());
+        assert!(reduced[1].is_empty());
+
+        let sum: u32 = reduced[0]
+            .chunks_exact(buffer_width)
+            .take(height)
+            .flat_map(|row| row[..width].iter().map(|&v| u32::from(v)))
+            .sum();
+
+        assert!(reduced[0].iter().all(|&v| v == 100 || v == 101));
+        assert!((sum as i32 - (100 * 96 + 24)).abs() <= 1);
+
+        for row in reduced[0].chunks_exact(buffer_width) {
+            assert!(row[width..].iter().all(|&v| v == row[width - 1]));
+        }
+        assert_eq!(
+            reduced[0][height * buffer_width..],
+            reduced[0][(height - 1) * buffer_width..height * buffer_width].repeat(2)
+        );
+    }
+}
@@ -0,0 +1,87 @@
+/*
+ * Filters for the downsampling of subsampled components
+ */
+
+/// # Filters for the downsampling of subsampled components
+///
+/// The filters are applied separately in horizontal and vertical direction. At the edges
+/// of the image the outermost samples are repeated.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DownsamplingFilter {
+    /// Takes the top left sample of each area without filtering
+    ///
+    /// This is the fastest filter but causes aliasing at sharp edges.
+    Point,
+
+    /// Averages the samples of each area
+    Box,
+
+    /// Weights the samples with a triangle (bilinear) filter reaching into the neighboring areas
+    Triangle,
+
+    /// Weights the samples with a Lanczos filter with 3 lobes
+    ///
+    /// This keeps edges sharper than the other filters but can cause slight ringing.
+    Lanczos,
+}
+
+impl DownsamplingFilter {
+    /// Returns if the filter reads samples outside of the area of a downsampled sample
+    pub(crate) fn needs_context(self) -> bool {
+        matches!(
+            self,
+            DownsamplingFilter::Triangle | DownsamplingFilter::Lanczos
+        )
+    }
+
+    /// Returns the weights of the samples for a downsampling by `scale` and the offset of
+    /// the first weight to the start of the area of a downsampled sample
+    ///
+    /// The weights of each filter have the same sum, which is a power of two.
+    pub(crate) fn get_taps(self, scale: usize) -> (isize, &'static [i32]) {
+        use DownsamplingFilter::*;
+
+        match (self, scale) {
+            (_, 1) => (0, &[1024]),
+            (Point, _) => (0, &[1024]),
+            (Box, 2) => (0, &[512, 512]),
+            (Box, 4) => (0, &[256, 256, 256, 256]),
+            (Triangle, 2) => (-1, &[128, 384, 384, 128]),
+            (Triangle, 4) => (-2, &[32, 96, 160, 224, 224, 160, 96, 32]),
+            (Lanczos, 2) => (-5, &[4, 15, -35, -68, 139, 457, 457, 139, -68, -35, 15, 4]),
+            (Lanczos, 4) => (
+                -9,
+                &[
+                    4, 8, 5, -8, -27, -38, -22, 31, 112, 196, 251, 251, 196, 112, 31, -22, -38,
+                    -27, -8, 5, 8, 4,
+                ],
+            ),
+            _ => unreachable!("Unsupported downsampling scale: {}", scale),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DownsamplingFilter;
+
+    #[test]
+    fn test_taps() {
+        use DownsamplingFilter::*;
+
+        for filter in [Point, Box, Triangle, Lanczos] {
+            for scale in [1, 2, 4] {
+                let (offset, taps) = filter.get_taps(scale);
+
+                assert_eq!(taps.iter().sum::<i32>(), 1024);
+
+                // The filters are centered on the area
+                assert!(
+                    filter == Point
+                        || scale == 1
+                        || offset * 2 + taps.len() as isize == scale as isize
+                );
+            }
+        }
+    }
+}
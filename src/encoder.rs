@@ -1,13 +1,34 @@
-use crate::fdct::fdct;
+#[cfg(feature = "arithmetic")]
+use crate::arithmetic::{ArithmeticConditioning, ArithmeticEncoder};
+use crate::dithering::{diffuse_errors, fill_buffers_reduced, fixed_point_sample};
+use crate::downsampling::DownsamplingFilter;
+use crate::fdct::{fdct, fdct_12bit};
+use crate::hierarchical::Plane;
 use crate::huffman::{CodingClass, HuffmanTable};
+use crate::idct::{idct, idct_wide};
 use crate::image_buffer::*;
-use crate::marker::Marker;
+#[cfg(feature = "std")]
+use crate::linear_light::{linear_light_downsample, TransferFunction};
+use crate::lossless::{
+    compute_differences, get_difference_category, get_restart_interval, Predictor,
+};
+use crate::marker::{Marker, SOFType};
+#[cfg(feature = "std")]
+use crate::metrics::QualityTarget;
+use crate::parallel::{concat, map_ranges};
 use crate::quantization::{QuantizationTable, QuantizationTableType};
-use crate::writer::{JfifWrite, JfifWriter, ZIGZAG};
-use crate::{Density, EncodingError};
-
+use crate::scan_script::{ScanInfo, ScanScript};
+use crate::sharp_yuv::sharp_yuv_downsample;
+use crate::streaming::StreamingEncoder;
+use crate::trellis::trellis_quantize;
+use crate::writer::{get_eobrun_bits, JfifWrite, JfifWriter, MAX_CORRECTION_BITS, ZIGZAG};
+use crate::yuv::{YuvFormat, YuvPlane};
+use crate::{AlphaPolicy, ColorMatrix, Density, Dithering, EncodingError};
+
+use alloc::boxed::Box;
 use alloc::vec;
 use alloc::vec::Vec;
+use core::ops::Range;
 
 #[cfg(feature = "std")]
 use std::io::BufWriter;
@@ -32,6 +53,11 @@ pub enum JpegColorType {
 
     /// 4 Component YCbCrK colorspace
     Ycck,
+
+    /// Three component RGB colorspace without color transform
+    ///
+    /// Images are written with an Adobe APP14 segment and without a JFIF header.
+    Rgb,
 }
 
 impl JpegColorType {
@@ -40,7 +66,7 @@ impl JpegColorType {
 
         match self {
             Luma => 1,
-            Ycbcr => 3,
+            Ycbcr | Rgb => 3,
             Cmyk | Ycck => 4,
         }
     }
@@ -58,15 +84,37 @@ pub enum ColorType {
     /// RGB with 3 bytes per pixel
     Rgb,
 
-    /// Red, Green, Blue with 4 bytes per pixel. The alpha channel will be ignored during encoding.
+    /// Red, Green, Blue with 4 bytes per pixel
+    ///
+    /// The alpha channel is handled by the [alpha policy](Encoder::set_alpha_policy).
     Rgba,
 
     /// RGB with 3 bytes per pixel
     Bgr,
 
-    /// RGBA with 4 bytes per pixel. The alpha channel will be ignored during encoding.
+    /// RGBA with 4 bytes per pixel
+    ///
+    /// The alpha channel is handled by the [alpha policy](Encoder::set_alpha_policy).
     Bgra,
 
+    /// ARGB with 4 bytes per pixel and the alpha value first
+    Argb,
+
+    /// ABGR with 4 bytes per pixel and the alpha value first
+    Abgr,
+
+    /// RGBA with 4 bytes per pixel and color values premultiplied by alpha
+    RgbaPremultiplied,
+
+    /// BGRA with 4 bytes per pixel and color values premultiplied by alpha
+    BgraPremultiplied,
+
+    /// ARGB with 4 bytes per pixel and color values premultiplied by alpha
+    ArgbPremultiplied,
+
+    /// ABGR with 4 bytes per pixel and color values premultiplied by alpha
+    AbgrPremultiplied,
+
     /// YCbCr with 3 bytes per pixel.
     Ycbcr,
 
@@ -78,6 +126,28 @@ pub enum ColorType {
 
     /// YCCK (YCbCrK) with 4 bytes per pixel.
     Ycck,
+
+    /// Grayscale with 2 bytes per pixel in native endian order
+    ///
+    /// Sample values are reduced to the [sample precision](Encoder::set_sample_precision).
+    Luma16,
+
+    /// RGB with 3 values of 2 bytes per pixel in native endian order
+    ///
+    /// Sample values are reduced to the [sample precision](Encoder::set_sample_precision).
+    Rgb16,
+
+    /// RGBA with 4 values of 2 bytes per pixel in native endian order
+    ///
+    /// Sample values are reduced to the [sample precision](Encoder::set_sample_precision).
+    /// The alpha channel is handled by the [alpha policy](Encoder::set_alpha_policy).
+    Rgba16,
+
+    /// RGB with 3 floating point values of 4 bytes per pixel in native endian order
+    ///
+    /// Values between 0.0 and 1.0 are mapped to the range of samples and values outside
+    /// of it are clamped. Sample values are reduced to the [sample precision](Encoder::set_sample_precision).
+    RgbF32,
 }
 
 impl ColorType {
@@ -86,8 +156,13 @@ impl ColorType {
 
         match self {
             Luma => 1,
+            Luma16 => 2,
             Rgb | Bgr | Ycbcr => 3,
-            Rgba | Bgra | Cmyk | CmykAsYcck | Ycck => 4,
+            Rgba | Bgra | Argb | Abgr | Cmyk | CmykAsYcck | Ycck => 4,
+            RgbaPremultiplied | BgraPremultiplied | ArgbPremultiplied | AbgrPremultiplied => 4,
+            Rgb16 => 6,
+            Rgba16 => 8,
+            RgbF32 => 12,
         }
     }
 }
@@ -169,6 +244,29 @@ impl SamplingFactor {
     }
 }
 
+/// Rows of MCUs transformed by each thread for a batch of an interleaved scan
+const MCU_ROWS_PER_THREAD: usize = 4;
+
+/// Blocks of all components of an image, transformed with a sampling factor but not quantized
+type TransformedBlocks = (SamplingFactor, [Vec<[i16; 64]>; 4]);
+
+/// State of an interleaved scan between rows of MCUs
+pub(crate) struct InterleavedState {
+    prev_dc: [i16; 4],
+    restarts: u16,
+    restarts_to_go: u16,
+}
+
+impl InterleavedState {
+    pub(crate) fn new(restart_interval: Option<u16>) -> InterleavedState {
+        InterleavedState {
+            prev_dc: [0; 4],
+            restarts: 0,
+            restarts_to_go: restart_interval.unwrap_or(0),
+        }
+    }
+}
+
 pub(crate) struct Component {
     pub id: u8,
     pub quantization_table: u8,
@@ -202,13 +300,51 @@ pub struct Encoder<W: JfifWrite> {
     huffman_tables: [(HuffmanTable, HuffmanTable); 2],
 
     sampling_factor: SamplingFactor,
+    downsampling_filter: DownsamplingFilter,
+    sharp_yuv: bool,
+    #[cfg(feature = "std")]
+    linear_light: Option<TransferFunction>,
+    color_matrix: ColorMatrix,
+    rgb_without_transform: bool,
+    alpha_policy: AlphaPolicy,
+    dithering: Dithering,
+    high_precision_fdct: bool,
 
     progressive_scans: Option<u8>,
 
+    successive_approximation: bool,
+
+    interleaved_dc_scan: bool,
+
+    scan_script: Option<ScanScript>,
+
     restart_interval: Option<u16>,
 
     optimize_huffman_table: bool,
 
+    trellis_quantization: bool,
+
+    lossless_predictor: Option<Predictor>,
+    point_transform: u8,
+    sample_precision: u8,
+
+    hierarchical_levels: u8,
+
+    target_size: Option<usize>,
+    target_size_subsampling: bool,
+
+    #[cfg(feature = "std")]
+    quality_target: Option<QualityTarget>,
+
+    #[cfg(feature = "parallel")]
+    num_threads: usize,
+
+    #[cfg(feature = "arithmetic")]
+    arithmetic_coding: bool,
+
+    #[cfg(feature = "arithmetic")]
+    arithmetic_conditioning: [ArithmeticConditioning; 2],
+
     app_segments: Vec<(u8, Vec<u8>)>,
 }
 
@@ -235,11 +371,7 @@ impl<W: JfifWrite> Encoder<W> {
             QuantizationTableType::Default,
         ];
 
-        let sampling_factor = if quality < 90 {
-            SamplingFactor::F_2_2
-        } else {
-            SamplingFactor::F_1_1
-        };
+        let sampling_factor = default_sampling_factor(quality);
 
         Encoder {
             writer: JfifWriter::new(w),
@@ -249,9 +381,36 @@ impl<W: JfifWrite> Encoder<W> {
             quantization_tables,
             huffman_tables,
             sampling_factor,
+            downsampling_filter: DownsamplingFilter::Box,
+            sharp_yuv: false,
+            #[cfg(feature = "std")]
+            linear_light: None,
+            color_matrix: ColorMatrix::Bt601Full,
+            rgb_without_transform: false,
+            alpha_policy: AlphaPolicy::Ignore,
+            dithering: Dithering::Round,
+            high_precision_fdct: false,
             progressive_scans: None,
+            successive_approximation: false,
+            interleaved_dc_scan: true,
+            scan_script: None,
             restart_interval: None,
             optimize_huffman_table: false,
+            trellis_quantization: false,
+            lossless_predictor: None,
+            point_transform: 0,
+            sample_precision: 8,
+            hierarchical_levels: 1,
+            target_size: None,
+            target_size_subsampling: false,
+            #[cfg(feature = "std")]
+            quality_target: None,
+            #[cfg(feature = "parallel")]
+            num_threads: crate::parallel::available_threads(),
+            #[cfg(feature = "arithmetic")]
+            arithmetic_coding: false,
+            #[cfg(feature = "arithmetic")]
+            arithmetic_conditioning: [ArithmeticConditioning::default(); 2],
             app_segments: Vec::new(),
         }
     }
@@ -278,6 +437,154 @@ impl<W: JfifWrite> Encoder<W> {
         self.sampling_factor
     }
 
+    /// Set the filter used to downsample subsampled components
+    ///
+    /// Defaults to [DownsamplingFilter::Box]. The triangle and Lanczos filters read the
+    /// samples of neighboring MCUs, so subsampled images are encoded in separate scans per
+    /// component and can't be encoded with the [streaming encoder](Encoder::into_streaming).
+    /// The filter isn't used for [pre-subsampled input](Encoder::encode_yuv).
+    pub fn set_downsampling_filter(&mut self, filter: DownsamplingFilter) {
+        self.downsampling_filter = filter;
+    }
+
+    /// Get downsampling filter
+    pub fn downsampling_filter(&self) -> DownsamplingFilter {
+        self.downsampling_filter
+    }
+
+    /// Controls if the chroma of subsampled YCbCr images is downsampled iteratively
+    ///
+    /// Sharp YUV adjusts the subsampled chroma and the luma in a few iterations, so that
+    /// the RGB image reconstructed by a decoder matches the input better. This keeps
+    /// saturated colors at edges from bleeding into their surroundings, but is slower than
+    /// the [downsampling filters](Encoder::set_downsampling_filter), which it replaces.
+    ///
+    /// Like the triangle and Lanczos filters it needs the whole image, so it isn't supported
+    /// by the [streaming encoder](Encoder::into_streaming). It is only used for 8 bit images.
+    pub fn set_sharp_yuv(&mut self, sharp_yuv: bool) {
+        self.sharp_yuv = sharp_yuv;
+    }
+
+    /// Returns if sharp YUV downsampling is enabled
+    pub fn sharp_yuv(&self) -> bool {
+        self.sharp_yuv
+    }
+
+    /// Set the transfer function used to downsample the chroma in linear light
+    ///
+    /// Averaging gamma encoded samples darkens thin saturated features, e.g. red text on
+    /// a green background. With a transfer function the colors of subsampled YCbCr images
+    /// are converted to linear light, filtered with the
+    /// [downsampling filter](Encoder::set_downsampling_filter) and converted back before
+    /// the chroma is computed. [TransferFunction::Srgb] matches most images.
+    ///
+    /// Defaults to `None`, which downsamples the encoded samples. Like sharp YUV it needs
+    /// the whole image, so it isn't supported by the [streaming encoder](Encoder::into_streaming).
+    /// It is only used for 8 bit images and is ignored if [sharp YUV](Encoder::set_sharp_yuv)
+    /// is enabled.
+    #[cfg(feature = "std")]
+    pub fn set_linear_light_downsampling(&mut self, transfer_function: Option<TransferFunction>) {
+        self.linear_light = transfer_function;
+    }
+
+    /// Returns the transfer function used to downsample the chroma in linear light
+    #[cfg(feature = "std")]
+    pub fn linear_light_downsampling(&self) -> Option<TransferFunction> {
+        self.linear_light
+    }
+
+    /// Set the matrix used to convert RGB input to YCbCr
+    ///
+    /// Defaults to [ColorMatrix::Bt601Full], which is what JPEG decoders expect. The other
+    /// matrices only reconstruct the correct colors with decoders which use the same
+    /// conversion, as nothing in the image signals it. Pre-converted YCbCr input and
+    /// CMYK encoded as YCCK aren't affected.
+    pub fn set_color_matrix(&mut self, matrix: ColorMatrix) {
+        self.color_matrix = matrix;
+    }
+
+    /// Returns the matrix used to convert RGB input to YCbCr
+    pub fn color_matrix(&self) -> ColorMatrix {
+        self.color_matrix
+    }
+
+    /// Controls if RGB input is stored as RGB instead of being converted to YCbCr
+    ///
+    /// The red, green and blue samples are stored as components with the IDs 'R', 'G' and 'B'
+    /// and an Adobe APP14 segment signals that no color transform is used. This avoids the
+    /// rounding errors of the color conversion at high qualities, but the files are larger,
+    /// as no component can be subsampled. The sampling factor and the color matrix are
+    /// ignored for such images.
+    ///
+    /// Defaults to `false`. Lossless images are always stored without color transform.
+    pub fn set_rgb_without_transform(&mut self, rgb_without_transform: bool) {
+        self.rgb_without_transform = rgb_without_transform;
+    }
+
+    /// Returns if RGB input is stored as RGB
+    pub fn rgb_without_transform(&self) -> bool {
+        self.rgb_without_transform
+    }
+
+    /// Set how the alpha channel of color types like [RGBA](ColorType::Rgba) and
+    /// [BGRA](ColorType::Bgra) is handled
+    ///
+    /// Defaults to [AlphaPolicy::Ignore], which encodes the color values of transparent
+    /// pixels as they are. Premultiplied color types are therefore encoded as if they were
+    /// composited onto black.
+    pub fn set_alpha_policy(&mut self, alpha_policy: AlphaPolicy) {
+        self.alpha_policy = alpha_policy;
+    }
+
+    /// Returns how the alpha channel of color types with alpha is handled
+    pub fn alpha_policy(&self) -> AlphaPolicy {
+        self.alpha_policy
+    }
+
+    fn has_linear_light(&self) -> bool {
+        #[cfg(feature = "std")]
+        {
+            self.linear_light.is_some()
+        }
+
+        #[cfg(not(feature = "std"))]
+        {
+            false
+        }
+    }
+
+    /// Returns if subsampled components are downsampled from the samples of neighboring MCUs
+    fn needs_downsampling_context(&self) -> bool {
+        (self.downsampling_filter.needs_context() || self.sharp_yuv || self.has_linear_light())
+            && self.sampling_factor != SamplingFactor::F_1_1
+    }
+
+    /// Returns if the samples of an image with the given precision are reduced to 8 bits
+    /// with error diffusion
+    fn diffuses_errors(&self, image_precision: u8) -> bool {
+        self.dithering == Dithering::ErrorDiffusion
+            && image_precision > 8
+            && self.sample_precision == 8
+    }
+
+    /// Returns if the FDCT of an image is computed with fractional samples
+    fn uses_high_precision_fdct<I: ImageBuffer + ?Sized>(&self, image: &I) -> bool {
+        self.high_precision_fdct
+            && image.sample_precision() > 8
+            && self.sample_precision == 8
+            && !self.trellis_quantization
+            && !self.downsamples_rows(image.get_jpeg_color_type())
+    }
+
+    /// Returns if the chroma of an image with the given color type is downsampled while
+    /// reading the rows, with sharp YUV or in linear light
+    fn downsamples_rows(&self, color_type: JpegColorType) -> bool {
+        (self.sharp_yuv || self.has_linear_light())
+            && color_type == JpegColorType::Ycbcr
+            && self.sampling_factor != SamplingFactor::F_1_1
+            && self.sample_precision == 8
+    }
+
     /// Set quantization tables for luma and chroma components
     pub fn set_quantization_tables(
         &mut self,
@@ -298,6 +605,7 @@ impl<W: JfifWrite> Encoder<W> {
     /// Use [set_progressive_scans](Encoder::set_progressive_scans) to use a different number of scans
     pub fn set_progressive(&mut self, progressive: bool) {
         self.progressive_scans = if progressive { Some(4) } else { None };
+        self.scan_script = None;
     }
 
     /// Set number of scans per component for progressive encoding
@@ -314,13 +622,71 @@ impl<W: JfifWrite> Encoder<W> {
             scans
         );
         self.progressive_scans = Some(scans);
+        self.scan_script = None;
     }
 
     /// Return number of progressive scans if progressive encoding is enabled
+    ///
+    /// Returns `None` if a custom [scan script](Encoder::set_scan_script) is used.
     pub fn progressive_scans(&self) -> Option<u8> {
         self.progressive_scans
     }
 
+    /// Controls if successive approximation is used in progressive encoding
+    ///
+    /// If enabled, the DC and AC coefficients are first transferred with reduced precision
+    /// followed by refinement scans for the least significant bit.
+    /// This adds a DC and an AC refinement scan per component to the number of scans set by
+    /// [set_progressive_scans](Encoder::set_progressive_scans). With an
+    /// [interleaved DC scan](Encoder::set_interleaved_dc_scan) a single DC refinement scan is
+    /// used for all components.
+    pub fn set_successive_approximation(&mut self, successive_approximation: bool) {
+        self.successive_approximation = successive_approximation;
+    }
+
+    /// Returns if successive approximation is used in progressive encoding
+    pub fn successive_approximation(&self) -> bool {
+        self.successive_approximation
+    }
+
+    /// Set if the DC coefficients of all components are transferred in a single scan
+    ///
+    /// The interleaved DC scan allows decoders to show a full color preview after the first scan.
+    /// If disabled or if the sampling factor doesn't support interleaved mode, a DC scan is
+    /// written for each component.
+    ///
+    /// This setting is enabled by default.
+    pub fn set_interleaved_dc_scan(&mut self, interleaved_dc_scan: bool) {
+        self.interleaved_dc_scan = interleaved_dc_scan;
+    }
+
+    /// Returns if an interleaved DC scan is used in progressive encoding
+    pub fn interleaved_dc_scan(&self) -> bool {
+        self.interleaved_dc_scan
+    }
+
+    /// Enables progressive encoding with a custom scan script
+    ///
+    /// The script replaces the scans created by [set_progressive_scans](Encoder::set_progressive_scans)
+    /// and [set_successive_approximation](Encoder::set_successive_approximation).
+    ///
+    /// The script is validated before encoding and an
+    /// [InvalidScanScript](EncodingError::InvalidScanScript) error is returned if it violates
+    /// the progression rules.
+    pub fn set_scan_script(&mut self, script: ScanScript) {
+        self.scan_script = Some(script);
+        self.progressive_scans = None;
+    }
+
+    /// Return the custom scan script if set
+    pub fn scan_script(&self) -> Option<&ScanScript> {
+        self.scan_script.as_ref()
+    }
+
+    pub(crate) fn is_progressive(&self) -> bool {
+        self.progressive_scans.is_some() || self.scan_script.is_some()
+    }
+
     /// Set restart interval
     ///
     /// Set numbers of MCUs between restart markers.
@@ -336,6 +702,7 @@ impl<W: JfifWrite> Encoder<W> {
     /// Set if optimized huffman table should be created
     ///
     /// Optimized tables result in slightly smaller file sizes but decrease encoding performance.
+    /// Progressive encoding always uses optimized tables.
     pub fn set_optimized_huffman_tables(&mut self, optimize_huffman_table: bool) {
         self.optimize_huffman_table = optimize_huffman_table;
     }
@@ -345,6 +712,284 @@ impl<W: JfifWrite> Encoder<W> {
         self.optimize_huffman_table
     }
 
+    /// Set if trellis quantization should be used
+    ///
+    /// Trellis quantization chooses the quantized AC coefficients by minimizing the size
+    /// of the coded block plus the weighted distortion, based on the huffman tables in use.
+    /// This results in smaller files at a similar visual quality but decreases encoding performance.
+    ///
+    /// If optimized huffman tables are used, which is always the case for progressive images,
+    /// the sizes are estimated with tables optimized for the usual quantization first.
+    /// Trellis quantization is only used for 8 bit images with huffman coding and
+    /// is ignored for hierarchical and lossless images.
+    pub fn set_trellis_quantization(&mut self, trellis_quantization: bool) {
+        self.trellis_quantization = trellis_quantization;
+    }
+
+    /// Returns if trellis quantization is used
+    pub fn trellis_quantization(&self) -> bool {
+        self.trellis_quantization
+    }
+
+    /// Enables lossless encoding with the given predictor
+    ///
+    /// Lossless images are written as a single interleaved scan (SOF3) with optimized huffman tables.
+    /// The settings for quality, quantization tables, sampling factor, progressive encoding
+    /// and arithmetic coding are ignored in lossless mode.
+    /// Color values are stored without color conversion, so RGB images are written as
+    /// [RGB](JpegColorType::Rgb) instead of YCbCr.
+    ///
+    /// Restart intervals are rounded up to a multiple of the image width.
+    pub fn set_lossless(&mut self, predictor: Option<Predictor>) {
+        self.lossless_predictor = predictor;
+    }
+
+    /// Returns the predictor if lossless encoding is enabled
+    pub fn lossless(&self) -> Option<Predictor> {
+        self.lossless_predictor
+    }
+
+    /// Set the point transform used in lossless encoding
+    ///
+    /// Samples are divided by 2^point_transform before encoding, which discards the lowest bits.
+    /// The point transform must be smaller than the [sample precision](Encoder::set_sample_precision).
+    ///
+    /// By default, no point transform is used.
+    pub fn set_point_transform(&mut self, point_transform: u8) {
+        self.point_transform = point_transform;
+    }
+
+    /// Returns the point transform used in lossless encoding
+    pub fn point_transform(&self) -> u8 {
+        self.point_transform
+    }
+
+    /// Set the sample precision in bits
+    ///
+    /// Lossless encoding supports a precision between 2 and 16 bits. DCT based encoding
+    /// supports 8 bits, which is the default, and 12 bits.
+    ///
+    /// Sequential 12 bit images are written as extended sequential (SOF1) frames
+    /// with one scan per component and optimized huffman tables.
+    /// Custom quantization table values above 255 are only used for 12 bit images.
+    pub fn set_sample_precision(&mut self, sample_precision: u8) {
+        self.sample_precision = sample_precision;
+    }
+
+    /// Returns the sample precision in bits
+    pub fn sample_precision(&self) -> u8 {
+        self.sample_precision
+    }
+
+    /// Set how samples with more than 8 bits are reduced to a sample precision of 8 bits
+    ///
+    /// This is used for input like [ColorType::Rgb16] or [ColorType::RgbF32] and defaults to
+    /// [Dithering::Round]. Dithering avoids banding in smooth gradients, e.g. of HDR renders.
+    pub fn set_dithering(&mut self, dithering: Dithering) {
+        self.dithering = dithering;
+    }
+
+    /// Returns how samples with more than 8 bits are reduced to 8 bits
+    pub fn dithering(&self) -> Dithering {
+        self.dithering
+    }
+
+    /// Controls if the FDCT of input with more than 8 bits per sample uses fractional samples
+    ///
+    /// Instead of reducing the samples to 8 bits, 4 fractional bits are kept until the
+    /// coefficients are quantized and [dithering](Encoder::set_dithering) isn't applied.
+    /// This isn't used with trellis quantization, sharp YUV or linear light downsampling
+    /// and isn't supported by the [StreamingEncoder].
+    ///
+    /// Defaults to `false`.
+    pub fn set_high_precision_fdct(&mut self, high_precision_fdct: bool) {
+        self.high_precision_fdct = high_precision_fdct;
+    }
+
+    /// Returns if the FDCT of input with more than 8 bits per sample uses fractional samples
+    pub fn high_precision_fdct(&self) -> bool {
+        self.high_precision_fdct
+    }
+
+    /// Set the number of frames for hierarchical encoding
+    ///
+    /// Hierarchical images consist of a pyramid of frames. The first frame contains the image
+    /// downscaled by 2^(levels - 1) and each following frame codes the difference to the
+    /// upsampled previous frame at twice its size until the full image size is reached.
+    ///
+    /// Each frame is written as extended sequential (SOF1) or differential sequential (SOF5)
+    /// frame with one scan per component and optimized huffman tables.
+    /// Hierarchical images don't use subsampling and only support 8 bit samples.
+    /// The settings for progressive encoding and arithmetic coding are ignored.
+    ///
+    /// By default, a single level is used which disables hierarchical encoding.
+    ///
+    /// # Panics
+    /// If number of levels is not within valid range
+    pub fn set_hierarchical_levels(&mut self, levels: u8) {
+        assert!(
+            (1..=16).contains(&levels),
+            "Invalid number of hierarchical levels: {}",
+            levels
+        );
+
+        self.hierarchical_levels = levels;
+    }
+
+    /// Returns the number of frames used for hierarchical encoding
+    pub fn hierarchical_levels(&self) -> u8 {
+        self.hierarchical_levels
+    }
+
+    fn is_hierarchical(&self) -> bool {
+        self.hierarchical_levels > 1
+    }
+
+    /// Set a maximum size in bytes for the encoded image
+    ///
+    /// With a target size, [encode](Encoder::encode) and [encode_image](Encoder::encode_image)
+    /// search for the highest quality, up to the quality passed to [Encoder::new], whose output
+    /// fits into the target size. The starting point of the search is estimated from the
+    /// statistics of the huffman coded data, which only requires the image to be transformed once.
+    /// The output of the trial encodings is kept in memory until the best fitting one is written.
+    ///
+    /// If the image doesn't fit even with quality 1, encoding fails with
+    /// [TargetSizeTooSmall](EncodingError::TargetSizeTooSmall) and nothing is written.
+    ///
+    /// By default, this value is 0 which disables the target size.
+    pub fn set_target_size(&mut self, size: usize) {
+        self.target_size = if size == 0 { None } else { Some(size) };
+    }
+
+    /// Return the target size
+    pub fn target_size(&self) -> Option<usize> {
+        self.target_size
+    }
+
+    /// Set if the chroma subsampling should be chosen for each quality tried for the target size
+    ///
+    /// If set, qualities below 90 use a chroma subsampling of 2x2 and higher qualities none,
+    /// like the default of [Encoder::new]. Otherwise the sampling factor of the encoder is used.
+    ///
+    /// By default, this is false.
+    pub fn set_target_size_subsampling(&mut self, target_size_subsampling: bool) {
+        self.target_size_subsampling = target_size_subsampling;
+    }
+
+    /// Returns if the chroma subsampling is chosen for each quality tried for the target size
+    pub fn target_size_subsampling(&self) -> bool {
+        self.target_size_subsampling
+    }
+
+    /// Set a minimum image quality measured by an objective metric
+    ///
+    /// With a quality target, [encode](Encoder::encode) and [encode_image](Encoder::encode_image)
+    /// use the lowest quality, up to the quality passed to [Encoder::new], whose reconstructed
+    /// image reaches the target. The image is transformed once and only dequantized and inverse
+    /// transformed for each quality tried. The metric is measured on all components, with
+    /// subsampled components scaled up to full resolution like a decoder would do. The PSNR
+    /// is computed from the errors of the samples of all components and the SSIM is the mean
+    /// SSIM of the components.
+    ///
+    /// If the target can't be reached, the quality passed to [Encoder::new] is used.
+    /// With a [target size](Encoder::set_target_size), the quality found is the upper limit
+    /// of the size search. The quality target is ignored for lossless, hierarchical
+    /// and 12 bit images.
+    #[cfg(feature = "std")]
+    pub fn set_quality_target(&mut self, quality_target: Option<QualityTarget>) {
+        self.quality_target = quality_target;
+    }
+
+    /// Returns the quality target
+    #[cfg(feature = "std")]
+    pub fn quality_target(&self) -> Option<QualityTarget> {
+        self.quality_target
+    }
+
+    /// Set the number of threads used for encoding
+    ///
+    /// By default, all available threads are used. With more than one thread, the color
+    /// conversion, DCT and quantization of the blocks run in parallel. The entropy coding of
+    /// huffman coded sequential images runs in parallel if a
+    /// [restart interval](Encoder::set_restart_interval) is set. The output is the same for
+    /// any number of threads.
+    ///
+    /// Color conversion of custom [ImageBuffer] implementations passed to
+    /// [encode_image](Encoder::encode_image) always runs on the calling thread.
+    ///
+    /// # Panics
+    /// Panics if the number of threads is zero.
+    #[cfg(feature = "parallel")]
+    pub fn set_num_threads(&mut self, num_threads: usize) {
+        assert!(
+            num_threads > 0,
+            "Number of threads must be greater than zero"
+        );
+        self.num_threads = num_threads;
+    }
+
+    /// Returns the number of threads used for encoding
+    #[cfg(feature = "parallel")]
+    pub fn num_threads(&self) -> usize {
+        self.num_threads
+    }
+
+    fn threads(&self) -> usize {
+        #[cfg(feature = "parallel")]
+        {
+            self.num_threads
+        }
+
+        #[cfg(not(feature = "parallel"))]
+        {
+            1
+        }
+    }
+
+    /// Set if arithmetic coding should be used instead of huffman coding
+    ///
+    /// Arithmetic coding results in smaller files but isn't supported by all decoders.
+    /// Sequential images are written as extended sequential (SOF9) and progressive images
+    /// as progressive (SOF10) frames.
+    #[cfg(feature = "arithmetic")]
+    pub fn set_arithmetic_coding(&mut self, arithmetic_coding: bool) {
+        self.arithmetic_coding = arithmetic_coding;
+    }
+
+    /// Returns if arithmetic coding is used
+    #[cfg(feature = "arithmetic")]
+    pub fn arithmetic_coding(&self) -> bool {
+        self.arithmetic_coding
+    }
+
+    /// Set the conditioning parameters for luma and chroma components used in arithmetic coding
+    #[cfg(feature = "arithmetic")]
+    pub fn set_arithmetic_conditioning(
+        &mut self,
+        luma: ArithmeticConditioning,
+        chroma: ArithmeticConditioning,
+    ) {
+        self.arithmetic_conditioning = [luma, chroma];
+    }
+
+    /// Returns the conditioning parameters for luma and chroma components
+    #[cfg(feature = "arithmetic")]
+    pub fn arithmetic_conditioning(&self) -> &[ArithmeticConditioning; 2] {
+        &self.arithmetic_conditioning
+    }
+
+    fn is_arithmetic(&self) -> bool {
+        #[cfg(feature = "arithmetic")]
+        {
+            self.arithmetic_coding
+        }
+
+        #[cfg(not(feature = "arithmetic"))]
+        {
+            false
+        }
+    }
+
     /// Appends a custom app segment to the JFIF file
     ///
     /// Segment numbers need to be in the range between 1 and 15<br>
@@ -410,7 +1055,38 @@ impl<W: JfifWrite> Encoder<W> {
         height: u16,
         color_type: ColorType,
     ) -> Result<(), EncodingError> {
-        let required_data_len = width as usize * height as usize * color_type.get_bytes_per_pixel();
+        let stride = usize::from(width) * color_type.get_bytes_per_pixel();
+
+        self.encode_with_stride(data, width, height, stride, color_type)
+    }
+
+    /// Encode an image with rows that are `stride` bytes apart
+    ///
+    /// This allows encoding images with padded rows, like frame buffers, or a
+    /// rectangle of a bigger image without copying the data. The stride must be at least
+    /// the length of a row of the color type. The last row doesn't need to be padded.
+    pub fn encode_with_stride(
+        self,
+        data: &[u8],
+        width: u16,
+        height: u16,
+        stride: usize,
+        color_type: ColorType,
+    ) -> Result<(), EncodingError> {
+        let row_length = usize::from(width) * color_type.get_bytes_per_pixel();
+
+        if stride < row_length {
+            return Err(EncodingError::InvalidRowStride {
+                stride,
+                required: row_length,
+            });
+        }
+
+        let required_data_len = if height == 0 {
+            0
+        } else {
+            (usize::from(height) - 1) * stride + row_length
+        };
 
         if data.len() < required_data_len {
             return Err(EncodingError::BadImageData {
@@ -419,71 +1095,367 @@ impl<W: JfifWrite> Encoder<W> {
             });
         }
 
+        let image = self.get_image_buffer(color_type, data, width, height, stride);
+
         #[cfg(all(feature = "simd", any(target_arch = "x86", target_arch = "x86_64")))]
         {
             if std::is_x86_feature_detected!("avx2") {
                 use crate::avx2::*;
-
-                return match color_type {
-                    ColorType::Luma => self
-                        .encode_image_internal::<_, AVX2Operations>(GrayImage(data, width, height)),
-                    ColorType::Rgb => self.encode_image_internal::<_, AVX2Operations>(
-                        RgbImageAVX2(data, width, height),
-                    ),
-                    ColorType::Rgba => self.encode_image_internal::<_, AVX2Operations>(
-                        RgbaImageAVX2(data, width, height),
-                    ),
-                    ColorType::Bgr => self.encode_image_internal::<_, AVX2Operations>(
-                        BgrImageAVX2(data, width, height),
-                    ),
-                    ColorType::Bgra => self.encode_image_internal::<_, AVX2Operations>(
-                        BgraImageAVX2(data, width, height),
-                    ),
-                    ColorType::Ycbcr => self.encode_image_internal::<_, AVX2Operations>(
-                        YCbCrImage(data, width, height),
-                    ),
-                    ColorType::Cmyk => self
-                        .encode_image_internal::<_, AVX2Operations>(CmykImage(data, width, height)),
-                    ColorType::CmykAsYcck => self.encode_image_internal::<_, AVX2Operations>(
-                        CmykAsYcckImage(data, width, height),
-                    ),
-                    ColorType::Ycck => self
-                        .encode_image_internal::<_, AVX2Operations>(YcckImage(data, width, height)),
-                };
+                return self.encode_sync_image::<_, AVX2Operations>(DynImageBuffer(&*image));
             }
         }
 
-        match color_type {
-            ColorType::Luma => self.encode_image(GrayImage(data, width, height))?,
-            ColorType::Rgb => self.encode_image(RgbImage(data, width, height))?,
-            ColorType::Rgba => self.encode_image(RgbaImage(data, width, height))?,
-            ColorType::Bgr => self.encode_image(BgrImage(data, width, height))?,
-            ColorType::Bgra => self.encode_image(BgraImage(data, width, height))?,
-            ColorType::Ycbcr => self.encode_image(YCbCrImage(data, width, height))?,
-            ColorType::Cmyk => self.encode_image(CmykImage(data, width, height))?,
-            ColorType::CmykAsYcck => self.encode_image(CmykAsYcckImage(data, width, height))?,
-            ColorType::Ycck => self.encode_image(YcckImage(data, width, height))?,
-        }
-
-        Ok(())
+        self.encode_sync_image::<_, DefaultOperations>(DynImageBuffer(&*image))
     }
 
-    /// Encode an image
-    pub fn encode_image<I: ImageBuffer>(self, image: I) -> Result<(), EncodingError> {
+    /// Returns the image buffer used to read `data` with the given color type and row stride
+    /// and the color conversion settings of this encoder
+    pub(crate) fn get_image_buffer<'a>(
+        &self,
+        color_type: ColorType,
+        data: &'a [u8],
+        width: u16,
+        height: u16,
+        stride: usize,
+    ) -> Box<dyn ImageBuffer + Sync + 'a> {
+        let matrix = self.color_matrix;
+        let alpha = self.alpha_policy;
+
+        if self.lossless_predictor.is_some() || self.rgb_without_transform {
+            // Lossless images are stored without color conversion
+            match color_type {
+                ColorType::Rgb => return Box::new(RgbAsRgbImage(data, width, height, stride)),
+                ColorType::Rgba => {
+                    return Box::new(RgbaAsRgbImage(data, width, height, stride, alpha))
+                }
+                ColorType::Bgr => return Box::new(BgrAsRgbImage(data, width, height, stride)),
+                ColorType::Bgra => {
+                    return Box::new(BgraAsRgbImage(data, width, height, stride, alpha))
+                }
+                ColorType::Argb => {
+                    return Box::new(ArgbAsRgbImage(data, width, height, stride, alpha))
+                }
+                ColorType::Abgr => {
+                    return Box::new(AbgrAsRgbImage(data, width, height, stride, alpha))
+                }
+                ColorType::RgbaPremultiplied => {
+                    return Box::new(RgbaPremultipliedAsRgbImage(
+                        data, width, height, stride, alpha,
+                    ))
+                }
+                ColorType::BgraPremultiplied => {
+                    return Box::new(BgraPremultipliedAsRgbImage(
+                        data, width, height, stride, alpha,
+                    ))
+                }
+                ColorType::ArgbPremultiplied => {
+                    return Box::new(ArgbPremultipliedAsRgbImage(
+                        data, width, height, stride, alpha,
+                    ))
+                }
+                ColorType::AbgrPremultiplied => {
+                    return Box::new(AbgrPremultipliedAsRgbImage(
+                        data, width, height, stride, alpha,
+                    ))
+                }
+                ColorType::CmykAsYcck if self.lossless_predictor.is_some() => {
+                    return Box::new(CmykImage(data, width, height, stride))
+                }
+                ColorType::Rgb16 => return Box::new(Rgb16AsRgbImage(data, width, height, stride)),
+                ColorType::Rgba16 => {
+                    return Box::new(Rgba16AsRgbImage(data, width, height, stride, alpha))
+                }
+                ColorType::RgbF32 => {
+                    return Box::new(RgbF32AsRgbImage(data, width, height, stride))
+                }
+                _ => {}
+            }
+        }
+
         #[cfg(all(feature = "simd", any(target_arch = "x86", target_arch = "x86_64")))]
         {
             if std::is_x86_feature_detected!("avx2") {
                 use crate::avx2::*;
-                return self.encode_image_internal::<_, AVX2Operations>(image);
+
+                match color_type {
+                    ColorType::Rgb => {
+                        return Box::new(RgbImageAVX2(data, width, height, stride, matrix))
+                    }
+                    ColorType::Rgba => {
+                        return Box::new(RgbaImageAVX2(data, width, height, stride, matrix, alpha))
+                    }
+                    ColorType::Bgr => {
+                        return Box::new(BgrImageAVX2(data, width, height, stride, matrix))
+                    }
+                    ColorType::Bgra => {
+                        return Box::new(BgraImageAVX2(data, width, height, stride, matrix, alpha))
+                    }
+                    ColorType::Argb => {
+                        return Box::new(ArgbImageAVX2(data, width, height, stride, matrix, alpha))
+                    }
+                    ColorType::Abgr => {
+                        return Box::new(AbgrImageAVX2(data, width, height, stride, matrix, alpha))
+                    }
+                    ColorType::RgbaPremultiplied => {
+                        return Box::new(RgbaPremultipliedImageAVX2(
+                            data, width, height, stride, matrix, alpha,
+                        ))
+                    }
+                    ColorType::BgraPremultiplied => {
+                        return Box::new(BgraPremultipliedImageAVX2(
+                            data, width, height, stride, matrix, alpha,
+                        ))
+                    }
+                    ColorType::ArgbPremultiplied => {
+                        return Box::new(ArgbPremultipliedImageAVX2(
+                            data, width, height, stride, matrix, alpha,
+                        ))
+                    }
+                    ColorType::AbgrPremultiplied => {
+                        return Box::new(AbgrPremultipliedImageAVX2(
+                            data, width, height, stride, matrix, alpha,
+                        ))
+                    }
+                    _ => {}
+                }
             }
         }
-        self.encode_image_internal::<_, DefaultOperations>(image)
+
+        match color_type {
+            ColorType::Luma => Box::new(GrayImage(data, width, height, stride)),
+            ColorType::Rgb => Box::new(RgbImage(data, width, height, stride, matrix)),
+            ColorType::Rgba => Box::new(RgbaImage(data, width, height, stride, matrix, alpha)),
+            ColorType::Bgr => Box::new(BgrImage(data, width, height, stride, matrix)),
+            ColorType::Bgra => Box::new(BgraImage(data, width, height, stride, matrix, alpha)),
+            ColorType::Argb => Box::new(ArgbImage(data, width, height, stride, matrix, alpha)),
+            ColorType::Abgr => Box::new(AbgrImage(data, width, height, stride, matrix, alpha)),
+            ColorType::RgbaPremultiplied => Box::new(RgbaPremultipliedImage(
+                data, width, height, stride, matrix, alpha,
+            )),
+            ColorType::BgraPremultiplied => Box::new(BgraPremultipliedImage(
+                data, width, height, stride, matrix, alpha,
+            )),
+            ColorType::ArgbPremultiplied => Box::new(ArgbPremultipliedImage(
+                data, width, height, stride, matrix, alpha,
+            )),
+            ColorType::AbgrPremultiplied => Box::new(AbgrPremultipliedImage(
+                data, width, height, stride, matrix, alpha,
+            )),
+            ColorType::Ycbcr => Box::new(YCbCrImage(data, width, height, stride)),
+            ColorType::Cmyk => Box::new(CmykImage(data, width, height, stride)),
+            ColorType::CmykAsYcck => Box::new(CmykAsYcckImage(data, width, height, stride)),
+            ColorType::Ycck => Box::new(YcckImage(data, width, height, stride)),
+            ColorType::Luma16 => Box::new(GrayImage16(data, width, height, stride)),
+            ColorType::Rgb16 => Box::new(Rgb16Image(data, width, height, stride, matrix)),
+            ColorType::Rgba16 => Box::new(Rgba16Image(data, width, height, stride, matrix, alpha)),
+            ColorType::RgbF32 => Box::new(RgbF32Image(data, width, height, stride, matrix)),
+        }
     }
 
-    fn encode_image_internal<I: ImageBuffer, OP: Operations>(
-        mut self,
+    /// Encode an image
+    pub fn encode_image<I: ImageBuffer>(self, image: I) -> Result<(), EncodingError> {
+        #[cfg(all(feature = "simd", any(target_arch = "x86", target_arch = "x86_64")))]
+        {
+            if std::is_x86_feature_detected!("avx2") {
+                use crate::avx2::*;
+                return self.encode_image_internal::<_, AVX2Operations>(&image, None);
+            }
+        }
+        self.encode_image_internal::<_, DefaultOperations>(&image, None)
+    }
+
+    /// Encode one of the image buffers of this crate, which can be read from multiple threads
+    fn encode_sync_image<I: ImageBuffer + Sync, OP: Operations>(
+        self,
         image: I,
     ) -> Result<(), EncodingError> {
+        self.encode_image_internal::<_, OP>(&image, Some(&image))
+    }
+
+    /// Encode a YCbCr image with subsampled chroma components
+    ///
+    /// The chroma components are encoded with the resolution of the input without
+    /// resampling. The [sampling factor](Encoder::set_sampling_factor) is set to match
+    /// the format and the settings for lossless and hierarchical encoding are ignored.
+    pub fn encode_yuv(
+        self,
+        data: &[u8],
+        width: u16,
+        height: u16,
+        format: YuvFormat,
+    ) -> Result<(), EncodingError> {
+        #[cfg(all(feature = "simd", any(target_arch = "x86", target_arch = "x86_64")))]
+        {
+            if std::is_x86_feature_detected!("avx2") {
+                use crate::avx2::*;
+                return self.encode_yuv_internal::<AVX2Operations>(data, width, height, format);
+            }
+        }
+        self.encode_yuv_internal::<DefaultOperations>(data, width, height, format)
+    }
+
+    /// Encode already quantized DCT coefficients
+    ///
+    /// This allows transcoding of JPEG images without generation loss, for example to convert
+    /// baseline images to progressive images, to optimize the huffman tables or to strip metadata.
+    ///
+    /// `coefficients` contains the blocks of each component of the color type in row major order.
+    /// The 64 coefficients of each block are in natural order, not in zigzag order.
+    /// Each component has as many blocks as needed to cover its size with the
+    /// [sampling factor](Encoder::set_sampling_factor), without padding to complete MCUs.
+    /// E.g. a 20x20 YCbCr image with 2x2 subsampling has 3x3 luma blocks and 2x2 blocks per chroma component.
+    ///
+    /// The coefficients must be quantized with the [quantization tables](Encoder::set_quantization_tables),
+    /// which are usually [custom tables](QuantizationTableType::Custom) with the values of the original image
+    /// as these aren't scaled by the quality. The luma table is used for the Y and K components and
+    /// the chroma table for all other components. RGB images only use the luma table.
+    ///
+    /// DC coefficients must be in the range of `-1024..=1023` and AC coefficients in the range
+    /// of `-1023..=1023` for a sample precision of 8 bits. For 12 bits the ranges are
+    /// `-16384..=16383` and `-16383..=16383`.
+    ///
+    /// The settings for lossless, hierarchical and trellis quantization are ignored.
+    pub fn encode_coefficients(
+        mut self,
+        coefficients: &[Vec<[i16; 64]>],
+        width: u16,
+        height: u16,
+        color_type: JpegColorType,
+    ) -> Result<(), EncodingError> {
+        if width == 0 || height == 0 {
+            return Err(EncodingError::ZeroImageDimensions { width, height });
+        }
+
+        if self.sample_precision != 8 && self.sample_precision != 12 {
+            return Err(EncodingError::UnsupportedSamplePrecision(
+                self.sample_precision,
+            ));
+        }
+
+        let num_components = color_type.get_num_components();
+
+        if coefficients.len() != num_components {
+            return Err(EncodingError::BadCoefficientData {
+                component: None,
+                length: coefficients.len(),
+                required: num_components,
+            });
+        }
+
+        let q_tables = self.get_quantization_tables();
+
+        self.init_components(color_type);
+
+        if let Some(script) = &self.scan_script {
+            script.validate(&self.components, self.sample_precision)?;
+        }
+
+        let mut blocks = self.init_block_buffers(0);
+
+        // Limits the DC differences and AC coefficients to the largest huffman categories
+        let limit = 1i32 << (self.sample_precision + 2);
+
+        for (i, component_blocks) in coefficients.iter().enumerate() {
+            let (cols, rows) = self.get_component_size(i, width, height);
+
+            if component_blocks.len() != cols * rows {
+                return Err(EncodingError::BadCoefficientData {
+                    component: Some(i),
+                    length: component_blocks.len(),
+                    required: cols * rows,
+                });
+            }
+
+            for (block_index, block) in component_blocks.iter().enumerate() {
+                for (index, &value) in block.iter().enumerate() {
+                    let min = if index == 0 { -limit } else { -limit + 1 };
+
+                    if !(min..limit).contains(&i32::from(value)) {
+                        return Err(EncodingError::CoefficientOutOfRange {
+                            component: i,
+                            block: block_index,
+                            index,
+                            value,
+                        });
+                    }
+                }
+            }
+
+            blocks[i] = component_blocks
+                .iter()
+                .map(|block| {
+                    let mut q_block = [0i16; 64];
+                    for (i, q) in q_block.iter_mut().enumerate() {
+                        *q = block[ZIGZAG[i] as usize & 0x3f];
+                    }
+                    q_block
+                })
+                .collect();
+        }
+
+        self.write_headers(color_type)?;
+
+        self.encode_image_blocks(&blocks, width, height, &q_tables)?;
+
+        self.writer.write_marker(Marker::EOI)?;
+
+        Ok(())
+    }
+
+    /// Returns the samples a decoder would reconstruct from the encoded image
+    ///
+    /// The image is transformed and quantized with the settings of this encoder like in
+    /// [encode](Encoder::encode), but the quantized blocks are dequantized and inverse
+    /// transformed instead of being written. This allows judging the quality of the
+    /// settings without encoding and decoding the image.
+    ///
+    /// The samples are interleaved in the color space of the JPEG image, e.g. YCbCr for
+    /// RGB input, and subsampled components are upsampled by repeating their samples.
+    /// Decoders may use other upsampling filters, so their output can differ slightly.
+    ///
+    /// The quality isn't adjusted to a [target size](Encoder::set_target_size) or a
+    /// [quality target](Encoder::set_quality_target). Lossless, hierarchical and 12 bit
+    /// images aren't supported.
+    pub fn reconstruct(
+        &self,
+        data: &[u8],
+        width: u16,
+        height: u16,
+        color_type: ColorType,
+    ) -> Result<Vec<u8>, EncodingError> {
+        let row_length = usize::from(width) * color_type.get_bytes_per_pixel();
+        let required_data_len = usize::from(height) * row_length;
+
+        if data.len() < required_data_len {
+            return Err(EncodingError::BadImageData {
+                length: data.len(),
+                required: required_data_len,
+            });
+        }
+
+        let image = self.get_image_buffer(color_type, data, width, height, row_length);
+
+        self.reconstruct_image(DynImageBuffer(&*image))
+    }
+
+    /// Returns the samples a decoder would reconstruct from the encoded image
+    ///
+    /// See [reconstruct](Encoder::reconstruct) for details.
+    pub fn reconstruct_image<I: ImageBuffer>(&self, image: I) -> Result<Vec<u8>, EncodingError> {
+        #[cfg(all(feature = "simd", any(target_arch = "x86", target_arch = "x86_64")))]
+        {
+            if std::is_x86_feature_detected!("avx2") {
+                use crate::avx2::*;
+                return self.reconstruct_internal::<_, AVX2Operations>(&image);
+            }
+        }
+        self.reconstruct_internal::<_, DefaultOperations>(&image)
+    }
+
+    fn reconstruct_internal<I: ImageBuffer, OP: Operations>(
+        &self,
+        image: &I,
+    ) -> Result<Vec<u8>, EncodingError> {
         if image.width() == 0 || image.height() == 0 {
             return Err(EncodingError::ZeroImageDimensions {
                 width: image.width(),
@@ -491,499 +1463,2314 @@ impl<W: JfifWrite> Encoder<W> {
             });
         }
 
-        let q_tables = [
-            QuantizationTable::new_with_quality(&self.quantization_tables[0], self.quality, true),
-            QuantizationTable::new_with_quality(&self.quantization_tables[1], self.quality, false),
-        ];
+        if self.lossless_predictor.is_some() {
+            return Err(EncodingError::UnsupportedReconstructionSettings("lossless"));
+        }
+
+        if self.is_hierarchical() {
+            return Err(EncodingError::UnsupportedReconstructionSettings(
+                "hierarchical",
+            ));
+        }
+
+        if self.sample_precision != 8 {
+            return Err(EncodingError::UnsupportedSamplePrecision(
+                self.sample_precision,
+            ));
+        }
+
+        let mut encoder = self.with_writer(Vec::new());
+        encoder.init_components(image.get_jpeg_color_type());
+
+        let q_tables = encoder.get_quantization_tables();
+        let blocks = encoder.encode_blocks::<_, OP>(image, None, &q_tables);
+
+        let width = usize::from(image.width());
+        let height = usize::from(image.height());
+
+        let (max_h_sampling, max_v_sampling) = encoder.get_max_sampling_size();
+
+        let planes: Vec<_> = encoder
+            .components
+            .iter()
+            .zip(blocks.iter())
+            .map(|(component, blocks)| {
+                let h_scale = max_h_sampling / component.horizontal_sampling_factor as usize;
+                let v_scale = max_v_sampling / component.vertical_sampling_factor as usize;
+
+                let plane = reconstruct_plane::<OP>(
+                    blocks,
+                    &q_tables[component.quantization_table as usize],
+                    ceil_div(width, h_scale),
+                    ceil_div(height, v_scale),
+                    None,
+                );
+
+                replicate_samples(&plane, h_scale, v_scale, width, height)
+            })
+            .collect();
+
+        let mut samples = Vec::with_capacity(width * height * planes.len());
+
+        for index in 0..width * height {
+            for plane in &planes {
+                samples.push(plane.samples[index] as u8);
+            }
+        }
+
+        Ok(samples)
+    }
+
+    /// Start encoding an image row by row
+    ///
+    /// The returned [StreamingEncoder] takes the image data in rows of the given color type
+    /// and writes each row of MCUs as soon as enough rows are available.
+    /// Its memory use only depends on the image width, not on the height.
+    ///
+    /// Streaming requires a single interleaved scan, so lossless, hierarchical and progressive
+    /// encoding, arithmetic coding, optimized huffman tables, sample precisions other than 8 bits
+    /// and sampling factors of 4 aren't supported.
+    pub fn into_streaming(
+        mut self,
+        width: u16,
+        height: u16,
+        color_type: ColorType,
+    ) -> Result<StreamingEncoder<W>, EncodingError> {
+        if width == 0 || height == 0 {
+            return Err(EncodingError::ZeroImageDimensions { width, height });
+        }
+
+        let image = self.get_image_buffer(color_type, &[], width, 0, 0);
+
+        let unsupported = if self.lossless_predictor.is_some() {
+            Some("lossless encoding")
+        } else if self.is_hierarchical() {
+            Some("hierarchical encoding")
+        } else if self.is_progressive() {
+            Some("progressive encoding")
+        } else if self.is_arithmetic() {
+            Some("arithmetic coding")
+        } else if self.optimize_huffman_table {
+            Some("optimized huffman tables")
+        } else if self.sample_precision != 8 {
+            Some("sample precision other than 8 bits")
+        } else if self.target_size.is_some() {
+            Some("target size")
+        } else if self.has_quality_target() {
+            Some("quality target")
+        } else if !self.sampling_factor.supports_interleaved() {
+            Some("sampling factors of 4")
+        } else if self.sharp_yuv && self.sampling_factor != SamplingFactor::F_1_1 {
+            Some("sharp YUV")
+        } else if self.has_linear_light() && self.sampling_factor != SamplingFactor::F_1_1 {
+            Some("linear light downsampling")
+        } else if self.needs_downsampling_context() {
+            Some("triangle and Lanczos downsampling filters")
+        } else if self.diffuses_errors(image.sample_precision()) {
+            Some("error diffusion dithering")
+        } else if self.uses_high_precision_fdct(&*image) {
+            Some("high precision FDCT")
+        } else {
+            None
+        };
+
+        if let Some(setting) = unsupported {
+            return Err(EncodingError::UnsupportedStreamingSettings(setting));
+        }
+
+        let q_tables = self.get_quantization_tables();
 
         let jpeg_color_type = image.get_jpeg_color_type();
         self.init_components(jpeg_color_type);
 
-        self.writer.write_marker(Marker::SOI)?;
+        self.write_headers(jpeg_color_type)?;
+        self.write_frame_header(width, height, &q_tables)?;
+        self.writer
+            .write_scan_header(&self.components.iter().collect::<Vec<_>>(), None, (0, 0))?;
 
-        self.writer.write_header(&self.density)?;
+        Ok(StreamingEncoder::new(
+            self, width, height, color_type, q_tables,
+        ))
+    }
 
-        if jpeg_color_type == JpegColorType::Cmyk {
-            //Set ColorTransform info to "Unknown"
-            let app_14 = b"Adobe\0\0\0\0\0\0\0";
-            self.writer
-                .write_segment(Marker::APP(14), app_14.as_ref())?;
-        } else if jpeg_color_type == JpegColorType::Ycck {
-            //Set ColorTransform info to YCCK
-            let app_14 = b"Adobe\0\0\0\0\0\0\x02";
-            self.writer
-                .write_segment(Marker::APP(14), app_14.as_ref())?;
+    /// Finish an interleaved scan and write the end of the image
+    pub(crate) fn finish_interleaved(&mut self) -> Result<(), EncodingError> {
+        self.writer.finalize_bit_buffer()?;
+        self.writer.write_marker(Marker::EOI)?;
+
+        Ok(())
+    }
+
+    fn encode_yuv_internal<OP: Operations>(
+        mut self,
+        data: &[u8],
+        width: u16,
+        height: u16,
+        format: YuvFormat,
+    ) -> Result<(), EncodingError> {
+        if width == 0 || height == 0 {
+            return Err(EncodingError::ZeroImageDimensions { width, height });
         }
 
-        for (nr, data) in &self.app_segments {
-            self.writer.write_segment(Marker::APP(*nr), data)?;
+        if self.sample_precision != 8 {
+            return Err(EncodingError::UnsupportedSamplePrecision(
+                self.sample_precision,
+            ));
         }
 
-        if let Some(scans) = self.progressive_scans {
-            self.encode_image_progressive::<_, OP>(image, scans, &q_tables)?;
-        } else if self.optimize_huffman_table || !self.sampling_factor.supports_interleaved() {
-            self.encode_image_sequential::<_, OP>(image, &q_tables)?;
-        } else {
-            self.encode_image_interleaved::<_, OP>(image, &q_tables)?;
+        let required_data_len = format.required_data_len(width, height);
+
+        if data.len() < required_data_len {
+            return Err(EncodingError::BadImageData {
+                length: data.len(),
+                required: required_data_len,
+            });
+        }
+
+        self.sampling_factor = format.sampling_factor();
+
+        let q_tables = self.get_quantization_tables();
+
+        self.init_components(JpegColorType::Ycbcr);
+
+        if let Some(script) = &self.scan_script {
+            script.validate(&self.components, self.sample_precision)?;
         }
 
+        let planes = format.planes(data, width, height);
+        let blocks = self.encode_plane_blocks::<OP>(&planes, width, height, &q_tables);
+
+        self.write_headers(JpegColorType::Ycbcr)?;
+
+        self.encode_image_blocks(&blocks, width, height, &q_tables)?;
+
         self.writer.write_marker(Marker::EOI)?;
 
         Ok(())
     }
 
-    fn init_components(&mut self, color: JpegColorType) {
-        let (horizontal_sampling_factor, vertical_sampling_factor) =
-            self.sampling_factor.get_sampling_factors();
+    /// Encode an image
+    ///
+    /// `sync_image` is the same image if it can be read from multiple threads.
+    fn encode_image_internal<I: ImageBuffer, OP: Operations>(
+        mut self,
+        image: &I,
+        sync_image: Option<&(dyn ImageBuffer + Sync)>,
+    ) -> Result<(), EncodingError> {
+        if image.width() == 0 || image.height() == 0 {
+            return Err(EncodingError::ZeroImageDimensions {
+                width: image.width(),
+                height: image.height(),
+            });
+        }
 
-        match color {
-            JpegColorType::Luma => {
-                add_component!(self.components, 0, 0, 1, 1);
-            }
-            JpegColorType::Ycbcr => {
-                add_component!(
-                    self.components,
-                    0,
-                    0,
-                    horizontal_sampling_factor,
-                    vertical_sampling_factor
-                );
-                add_component!(self.components, 1, 1, 1, 1);
-                add_component!(self.components, 2, 1, 1, 1);
+        #[cfg(feature = "std")]
+        if let Some(quality_target) = self.quality_target.take() {
+            self.quality = self.find_target_quality::<_, OP>(image, sync_image, quality_target);
+        }
+
+        if let Some(target_size) = self.target_size {
+            return self.encode_with_target_size::<_, OP>(image, sync_image, target_size);
+        }
+
+        if self.lossless_predictor.is_some() {
+            if !(2..=16).contains(&self.sample_precision) {
+                return Err(EncodingError::UnsupportedSamplePrecision(
+                    self.sample_precision,
+                ));
             }
-            JpegColorType::Cmyk => {
-                add_component!(self.components, 0, 1, 1, 1);
-                add_component!(self.components, 1, 1, 1, 1);
-                add_component!(self.components, 2, 1, 1, 1);
-                add_component!(
-                    self.components,
-                    3,
-                    0,
-                    horizontal_sampling_factor,
-                    vertical_sampling_factor
-                );
+
+            if self.point_transform >= self.sample_precision {
+                return Err(EncodingError::InvalidPointTransform {
+                    point_transform: self.point_transform,
+                    precision: self.sample_precision,
+                });
             }
-            JpegColorType::Ycck => {
-                add_component!(
-                    self.components,
-                    0,
-                    0,
-                    horizontal_sampling_factor,
-                    vertical_sampling_factor
-                );
-                add_component!(self.components, 1, 1, 1, 1);
-                add_component!(self.components, 2, 1, 1, 1);
-                add_component!(
-                    self.components,
-                    3,
-                    0,
-                    horizontal_sampling_factor,
-                    vertical_sampling_factor
-                );
+
+            // Lossless images don't use subsampling
+            self.sampling_factor = SamplingFactor::F_1_1;
+        } else if self.is_hierarchical() {
+            if self.sample_precision != 8 {
+                return Err(EncodingError::UnsupportedSamplePrecision(
+                    self.sample_precision,
+                ));
             }
+
+            // Resampling between the frames is done on full resolution components
+            self.sampling_factor = SamplingFactor::F_1_1;
+        } else if self.sample_precision != 8 && self.sample_precision != 12 {
+            return Err(EncodingError::UnsupportedSamplePrecision(
+                self.sample_precision,
+            ));
         }
-    }
 
-    fn get_max_sampling_size(&self) -> (usize, usize) {
-        let max_h_sampling = self.components.iter().fold(1, |value, component| {
-            value.max(component.horizontal_sampling_factor)
-        });
+        let q_tables = self.get_quantization_tables();
+
+        let jpeg_color_type = image.get_jpeg_color_type();
+        self.init_components(jpeg_color_type);
+
+        if let Some(script) = &self.scan_script {
+            script.validate(&self.components, self.sample_precision)?;
+        }
+
+        self.write_headers(jpeg_color_type)?;
+
+        if let Some(predictor) = self.lossless_predictor {
+            self.encode_image_lossless(image, predictor)?;
+        } else if self.is_hierarchical() {
+            self.encode_image_hierarchical(image, &q_tables)?;
+        } else if self.uses_interleaved_scan(image) {
+            self.encode_image_interleaved::<_, OP>(image, sync_image, &q_tables)?;
+        } else {
+            let blocks = self.encode_blocks::<_, OP>(image, sync_image, &q_tables);
+            self.encode_image_blocks(&blocks, image.width(), image.height(), &q_tables)?;
+        }
+
+        self.writer.write_marker(Marker::EOI)?;
+
+        Ok(())
+    }
+
+    /// Returns if the image is written as a single interleaved sequential scan
+    ///
+    /// All other images are transformed as a whole into the blocks of each component.
+    fn uses_interleaved_scan<I: ImageBuffer>(&self, image: &I) -> bool {
+        !(self.is_arithmetic()
+            || self.is_progressive()
+            || self.sample_precision != 8
+            || self.optimize_huffman_table
+            || !self.sampling_factor.supports_interleaved()
+            || self.needs_downsampling_context()
+            || self.diffuses_errors(image.sample_precision())
+            || self.uses_high_precision_fdct(image))
+    }
+
+    fn has_quality_target(&self) -> bool {
+        #[cfg(feature = "std")]
+        {
+            self.quality_target.is_some()
+        }
+
+        #[cfg(not(feature = "std"))]
+        {
+            false
+        }
+    }
+
+    /// Finds the lowest quality whose reconstructed image reaches the quality target
+    #[cfg(feature = "std")]
+    fn find_target_quality<I: ImageBuffer, OP: Operations>(
+        &self,
+        image: &I,
+        sync_image: Option<&(dyn ImageBuffer + Sync)>,
+        quality_target: QualityTarget,
+    ) -> u8 {
+        let max_quality = self.quality.clamp(1, 100);
+
+        if self.lossless_predictor.is_some() || self.is_hierarchical() || self.sample_precision != 8
+        {
+            return max_quality;
+        }
+
+        let mut encoder = self.with_writer(Vec::new());
+        let jpeg_color_type = image.get_jpeg_color_type();
+        encoder.init_components(jpeg_color_type);
+
+        let q_tables = encoder.get_quantization_tables();
+
+        // Chroma downsampled while reading the rows replaces the original chroma samples
+        let original_rows = if encoder.downsamples_rows(jpeg_color_type) {
+            Some(encoder.read_rows_8bit(image, sync_image).0)
+        } else {
+            None
+        };
+
+        let (rows, buffer_width) = encoder.read_image_rows(image, sync_image);
+
+        let blocks =
+            encoder.transform_blocks(image, &rows, buffer_width, &q_tables, 128, |block, _| {
+                OP::fdct(block);
+                *block
+            });
+
+        let width = usize::from(image.width());
+        let height = usize::from(image.height());
+
+        let originals: Vec<_> = original_rows
+            .as_ref()
+            .unwrap_or(&rows)
+            .iter()
+            .take(encoder.components.len())
+            .map(|rows| {
+                let samples = rows
+                    .chunks(buffer_width)
+                    .take(height)
+                    .flat_map(|row| row[..width].iter().map(|&value| i16::from(value)))
+                    .collect();
+
+                Plane::new(width, height, samples)
+            })
+            .collect();
+
+        let (max_h_sampling, max_v_sampling) = encoder.get_max_sampling_size();
+        let trellis = self.trellis_quantization && !self.is_arithmetic();
+
+        let mut is_reached = |quality: u8| {
+            encoder.quality = quality;
+
+            let q_tables = encoder.get_quantization_tables();
+
+            let reconstructed: Vec<_> = encoder
+                .components
+                .iter()
+                .zip(blocks.iter())
+                .map(|(component, blocks)| {
+                    let table = &q_tables[component.quantization_table as usize];
+                    let ac_table = &encoder.huffman_tables[component.ac_huffman_table as usize].1;
+
+                    let quantized: Vec<_> = blocks
+                        .iter()
+                        .map(|block| {
+                            if trellis {
+                                trellis_quantize(block, table, ac_table)
+                            } else {
+                                let mut q_block = [0i16; 64];
+                                OP::quantize_block(block, &mut q_block, table);
+                                q_block
+                            }
+                        })
+                        .collect();
+
+                    let h_scale = max_h_sampling / component.horizontal_sampling_factor as usize;
+                    let v_scale = max_v_sampling / component.vertical_sampling_factor as usize;
+
+                    let plane = reconstruct_plane::<OP>(
+                        &quantized,
+                        table,
+                        ceil_div(width, h_scale),
+                        ceil_div(height, v_scale),
+                        None,
+                    );
+
+                    replicate_samples(&plane, h_scale, v_scale, width, height)
+                })
+                .collect();
+
+            quality_target.is_reached(&originals, &reconstructed)
+        };
+
+        let mut low = 1;
+        let mut high = max_quality;
+
+        while low < high {
+            let quality = (low + high) / 2;
+
+            if is_reached(quality) {
+                high = quality;
+            } else {
+                low = quality + 1;
+            }
+        }
+
+        low
+    }
+
+    /// Encode the image with the highest quality whose output fits into the target size
+    fn encode_with_target_size<I: ImageBuffer, OP: Operations>(
+        mut self,
+        image: &I,
+        sync_image: Option<&(dyn ImageBuffer + Sync)>,
+        target_size: usize,
+    ) -> Result<(), EncodingError> {
+        let mut low = 1;
+        let mut high = self.quality.clamp(1, 100);
+
+        // Transformed blocks for each sampling factor, which are shared by all qualities
+        let mut transformed = Vec::new();
+
+        let mut next = self.estimate_target_quality::<_, OP>(
+            image,
+            sync_image,
+            target_size,
+            high,
+            &mut transformed,
+        )?;
+        let mut step = 1u8;
+
+        let mut best = None;
+        let mut smallest = usize::MAX;
+
+        while low <= high {
+            let quality = next.clamp(low, high);
+            let output =
+                self.encode_with_quality::<_, OP>(image, sync_image, quality, &mut transformed)?;
+            let fits = output.len() <= target_size;
+
+            if fits {
+                low = quality + 1;
+                best = Some(output);
+            } else {
+                high = quality - 1;
+                smallest = smallest.min(output.len());
+            }
+
+            // The estimate is usually close, so the search moves away from it with growing
+            // steps until the quality is enclosed and bisects the remaining range afterwards
+            next = if best.is_some() && smallest != usize::MAX {
+                (low + high) / 2
+            } else if fits {
+                quality.saturating_add(step)
+            } else {
+                quality.saturating_sub(step)
+            };
+
+            step = step.saturating_mul(2);
+        }
+
+        match best {
+            Some(output) => self.writer.write(&output),
+            None => Err(EncodingError::TargetSizeTooSmall {
+                size: smallest,
+                target_size,
+            }),
+        }
+    }
+
+    /// Encode the image into a buffer with the settings of this encoder and the given quality
+    ///
+    /// Images which are transformed into blocks of 8 bit samples are only transformed once for
+    /// each sampling factor and the blocks are kept in `transformed` for the other qualities.
+    fn encode_with_quality<I: ImageBuffer, OP: Operations>(
+        &self,
+        image: &I,
+        sync_image: Option<&(dyn ImageBuffer + Sync)>,
+        quality: u8,
+        transformed: &mut Vec<TransformedBlocks>,
+    ) -> Result<Vec<u8>, EncodingError> {
+        let mut output = Vec::new();
+
+        let mut encoder = self.with_writer(&mut output);
+        encoder.quality = quality;
+        encoder.target_size = None;
+
+        if self.target_size_subsampling {
+            encoder.sampling_factor = default_sampling_factor(quality);
+        }
+
+        if encoder.lossless_predictor.is_some()
+            || encoder.is_hierarchical()
+            || encoder.sample_precision != 8
+            || encoder.uses_high_precision_fdct(image)
+        {
+            encoder.encode_image_internal::<_, OP>(image, sync_image)?;
+            return Ok(output);
+        }
+
+        let q_tables = encoder.get_quantization_tables();
+
+        let jpeg_color_type = image.get_jpeg_color_type();
+        encoder.init_components(jpeg_color_type);
+
+        if let Some(script) = &encoder.scan_script {
+            script.validate(&encoder.components, encoder.sample_precision)?;
+        }
+
+        encoder.write_headers(jpeg_color_type)?;
+
+        let blocks = encoder
+            .get_transformed_blocks::<_, OP>(image, sync_image, transformed)
+            .clone();
+        let blocks = encoder.quantize_transformed_blocks::<OP>(blocks, &q_tables);
+
+        if encoder.uses_interleaved_scan(image) {
+            encoder.encode_interleaved_blocks(&blocks, image.width(), image.height(), &q_tables)?;
+        } else {
+            encoder.encode_image_blocks(&blocks, image.width(), image.height(), &q_tables)?;
+        }
+        encoder.writer.write_marker(Marker::EOI)?;
+
+        Ok(output)
+    }
+
+    /// Returns the transformed blocks of an 8 bit image for the sampling factor of this encoder
+    ///
+    /// The image is only transformed if `transformed` doesn't contain the blocks yet. Images
+    /// written as interleaved scan are transformed in whole MCUs.
+    fn get_transformed_blocks<'a, I: ImageBuffer, OP: Operations>(
+        &mut self,
+        image: &I,
+        sync_image: Option<&(dyn ImageBuffer + Sync)>,
+        transformed: &'a mut Vec<TransformedBlocks>,
+    ) -> &'a [Vec<[i16; 64]>; 4] {
+        let index = match transformed
+            .iter()
+            .position(|(sampling_factor, _)| *sampling_factor == self.sampling_factor)
+        {
+            Some(index) => index,
+            None => {
+                let blocks = if self.uses_interleaved_scan(image) {
+                    self.transform_interleaved::<_, OP>(image, sync_image)
+                } else {
+                    let q_tables = self.get_quantization_tables();
+                    self.transform_image::<_, OP>(image, sync_image, &q_tables)
+                };
+
+                transformed.push((self.sampling_factor, blocks));
+                transformed.len() - 1
+            }
+        };
+
+        &transformed[index].1
+    }
+
+    /// Quantizes transformed blocks with trellis quantization if enabled
+    fn quantize_transformed_blocks<OP: Operations>(
+        &mut self,
+        mut blocks: [Vec<[i16; 64]>; 4],
+        q_tables: &[QuantizationTable; 2],
+    ) -> [Vec<[i16; 64]>; 4] {
+        if self.trellis_quantization && !self.is_arithmetic() {
+            self.trellis_quantize_blocks::<OP>(blocks, q_tables)
+        } else {
+            self.quantize_blocks::<OP>(&mut blocks, q_tables);
+            blocks
+        }
+    }
+
+    /// Quantizes transformed blocks with the tables of their components
+    fn quantize_blocks<OP: Operations>(
+        &self,
+        blocks: &mut [Vec<[i16; 64]>; 4],
+        q_tables: &[QuantizationTable; 2],
+    ) {
+        for (i, component) in self.components.iter().enumerate() {
+            let table = &q_tables[component.quantization_table as usize];
+
+            for block in &mut blocks[i] {
+                let mut q_block = [0i16; 64];
+                OP::quantize_block(block, &mut q_block, table);
+                *block = q_block;
+            }
+        }
+    }
+
+    /// Estimates the highest quality whose output fits into the target size
+    ///
+    /// The blocks are transformed once for each sampling factor and quantized for each quality
+    /// tried. The size of a quality is estimated from the symbol statistics of sequential
+    /// huffman coding, so no estimate is made for arithmetic, 12 bit, hierarchical
+    /// and lossless images.
+    fn estimate_target_quality<I: ImageBuffer, OP: Operations>(
+        &self,
+        image: &I,
+        sync_image: Option<&(dyn ImageBuffer + Sync)>,
+        target_size: usize,
+        max_quality: u8,
+        transformed: &mut Vec<TransformedBlocks>,
+    ) -> Result<u8, EncodingError> {
+        if self.lossless_predictor.is_some()
+            || self.is_hierarchical()
+            || self.is_arithmetic()
+            || self.sample_precision != 8
+        {
+            return Ok(max_quality);
+        }
+
+        let jpeg_color_type = image.get_jpeg_color_type();
+
+        let mut headers = self.with_writer(Vec::new());
+        headers.write_headers(jpeg_color_type)?;
+        let header_size = headers.writer.into_inner().len();
+
+        let mut estimate = |quality: u8| {
+            let mut encoder = self.with_writer(Vec::new());
+            encoder.quality = quality;
+
+            if self.target_size_subsampling {
+                encoder.sampling_factor = default_sampling_factor(quality);
+            }
+
+            encoder.init_components(jpeg_color_type);
+
+            let mut quantized = encoder
+                .get_transformed_blocks::<_, OP>(image, sync_image, transformed)
+                .clone();
+
+            let q_tables = encoder.get_quantization_tables();
+            encoder.quantize_blocks::<OP>(&mut quantized, &q_tables);
+
+            header_size + encoder.estimate_sequential_size(&quantized)
+        };
+
+        let mut low = 1;
+        let mut high = max_quality;
+
+        while low < high {
+            let quality = (low + high + 1) / 2;
+
+            if estimate(quality) <= target_size {
+                low = quality;
+            } else {
+                high = quality - 1;
+            }
+        }
+
+        Ok(low)
+    }
+
+    /// Estimates the size of the quantized blocks written as sequential huffman coded image
+    ///
+    /// This includes the frame, scan and table segments but not the other headers.
+    fn estimate_sequential_size(&mut self, blocks: &[Vec<[i16; 64]>; 4]) -> usize {
+        if self.optimize_huffman_table || self.is_progressive() {
+            self.optimize_huffman_table(blocks, None, false);
+        }
+
+        let restart_interval = usize::from(self.restart_interval.unwrap_or(0));
+        let num_components = self.components.len();
+
+        let mut bits = 0;
+
+        for (i, component) in self.components.iter().enumerate() {
+            let mut dc_freq = [0u32; 257];
+            let mut ac_freq = [0u32; 257];
+
+            let order: Vec<_> = (0..blocks[i].len()).map(|index| (i, index)).collect();
+            count_dc_symbols(blocks, i, &order, 1, 0, restart_interval, &mut dc_freq);
+            count_ac_symbols(&blocks[i], &mut ac_freq);
+
+            let (dc_table, _) = &self.huffman_tables[component.dc_huffman_table as usize];
+            let (_, ac_table) = &self.huffman_tables[component.ac_huffman_table as usize];
+
+            bits += get_coded_bits(&dc_freq, dc_table) + get_coded_bits(&ac_freq, ac_table);
+        }
+
+        let data_size = bits / 8;
+
+        // Roughly every 256th byte of the coded data needs a stuffed zero byte
+        let stuffing_size = data_size / 256;
+
+        let table_size: usize = self
+            .huffman_tables
+            .iter()
+            .map(|(dc, ac)| 2 * (2 + 2 + 1 + 16) + dc.values().len() + ac.values().len())
+            .sum();
+
+        // DQT, SOF, SOS and EOI segments
+        let segment_size = 2 * (4 + 65) + (10 + 3 * num_components) + (8 + 2 * num_components) + 2;
+
+        data_size + stuffing_size + table_size + segment_size
+    }
+
+    /// Copy the settings of this encoder to a new encoder writing to `w`
+    fn with_writer<V: JfifWrite>(&self, w: V) -> Encoder<V> {
+        Encoder {
+            writer: JfifWriter::new(w),
+            density: self.density,
+            quality: self.quality,
+            components: vec![],
+            quantization_tables: self.quantization_tables.clone(),
+            huffman_tables: self.huffman_tables.clone(),
+            sampling_factor: self.sampling_factor,
+            downsampling_filter: self.downsampling_filter,
+            sharp_yuv: self.sharp_yuv,
+            #[cfg(feature = "std")]
+            linear_light: self.linear_light,
+            color_matrix: self.color_matrix,
+            rgb_without_transform: self.rgb_without_transform,
+            alpha_policy: self.alpha_policy,
+            dithering: self.dithering,
+            high_precision_fdct: self.high_precision_fdct,
+            progressive_scans: self.progressive_scans,
+            successive_approximation: self.successive_approximation,
+            interleaved_dc_scan: self.interleaved_dc_scan,
+            scan_script: self.scan_script.clone(),
+            restart_interval: self.restart_interval,
+            optimize_huffman_table: self.optimize_huffman_table,
+            trellis_quantization: self.trellis_quantization,
+            lossless_predictor: self.lossless_predictor,
+            point_transform: self.point_transform,
+            sample_precision: self.sample_precision,
+            hierarchical_levels: self.hierarchical_levels,
+            target_size: self.target_size,
+            target_size_subsampling: self.target_size_subsampling,
+            #[cfg(feature = "std")]
+            quality_target: self.quality_target,
+            #[cfg(feature = "parallel")]
+            num_threads: self.num_threads,
+            #[cfg(feature = "arithmetic")]
+            arithmetic_coding: self.arithmetic_coding,
+            #[cfg(feature = "arithmetic")]
+            arithmetic_conditioning: self.arithmetic_conditioning,
+            app_segments: self.app_segments.clone(),
+        }
+    }
+
+    fn get_quantization_tables(&self) -> [QuantizationTable; 2] {
+        [
+            QuantizationTable::new_with_quality(
+                &self.quantization_tables[0],
+                self.quality,
+                true,
+                self.sample_precision,
+            ),
+            QuantizationTable::new_with_quality(
+                &self.quantization_tables[1],
+                self.quality,
+                false,
+                self.sample_precision,
+            ),
+        ]
+    }
+
+    fn write_headers(&mut self, jpeg_color_type: JpegColorType) -> Result<(), EncodingError> {
+        self.writer.write_marker(Marker::SOI)?;
+
+        // Decoders treat 3 component images with a JFIF header as YCbCr
+        if jpeg_color_type != JpegColorType::Rgb {
+            self.writer.write_header(&self.density)?;
+        }
+
+        if jpeg_color_type == JpegColorType::Cmyk || jpeg_color_type == JpegColorType::Rgb {
+            //Set ColorTransform info to "Unknown"
+            let app_14 = b"Adobe\0\0\0\0\0\0\0";
+            self.writer
+                .write_segment(Marker::APP(14), app_14.as_ref())?;
+        } else if jpeg_color_type == JpegColorType::Ycck {
+            //Set ColorTransform info to YCCK
+            let app_14 = b"Adobe\0\0\0\0\0\0\x02";
+            self.writer
+                .write_segment(Marker::APP(14), app_14.as_ref())?;
+        }
+
+        for (nr, data) in &self.app_segments {
+            self.writer.write_segment(Marker::APP(*nr), data)?;
+        }
+
+        Ok(())
+    }
+
+    /// Encode the quantized blocks of all components
+    fn encode_image_blocks(
+        &mut self,
+        blocks: &[Vec<[i16; 64]>; 4],
+        width: u16,
+        height: u16,
+        q_tables: &[QuantizationTable; 2],
+    ) -> Result<(), EncodingError> {
+        if self.is_arithmetic() {
+            #[cfg(feature = "arithmetic")]
+            self.encode_image_arithmetic(blocks, width, height, q_tables)?;
+        } else if self.is_progressive() {
+            self.encode_image_progressive(blocks, width, height, q_tables)?;
+        } else {
+            if self.sample_precision != 8 {
+                // The default huffman tables don't contain the values needed for 12 bit samples
+                self.optimize_huffman_table = true;
+            }
+
+            self.encode_image_sequential(blocks, width, height, q_tables)?;
+        }
+
+        Ok(())
+    }
+
+    fn init_components(&mut self, color: JpegColorType) {
+        let (horizontal_sampling_factor, vertical_sampling_factor) =
+            self.sampling_factor.get_sampling_factors();
+
+        match color {
+            JpegColorType::Luma => {
+                add_component!(self.components, 0, 0, 1, 1);
+            }
+            JpegColorType::Ycbcr => {
+                add_component!(
+                    self.components,
+                    0,
+                    0,
+                    horizontal_sampling_factor,
+                    vertical_sampling_factor
+                );
+                add_component!(self.components, 1, 1, 1, 1);
+                add_component!(self.components, 2, 1, 1, 1);
+            }
+            JpegColorType::Cmyk => {
+                add_component!(self.components, 0, 1, 1, 1);
+                add_component!(self.components, 1, 1, 1, 1);
+                add_component!(self.components, 2, 1, 1, 1);
+                add_component!(
+                    self.components,
+                    3,
+                    0,
+                    horizontal_sampling_factor,
+                    vertical_sampling_factor
+                );
+            }
+            JpegColorType::Ycck => {
+                add_component!(
+                    self.components,
+                    0,
+                    0,
+                    horizontal_sampling_factor,
+                    vertical_sampling_factor
+                );
+                add_component!(self.components, 1, 1, 1, 1);
+                add_component!(self.components, 2, 1, 1, 1);
+                add_component!(
+                    self.components,
+                    3,
+                    0,
+                    horizontal_sampling_factor,
+                    vertical_sampling_factor
+                );
+            }
+            JpegColorType::Rgb => {
+                add_component!(self.components, b'R', 0, 1, 1);
+                add_component!(self.components, b'G', 0, 1, 1);
+                add_component!(self.components, b'B', 0, 1, 1);
+            }
+        }
+    }
+
+    pub(crate) fn get_max_sampling_size(&self) -> (usize, usize) {
+        let max_h_sampling = self.components.iter().fold(1, |value, component| {
+            value.max(component.horizontal_sampling_factor)
+        });
+
+        let max_v_sampling = self.components.iter().fold(1, |value, component| {
+            value.max(component.vertical_sampling_factor)
+        });
+
+        (usize::from(max_h_sampling), usize::from(max_v_sampling))
+    }
+
+    fn write_frame_header(
+        &mut self,
+        width: u16,
+        height: u16,
+        q_tables: &[QuantizationTable; 2],
+    ) -> Result<(), EncodingError> {
+        let baseline = self.sample_precision == 8;
+
+        let sof_type = match (self.is_progressive(), self.is_arithmetic()) {
+            (false, false) if baseline => SOFType::BaselineDCT,
+            (false, false) => SOFType::ExtendedSequentialDCT,
+            (true, false) => SOFType::ProgressiveDCT,
+            (false, true) => SOFType::ExtendedSequentialDCTArithmetic,
+            (true, true) => SOFType::ProgressiveDCTArithmetic,
+        };
+
+        self.writer.write_frame_header(
+            width,
+            height,
+            &self.components,
+            sof_type,
+            self.sample_precision,
+        )?;
+
+        self.writer.write_quantization_segment(0, &q_tables[0])?;
+        self.writer.write_quantization_segment(1, &q_tables[1])?;
+
+        #[cfg(feature = "arithmetic")]
+        if self.arithmetic_coding {
+            let num_tables = self.components.len().min(2);
+
+            self.writer.write_arithmetic_conditioning_segment(
+                &self.arithmetic_conditioning[..num_tables],
+            )?;
+
+            if let Some(restart_interval) = self.restart_interval {
+                self.writer.write_dri(restart_interval)?;
+            }
+
+            return Ok(());
+        }
+
+        self.write_huffman_tables()?;
+
+        if let Some(restart_interval) = self.restart_interval {
+            self.writer.write_dri(restart_interval)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_huffman_tables(&mut self) -> Result<(), EncodingError> {
+        self.writer
+            .write_huffman_segment(CodingClass::Dc, 0, &self.huffman_tables[0].0)?;
+
+        self.writer
+            .write_huffman_segment(CodingClass::Ac, 0, &self.huffman_tables[0].1)?;
+
+        if self.components.len() >= 3 {
+            self.writer
+                .write_huffman_segment(CodingClass::Dc, 1, &self.huffman_tables[1].0)?;
+
+            self.writer
+                .write_huffman_segment(CodingClass::Ac, 1, &self.huffman_tables[1].1)?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn init_rows<T>(&mut self, buffer_size: usize) -> [Vec<T>; 4] {
+        init_rows(self.components.len(), buffer_size)
+    }
+
+    /// Encode all components with one scan
+    ///
+    /// This is only valid for sampling factors of 1 and 2
+    fn encode_image_interleaved<I: ImageBuffer, OP: Operations>(
+        &mut self,
+        image: &I,
+        sync_image: Option<&(dyn ImageBuffer + Sync)>,
+        q_tables: &[QuantizationTable; 2],
+    ) -> Result<(), EncodingError> {
+        self.write_frame_header(image.width(), image.height(), q_tables)?;
+        self.writer
+            .write_scan_header(&self.components.iter().collect::<Vec<_>>(), None, (0, 0))?;
+
+        let (max_h_sampling, max_v_sampling) = self.get_max_sampling_size();
+
+        let width = image.width();
+        let height = image.height();
+
+        let num_cols = ceil_div(usize::from(width), 8 * max_h_sampling);
+        let num_rows = ceil_div(usize::from(height), 8 * max_v_sampling);
+
+        let buffer_width = num_cols * 8 * max_h_sampling;
+        let buffer_size = buffer_width * 8 * max_v_sampling;
+
+        let mut state = InterleavedState::new(self.restart_interval);
+
+        if sync_image.is_some() && self.threads() > 1 {
+            // Only a batch of MCU rows for each thread is kept in memory
+            let batch_size = self.threads() * MCU_ROWS_PER_THREAD;
+
+            for start in (0..num_rows).step_by(batch_size) {
+                let mcu_rows = start..(start + batch_size).min(num_rows);
+
+                let blocks = self.transform_mcu_rows::<_, OP>(image, sync_image, mcu_rows);
+                let blocks = self.quantize_transformed_blocks::<OP>(blocks, q_tables);
+
+                self.write_mcu_batch(&blocks, num_cols, &mut state)?;
+            }
+
+            return self.writer.finalize_bit_buffer();
+        }
+
+        let mut row: [Vec<_>; 4] = self.init_rows(buffer_size);
+
+        for block_y in 0..num_rows {
+            for r in &mut row {
+                r.clear();
+            }
+
+            for y in 0..(8 * max_v_sampling) {
+                let y = y + block_y * 8 * max_v_sampling;
+                let y = (y.min(height as usize - 1)) as u16;
+
+                fill_buffers_reduced(image, y, usize::from(y), self.dithering, &mut row);
+
+                for _ in usize::from(width)..buffer_width {
+                    for channel in &mut row {
+                        if !channel.is_empty() {
+                            channel.push(channel[channel.len() - 1]);
+                        }
+                    }
+                }
+            }
+
+            self.encode_mcu_row::<OP>(&row, buffer_width, q_tables, &mut state)?;
+        }
+
+        self.writer.finalize_bit_buffer()?;
+
+        Ok(())
+    }
+
+    /// Transforms the blocks of an 8 bit image without quantization for an interleaved scan
+    ///
+    /// Unlike `encode_image_interleaved`, which only keeps a single row of MCUs, this keeps the
+    /// blocks of the whole image to reuse them for the qualities tried for a target size.
+    /// The blocks of each component cover the whole MCUs, including the MCUs at the right and
+    /// bottom edges of the image. The rows of MCUs are transformed by multiple threads if the
+    /// image is also passed as `sync_image`.
+    fn transform_interleaved<I: ImageBuffer, OP: Operations>(
+        &mut self,
+        image: &I,
+        sync_image: Option<&(dyn ImageBuffer + Sync)>,
+    ) -> [Vec<[i16; 64]>; 4] {
+        let (_, max_v_sampling) = self.get_max_sampling_size();
+        let num_rows = ceil_div(usize::from(image.height()), 8 * max_v_sampling);
+
+        self.transform_mcu_rows::<_, OP>(image, sync_image, 0..num_rows)
+    }
+
+    /// Transforms the blocks of the given rows of MCUs without quantization
+    ///
+    /// The rows are transformed by multiple threads if the image is also passed as `sync_image`.
+    fn transform_mcu_rows<I: ImageBuffer, OP: Operations>(
+        &self,
+        image: &I,
+        sync_image: Option<&(dyn ImageBuffer + Sync)>,
+        mcu_rows: Range<usize>,
+    ) -> [Vec<[i16; 64]>; 4] {
+        let max_sampling = self.get_max_sampling_size();
+        let (max_h_sampling, max_v_sampling) = max_sampling;
+
+        let width = image.width();
+        let height = image.height();
+
+        let num_cols = ceil_div(usize::from(width), 8 * max_h_sampling);
+
+        let buffer_width = num_cols * 8 * max_h_sampling;
+        let buffer_size = buffer_width * 8 * max_v_sampling;
+
+        let components = &self.components;
+        let dithering = self.dithering;
+        let filter = self.downsampling_filter;
+
+        let transform_rows = |image: &dyn ImageBuffer, mcu_rows: Range<usize>| {
+            let mut row: [Vec<_>; 4] = init_rows(components.len(), buffer_size);
+            let mut blocks = init_rows(components.len(), 0);
+
+            for block_y in mcu_rows {
+                for r in &mut row {
+                    r.clear();
+                }
+
+                for y in 0..(8 * max_v_sampling) {
+                    let y = y + block_y * 8 * max_v_sampling;
+                    let y = (y.min(height as usize - 1)) as u16;
+
+                    fill_buffers_reduced(image, y, usize::from(y), dithering, &mut row);
+
+                    for _ in usize::from(width)..buffer_width {
+                        for channel in &mut row {
+                            if !channel.is_empty() {
+                                channel.push(channel[channel.len() - 1]);
+                            }
+                        }
+                    }
+                }
+
+                transform_mcu_row::<OP>(
+                    components,
+                    max_sampling,
+                    filter,
+                    &row,
+                    buffer_width,
+                    &mut blocks,
+                );
+            }
+
+            blocks
+        };
+
+        match sync_image {
+            Some(image) if self.threads() > 1 => {
+                let start = mcu_rows.start;
+
+                let chunks = map_ranges(mcu_rows.len(), self.threads(), |range| {
+                    transform_rows(image, start + range.start..start + range.end)
+                });
+
+                let mut blocks: [Vec<Vec<[i16; 64]>>; 4] = Default::default();
+
+                for chunk in chunks {
+                    for (blocks, chunk) in blocks.iter_mut().zip(chunk) {
+                        blocks.push(chunk);
+                    }
+                }
+
+                blocks.map(concat)
+            }
+            _ => transform_rows(image, mcu_rows),
+        }
+    }
+
+    /// Encode a row of MCUs of an interleaved scan
+    ///
+    /// The rows contain the samples of all components for the full height of the MCUs
+    /// with a width of `buffer_width`, which must be a multiple of the MCU width.
+    pub(crate) fn encode_mcu_row<OP: Operations>(
+        &mut self,
+        row: &[Vec<u8>; 4],
+        buffer_width: usize,
+        q_tables: &[QuantizationTable; 2],
+        state: &mut InterleavedState,
+    ) -> Result<(), EncodingError> {
+        let max_sampling = self.get_max_sampling_size();
+
+        let mut blocks = self.init_block_buffers(0);
+        transform_mcu_row::<OP>(
+            &self.components,
+            max_sampling,
+            self.downsampling_filter,
+            row,
+            buffer_width,
+            &mut blocks,
+        );
+
+        let blocks = self.quantize_transformed_blocks::<OP>(blocks, q_tables);
+
+        let num_cols = buffer_width / (8 * max_sampling.0);
+        let num_mcus = get_num_mcus(&self.components, &blocks);
+
+        self.write_mcus(&blocks, num_cols, 0..num_mcus, state)
+    }
+
+    /// Encode quantized blocks covering whole MCUs as a single interleaved scan
+    ///
+    /// With multiple threads and a restart interval the intervals are coded in parallel.
+    fn encode_interleaved_blocks(
+        &mut self,
+        blocks: &[Vec<[i16; 64]>; 4],
+        width: u16,
+        height: u16,
+        q_tables: &[QuantizationTable; 2],
+    ) -> Result<(), EncodingError> {
+        self.write_frame_header(width, height, q_tables)?;
+        self.writer
+            .write_scan_header(&self.components.iter().collect::<Vec<_>>(), None, (0, 0))?;
+
+        let (max_h_sampling, _) = self.get_max_sampling_size();
+        let num_cols = ceil_div(usize::from(width), 8 * max_h_sampling);
+
+        let mut state = InterleavedState::new(self.restart_interval);
+
+        self.write_mcu_batch(blocks, num_cols, &mut state)?;
+
+        self.writer.finalize_bit_buffer()
+    }
+
+    /// Write a batch of MCUs of an interleaved scan
+    ///
+    /// With multiple threads and a restart interval the restart intervals which start and end
+    /// within the batch are coded in parallel. The MCUs before them continue the current
+    /// interval and the MCUs after them start the interval continued by the next batch.
+    fn write_mcu_batch(
+        &mut self,
+        blocks: &[Vec<[i16; 64]>; 4],
+        num_cols: usize,
+        state: &mut InterleavedState,
+    ) -> Result<(), EncodingError> {
+        let num_mcus = get_num_mcus(&self.components, blocks);
+
+        let threads = self.threads();
+        let restart_interval = usize::from(self.restart_interval.unwrap_or(0));
+
+        if threads <= 1 || restart_interval == 0 {
+            return self.write_mcus(blocks, num_cols, 0..num_mcus, state);
+        }
+
+        let start = usize::from(state.restarts_to_go).min(num_mcus);
+        self.write_mcus(blocks, num_cols, 0..start, state)?;
+
+        let count = (num_mcus - start) / restart_interval;
+        let end = start + count * restart_interval;
+
+        if count > 0 {
+            let intervals: Vec<_> = (0..count)
+                .map(|i| {
+                    let mcus = start + i * restart_interval..start + (i + 1) * restart_interval;
+                    let restart = (usize::from(state.restarts) + i) % 8;
+
+                    (mcus, restart as u8)
+                })
+                .collect();
+
+            let components = &self.components;
+            let huffman_tables = &self.huffman_tables;
+
+            let chunks = map_ranges(count, threads, |range| {
+                encode_interleaved_intervals(
+                    components,
+                    huffman_tables,
+                    blocks,
+                    num_cols,
+                    &intervals[range],
+                )
+            });
+
+            // Complete the current interval, which is otherwise done before the next RST marker
+            self.writer.finalize_bit_buffer()?;
+
+            for chunk in chunks {
+                self.writer.write(&chunk?)?;
+            }
+
+            state.restarts = ((usize::from(state.restarts) + count) % 8) as u16;
+            state.restarts_to_go = 0;
+        }
+
+        self.write_mcus(blocks, num_cols, end..num_mcus, state)
+    }
+
+    /// Write the MCUs in `mcus` of an interleaved scan
+    ///
+    /// The blocks of each component cover rows of `num_cols` MCUs.
+    fn write_mcus(
+        &mut self,
+        blocks: &[Vec<[i16; 64]>; 4],
+        num_cols: usize,
+        mcus: Range<usize>,
+        state: &mut InterleavedState,
+    ) -> Result<(), EncodingError> {
+        let restart_interval = self.restart_interval.unwrap_or(0);
+
+        for mcu in mcus {
+            if restart_interval > 0 && state.restarts_to_go == 0 {
+                self.writer.finalize_bit_buffer()?;
+                self.writer
+                    .write_marker(Marker::RST((state.restarts % 8) as u8))?;
+
+                state.prev_dc = [0; 4];
+            }
+
+            write_mcu(
+                &mut self.writer,
+                &self.components,
+                &self.huffman_tables,
+                blocks,
+                num_cols,
+                mcu,
+                &mut state.prev_dc,
+            )?;
+
+            if restart_interval > 0 {
+                if state.restarts_to_go == 0 {
+                    state.restarts_to_go = restart_interval;
+                    state.restarts += 1;
+                    state.restarts &= 7;
+                }
+                state.restarts_to_go -= 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Encode components with one scan per component
+    fn encode_image_sequential(
+        &mut self,
+        blocks: &[Vec<[i16; 64]>; 4],
+        width: u16,
+        height: u16,
+        q_tables: &[QuantizationTable; 2],
+    ) -> Result<(), EncodingError> {
+        if self.optimize_huffman_table {
+            self.optimize_huffman_table(blocks, None, false);
+        }
+
+        self.write_frame_header(width, height, q_tables)?;
+
+        self.write_sequential_scans(blocks, false)
+    }
+
+    /// Write one scan per component
+    ///
+    /// The DC coefficients of differential frames are coded without prediction.
+    /// With multiple threads and a restart interval the intervals are coded in parallel.
+    fn write_sequential_scans(
+        &mut self,
+        blocks: &[Vec<[i16; 64]>; 4],
+        differential: bool,
+    ) -> Result<(), EncodingError> {
+        let threads = self.threads();
+
+        for (i, component) in self.components.iter().enumerate() {
+            let restart_interval = self.restart_interval.unwrap_or(0);
+            let mut restarts = 0;
+            let mut restarts_to_go = restart_interval;
+
+            self.writer.write_scan_header(&[component], None, (0, 0))?;
+
+            if threads > 1 && restart_interval > 0 {
+                let intervals: Vec<_> = blocks[i].chunks(usize::from(restart_interval)).collect();
+                let dc_table = &self.huffman_tables[component.dc_huffman_table as usize].0;
+                let ac_table = &self.huffman_tables[component.ac_huffman_table as usize].1;
+
+                let chunks = map_ranges(intervals.len(), threads, |range| {
+                    encode_restart_intervals(&intervals, range, dc_table, ac_table, differential)
+                });
+
+                for chunk in chunks {
+                    self.writer.write(&chunk?)?;
+                }
+
+                continue;
+            }
+
+            let mut prev_dc = 0;
+
+            for block in &blocks[i] {
+                if restart_interval > 0 && restarts_to_go == 0 {
+                    self.writer.finalize_bit_buffer()?;
+                    self.writer
+                        .write_marker(Marker::RST((restarts % 8) as u8))?;
+
+                    prev_dc = 0;
+                }
+
+                self.writer.write_block(
+                    block,
+                    prev_dc,
+                    &self.huffman_tables[component.dc_huffman_table as usize].0,
+                    &self.huffman_tables[component.ac_huffman_table as usize].1,
+                )?;
+
+                if !differential {
+                    prev_dc = block[0];
+                }
+
+                if restart_interval > 0 {
+                    if restarts_to_go == 0 {
+                        restarts_to_go = restart_interval;
+                        restarts += 1;
+                        restarts &= 7;
+                    }
+                    restarts_to_go -= 1;
+                }
+            }
+
+            self.writer.finalize_bit_buffer()?;
+        }
+
+        Ok(())
+    }
+
+    /// Encode image as a pyramid of frames with increasing size
+    ///
+    /// The first frame contains the smallest image. Every following differential frame
+    /// codes the difference between the image at this level and the upsampled
+    /// reconstruction of the previous frame.
+    fn encode_image_hierarchical<I: ImageBuffer>(
+        &mut self,
+        image: &I,
+        q_tables: &[QuantizationTable; 2],
+    ) -> Result<(), EncodingError> {
+        let width = usize::from(image.width());
+        let height = usize::from(image.height());
+
+        let (rows, buffer_width) = self.read_rows_8bit(image, None);
+
+        let mut pyramid: Vec<Vec<Plane>> = Vec::with_capacity(self.hierarchical_levels as usize);
+
+        pyramid.push(
+            rows[..self.components.len()]
+                .iter()
+                .map(|row| {
+                    let mut samples = Vec::with_capacity(width * height);
+
+                    for y in 0..height {
+                        let start = y * buffer_width;
+                        samples.extend(row[start..start + width].iter().map(|&v| i16::from(v)));
+                    }
+
+                    Plane::new(width, height, samples)
+                })
+                .collect(),
+        );
+
+        for level in 1..usize::from(self.hierarchical_levels) {
+            let planes = pyramid[level - 1].iter().map(Plane::downsample).collect();
+            pyramid.push(planes);
+        }
+
+        self.writer.write_hierarchical_progression(
+            image.width(),
+            image.height(),
+            &self.components,
+            8,
+        )?;
+
+        self.writer.write_quantization_segment(0, &q_tables[0])?;
+        self.writer.write_quantization_segment(1, &q_tables[1])?;
+
+        if let Some(restart_interval) = self.restart_interval {
+            self.writer.write_dri(restart_interval)?;
+        }
+
+        let mut reference: Option<Vec<Plane>> = None;
+
+        for (level, planes) in pyramid.iter().enumerate().rev() {
+            let frame_width = planes[0].width;
+            let frame_height = planes[0].height;
+
+            let references: Option<Vec<Plane>> = reference.as_ref().map(|reference| {
+                reference
+                    .iter()
+                    .map(|plane| plane.upsample(frame_width, frame_height))
+                    .collect()
+            });
+
+            let differential = references.is_some();
+
+            let mut blocks =
+                self.init_block_buffers(ceil_div(frame_width, 8) * ceil_div(frame_height, 8));
+
+            for (i, plane) in planes.iter().enumerate() {
+                let table = &q_tables[self.components[i].quantization_table as usize];
+
+                let (samples, padded_width, level_shift) = match &references {
+                    Some(references) => {
+                        let difference = plane
+                            .samples
+                            .iter()
+                            .zip(references[i].samples.iter())
+                            .map(|(&sample, &reference)| sample - reference)
+                            .collect();
+
+                        let (samples, padded_width) =
+                            Plane::new(frame_width, frame_height, difference).padded_samples();
+
+                        // Differences aren't level shifted
+                        (samples, padded_width, 0)
+                    }
+                    None => {
+                        let (samples, padded_width) = plane.padded_samples();
+                        (samples, padded_width, 128)
+                    }
+                };
+
+                for block_y in 0..ceil_div(frame_height, 8) {
+                    for block_x in 0..ceil_div(frame_width, 8) {
+                        let mut block = get_block(
+                            &samples,
+                            block_x * 8,
+                            block_y * 8,
+                            1,
+                            1,
+                            padded_width,
+                            level_shift,
+                            DownsamplingFilter::Point,
+                        );
+
+                        // The differences exceed the range the AVX2 implementation is made for
+                        fdct(&mut block);
+
+                        let mut q_block = [0i16; 64];
+                        DefaultOperations::quantize_block(&block, &mut q_block, table);
+
+                        blocks[i].push(q_block);
+                    }
+                }
+            }
+
+            self.optimize_huffman_table(&blocks, None, differential);
+
+            if differential {
+                // Expand the reference components horizontally and vertically
+                self.writer.write_segment(Marker::EXP, &[0x11])?;
+            }
+
+            self.write_huffman_tables()?;
+
+            let sof_type = if differential {
+                SOFType::DifferentialSequentialDCT
+            } else {
+                SOFType::ExtendedSequentialDCT
+            };
+
+            self.writer.write_frame_header(
+                frame_width as u16,
+                frame_height as u16,
+                &self.components,
+                sof_type,
+                8,
+            )?;
+
+            self.write_sequential_scans(&blocks, differential)?;
+
+            if level > 0 {
+                let planes = self
+                    .components
+                    .iter()
+                    .enumerate()
+                    .map(|(i, component)| {
+                        reconstruct_plane::<DefaultOperations>(
+                            &blocks[i],
+                            &q_tables[component.quantization_table as usize],
+                            frame_width,
+                            frame_height,
+                            references.as_ref().map(|references| &references[i]),
+                        )
+                    })
+                    .collect();
+
+                reference = Some(planes);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Encode image in progressive mode
+    ///
+    /// Uses spectral selection and optionally successive approximation
+    fn encode_image_progressive(
+        &mut self,
+        blocks: &[Vec<[i16; 64]>; 4],
+        width: u16,
+        height: u16,
+        q_tables: &[QuantizationTable; 2],
+    ) -> Result<(), EncodingError> {
+        let script = self.get_progressive_script();
+
+        // Optimized tables are always needed because the default tables
+        // don't contain the end of band run symbols
+        self.optimize_huffman_table(blocks, Some((script.scans(), width, height)), false);
+
+        self.write_frame_header(width, height, q_tables)?;
+
+        for scan in script.scans() {
+            self.encode_progressive_scan(scan, blocks, width, height)?;
+        }
+
+        Ok(())
+    }
+
+    fn encode_image_lossless<I: ImageBuffer>(
+        &mut self,
+        image: &I,
+        predictor: Predictor,
+    ) -> Result<(), EncodingError> {
+        let width = usize::from(image.width());
+        let num_components = self.components.len();
+        let point_transform = self.point_transform;
+
+        let mut samples: [Vec<u16>; 4] = Default::default();
+        let mut row: [Vec<u16>; 4] = Default::default();
+
+        for y in 0..image.height() {
+            for buffer in &mut row {
+                buffer.clear();
+            }
+
+            image.fill_buffers_with_precision(y, self.sample_precision, &mut row);
+
+            for (samples, row) in samples.iter_mut().zip(&row).take(num_components) {
+                samples.extend(row.iter().map(|&value| value >> point_transform));
+            }
+        }
+
+        let restart_interval = self
+            .restart_interval
+            .map(|interval| get_restart_interval(interval, image.width()));
+
+        let restart_rows = usize::from(restart_interval.unwrap_or(0)) / width;
+
+        let differences: Vec<Vec<u16>> = samples[..num_components]
+            .iter()
+            .map(|samples| {
+                compute_differences(
+                    samples,
+                    width,
+                    predictor,
+                    self.sample_precision - point_transform,
+                    restart_rows,
+                )
+            })
+            .collect();
+
+        self.writer.write_frame_header(
+            image.width(),
+            image.height(),
+            &self.components,
+            SOFType::Lossless,
+            self.sample_precision,
+        )?;
+
+        for table in 0..2u8 {
+            let mut freq = [0u32; 257];
+            freq[256] = 1;
+
+            let mut had_table = false;
+
+            for (component, differences) in self.components.iter().zip(&differences) {
+                if component.dc_huffman_table == table {
+                    had_table = true;
+
+                    for &difference in differences {
+                        freq[usize::from(get_difference_category(difference))] += 1;
+                    }
+                }
+            }
+
+            if had_table {
+                let huffman_table = HuffmanTable::new_optimized(freq);
+
+                self.writer
+                    .write_huffman_segment(CodingClass::Dc, table, &huffman_table)?;
+
+                self.huffman_tables[usize::from(table)].0 = huffman_table;
+            }
+        }
+
+        if let Some(restart_interval) = restart_interval {
+            self.writer.write_dri(restart_interval)?;
+        }
+
+        self.writer.write_scan_header(
+            &self.components.iter().collect::<Vec<_>>(),
+            Some((predictor as u8, 0)),
+            (0, point_transform),
+        )?;
+
+        let restart_interval = usize::from(restart_interval.unwrap_or(0));
+
+        for i in 0..differences[0].len() {
+            if restart_interval > 0 && i > 0 && i % restart_interval == 0 {
+                self.writer.finalize_bit_buffer()?;
+                self.writer
+                    .write_marker(Marker::RST(((i / restart_interval - 1) % 8) as u8))?;
+            }
+
+            for (component, differences) in self.components.iter().zip(&differences) {
+                let table = &self.huffman_tables[usize::from(component.dc_huffman_table)].0;
+                self.writer
+                    .write_lossless_difference(differences[i], table)?;
+            }
+        }
+
+        self.writer.finalize_bit_buffer()?;
+
+        Ok(())
+    }
+
+    fn get_progressive_script(&self) -> ScanScript {
+        match &self.scan_script {
+            Some(script) => script.clone(),
+            None => ScanScript::spectral_selection(
+                self.components.len(),
+                self.progressive_scans.unwrap_or(4),
+                self.successive_approximation,
+                self.interleaved_dc_scan && self.sampling_factor.supports_interleaved(),
+            ),
+        }
+    }
+
+    /// Encode image with arithmetic coding
+    ///
+    /// Sequential images use a single interleaved scan if supported by the sampling factor
+    #[cfg(feature = "arithmetic")]
+    fn encode_image_arithmetic(
+        &mut self,
+        blocks: &[Vec<[i16; 64]>; 4],
+        width: u16,
+        height: u16,
+        q_tables: &[QuantizationTable; 2],
+    ) -> Result<(), EncodingError> {
+        let progressive = self.is_progressive();
+
+        let script = if progressive {
+            self.get_progressive_script()
+        } else if self.sampling_factor.supports_interleaved() {
+            let components: Vec<u8> = (0..self.components.len() as u8).collect();
+            ScanScript::from(vec![ScanInfo::new(&components, 0, 63, 0, 0)])
+        } else {
+            (0..self.components.len() as u8)
+                .map(|component| ScanInfo::new(&[component], 0, 63, 0, 0))
+                .collect::<Vec<_>>()
+                .into()
+        };
+
+        self.write_frame_header(width, height, q_tables)?;
+
+        for scan in script.scans() {
+            self.encode_arithmetic_scan(scan, blocks, width, height, progressive)?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "arithmetic")]
+    fn encode_arithmetic_scan(
+        &mut self,
+        scan: &ScanInfo,
+        blocks: &[Vec<[i16; 64]>; 4],
+        width: u16,
+        height: u16,
+        progressive: bool,
+    ) -> Result<(), EncodingError> {
+        let components: Vec<_> = scan
+            .components
+            .iter()
+            .map(|&i| &self.components[usize::from(i)])
+            .collect();
+
+        self.writer.write_scan_header(
+            &components,
+            Some((scan.spectral_start, scan.spectral_end)),
+            (scan.approximation_high, scan.approximation_low),
+        )?;
+
+        let start = scan.spectral_start as usize;
+        let end = scan.spectral_end as usize + 1;
+        let al = scan.approximation_low;
+        let refinement = scan.is_refinement();
+
+        let restart_interval = usize::from(self.restart_interval.unwrap_or(0));
+
+        let (order, blocks_per_mcu) = self.get_scan_order(scan, width, height);
+
+        let mut encoder = ArithmeticEncoder::new(&mut self.writer);
+
+        for (mcu, mcu_blocks) in order.chunks(blocks_per_mcu).enumerate() {
+            if restart_interval > 0 && mcu > 0 && mcu % restart_interval == 0 {
+                encoder.restart(((mcu / restart_interval - 1) % 8) as u8)?;
+            }
+
+            for &(i, index) in mcu_blocks {
+                let block = &blocks[i][index];
+
+                let component = &self.components[i];
+                let dc_table = usize::from(component.dc_huffman_table);
+                let ac_table = usize::from(component.ac_huffman_table);
+
+                if !progressive {
+                    encoder.encode_dc(
+                        block,
+                        i,
+                        0,
+                        dc_table,
+                        &self.arithmetic_conditioning[dc_table],
+                    )?;
+                    encoder.encode_ac_first(
+                        block,
+                        1,
+                        64,
+                        0,
+                        ac_table,
+                        &self.arithmetic_conditioning[ac_table],
+                    )?;
+                } else if start == 0 {
+                    if refinement {
+                        encoder.encode_dc_refinement(block, al)?;
+                    } else {
+                        encoder.encode_dc(
+                            block,
+                            i,
+                            al,
+                            dc_table,
+                            &self.arithmetic_conditioning[dc_table],
+                        )?;
+                    }
+                } else if refinement {
+                    encoder.encode_ac_refinement(block, start, end, al, ac_table)?;
+                } else {
+                    encoder.encode_ac_first(
+                        block,
+                        start,
+                        end,
+                        al,
+                        ac_table,
+                        &self.arithmetic_conditioning[ac_table],
+                    )?;
+                }
+            }
+        }
+
+        encoder.finish()
+    }
+
+    fn encode_progressive_scan(
+        &mut self,
+        scan: &ScanInfo,
+        blocks: &[Vec<[i16; 64]>; 4],
+        width: u16,
+        height: u16,
+    ) -> Result<(), EncodingError> {
+        let components: Vec<_> = scan
+            .components
+            .iter()
+            .map(|&i| &self.components[usize::from(i)])
+            .collect();
+
+        self.writer.write_scan_header(
+            &components,
+            Some((scan.spectral_start, scan.spectral_end)),
+            (scan.approximation_high, scan.approximation_low),
+        )?;
+
+        // AC scans always contain a single component
+        let ac_table = &self.huffman_tables[components[0].ac_huffman_table as usize].1;
+
+        let start = scan.spectral_start as usize;
+        let end = scan.spectral_end as usize + 1;
+        let al = scan.approximation_low;
+        let refinement = scan.is_refinement();
+
+        let restart_interval = usize::from(self.restart_interval.unwrap_or(0));
+
+        let (order, blocks_per_mcu) = self.get_scan_order(scan, width, height);
+
+        let mut prev_dc = [0i16; 4];
+
+        for (mcu, mcu_blocks) in order.chunks(blocks_per_mcu).enumerate() {
+            if restart_interval > 0 && mcu > 0 && mcu % restart_interval == 0 {
+                self.writer.write_eobrun(ac_table)?;
+                self.writer.finalize_bit_buffer()?;
+                self.writer
+                    .write_marker(Marker::RST(((mcu / restart_interval - 1) % 8) as u8))?;
+
+                prev_dc = [0; 4];
+            }
+
+            for &(i, index) in mcu_blocks {
+                let block = &blocks[i][index];
+
+                if start == 0 {
+                    if refinement {
+                        self.writer.write_dc_refinement(block[0], al)?;
+                    } else {
+                        let dc_table =
+                            &self.huffman_tables[self.components[i].dc_huffman_table as usize].0;
+
+                        let value = block[0] >> al;
+                        self.writer.write_dc(value, prev_dc[i], dc_table)?;
+                        prev_dc[i] = value;
+                    }
+                } else if refinement {
+                    self.writer
+                        .write_ac_refinement(block, start, end, al, ac_table)?;
+                } else {
+                    self.writer
+                        .write_ac_first(block, start, end, al, ac_table)?;
+                }
+            }
+        }
+
+        self.writer.write_eobrun(ac_table)?;
+        self.writer.finalize_bit_buffer()?;
+
+        Ok(())
+    }
+
+    /// Returns the number of block columns and rows of a component
+    fn get_component_size(&self, i: usize, width: u16, height: u16) -> (usize, usize) {
+        let (max_h_sampling, max_v_sampling) = self.get_max_sampling_size();
+
+        let num_cols = ceil_div(usize::from(width), 8);
+        let num_rows = ceil_div(usize::from(height), 8);
+
+        let component = &self.components[i];
+        let h_scale = max_h_sampling / component.horizontal_sampling_factor as usize;
+        let v_scale = max_v_sampling / component.vertical_sampling_factor as usize;
+
+        (ceil_div(num_cols, h_scale), ceil_div(num_rows, v_scale))
+    }
+
+    /// Returns the blocks of a scan in encoding order as (component, block index) pairs
+    /// and the number of blocks per MCU
+    ///
+    /// Interleaved scans contain the blocks of all components MCU by MCU (A.2.3).
+    /// Blocks of partial MCUs outside the component are replaced by the nearest block
+    /// within the component.
+    fn get_scan_order(
+        &self,
+        scan: &ScanInfo,
+        width: u16,
+        height: u16,
+    ) -> (Vec<(usize, usize)>, usize) {
+        let (max_h_sampling, max_v_sampling) = self.get_max_sampling_size();
+
+        if scan.components.len() == 1 {
+            let i = usize::from(scan.components[0]);
+            let (cols, rows) = self.get_component_size(i, width, height);
+
+            return ((0..cols * rows).map(|index| (i, index)).collect(), 1);
+        }
 
-        let max_v_sampling = self.components.iter().fold(1, |value, component| {
-            value.max(component.vertical_sampling_factor)
-        });
+        let mcu_cols = ceil_div(usize::from(width), 8 * max_h_sampling);
+        let mcu_rows = ceil_div(usize::from(height), 8 * max_v_sampling);
+
+        let blocks_per_mcu = scan
+            .components
+            .iter()
+            .map(|&i| {
+                let component = &self.components[usize::from(i)];
+                usize::from(component.horizontal_sampling_factor)
+                    * usize::from(component.vertical_sampling_factor)
+            })
+            .sum();
+
+        let mut order = Vec::with_capacity(mcu_cols * mcu_rows * blocks_per_mcu);
+
+        for mcu_y in 0..mcu_rows {
+            for mcu_x in 0..mcu_cols {
+                for &i in &scan.components {
+                    let i = usize::from(i);
+                    let component = &self.components[i];
+                    let (cols, rows) = self.get_component_size(i, width, height);
+
+                    let h = usize::from(component.horizontal_sampling_factor);
+                    let v = usize::from(component.vertical_sampling_factor);
+
+                    for v_offset in 0..v {
+                        let y = (mcu_y * v + v_offset).min(rows - 1);
+
+                        for h_offset in 0..h {
+                            let x = (mcu_x * h + h_offset).min(cols - 1);
+                            order.push((i, y * cols + x));
+                        }
+                    }
+                }
+            }
+        }
 
-        (usize::from(max_h_sampling), usize::from(max_v_sampling))
+        (order, blocks_per_mcu)
     }
 
-    fn write_frame_header<I: ImageBuffer>(
+    fn encode_blocks<I: ImageBuffer, OP: Operations>(
         &mut self,
         image: &I,
+        sync_image: Option<&(dyn ImageBuffer + Sync)>,
         q_tables: &[QuantizationTable; 2],
-    ) -> Result<(), EncodingError> {
-        self.writer.write_frame_header(
-            image.width(),
-            image.height(),
-            &self.components,
-            self.progressive_scans.is_some(),
-        )?;
+    ) -> [Vec<[i16; 64]>; 4] {
+        let precision = self.sample_precision;
 
-        self.writer.write_quantization_segment(0, &q_tables[0])?;
-        self.writer.write_quantization_segment(1, &q_tables[1])?;
+        if precision == 8 {
+            if self.trellis_quantization && !self.is_arithmetic() {
+                let blocks = self.transform_image::<_, OP>(image, sync_image, q_tables);
+                return self.trellis_quantize_blocks::<OP>(blocks, q_tables);
+            }
 
-        self.writer
-            .write_huffman_segment(CodingClass::Dc, 0, &self.huffman_tables[0].0)?;
+            if self.uses_high_precision_fdct(image) {
+                return self.transform_high_precision_blocks(image, sync_image, q_tables);
+            }
 
-        self.writer
-            .write_huffman_segment(CodingClass::Ac, 0, &self.huffman_tables[0].1)?;
+            let (rows, buffer_width) = self.read_image_rows(image, sync_image);
 
-        if image.get_jpeg_color_type().get_num_components() >= 3 {
-            self.writer
-                .write_huffman_segment(CodingClass::Dc, 1, &self.huffman_tables[1].0)?;
+            self.transform_blocks(image, &rows, buffer_width, q_tables, 128, |block, table| {
+                OP::fdct(block);
 
-            self.writer
-                .write_huffman_segment(CodingClass::Ac, 1, &self.huffman_tables[1].1)?;
-        }
+                let mut q_block = [0i16; 64];
+                OP::quantize_block(block, &mut q_block, table);
+                q_block
+            })
+        } else {
+            let (rows, buffer_width) = self.read_rows(image, sync_image, |image, y, row| {
+                image.fill_buffers_with_precision(y, precision, row)
+            });
 
-        if let Some(restart_interval) = self.restart_interval {
-            self.writer.write_dri(restart_interval)?;
-        }
+            self.transform_blocks(
+                image,
+                &rows,
+                buffer_width,
+                q_tables,
+                1 << (precision - 1),
+                |block, table| {
+                    let block = fdct_12bit(block);
 
-        Ok(())
+                    let mut q_block = [0i16; 64];
+                    for (i, q) in q_block.iter_mut().enumerate() {
+                        let z = ZIGZAG[i] as usize & 0x3f;
+                        *q = table.quantize_wide(block[z], z);
+                    }
+                    q_block
+                },
+            )
+        }
     }
 
-    fn init_rows(&mut self, buffer_size: usize) -> [Vec<u8>; 4] {
-        // To simplify the code and to give the compiler more infos to optimize stuff we always initialize 4 components
-        // Resource overhead should be minimal because an empty Vec doesn't allocate
+    /// Transforms and quantizes the blocks of an image with more than 8 bits per sample for
+    /// a sample precision of 8 bits
+    ///
+    /// The samples are converted to 8 bits with 4 fractional bits and the coefficients are
+    /// scaled back to 8 bits by the quantization.
+    fn transform_high_precision_blocks<I: ImageBuffer>(
+        &mut self,
+        image: &I,
+        sync_image: Option<&(dyn ImageBuffer + Sync)>,
+        q_tables: &[QuantizationTable; 2],
+    ) -> [Vec<[i16; 64]>; 4] {
+        let (mut rows, buffer_width) = self.read_rows(image, sync_image, |image, y, row| {
+            image.fill_buffers_with_precision(y, 16, row)
+        });
 
-        match self.components.len() {
-            1 => [
-                Vec::with_capacity(buffer_size),
-                Vec::new(),
-                Vec::new(),
-                Vec::new(),
-            ],
-            3 => [
-                Vec::with_capacity(buffer_size),
-                Vec::with_capacity(buffer_size),
-                Vec::with_capacity(buffer_size),
-                Vec::new(),
-            ],
-            4 => [
-                Vec::with_capacity(buffer_size),
-                Vec::with_capacity(buffer_size),
-                Vec::with_capacity(buffer_size),
-                Vec::with_capacity(buffer_size),
-            ],
-            len => unreachable!("Unsupported component length: {}", len),
+        for row in &mut rows {
+            for value in row.iter_mut() {
+                *value = fixed_point_sample(*value);
+            }
         }
+
+        self.transform_blocks(
+            image,
+            &rows,
+            buffer_width,
+            q_tables,
+            1 << 11,
+            |block, table| {
+                let block = fdct_12bit(block);
+
+                let mut q_block = [0i16; 64];
+                for (i, q) in q_block.iter_mut().enumerate() {
+                    let z = ZIGZAG[i] as usize & 0x3f;
+                    *q = table.quantize_scaled(block[z], z, 4);
+                }
+                q_block
+            },
+        )
     }
 
-    /// Encode all components with one scan
-    ///
-    /// This is only valid for sampling factors of 1 and 2
-    fn encode_image_interleaved<I: ImageBuffer, OP: Operations>(
+    /// Transforms the blocks of an 8 bit image without quantization
+    fn transform_image<I: ImageBuffer, OP: Operations>(
         &mut self,
-        image: I,
+        image: &I,
+        sync_image: Option<&(dyn ImageBuffer + Sync)>,
         q_tables: &[QuantizationTable; 2],
-    ) -> Result<(), EncodingError> {
-        self.write_frame_header(&image, q_tables)?;
-        self.writer
-            .write_scan_header(&self.components.iter().collect::<Vec<_>>(), None)?;
-
-        let (max_h_sampling, max_v_sampling) = self.get_max_sampling_size();
+    ) -> [Vec<[i16; 64]>; 4] {
+        let (rows, buffer_width) = self.read_image_rows(image, sync_image);
 
-        let width = image.width();
-        let height = image.height();
+        self.transform_blocks(image, &rows, buffer_width, q_tables, 128, |block, _| {
+            OP::fdct(block);
+            *block
+        })
+    }
 
-        let num_cols = ceil_div(usize::from(width), 8 * max_h_sampling);
-        let num_rows = ceil_div(usize::from(height), 8 * max_v_sampling);
+    /// Reads the samples of all components reduced to 8 bits with the configured dithering
+    fn read_rows_8bit<I: ImageBuffer>(
+        &mut self,
+        image: &I,
+        sync_image: Option<&(dyn ImageBuffer + Sync)>,
+    ) -> ([Vec<u8>; 4], usize) {
+        if self.diffuses_errors(image.sample_precision()) {
+            let (rows, buffer_width) = self.read_rows(image, sync_image, |image, y, row| {
+                image.fill_buffers_with_precision(y, 16, row)
+            });
 
-        let buffer_width = num_cols * 8 * max_h_sampling;
-        let buffer_size = buffer_width * 8 * max_v_sampling;
+            let width = usize::from(image.width());
+            let height = usize::from(image.height());
 
-        let mut row: [Vec<_>; 4] = self.init_rows(buffer_size);
+            let rows = diffuse_errors(&rows, buffer_width, width, height);
 
-        let mut prev_dc = [0i16; 4];
+            (rows, buffer_width)
+        } else {
+            let dithering = self.dithering;
 
-        let restart_interval = self.restart_interval.unwrap_or(0);
-        let mut restarts = 0;
-        let mut restarts_to_go = restart_interval;
+            self.read_rows(image, sync_image, |image, y, row| {
+                fill_buffers_reduced(image, y, usize::from(y), dithering, row)
+            })
+        }
+    }
 
-        for block_y in 0..num_rows {
-            for r in &mut row {
-                r.clear();
-            }
+    /// Reads the 8 bit samples of all components and downsamples the chroma with sharp YUV
+    /// or in linear light if enabled
+    fn read_image_rows<I: ImageBuffer>(
+        &mut self,
+        image: &I,
+        sync_image: Option<&(dyn ImageBuffer + Sync)>,
+    ) -> ([Vec<u8>; 4], usize) {
+        let (mut rows, buffer_width) = self.read_rows_8bit(image, sync_image);
 
-            for y in 0..(8 * max_v_sampling) {
-                let y = y + block_y * 8 * max_v_sampling;
-                let y = (y.min(height as usize - 1)) as u16;
+        if self.downsamples_rows(image.get_jpeg_color_type()) {
+            let (max_h_sampling, max_v_sampling) = self.get_max_sampling_size();
+            let chroma = &self.components[1];
 
-                image.fill_buffers(y, &mut row);
+            let h_scale = max_h_sampling / chroma.horizontal_sampling_factor as usize;
+            let v_scale = max_v_sampling / chroma.vertical_sampling_factor as usize;
 
-                for _ in usize::from(width)..buffer_width {
-                    for channel in &mut row {
-                        if !channel.is_empty() {
-                            channel.push(channel[channel.len() - 1]);
-                        }
-                    }
+            if self.sharp_yuv {
+                sharp_yuv_downsample(&mut rows, buffer_width, h_scale, v_scale, self.color_matrix);
+            } else {
+                #[cfg(feature = "std")]
+                if let Some(transfer_function) = self.linear_light {
+                    linear_light_downsample(
+                        &mut rows,
+                        buffer_width,
+                        h_scale,
+                        v_scale,
+                        self.downsampling_filter,
+                        transfer_function,
+                        self.color_matrix,
+                    );
                 }
             }
+        }
 
-            for block_x in 0..num_cols {
-                if restart_interval > 0 && restarts_to_go == 0 {
-                    self.writer.finalize_bit_buffer()?;
-                    self.writer
-                        .write_marker(Marker::RST((restarts % 8) as u8))?;
+        (rows, buffer_width)
+    }
 
-                    prev_dc[0] = 0;
-                    prev_dc[1] = 0;
-                    prev_dc[2] = 0;
-                    prev_dc[3] = 0;
-                }
+    /// Transforms and quantizes the blocks of components read from separate planes
+    fn encode_plane_blocks<OP: Operations>(
+        &mut self,
+        planes: &[YuvPlane; 3],
+        width: u16,
+        height: u16,
+        q_tables: &[QuantizationTable; 2],
+    ) -> [Vec<[i16; 64]>; 4] {
+        let trellis = self.trellis_quantization && !self.is_arithmetic();
+        let threads = self.threads();
 
-                for (i, component) in self.components.iter().enumerate() {
-                    for v_offset in 0..component.vertical_sampling_factor as usize {
-                        for h_offset in 0..component.horizontal_sampling_factor as usize {
-                            let mut block = get_block(
-                                &row[i],
-                                block_x * 8 * max_h_sampling + (h_offset * 8),
-                                v_offset * 8,
-                                max_h_sampling
-                                    / component.horizontal_sampling_factor as usize,
-                                max_v_sampling
-                                    / component.vertical_sampling_factor as usize,
-                                buffer_width,
-                            );
-
-                            OP::fdct(&mut block);
+        let mut blocks = self.init_block_buffers(0);
 
-                            let mut q_block = [0i16; 64];
+        for (i, plane) in planes.iter().enumerate() {
+            let (cols, rows) = self.get_component_size(i, width, height);
+            let table = &q_tables[self.components[i].quantization_table as usize];
+
+            let chunks = map_ranges(rows, threads, |block_rows| {
+                let mut blocks = Vec::with_capacity(block_rows.len() * cols);
 
-                            OP::quantize_block(
-                                &block,
-                                &mut q_block,
-                                &q_tables[component.quantization_table as usize],
-                            );
+                for block_y in block_rows {
+                    for block_x in 0..cols {
+                        let mut block = plane.get_block(block_x, block_y);
 
-                            self.writer.write_block(
-                                &q_block,
-                                prev_dc[i],
-                                &self.huffman_tables[component.dc_huffman_table as usize].0,
-                                &self.huffman_tables[component.ac_huffman_table as usize].1,
-                            )?;
+                        OP::fdct(&mut block);
 
-                            prev_dc[i] = q_block[0];
+                        if trellis {
+                            blocks.push(block);
+                        } else {
+                            let mut q_block = [0i16; 64];
+                            OP::quantize_block(&block, &mut q_block, table);
+                            blocks.push(q_block);
                         }
                     }
                 }
 
-                if restart_interval > 0 {
-                    if restarts_to_go == 0 {
-                        restarts_to_go = restart_interval;
-                        restarts += 1;
-                        restarts &= 7;
-                    }
-                    restarts_to_go -= 1;
-                }
-            }
+                blocks
+            });
+
+            blocks[i] = concat(chunks);
         }
 
-        self.writer.finalize_bit_buffer()?;
+        if trellis {
+            return self.trellis_quantize_blocks::<OP>(blocks, q_tables);
+        }
 
-        Ok(())
+        blocks
     }
 
-    /// Encode components with one scan per component
-    fn encode_image_sequential<I: ImageBuffer, OP: Operations>(
+    /// Quantizes the DCT coefficients of all components with trellis quantization
+    fn trellis_quantize_blocks<OP: Operations>(
         &mut self,
-        image: I,
+        mut blocks: [Vec<[i16; 64]>; 4],
         q_tables: &[QuantizationTable; 2],
-    ) -> Result<(), EncodingError> {
-        let blocks = self.encode_blocks::<_, OP>(&image, q_tables);
+    ) -> [Vec<[i16; 64]>; 4] {
+        if self.optimize_huffman_table || self.is_progressive() {
+            // Estimate the sizes with tables optimized for the usual quantization
+            let mut quantized = blocks.clone();
+            self.quantize_blocks::<OP>(&mut quantized, q_tables);
 
-        if self.optimize_huffman_table {
-            self.optimize_huffman_table(&blocks);
+            self.optimize_huffman_table(&quantized, None, false);
         }
 
-        self.write_frame_header(&image, q_tables)?;
+        let threads = self.threads();
 
         for (i, component) in self.components.iter().enumerate() {
-            let restart_interval = self.restart_interval.unwrap_or(0);
-            let mut restarts = 0;
-            let mut restarts_to_go = restart_interval;
-
-            self.writer.write_scan_header(&[component], None)?;
-
-            let mut prev_dc = 0;
-
-            for block in &blocks[i] {
-                if restart_interval > 0 && restarts_to_go == 0 {
-                    self.writer.finalize_bit_buffer()?;
-                    self.writer
-                        .write_marker(Marker::RST((restarts % 8) as u8))?;
-
-                    prev_dc = 0;
-                }
-
-                self.writer.write_block(
-                    block,
-                    prev_dc,
-                    &self.huffman_tables[component.dc_huffman_table as usize].0,
-                    &self.huffman_tables[component.ac_huffman_table as usize].1,
-                )?;
-
-                prev_dc = block[0];
-
-                if restart_interval > 0 {
-                    if restarts_to_go == 0 {
-                        restarts_to_go = restart_interval;
-                        restarts += 1;
-                        restarts &= 7;
-                    }
-                    restarts_to_go -= 1;
-                }
-            }
+            let table = &q_tables[component.quantization_table as usize];
+            let ac_table = &self.huffman_tables[component.ac_huffman_table as usize].1;
+            let component_blocks = &blocks[i];
+
+            let chunks = map_ranges(component_blocks.len(), threads, |range| {
+                component_blocks[range]
+                    .iter()
+                    .map(|block| trellis_quantize(block, table, ac_table))
+                    .collect()
+            });
 
-            self.writer.finalize_bit_buffer()?;
+            blocks[i] = concat(chunks);
         }
 
-        Ok(())
+        blocks
     }
 
-    /// Encode image in progressive mode
+    /// Reads all rows of the image padded to full MCUs
     ///
-    /// This only support spectral selection for now
-    fn encode_image_progressive<I: ImageBuffer, OP: Operations>(
+    /// The rows are read by multiple threads if the image is also passed as `sync_image`.
+    /// Returns the rows and the width of the padded rows
+    fn read_rows<I: ImageBuffer, T: Copy + Send>(
         &mut self,
-        image: I,
-        scans: u8,
-        q_tables: &[QuantizationTable; 2],
-    ) -> Result<(), EncodingError> {
-        let blocks = self.encode_blocks::<_, OP>(&image, q_tables);
-
-        if self.optimize_huffman_table {
-            self.optimize_huffman_table(&blocks);
-        }
-
-        self.write_frame_header(&image, q_tables)?;
+        image: &I,
+        sync_image: Option<&(dyn ImageBuffer + Sync)>,
+        fill_buffers: impl Fn(&dyn ImageBuffer, u16, &mut [Vec<T>; 4]) + Sync,
+    ) -> ([Vec<T>; 4], usize) {
+        let width = image.width();
+        let height = image.height();
 
-        // Phase 1: DC Scan
-        //          Only the DC coefficients can be transfer in the first component scans
-        for (i, component) in self.components.iter().enumerate() {
-            self.writer.write_scan_header(&[component], Some((0, 0)))?;
+        let (max_h_sampling, max_v_sampling) = self.get_max_sampling_size();
 
-            let restart_interval = self.restart_interval.unwrap_or(0);
-            let mut restarts = 0;
-            let mut restarts_to_go = restart_interval;
+        let num_cols = ceil_div(usize::from(width), 8 * max_h_sampling) * max_h_sampling;
+        let num_rows = ceil_div(usize::from(height), 8 * max_v_sampling) * max_v_sampling;
 
-            let mut prev_dc = 0;
+        debug_assert!(num_cols > 0);
+        debug_assert!(num_rows > 0);
 
-            for block in &blocks[i] {
-                if restart_interval > 0 && restarts_to_go == 0 {
-                    self.writer.finalize_bit_buffer()?;
-                    self.writer
-                        .write_marker(Marker::RST((restarts % 8) as u8))?;
+        let buffer_width = num_cols * 8;
+        let num_components = self.components.len();
 
-                    prev_dc = 0;
-                }
+        let read_lines = |image: &dyn ImageBuffer, lines: Range<usize>| {
+            let mut row: [Vec<_>; 4] = init_rows(num_components, lines.len() * buffer_width);
 
-                self.writer.write_dc(
-                    block[0],
-                    prev_dc,
-                    &self.huffman_tables[component.dc_huffman_table as usize].0,
-                )?;
+            for y in lines {
+                let y = (y.min(usize::from(height) - 1)) as u16;
 
-                prev_dc = block[0];
+                fill_buffers(image, y, &mut row);
 
-                if restart_interval > 0 {
-                    if restarts_to_go == 0 {
-                        restarts_to_go = restart_interval;
-                        restarts += 1;
-                        restarts &= 7;
+                for _ in usize::from(width)..buffer_width {
+                    for channel in &mut row {
+                        if !channel.is_empty() {
+                            channel.push(channel[channel.len() - 1]);
+                        }
                     }
-                    restarts_to_go -= 1;
                 }
             }
 
-            self.writer.finalize_bit_buffer()?;
-        }
-
-        // Phase 2: AC scans
-        let scans = scans as usize - 1;
-
-        let values_per_scan = 64 / scans;
-
-        for scan in 0..scans {
-            let start = (scan * values_per_scan).max(1);
-            let end = if scan == scans - 1 {
-                // ensure last scan is always transfers the remaining coefficients
-                64
-            } else {
-                (scan + 1) * values_per_scan
-            };
-
-            for (i, component) in self.components.iter().enumerate() {
-                let restart_interval = self.restart_interval.unwrap_or(0);
-                let mut restarts = 0;
-                let mut restarts_to_go = restart_interval;
-
-                self.writer
-                    .write_scan_header(&[component], Some((start as u8, end as u8 - 1)))?;
+            row
+        };
 
-                for block in &blocks[i] {
-                    if restart_interval > 0 && restarts_to_go == 0 {
-                        self.writer.finalize_bit_buffer()?;
-                        self.writer
-                            .write_marker(Marker::RST((restarts % 8) as u8))?;
-                    }
+        let row = match sync_image {
+            Some(image) if self.threads() > 1 => {
+                let chunks = map_ranges(num_rows * 8, self.threads(), |lines| {
+                    read_lines(image, lines)
+                });
 
-                    self.writer.write_ac_block(
-                        block,
-                        start,
-                        end,
-                        &self.huffman_tables[component.ac_huffman_table as usize].1,
-                    )?;
+                let mut channels: [Vec<Vec<T>>; 4] = Default::default();
 
-                    if restart_interval > 0 {
-                        if restarts_to_go == 0 {
-                            restarts_to_go = restart_interval;
-                            restarts += 1;
-                            restarts &= 7;
-                        }
-                        restarts_to_go -= 1;
+                for chunk in chunks {
+                    for (channel, rows) in channels.iter_mut().zip(chunk) {
+                        channel.push(rows);
                     }
                 }
 
-                self.writer.finalize_bit_buffer()?;
+                channels.map(concat)
             }
-        }
+            _ => read_lines(image, 0..num_rows * 8),
+        };
 
-        Ok(())
+        (row, buffer_width)
     }
 
-    fn encode_blocks<I: ImageBuffer, OP: Operations>(
+    /// Transforms and quantizes the blocks of all components
+    fn transform_blocks<I: ImageBuffer, T: Copy + Into<i32> + Sync>(
         &mut self,
         image: &I,
+        row: &[Vec<T>; 4],
+        buffer_width: usize,
         q_tables: &[QuantizationTable; 2],
+        level_shift: i32,
+        transform: impl Fn(&mut [i16; 64], &QuantizationTable) -> [i16; 64] + Sync,
     ) -> [Vec<[i16; 64]>; 4] {
-        let width = image.width();
-        let height = image.height();
-
         let (max_h_sampling, max_v_sampling) = self.get_max_sampling_size();
 
-        let num_cols = ceil_div(usize::from(width), 8 * max_h_sampling) * max_h_sampling;
-        let num_rows = ceil_div(usize::from(height), 8 * max_v_sampling) * max_v_sampling;
+        let num_cols = ceil_div(usize::from(image.width()), 8);
+        let num_rows = ceil_div(usize::from(image.height()), 8);
 
         debug_assert!(num_cols > 0);
         debug_assert!(num_rows > 0);
 
-        let buffer_width = num_cols * 8;
-        let buffer_size = num_cols * num_rows * 64;
-
-        let mut row: [Vec<_>; 4] = self.init_rows(buffer_size);
-
-        for y in 0..num_rows * 8 {
-            let y = (y.min(usize::from(height) - 1)) as u16;
-
-            image.fill_buffers(y, &mut row);
+        let threads = self.threads();
 
-            for _ in usize::from(width)..num_cols * 8 {
-                for channel in &mut row {
-                    if !channel.is_empty() {
-                        channel.push(channel[channel.len() - 1]);
-                    }
-                }
-            }
-        }
-
-        let num_cols = ceil_div(usize::from(width), 8);
-        let num_rows = ceil_div(usize::from(height), 8);
-
-        debug_assert!(num_cols > 0);
-        debug_assert!(num_rows > 0);
+        // Chroma downsampled while reading the rows is already repeated for the whole area
+        let filter = if self.downsamples_rows(image.get_jpeg_color_type()) {
+            DownsamplingFilter::Point
+        } else {
+            self.downsampling_filter
+        };
 
-        let mut blocks: [Vec<_>; 4] = self.init_block_buffers(buffer_size / 64);
+        let mut blocks: [Vec<_>; 4] = self.init_block_buffers(0);
 
         for (i, component) in self.components.iter().enumerate() {
             let h_scale = max_h_sampling / component.horizontal_sampling_factor as usize;
@@ -995,30 +3782,34 @@ impl<W: JfifWrite> Encoder<W> {
             debug_assert!(cols > 0);
             debug_assert!(rows > 0);
 
-            for block_y in 0..rows {
-                for block_x in 0..cols {
-                    let mut block = get_block(
-                        &row[i],
-                        block_x * 8 * h_scale,
-                        block_y * 8 * v_scale,
-                        h_scale,
-                        v_scale,
-                        buffer_width,
-                    );
-
-                    OP::fdct(&mut block);
-
-                    let mut q_block = [0i16; 64];
+            let table = &q_tables[component.quantization_table as usize];
+            let row = &row[i];
+            let transform = &transform;
+
+            let chunks = map_ranges(rows, threads, |block_rows| {
+                let mut blocks = Vec::with_capacity(block_rows.len() * cols);
+
+                for block_y in block_rows {
+                    for block_x in 0..cols {
+                        let mut block = get_block(
+                            row,
+                            block_x * 8 * h_scale,
+                            block_y * 8 * v_scale,
+                            h_scale,
+                            v_scale,
+                            buffer_width,
+                            level_shift,
+                            filter,
+                        );
+
+                        blocks.push(transform(&mut block, table));
+                    }
+                }
 
-                    OP::quantize_block(
-                        &block,
-                        &mut q_block,
-                        &q_tables[component.quantization_table as usize],
-                    );
+                blocks
+            });
 
-                    blocks[i].push(q_block);
-                }
-            }
+            blocks[i] = concat(chunks);
         }
         blocks
     }
@@ -1051,10 +3842,22 @@ impl<W: JfifWrite> Encoder<W> {
     }
 
     // Create new huffman tables optimized for this image
-    fn optimize_huffman_table(&mut self, blocks: &[Vec<[i16; 64]>; 4]) {
+    fn optimize_huffman_table(
+        &mut self,
+        blocks: &[Vec<[i16; 64]>; 4],
+        progressive_scans: Option<(&[ScanInfo], u16, u16)>,
+        differential: bool,
+    ) {
         // TODO: Find out if it's possible to reuse some code from the writer
 
-        let max_tables = self.components.len().min(2) as u8;
+        let max_tables = self
+            .components
+            .iter()
+            .map(|component| component.dc_huffman_table.max(component.ac_huffman_table) + 1)
+            .max()
+            .unwrap_or(1);
+
+        let restart_interval = usize::from(self.restart_interval.unwrap_or(0));
 
         for table in 0..max_tables {
             let mut dc_freq = [0u32; 257];
@@ -1066,93 +3869,75 @@ impl<W: JfifWrite> Encoder<W> {
             let mut had_dc = false;
 
             for (i, component) in self.components.iter().enumerate() {
+                debug_assert!(!blocks[i].is_empty());
+
                 if component.dc_huffman_table == table {
                     had_dc = true;
 
-                    let mut prev_dc = 0;
-
-                    debug_assert!(!blocks[i].is_empty());
-
-                    for block in &blocks[i] {
-                        let value = block[0];
-                        let diff = value - prev_dc;
-                        let num_bits = get_num_bits(diff);
-
-                        dc_freq[num_bits as usize] += 1;
+                    if let Some((scans, width, height)) = progressive_scans {
+                        for scan in scans {
+                            if scan.components.contains(&(i as u8))
+                                && scan.is_dc()
+                                && !scan.is_refinement()
+                            {
+                                let (order, blocks_per_mcu) =
+                                    self.get_scan_order(scan, width, height);
+
+                                count_dc_symbols(
+                                    blocks,
+                                    i,
+                                    &order,
+                                    blocks_per_mcu,
+                                    scan.approximation_low,
+                                    restart_interval,
+                                    &mut dc_freq,
+                                );
+                            }
+                        }
+                    } else {
+                        // DC coefficients of differential frames aren't predicted,
+                        // which is the same as a restart after every block
+                        let restart_interval = if differential { 1 } else { restart_interval };
 
-                        prev_dc = value;
+                        let order: Vec<_> = (0..blocks[i].len()).map(|index| (i, index)).collect();
+                        count_dc_symbols(blocks, i, &order, 1, 0, restart_interval, &mut dc_freq);
                     }
                 }
 
                 if component.ac_huffman_table == table {
                     had_ac = true;
 
-                    if let Some(scans) = self.progressive_scans {
-                        let scans = scans as usize - 1;
-
-                        let values_per_scan = 64 / scans;
+                    if let Some((scans, _, _)) = progressive_scans {
+                        for scan in scans {
+                            if !scan.components.contains(&(i as u8)) || scan.is_dc() {
+                                continue;
+                            }
 
-                        for scan in 0..scans {
-                            let start = (scan * values_per_scan).max(1);
-                            let end = if scan == scans - 1 {
-                                // Due to rounding we might need to transfer more than values_per_scan values in the last scan
-                                64
+                            let start = scan.spectral_start as usize;
+                            let end = scan.spectral_end as usize + 1;
+
+                            if !scan.is_refinement() {
+                                count_ac_first_symbols(
+                                    &blocks[i],
+                                    start,
+                                    end,
+                                    scan.approximation_low,
+                                    restart_interval,
+                                    &mut ac_freq,
+                                );
                             } else {
-                                (scan + 1) * values_per_scan
-                            };
-
-                            debug_assert!(!blocks[i].is_empty());
-
-                            for block in &blocks[i] {
-                                let mut zero_run = 0;
-
-                                for &value in &block[start..end] {
-                                    if value == 0 {
-                                        zero_run += 1;
-                                    } else {
-                                        while zero_run > 15 {
-                                            ac_freq[0xF0] += 1;
-                                            zero_run -= 16;
-                                        }
-                                        let num_bits = get_num_bits(value);
-                                        let symbol = (zero_run << 4) | num_bits;
-
-                                        ac_freq[symbol as usize] += 1;
-
-                                        zero_run = 0;
-                                    }
-                                }
-
-                                if zero_run > 0 {
-                                    ac_freq[0] += 1;
-                                }
+                                count_ac_refinement_symbols(
+                                    &blocks[i],
+                                    start,
+                                    end,
+                                    scan.approximation_low,
+                                    restart_interval,
+                                    &mut ac_freq,
+                                );
                             }
                         }
                     } else {
-                        for block in &blocks[i] {
-                            let mut zero_run = 0;
-
-                            for &value in &block[1..] {
-                                if value == 0 {
-                                    zero_run += 1;
-                                } else {
-                                    while zero_run > 15 {
-                                        ac_freq[0xF0] += 1;
-                                        zero_run -= 16;
-                                    }
-                                    let num_bits = get_num_bits(value);
-                                    let symbol = (zero_run << 4) | num_bits;
-
-                                    ac_freq[symbol as usize] += 1;
-
-                                    zero_run = 0;
-                                }
-                            }
-
-                            if zero_run > 0 {
-                                ac_freq[0] += 1;
-                            }
-                        }
+                        count_ac_symbols(&blocks[i], &mut ac_freq);
                     }
                 }
             }
@@ -1168,6 +3953,222 @@ impl<W: JfifWrite> Encoder<W> {
     }
 }
 
+// Count the DC symbols of a component in a (first) scan with the given block order.
+// Mirrors the encoding done by JfifWriter::write_dc
+fn count_dc_symbols(
+    blocks: &[Vec<[i16; 64]>; 4],
+    component: usize,
+    order: &[(usize, usize)],
+    blocks_per_mcu: usize,
+    al: u8,
+    restart_interval: usize,
+    freq: &mut [u32; 257],
+) {
+    let mut prev_dc = 0;
+
+    for (mcu, mcu_blocks) in order.chunks(blocks_per_mcu).enumerate() {
+        if restart_interval > 0 && mcu > 0 && mcu % restart_interval == 0 {
+            prev_dc = 0;
+        }
+
+        for &(i, index) in mcu_blocks {
+            if i != component {
+                continue;
+            }
+
+            let value = blocks[i][index][0] >> al;
+            let diff = value - prev_dc;
+            let num_bits = get_num_bits(diff);
+
+            freq[num_bits as usize] += 1;
+
+            prev_dc = value;
+        }
+    }
+}
+
+// Returns the number of bits needed to code the counted symbols including their additional bits
+fn get_coded_bits(freq: &[u32; 257], table: &HuffmanTable) -> usize {
+    freq[..256]
+        .iter()
+        .enumerate()
+        .filter(|(_, &count)| count > 0)
+        .map(|(symbol, &count)| {
+            count as usize * (usize::from(table.get_code_length(symbol as u8)) + (symbol & 0x0F))
+        })
+        .sum()
+}
+
+// Count the AC symbols of a sequential scan.
+// Mirrors the encoding done by JfifWriter::write_ac_block
+fn count_ac_symbols(blocks: &[[i16; 64]], freq: &mut [u32; 257]) {
+    for block in blocks {
+        let mut zero_run = 0;
+
+        for &value in &block[1..] {
+            if value == 0 {
+                zero_run += 1;
+            } else {
+                while zero_run > 15 {
+                    freq[0xF0] += 1;
+                    zero_run -= 16;
+                }
+                let num_bits = get_num_bits(value);
+                let symbol = (zero_run << 4) | num_bits;
+
+                freq[symbol as usize] += 1;
+
+                zero_run = 0;
+            }
+        }
+
+        if zero_run > 0 {
+            freq[0] += 1;
+        }
+    }
+}
+
+// Count the symbols of a pending end of band run
+fn count_eobrun(eobrun: &mut u16, freq: &mut [u32; 257]) {
+    if *eobrun > 0 {
+        freq[(get_eobrun_bits(*eobrun) << 4) as usize] += 1;
+        *eobrun = 0;
+    }
+}
+
+// Count the AC symbols of a progressive first scan.
+// Mirrors the encoding done by JfifWriter::write_ac_first
+fn count_ac_first_symbols(
+    blocks: &[[i16; 64]],
+    start: usize,
+    end: usize,
+    al: u8,
+    restart_interval: usize,
+    freq: &mut [u32; 257],
+) {
+    let mut eobrun = 0;
+
+    for (i, block) in blocks.iter().enumerate() {
+        if restart_interval > 0 && i > 0 && i % restart_interval == 0 {
+            count_eobrun(&mut eobrun, freq);
+        }
+
+        let mut zero_run = 0;
+
+        for &value in &block[start..end] {
+            let abs_value = value.unsigned_abs() >> al;
+
+            if abs_value == 0 {
+                zero_run += 1;
+            } else {
+                count_eobrun(&mut eobrun, freq);
+
+                while zero_run > 15 {
+                    freq[0xF0] += 1;
+                    zero_run -= 16;
+                }
+                let num_bits = get_num_bits(abs_value as i16);
+                let symbol = (zero_run << 4) | num_bits;
+
+                freq[symbol as usize] += 1;
+
+                zero_run = 0;
+            }
+        }
+
+        if zero_run > 0 {
+            eobrun += 1;
+
+            if eobrun == 0x7FFF {
+                count_eobrun(&mut eobrun, freq);
+            }
+        }
+    }
+
+    count_eobrun(&mut eobrun, freq);
+}
+
+// Count the AC symbols of a successive approximation refinement scan.
+// Mirrors the encoding done by JfifWriter::write_ac_refinement
+fn count_ac_refinement_symbols(
+    blocks: &[[i16; 64]],
+    start: usize,
+    end: usize,
+    al: u8,
+    restart_interval: usize,
+    freq: &mut [u32; 257],
+) {
+    // The buffered correction bits are written together with the end of band run
+    fn count_refinement_eobrun(
+        eobrun: &mut u16,
+        num_correction_bits: &mut usize,
+        freq: &mut [u32; 257],
+    ) {
+        count_eobrun(eobrun, freq);
+        *num_correction_bits = 0;
+    }
+
+    let mut eobrun = 0;
+    let mut num_correction_bits = 0;
+
+    for (i, block) in blocks.iter().enumerate() {
+        if restart_interval > 0 && i > 0 && i % restart_interval == 0 {
+            count_refinement_eobrun(&mut eobrun, &mut num_correction_bits, freq);
+        }
+
+        let mut abs_values = [0u16; 64];
+        let mut eob = 0;
+
+        for (k, &value) in block.iter().enumerate().take(end).skip(start) {
+            let abs_value = value.unsigned_abs() >> al;
+            abs_values[k] = abs_value;
+
+            if abs_value == 1 {
+                eob = k;
+            }
+        }
+
+        let mut zero_run = 0;
+        let mut block_correction_bits = 0;
+
+        for (k, &abs_value) in abs_values.iter().enumerate().take(end).skip(start) {
+            if abs_value == 0 {
+                zero_run += 1;
+                continue;
+            }
+
+            while zero_run > 15 && k <= eob {
+                count_refinement_eobrun(&mut eobrun, &mut num_correction_bits, freq);
+                freq[0xF0] += 1;
+                zero_run -= 16;
+                block_correction_bits = 0;
+            }
+
+            if abs_value > 1 {
+                block_correction_bits += 1;
+                continue;
+            }
+
+            count_refinement_eobrun(&mut eobrun, &mut num_correction_bits, freq);
+            freq[(zero_run << 4) | 1] += 1;
+
+            block_correction_bits = 0;
+            zero_run = 0;
+        }
+
+        if zero_run > 0 || block_correction_bits > 0 {
+            eobrun += 1;
+            num_correction_bits += block_correction_bits;
+
+            if eobrun == 0x7FFF || num_correction_bits > MAX_CORRECTION_BITS - 64 + 1 {
+                count_refinement_eobrun(&mut eobrun, &mut num_correction_bits, freq);
+            }
+        }
+    }
+
+    count_refinement_eobrun(&mut eobrun, &mut num_correction_bits, freq);
+}
+
 #[cfg(feature = "std")]
 impl Encoder<BufWriter<File>> {
     /// Create a new decoder that writes into a file
@@ -1187,29 +4188,331 @@ impl Encoder<BufWriter<File>> {
     }
 }
 
-fn get_block(
-    data: &[u8],
+#[allow(clippy::too_many_arguments)]
+fn get_block<T: Copy + Into<i32>>(
+    data: &[T],
     start_x: usize,
     start_y: usize,
     col_stride: usize,
     row_stride: usize,
     width: usize,
+    level_shift: i32,
+    filter: DownsamplingFilter,
 ) -> [i16; 64] {
     let mut block = [0i16; 64];
 
+    if filter == DownsamplingFilter::Point || (col_stride == 1 && row_stride == 1) {
+        for y in 0..8 {
+            for x in 0..8 {
+                let ix = start_x + (x * col_stride);
+                let iy = start_y + (y * row_stride);
+
+                block[y * 8 + x] = (data[iy * width + ix].into() - level_shift) as i16;
+            }
+        }
+
+        return block;
+    }
+
+    let (h_offset, h_taps) = filter.get_taps(col_stride);
+    let (v_offset, v_taps) = filter.get_taps(row_stride);
+
+    let height = data.len() / width;
+    let max_value = 2 * level_shift - 1;
+
+    // The weights of each direction sum up to 1024
+    let total = 1i64 << 20;
+
     for y in 0..8 {
+        let first_y = (start_y + y * row_stride) as isize + v_offset;
+
         for x in 0..8 {
-            let ix = start_x + (x * col_stride);
-            let iy = start_y + (y * row_stride);
+            let first_x = (start_x + x * col_stride) as isize + h_offset;
+
+            let mut sum = 0i64;
+
+            for (dy, &v_weight) in v_taps.iter().enumerate() {
+                let iy = (first_y + dy as isize).clamp(0, height as isize - 1) as usize;
+                let row = &data[iy * width..(iy + 1) * width];
+
+                let mut row_sum = 0i64;
+                for (dx, &h_weight) in h_taps.iter().enumerate() {
+                    let ix = (first_x + dx as isize).clamp(0, width as isize - 1) as usize;
+                    row_sum += i64::from(h_weight) * i64::from(row[ix].into());
+                }
+
+                sum += i64::from(v_weight) * row_sum;
+            }
+
+            let value = ((sum + total / 2).div_euclid(total) as i32).clamp(0, max_value);
 
-            block[y * 8 + x] = (data[iy * width + ix] as i16) - 128;
+            block[y * 8 + x] = (value - level_shift) as i16;
         }
     }
 
     block
 }
 
-fn ceil_div(value: usize, div: usize) -> usize {
+/// Decodes the blocks of a component like a decoder would do
+///
+/// The samples of differential frames are added to the reference.
+pub(crate) fn reconstruct_plane<OP: Operations>(
+    blocks: &[[i16; 64]],
+    table: &QuantizationTable,
+    width: usize,
+    height: usize,
+    reference: Option<&Plane>,
+) -> Plane {
+    let cols = ceil_div(width, 8);
+
+    let mut samples = vec![0i16; width * height];
+
+    for (index, block) in blocks.iter().enumerate() {
+        let start_x = (index % cols) * 8;
+        let start_y = (index / cols) * 8;
+
+        let mut coefficients = [0i32; 64];
+        for (i, &value) in block.iter().enumerate() {
+            let z = ZIGZAG[i] as usize & 0x3f;
+            coefficients[z] = i32::from(value) * i32::from(table.get(z));
+        }
+
+        let values = if reference.is_some() {
+            // Differential coefficients can exceed the range of 16 bit integers
+            idct_wide(&coefficients)
+        } else {
+            let mut block = [0i16; 64];
+            for (b, &c) in block.iter_mut().zip(coefficients.iter()) {
+                *b = c.clamp(i16::MIN.into(), i16::MAX.into()) as i16;
+            }
+
+            OP::idct(&mut block);
+            block.map(i32::from)
+        };
+
+        for y in start_y..(start_y + 8).min(height) {
+            for x in start_x..(start_x + 8).min(width) {
+                let prediction = match reference {
+                    Some(reference) => i32::from(reference.samples[y * width + x]),
+                    None => 128,
+                };
+
+                let value = values[(y - start_y) * 8 + x - start_x] + prediction;
+                samples[y * width + x] = value.clamp(0, 255) as i16;
+            }
+        }
+    }
+
+    Plane::new(width, height, samples)
+}
+
+/// Scales a plane of a subsampled component up to the image size by repeating its samples
+/// like a decoder would do
+fn replicate_samples(
+    plane: &Plane,
+    h_scale: usize,
+    v_scale: usize,
+    width: usize,
+    height: usize,
+) -> Plane {
+    let samples = (0..height)
+        .flat_map(|y| {
+            (0..width).map(move |x| plane.samples[(y / v_scale) * plane.width + x / h_scale])
+        })
+        .collect();
+
+    Plane::new(width, height, samples)
+}
+
+/// Transforms the blocks of a row of MCUs and appends them to the blocks of each component
+fn transform_mcu_row<OP: Operations>(
+    components: &[Component],
+    (max_h_sampling, max_v_sampling): (usize, usize),
+    filter: DownsamplingFilter,
+    row: &[Vec<u8>; 4],
+    buffer_width: usize,
+    blocks: &mut [Vec<[i16; 64]>; 4],
+) {
+    let num_cols = buffer_width / (8 * max_h_sampling);
+
+    for (i, component) in components.iter().enumerate() {
+        let h_scale = max_h_sampling / component.horizontal_sampling_factor as usize;
+        let v_scale = max_v_sampling / component.vertical_sampling_factor as usize;
+
+        for v_offset in 0..component.vertical_sampling_factor as usize {
+            for block_x in 0..num_cols * component.horizontal_sampling_factor as usize {
+                let mut block = get_block(
+                    &row[i],
+                    block_x * 8 * h_scale,
+                    v_offset * 8 * v_scale,
+                    h_scale,
+                    v_scale,
+                    buffer_width,
+                    128,
+                    filter,
+                );
+
+                OP::fdct(&mut block);
+
+                blocks[i].push(block);
+            }
+        }
+    }
+}
+
+/// Returns the number of MCUs of an interleaved scan covered by the blocks
+fn get_num_mcus(components: &[Component], blocks: &[Vec<[i16; 64]>; 4]) -> usize {
+    let first = &components[0];
+
+    blocks[0].len()
+        / (usize::from(first.horizontal_sampling_factor)
+            * usize::from(first.vertical_sampling_factor))
+}
+
+/// Writes the blocks of all components of an MCU of an interleaved scan
+///
+/// The blocks of each component cover rows of `num_cols` MCUs.
+fn write_mcu<W: JfifWrite>(
+    writer: &mut JfifWriter<W>,
+    components: &[Component],
+    huffman_tables: &[(HuffmanTable, HuffmanTable); 2],
+    blocks: &[Vec<[i16; 64]>; 4],
+    num_cols: usize,
+    mcu: usize,
+    prev_dc: &mut [i16; 4],
+) -> Result<(), EncodingError> {
+    let mcu_x = mcu % num_cols;
+    let mcu_y = mcu / num_cols;
+
+    for (i, component) in components.iter().enumerate() {
+        let h = usize::from(component.horizontal_sampling_factor);
+        let v = usize::from(component.vertical_sampling_factor);
+
+        for v_offset in 0..v {
+            for h_offset in 0..h {
+                let index = (mcu_y * v + v_offset) * num_cols * h + mcu_x * h + h_offset;
+                let block = &blocks[i][index];
+
+                writer.write_block(
+                    block,
+                    prev_dc[i],
+                    &huffman_tables[component.dc_huffman_table as usize].0,
+                    &huffman_tables[component.ac_huffman_table as usize].1,
+                )?;
+
+                prev_dc[i] = block[0];
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Encodes restart intervals of an interleaved scan into a new buffer
+///
+/// Each interval is given by its MCUs and the number of its RST marker, which precedes it.
+fn encode_interleaved_intervals(
+    components: &[Component],
+    huffman_tables: &[(HuffmanTable, HuffmanTable); 2],
+    blocks: &[Vec<[i16; 64]>; 4],
+    num_cols: usize,
+    intervals: &[(Range<usize>, u8)],
+) -> Result<Vec<u8>, EncodingError> {
+    let mut writer = JfifWriter::new(Vec::new());
+
+    for (mcus, restart) in intervals {
+        writer.write_marker(Marker::RST(*restart))?;
+
+        let mut prev_dc = [0; 4];
+
+        for mcu in mcus.clone() {
+            write_mcu(
+                &mut writer,
+                components,
+                huffman_tables,
+                blocks,
+                num_cols,
+                mcu,
+                &mut prev_dc,
+            )?;
+        }
+
+        writer.finalize_bit_buffer()?;
+    }
+
+    Ok(writer.into_inner())
+}
+
+/// Encodes the restart intervals in `range` of a sequential scan into a new buffer
+///
+/// Every interval except the first of the scan is preceded by its RST marker.
+fn encode_restart_intervals(
+    intervals: &[&[[i16; 64]]],
+    range: Range<usize>,
+    dc_table: &HuffmanTable,
+    ac_table: &HuffmanTable,
+    differential: bool,
+) -> Result<Vec<u8>, EncodingError> {
+    let mut writer = JfifWriter::new(Vec::new());
+
+    for index in range {
+        if index > 0 {
+            writer.write_marker(Marker::RST(((index - 1) % 8) as u8))?;
+        }
+
+        let mut prev_dc = 0;
+
+        for block in intervals[index] {
+            writer.write_block(block, prev_dc, dc_table, ac_table)?;
+
+            if !differential {
+                prev_dc = block[0];
+            }
+        }
+
+        writer.finalize_bit_buffer()?;
+    }
+
+    Ok(writer.into_inner())
+}
+
+fn init_rows<T>(num_components: usize, buffer_size: usize) -> [Vec<T>; 4] {
+    // To simplify the code and to give the compiler more infos to optimize stuff we always initialize 4 components
+    // Resource overhead should be minimal because an empty Vec doesn't allocate
+
+    match num_components {
+        1 => [
+            Vec::with_capacity(buffer_size),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+        ],
+        3 => [
+            Vec::with_capacity(buffer_size),
+            Vec::with_capacity(buffer_size),
+            Vec::with_capacity(buffer_size),
+            Vec::new(),
+        ],
+        4 => [
+            Vec::with_capacity(buffer_size),
+            Vec::with_capacity(buffer_size),
+            Vec::with_capacity(buffer_size),
+            Vec::with_capacity(buffer_size),
+        ],
+        len => unreachable!("Unsupported component length: {}", len),
+    }
+}
+
+/// Returns the sampling factor used by default for the given quality
+fn default_sampling_factor(quality: u8) -> SamplingFactor {
+    if quality < 90 {
+        SamplingFactor::F_2_2
+    } else {
+        SamplingFactor::F_1_1
+    }
+}
+
+pub(crate) fn ceil_div(value: usize, div: usize) -> usize {
     value / div + usize::from(value % div != 0)
 }
 
@@ -1241,6 +4544,11 @@ pub(crate) trait Operations {
             q_block[i] = table.quantize(block[z], z);
         }
     }
+
+    #[inline(always)]
+    fn idct(data: &mut [i16; 64]) {
+        idct(data);
+    }
 }
 
 pub(crate) struct DefaultOperations;
@@ -1251,13 +4559,18 @@ impl Operations for DefaultOperations {}
 mod tests {
     use alloc::vec;
 
-    use crate::encoder::get_num_bits;
-    use crate::writer::get_code;
-    use crate::{Encoder, SamplingFactor};
+    use alloc::vec::Vec;
+
+    use crate::encoder::{get_block, get_num_bits, DefaultOperations};
+    use crate::image_buffer::RgbImage;
+    use crate::writer::{get_code, ZIGZAG};
+    use crate::{
+        ColorMatrix, ColorType, DownsamplingFilter, Encoder, JpegColorType, SamplingFactor,
+    };
 
     #[test]
     fn test_get_num_bits() {
-        let min_max = 2i16.pow(13);
+        let min_max = i16::MAX;
 
         for value in -min_max..=min_max {
             let num_bits1 = get_num_bits(value);
@@ -1292,6 +4605,32 @@ mod tests {
         assert_eq!(SamplingFactor::R_4_1_0.get_sampling_factors(), (4, 2));
     }
 
+    #[test]
+    fn test_get_block_downsampling() {
+        // Vertical lines with a width of one sample
+        let data: Vec<u8> = (0..32 * 16)
+            .map(|i| if i % 2 == 0 { 0 } else { 255 })
+            .collect();
+
+        let get_block = |filter| get_block(&data, 0, 0, 2, 2, 32, 128, filter);
+
+        assert_eq!(get_block(DownsamplingFilter::Point), [-128; 64]);
+        assert_eq!(get_block(DownsamplingFilter::Box), [0; 64]);
+
+        // The filters reaching over the left edge see the repeated edge sample
+        for filter in [DownsamplingFilter::Triangle, DownsamplingFilter::Lanczos] {
+            let block = get_block(filter);
+
+            for (i, &value) in block.iter().enumerate() {
+                if i % 8 > 2 {
+                    assert_eq!(value, 0);
+                }
+            }
+
+            assert!(block[0] < 0);
+        }
+    }
+
     #[test]
     fn test_set_progressive() {
         let mut encoder = Encoder::new(vec![], 100);
@@ -1301,4 +4640,86 @@ mod tests {
         encoder.set_progressive(false);
         assert_eq!(encoder.progressive_scans(), None);
     }
+
+    fn encode_with_coefficients(configure: impl Fn(&mut Encoder<&mut Vec<u8>>)) {
+        let width = 37;
+        let height = 21;
+
+        let data: Vec<u8> = (0..width * height * 3)
+            .map(|i| ((i * 7 + (i / 111) * 13) % 256) as u8)
+            .collect();
+
+        let mut reference = Vec::new();
+        let mut encoder = Encoder::new(&mut reference, 80);
+        configure(&mut encoder);
+        encoder
+            .encode(&data, width, height, ColorType::Rgb)
+            .unwrap();
+
+        let mut output = Vec::new();
+        let mut encoder = Encoder::new(&mut output, 80);
+        configure(&mut encoder);
+
+        encoder.init_components(JpegColorType::Ycbcr);
+        let q_tables = encoder.get_quantization_tables();
+        let image = RgbImage(
+            &data,
+            width,
+            height,
+            usize::from(width) * 3,
+            ColorMatrix::Bt601Full,
+        );
+
+        // The blocks must be transformed with the same DCT implementation as in encode
+        #[cfg(all(feature = "simd", any(target_arch = "x86", target_arch = "x86_64")))]
+        let blocks = if std::is_x86_feature_detected!("avx2") {
+            encoder.encode_blocks::<_, crate::avx2::AVX2Operations>(&image, None, &q_tables)
+        } else {
+            encoder.encode_blocks::<_, DefaultOperations>(&image, None, &q_tables)
+        };
+
+        #[cfg(not(all(feature = "simd", any(target_arch = "x86", target_arch = "x86_64"))))]
+        let blocks = encoder.encode_blocks::<_, DefaultOperations>(&image, None, &q_tables);
+
+        encoder.components.clear();
+
+        let coefficients: Vec<Vec<[i16; 64]>> = blocks[..3]
+            .iter()
+            .map(|blocks| {
+                blocks
+                    .iter()
+                    .map(|q_block| {
+                        let mut block = [0i16; 64];
+                        for (i, &value) in q_block.iter().enumerate() {
+                            block[ZIGZAG[i] as usize & 0x3f] = value;
+                        }
+                        block
+                    })
+                    .collect()
+            })
+            .collect();
+
+        encoder
+            .encode_coefficients(&coefficients, width, height, JpegColorType::Ycbcr)
+            .unwrap();
+
+        assert_eq!(output, reference);
+    }
+
+    #[test]
+    fn test_encode_coefficients_sequential() {
+        encode_with_coefficients(|encoder| {
+            encoder.set_sampling_factor(SamplingFactor::F_2_1);
+            encoder.set_optimized_huffman_tables(true);
+        });
+    }
+
+    #[test]
+    fn test_encode_coefficients_progressive() {
+        encode_with_coefficients(|encoder| {
+            encoder.set_sampling_factor(SamplingFactor::F_2_2);
+            encoder.set_progressive(true);
+            encoder.set_restart_interval(3);
+        });
+    }
 }
@@ -17,9 +17,55 @@ pub enum EncodingError {
     /// Image data is too short
     BadImageData { length: usize, required: usize },
 
+    /// The number of components or blocks of coefficient data doesn't match the image
+    ///
+    /// `component` is `None` if the number of components is wrong.
+    BadCoefficientData {
+        component: Option<usize>,
+        length: usize,
+        required: usize,
+    },
+
+    /// A coefficient is outside of the range of the sample precision
+    ///
+    /// `index` is the position of the coefficient in natural order within the block.
+    CoefficientOutOfRange {
+        component: usize,
+        block: usize,
+        index: usize,
+        value: i16,
+    },
+
+    /// The row stride is smaller than the length of a row
+    InvalidRowStride { stride: usize, required: usize },
+
     /// Width or height is zero
     ZeroImageDimensions { width: u16, height: u16 },
 
+    /// A scan script violates the progression rules
+    InvalidScanScript {
+        scan: Option<usize>,
+        reason: &'static str,
+    },
+
+    /// The sample precision isn't supported by the selected encoding process
+    UnsupportedSamplePrecision(u8),
+
+    /// The point transform of a lossless image isn't smaller than the sample precision
+    InvalidPointTransform { point_transform: u8, precision: u8 },
+
+    /// A setting of the encoder isn't supported by the streaming encoder
+    UnsupportedStreamingSettings(&'static str),
+
+    /// A setting of the encoder isn't supported by the reconstruction of the image
+    UnsupportedReconstructionSettings(&'static str),
+
+    /// The number of rows written to the streaming encoder doesn't match the image height
+    InvalidRowCount { rows: usize, height: u16 },
+
+    /// The image doesn't fit into the target size even at the lowest quality
+    TargetSizeTooSmall { size: usize, target_size: usize },
+
     /// An io error occurred during writing
     #[cfg(feature = "std")]
     IoError(std::io::Error),
@@ -55,9 +101,76 @@ impl Display for EncodingError {
                 "Image data too small for dimensions and color_type: {} need at least {}",
                 length, required
             ),
+            BadCoefficientData {
+                component: Some(component),
+                length,
+                required,
+            } => write!(
+                f,
+                "Number of blocks of component {} doesn't match the image size: {} need {}",
+                component, length, required
+            ),
+            BadCoefficientData {
+                component: None,
+                length,
+                required,
+            } => write!(
+                f,
+                "Number of components doesn't match the color type: {} need {}",
+                length, required
+            ),
+            CoefficientOutOfRange {
+                component,
+                block,
+                index,
+                value,
+            } => write!(
+                f,
+                "Coefficient {} of block {} of component {} is out of range: {}",
+                index, block, component, value
+            ),
+            InvalidRowStride { stride, required } => write!(
+                f,
+                "Row stride too small for width and color_type: {} need at least {}",
+                stride, required
+            ),
             ZeroImageDimensions { width, height } => {
                 write!(f, "Image dimensions must be non zero: {}x{}", width, height)
             }
+            InvalidScanScript {
+                scan: Some(scan),
+                reason,
+            } => write!(f, "Invalid scan {} in scan script: {}", scan, reason),
+            InvalidScanScript { scan: None, reason } => {
+                write!(f, "Invalid scan script: {}", reason)
+            }
+            UnsupportedSamplePrecision(precision) => {
+                write!(f, "Unsupported sample precision: {}", precision)
+            }
+            InvalidPointTransform {
+                point_transform,
+                precision,
+            } => write!(
+                f,
+                "Point transform {} must be smaller than sample precision {}",
+                point_transform, precision
+            ),
+            UnsupportedStreamingSettings(setting) => {
+                write!(f, "Setting not supported for streaming: {}", setting)
+            }
+            UnsupportedReconstructionSettings(setting) => {
+                write!(f, "Setting not supported for reconstruction: {}", setting)
+            }
+            InvalidRowCount { rows, height } => write!(
+                f,
+                "Number of rows doesn't match the image height: {} rows for height {}",
+                rows, height
+            ),
+            TargetSizeTooSmall { size, target_size } => write!(
+                f,
+                "Target size too small: {} bytes needed at the lowest quality for a target of {}",
+                size, target_size
+            ),
             #[cfg(feature = "std")]
             IoError(err) => err.fmt(f),
             Write(err) => write!(f, "{}", err),
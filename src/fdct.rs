@@ -71,6 +71,8 @@
  * scaled fixed-point arithmetic, with a minimal number of shifts.
  */
 
+use core::ops::{Add, Mul, Shl, Shr, Sub};
+
 const CONST_BITS: i32 = 13;
 const PASS1_BITS: i32 = 2;
 
@@ -89,10 +91,29 @@ const FIX_3_072711026: i32 = 25172;
 
 const DCT_SIZE: usize = 8;
 
+/// Integer type used for the intermediate values
+///
+/// 12 bit samples need 64 bit integers to avoid overflows.
+trait DctInt:
+    Copy
+    + Default
+    + From<i16>
+    + From<i32>
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Shl<i32, Output = Self>
+    + Shr<i32, Output = Self>
+{
+}
+
+impl DctInt for i32 {}
+impl DctInt for i64 {}
+
 #[inline(always)]
-fn descale(x: i32, n: i32) -> i32 {
+fn descale<W: DctInt>(x: W, n: i32) -> W {
     // right shift with rounding
-    (x + (1 << (n - 1))) >> n
+    (x + (W::from(1) << (n - 1))) >> n
 }
 
 #[inline(always)]
@@ -100,26 +121,43 @@ fn into_el(v: i32) -> i16 {
     v as i16
 }
 
+pub fn fdct(data: &mut [i16; 64]) {
+    *data = fdct_internal::<i32, _>(data, into_el);
+}
+
+/// Forward DCT for blocks with 12 bit samples
+///
+/// The results exceed the range of an i16 and are returned as i32 values
+pub(crate) fn fdct_12bit(data: &[i16; 64]) -> [i32; 64] {
+    fdct_internal::<i64, _>(data, |v| v as i32)
+}
+
+#[inline(always)]
 #[allow(clippy::erasing_op)]
 #[allow(clippy::identity_op)]
-pub fn fdct(data: &mut [i16; 64]) {
+fn fdct_internal<W: DctInt, T: Copy + Default>(
+    data: &[i16; 64],
+    into_el: impl Fn(W) -> T,
+) -> [T; 64] {
+    let mut out = [T::default(); 64];
+
     /* Pass 1: process rows. */
     /* Note results are scaled up by sqrt(8) compared to a true DCT; */
     /* furthermore, we scale the results by 2**PASS1_BITS. */
 
-    let mut data2 = [0i32; 64];
+    let mut data2 = [W::default(); 64];
 
     for y in 0..8 {
         let offset = y * 8;
 
-        let tmp0 = i32::from(data[offset + 0]) + i32::from(data[offset + 7]);
-        let tmp7 = i32::from(data[offset + 0]) - i32::from(data[offset + 7]);
-        let tmp1 = i32::from(data[offset + 1]) + i32::from(data[offset + 6]);
-        let tmp6 = i32::from(data[offset + 1]) - i32::from(data[offset + 6]);
-        let tmp2 = i32::from(data[offset + 2]) + i32::from(data[offset + 5]);
-        let tmp5 = i32::from(data[offset + 2]) - i32::from(data[offset + 5]);
-        let tmp3 = i32::from(data[offset + 3]) + i32::from(data[offset + 4]);
-        let tmp4 = i32::from(data[offset + 3]) - i32::from(data[offset + 4]);
+        let tmp0 = W::from(data[offset + 0]) + W::from(data[offset + 7]);
+        let tmp7 = W::from(data[offset + 0]) - W::from(data[offset + 7]);
+        let tmp1 = W::from(data[offset + 1]) + W::from(data[offset + 6]);
+        let tmp6 = W::from(data[offset + 1]) - W::from(data[offset + 6]);
+        let tmp2 = W::from(data[offset + 2]) + W::from(data[offset + 5]);
+        let tmp5 = W::from(data[offset + 2]) - W::from(data[offset + 5]);
+        let tmp3 = W::from(data[offset + 3]) + W::from(data[offset + 4]);
+        let tmp4 = W::from(data[offset + 3]) - W::from(data[offset + 4]);
 
         /* Even part per LL&M figure 1 --- note that published figure is faulty;
          * rotator "sqrt(2)*c1" should be "sqrt(2)*c6".
@@ -133,13 +171,13 @@ pub fn fdct(data: &mut [i16; 64]) {
         data2[offset + 0] = (tmp10 + tmp11) << PASS1_BITS;
         data2[offset + 4] = (tmp10 - tmp11) << PASS1_BITS;
 
-        let z1 = (tmp12 + tmp13) * FIX_0_541196100;
+        let z1 = (tmp12 + tmp13) * W::from(FIX_0_541196100);
         data2[offset + 2] = descale(
-            z1 + (tmp13 * FIX_0_765366865),
+            z1 + (tmp13 * W::from(FIX_0_765366865)),
             CONST_BITS - PASS1_BITS,
         );
         data2[offset + 6] = descale(
-            z1 + (tmp12 * -FIX_1_847759065),
+            z1 + (tmp12 * W::from(-FIX_1_847759065)),
             CONST_BITS - PASS1_BITS,
         );
 
@@ -152,16 +190,16 @@ pub fn fdct(data: &mut [i16; 64]) {
         let z2 = tmp5 + tmp6;
         let z3 = tmp4 + tmp6;
         let z4 = tmp5 + tmp7;
-        let z5 = (z3 + z4) * FIX_1_175875602; /* sqrt(2) * c3 */
+        let z5 = (z3 + z4) * W::from(FIX_1_175875602); /* sqrt(2) * c3 */
 
-        let tmp4 = tmp4 * FIX_0_298631336; /* sqrt(2) * (-c1+c3+c5-c7) */
-        let tmp5 = tmp5 * FIX_2_053119869; /* sqrt(2) * ( c1+c3-c5+c7) */
-        let tmp6 = tmp6 * FIX_3_072711026; /* sqrt(2) * ( c1+c3+c5-c7) */
-        let tmp7 = tmp7 * FIX_1_501321110; /* sqrt(2) * ( c1+c3-c5-c7) */
-        let z1 = z1 * -FIX_0_899976223; /* sqrt(2) * ( c7-c3) */
-        let z2 = z2 * -FIX_2_562915447; /* sqrt(2) * (-c1-c3) */
-        let z3 = z3 * -FIX_1_961570560; /* sqrt(2) * (-c3-c5) */
-        let z4 = z4 * -FIX_0_390180644; /* sqrt(2) * ( c5-c3) */
+        let tmp4 = tmp4 * W::from(FIX_0_298631336); /* sqrt(2) * (-c1+c3+c5-c7) */
+        let tmp5 = tmp5 * W::from(FIX_2_053119869); /* sqrt(2) * ( c1+c3-c5+c7) */
+        let tmp6 = tmp6 * W::from(FIX_3_072711026); /* sqrt(2) * ( c1+c3+c5-c7) */
+        let tmp7 = tmp7 * W::from(FIX_1_501321110); /* sqrt(2) * ( c1+c3-c5-c7) */
+        let z1 = z1 * W::from(-FIX_0_899976223); /* sqrt(2) * ( c7-c3) */
+        let z2 = z2 * W::from(-FIX_2_562915447); /* sqrt(2) * (-c1-c3) */
+        let z3 = z3 * W::from(-FIX_1_961570560); /* sqrt(2) * (-c3-c5) */
+        let z4 = z4 * W::from(-FIX_0_390180644); /* sqrt(2) * ( c5-c3) */
 
         let z3 = z3 + z5;
         let z4 = z4 + z5;
@@ -196,16 +234,16 @@ pub fn fdct(data: &mut [i16; 64]) {
         let tmp11 = tmp1 + tmp2;
         let tmp12 = tmp1 - tmp2;
 
-        data[DCT_SIZE * 0 + x] = into_el(descale(tmp10 + tmp11, PASS1_BITS));
-        data[DCT_SIZE * 4 + x] = into_el(descale(tmp10 - tmp11, PASS1_BITS));
+        out[DCT_SIZE * 0 + x] = into_el(descale(tmp10 + tmp11, PASS1_BITS));
+        out[DCT_SIZE * 4 + x] = into_el(descale(tmp10 - tmp11, PASS1_BITS));
 
-        let z1 = (tmp12 + tmp13) * FIX_0_541196100;
-        data[DCT_SIZE * 2 + x] = into_el(descale(
-            z1 + tmp13 * FIX_0_765366865,
+        let z1 = (tmp12 + tmp13) * W::from(FIX_0_541196100);
+        out[DCT_SIZE * 2 + x] = into_el(descale(
+            z1 + tmp13 * W::from(FIX_0_765366865),
             CONST_BITS + PASS1_BITS,
         ));
-        data[DCT_SIZE * 6 + x] = into_el(descale(
-            z1 + tmp12 * -FIX_1_847759065,
+        out[DCT_SIZE * 6 + x] = into_el(descale(
+            z1 + tmp12 * W::from(-FIX_1_847759065),
             CONST_BITS + PASS1_BITS,
         ));
 
@@ -218,25 +256,27 @@ pub fn fdct(data: &mut [i16; 64]) {
         let z2 = tmp5 + tmp6;
         let z3 = tmp4 + tmp6;
         let z4 = tmp5 + tmp7;
-        let z5 = (z3 + z4) * FIX_1_175875602; /* sqrt(2) * c3 */
+        let z5 = (z3 + z4) * W::from(FIX_1_175875602); /* sqrt(2) * c3 */
 
-        let tmp4 = tmp4 * FIX_0_298631336; /* sqrt(2) * (-c1+c3+c5-c7) */
-        let tmp5 = tmp5 * FIX_2_053119869; /* sqrt(2) * ( c1+c3-c5+c7) */
-        let tmp6 = tmp6 * FIX_3_072711026; /* sqrt(2) * ( c1+c3+c5-c7) */
-        let tmp7 = tmp7 * FIX_1_501321110; /* sqrt(2) * ( c1+c3-c5-c7) */
-        let z1 = z1 * -FIX_0_899976223; /* sqrt(2) * ( c7-c3) */
-        let z2 = z2 * -FIX_2_562915447; /* sqrt(2) * (-c1-c3) */
-        let z3 = z3 * -FIX_1_961570560; /* sqrt(2) * (-c3-c5) */
-        let z4 = z4 * -FIX_0_390180644; /* sqrt(2) * ( c5-c3) */
+        let tmp4 = tmp4 * W::from(FIX_0_298631336); /* sqrt(2) * (-c1+c3+c5-c7) */
+        let tmp5 = tmp5 * W::from(FIX_2_053119869); /* sqrt(2) * ( c1+c3-c5+c7) */
+        let tmp6 = tmp6 * W::from(FIX_3_072711026); /* sqrt(2) * ( c1+c3+c5-c7) */
+        let tmp7 = tmp7 * W::from(FIX_1_501321110); /* sqrt(2) * ( c1+c3-c5-c7) */
+        let z1 = z1 * W::from(-FIX_0_899976223); /* sqrt(2) * ( c7-c3) */
+        let z2 = z2 * W::from(-FIX_2_562915447); /* sqrt(2) * (-c1-c3) */
+        let z3 = z3 * W::from(-FIX_1_961570560); /* sqrt(2) * (-c3-c5) */
+        let z4 = z4 * W::from(-FIX_0_390180644); /* sqrt(2) * ( c5-c3) */
 
         let z3 = z3 + z5;
         let z4 = z4 + z5;
 
-        data[DCT_SIZE * 7 + x] = into_el(descale(tmp4 + z1 + z3, CONST_BITS + PASS1_BITS));
-        data[DCT_SIZE * 5 + x] = into_el(descale(tmp5 + z2 + z4, CONST_BITS + PASS1_BITS));
-        data[DCT_SIZE * 3 + x] = into_el(descale(tmp6 + z2 + z3, CONST_BITS + PASS1_BITS));
-        data[DCT_SIZE * 1 + x] = into_el(descale(tmp7 + z1 + z4, CONST_BITS + PASS1_BITS));
+        out[DCT_SIZE * 7 + x] = into_el(descale(tmp4 + z1 + z3, CONST_BITS + PASS1_BITS));
+        out[DCT_SIZE * 5 + x] = into_el(descale(tmp5 + z2 + z4, CONST_BITS + PASS1_BITS));
+        out[DCT_SIZE * 3 + x] = into_el(descale(tmp6 + z2 + z3, CONST_BITS + PASS1_BITS));
+        out[DCT_SIZE * 1 + x] = into_el(descale(tmp7 + z1 + z4, CONST_BITS + PASS1_BITS));
     }
+
+    out
 }
 
 #[cfg(test)]
@@ -244,7 +284,7 @@ mod tests {
 
     // Inputs and outputs are taken from libjpegs jpeg_fdct_islow for a typical image
 
-    use super::fdct;
+    use super::{fdct, fdct_12bit};
 
     const INPUT1: [i16; 64] = [
         -70, -71, -70, -68, -67, -67, -67, -67, -72, -73, -72, -70, -69, -69, -68, -69, -75, -76,
@@ -275,12 +315,33 @@ mod tests {
 
     #[test]
     pub fn test_fdct_libjpeg() {
-        let mut i1 = INPUT1.clone();
+        let mut i1 = INPUT1;
         fdct(&mut i1);
         assert_eq!(i1, OUTPUT1);
 
-        let mut i2 = INPUT2.clone();
+        let mut i2 = INPUT2;
         fdct(&mut i2);
         assert_eq!(i2, OUTPUT2);
     }
+
+    #[test]
+    pub fn test_fdct_12bit() {
+        // Results for 8 bit input with identical precision
+        assert_eq!(fdct_12bit(&INPUT1).map(|v| v as i16), OUTPUT1);
+        assert_eq!(fdct_12bit(&INPUT2).map(|v| v as i16), OUTPUT2);
+
+        // Full range of 12 bit samples
+        let mut input = [2047i16; 64];
+        for (i, v) in input.iter_mut().enumerate() {
+            if (i + i / 8) % 2 == 1 {
+                *v = -2048;
+            }
+        }
+
+        let output = fdct_12bit(&input);
+
+        assert_eq!(output[0], -4 * 8);
+        assert!(output.iter().all(|&v| v.abs() < 16384 * 8 * 2));
+        assert!(output[63] > 32767);
+    }
 }
@@ -0,0 +1,150 @@
+/*
+ * Resampling of components for hierarchical encoding as described in Annex J
+ */
+
+use alloc::vec::Vec;
+
+/// A single component of a frame in the pyramid
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Plane {
+    pub width: usize,
+    pub height: usize,
+    pub samples: Vec<i16>,
+}
+
+impl Plane {
+    pub fn new(width: usize, height: usize, samples: Vec<i16>) -> Plane {
+        debug_assert_eq!(samples.len(), width * height);
+
+        Plane {
+            width,
+            height,
+            samples,
+        }
+    }
+
+    #[inline]
+    fn get(&self, x: usize, y: usize) -> i32 {
+        let x = x.min(self.width - 1);
+        let y = y.min(self.height - 1);
+
+        i32::from(self.samples[y * self.width + x])
+    }
+
+    /// Halves the size of the plane by averaging 2x2 samples
+    ///
+    /// Odd dimensions are rounded up by repeating the last column and row.
+    pub fn downsample(&self) -> Plane {
+        let width = (self.width + 1) / 2;
+        let height = (self.height + 1) / 2;
+
+        let mut samples = Vec::with_capacity(width * height);
+
+        for y in 0..height {
+            for x in 0..width {
+                let sum = self.get(2 * x, 2 * y)
+                    + self.get(2 * x + 1, 2 * y)
+                    + self.get(2 * x, 2 * y + 1)
+                    + self.get(2 * x + 1, 2 * y + 1);
+
+                samples.push(((sum + 2) >> 2) as i16);
+            }
+        }
+
+        Plane::new(width, height, samples)
+    }
+
+    /// Doubles the size of the plane with the bi-linear filter of J.1.1.2
+    ///
+    /// Samples between two reference samples are the average of both, rounded down.
+    /// The last column and row are repeated at the edges. The result is cropped
+    /// to `width` x `height`, which must not exceed twice the size of the plane.
+    pub fn upsample(&self, width: usize, height: usize) -> Plane {
+        debug_assert!(width <= self.width * 2);
+        debug_assert!(height <= self.height * 2);
+
+        let horizontal = |x: usize, y: usize| {
+            if x % 2 == 0 {
+                self.get(x / 2, y)
+            } else {
+                (self.get(x / 2, y) + self.get(x / 2 + 1, y)) >> 1
+            }
+        };
+
+        let mut samples = Vec::with_capacity(width * height);
+
+        for y in 0..height {
+            for x in 0..width {
+                let sample = if y % 2 == 0 {
+                    horizontal(x, y / 2)
+                } else {
+                    (horizontal(x, y / 2) + horizontal(x, (y / 2 + 1).min(self.height - 1))) >> 1
+                };
+
+                samples.push(sample as i16);
+            }
+        }
+
+        Plane::new(width, height, samples)
+    }
+
+    /// Returns the samples padded to full 8x8 blocks and the padded width
+    pub fn padded_samples(&self) -> (Vec<i16>, usize) {
+        let width = (self.width + 7) / 8 * 8;
+        let height = (self.height + 7) / 8 * 8;
+
+        let mut samples = Vec::with_capacity(width * height);
+
+        for y in 0..height {
+            for x in 0..width {
+                samples.push(self.get(x, y) as i16);
+            }
+        }
+
+        (samples, width)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::Plane;
+
+    #[test]
+    fn test_downsample() {
+        let plane = Plane::new(3, 3, vec![0, 2, 8, 4, 6, 8, 20, 21, 50]);
+
+        assert_eq!(plane.downsample(), Plane::new(2, 2, vec![3, 8, 21, 50]));
+    }
+
+    #[test]
+    fn test_upsample() {
+        let plane = Plane::new(2, 2, vec![10, 21, 30, 40]);
+
+        assert_eq!(
+            plane.upsample(4, 3),
+            Plane::new(
+                4,
+                3,
+                vec![
+                    10, 15, 21, 21, //
+                    20, 25, 30, 30, //
+                    30, 35, 40, 40, //
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn test_padded_samples() {
+        let plane = Plane::new(9, 1, (0..9).collect());
+
+        let (samples, width) = plane.padded_samples();
+
+        assert_eq!(width, 16);
+        assert_eq!(samples.len(), 16 * 8);
+        assert_eq!(samples[8..16], [8; 8]);
+        assert_eq!(samples[16 * 7 + 15], 8);
+    }
+}
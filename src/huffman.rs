@@ -5,6 +5,9 @@
 
 use alloc::vec::Vec;
 
+#[cfg(test)]
+use crate::coefficient_decoder::EntropyDecoder;
+
 #[derive(Copy, Clone, Debug)]
 pub enum CodingClass {
     Dc = 0,
@@ -63,6 +66,7 @@ static DEFAULT_CHROMA_AC_VALUES: [u8; 162] = [
     0xF9, 0xFA,
 ];
 
+#[derive(Clone)]
 pub struct HuffmanTable {
     lookup_table: [(u8, u16); 256],
     length: [u8; 16],
@@ -227,6 +231,12 @@ impl HuffmanTable {
         res
     }
 
+    /// Returns the code length of a value or 0 if the value isn't contained in the table
+    #[inline]
+    pub fn get_code_length(&self, value: u8) -> u8 {
+        self.lookup_table[value as usize].0
+    }
+
     pub fn length(&self) -> &[u8; 16] {
         &self.length
     }
@@ -236,6 +246,262 @@ impl HuffmanTable {
     }
 }
 
+/// Decoder for the huffman coded data of a restart interval
+///
+/// Only used to verify the encoder in tests. The decoding procedures are based on
+/// jdhuff.c and jdphuff.c of the IJG libjpeg.
+#[cfg(test)]
+pub(crate) struct HuffmanDecoder<'a> {
+    data: &'a [u8],
+    position: usize,
+
+    bits: u32,
+    num_bits: u8,
+
+    last_dc: [i32; 4],
+    end_of_band_run: u32,
+
+    dc_tables: &'a [Option<HuffmanTable>; 4],
+    ac_tables: &'a [Option<HuffmanTable>; 4],
+}
+
+#[cfg(test)]
+impl<'a> HuffmanDecoder<'a> {
+    /// Creates a decoder for the entropy coded data of a restart interval without markers
+    pub fn new(
+        data: &'a [u8],
+        dc_tables: &'a [Option<HuffmanTable>; 4],
+        ac_tables: &'a [Option<HuffmanTable>; 4],
+    ) -> Self {
+        HuffmanDecoder {
+            data,
+            position: 0,
+            bits: 0,
+            num_bits: 0,
+            last_dc: [0; 4],
+            end_of_band_run: 0,
+            dc_tables,
+            ac_tables,
+        }
+    }
+
+    fn read_bit(&mut self) -> bool {
+        if self.num_bits == 0 {
+            let value = *self
+                .data
+                .get(self.position)
+                .expect("Unexpected end of data");
+
+            self.position += 1;
+
+            if value == 0xFF {
+                assert_eq!(self.data.get(self.position), Some(&0), "Unexpected marker");
+                self.position += 1;
+            }
+
+            self.bits = u32::from(value);
+            self.num_bits = 8;
+        }
+
+        self.num_bits -= 1;
+        (self.bits >> self.num_bits) & 1 == 1
+    }
+
+    fn receive(&mut self, length: u8) -> u32 {
+        let mut value = 0;
+        for _ in 0..length {
+            value = (value << 1) | u32::from(self.read_bit());
+        }
+        value
+    }
+
+    /// Reads a value of the given magnitude category (F.2.2.1)
+    fn receive_extend(&mut self, category: u8) -> i32 {
+        if category == 0 {
+            return 0;
+        }
+
+        let value = self.receive(category) as i32;
+
+        if value < 1 << (category - 1) {
+            value - (1 << category) + 1
+        } else {
+            value
+        }
+    }
+
+    /// Decodes a huffman coded symbol (F.2.2.3)
+    fn decode(&mut self, table: &HuffmanTable) -> u8 {
+        let mut code = 0u16;
+
+        for length in 1..=16 {
+            code = (code << 1) | u16::from(self.read_bit());
+
+            let value = table
+                .values()
+                .iter()
+                .find(|&&value| table.get_for_value(value) == &(length, code));
+
+            if let Some(&value) = value {
+                return value;
+            }
+        }
+
+        panic!("Invalid huffman code");
+    }
+
+    /// Reads the correction bit of a previously non zero coefficient
+    fn refine(&mut self, coefficient: &mut i32, al: u8) {
+        if self.read_bit() {
+            if *coefficient < 0 {
+                *coefficient -= 1 << al;
+            } else {
+                *coefficient += 1 << al;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+impl EntropyDecoder for HuffmanDecoder<'_> {
+    /// Decode the DC coefficient of a block of the given component (F.2.2.1)
+    ///
+    /// The coefficient is point transformed by `al`.
+    fn decode_dc(&mut self, component: usize, table: usize, al: u8) -> i32 {
+        let table = self.dc_tables[table]
+            .as_ref()
+            .expect("Missing huffman table");
+
+        let category = self.decode(table);
+        self.last_dc[component] += self.receive_extend(category);
+
+        self.last_dc[component] << al
+    }
+
+    /// Decode the bit `al` of the DC coefficient in a refinement scan (G.1.2.1)
+    fn decode_dc_refinement(&mut self, coefficient: &mut i32, al: u8) {
+        if self.read_bit() {
+            *coefficient |= 1 << al;
+        }
+    }
+
+    /// Decode the AC coefficients `start..end` of a block in zigzag order (F.2.2.2 and G.1.2.2)
+    ///
+    /// The coefficients are point transformed by `al`.
+    fn decode_ac_first(
+        &mut self,
+        block: &mut [i32; 64],
+        start: usize,
+        end: usize,
+        al: u8,
+        table: usize,
+    ) {
+        if self.end_of_band_run > 0 {
+            self.end_of_band_run -= 1;
+            return;
+        }
+
+        let table = self.ac_tables[table]
+            .as_ref()
+            .expect("Missing huffman table");
+
+        let mut k = start;
+
+        while k < end {
+            let symbol = self.decode(table);
+            let run = symbol >> 4;
+            let category = symbol & 0x0F;
+
+            if category != 0 {
+                k += usize::from(run);
+                assert!(k < end, "Spectral overflow");
+
+                block[k] = self.receive_extend(category) << al;
+            } else if run == 15 {
+                k += 15;
+            } else {
+                // End of band run, which is a single block in sequential scans
+                self.end_of_band_run = (1 << run) + self.receive(run) - 1;
+                break;
+            }
+
+            k += 1;
+        }
+    }
+
+    /// Decode the bit `al` of the AC coefficients `start..end` in a refinement scan (G.1.2.3)
+    fn decode_ac_refinement(
+        &mut self,
+        block: &mut [i32; 64],
+        start: usize,
+        end: usize,
+        al: u8,
+        table: usize,
+    ) {
+        let table = self.ac_tables[table]
+            .as_ref()
+            .expect("Missing huffman table");
+
+        let mut k = start;
+
+        if self.end_of_band_run == 0 {
+            while k < end {
+                let symbol = self.decode(table);
+                let mut run = symbol >> 4;
+                let category = symbol & 0x0F;
+
+                let value = if category != 0 {
+                    assert_eq!(category, 1, "Invalid refinement value");
+
+                    if self.read_bit() {
+                        1
+                    } else {
+                        -1
+                    }
+                } else if run != 15 {
+                    self.end_of_band_run = (1 << run) + self.receive(run);
+                    break;
+                } else {
+                    0
+                };
+
+                // Skip the zero run and read the correction bits of the skipped coefficients
+                while k < end {
+                    if block[k] != 0 {
+                        self.refine(&mut block[k], al);
+                    } else if run == 0 {
+                        break;
+                    } else {
+                        run -= 1;
+                    }
+
+                    k += 1;
+                }
+
+                if value != 0 {
+                    assert!(k < end, "Spectral overflow");
+                    block[k] = value << al;
+                }
+
+                k += 1;
+            }
+        }
+
+        if self.end_of_band_run > 0 {
+            // Only correction bits for the remaining coefficients of the band
+            while k < end {
+                if block[k] != 0 {
+                    self.refine(&mut block[k], al);
+                }
+
+                k += 1;
+            }
+
+            self.end_of_band_run -= 1;
+        }
+    }
+}
+
 // Create huffman table code sizes as defined in Figure C.1
 fn create_sizes(code_lengths: &[u8; 16]) -> [u8; 256] {
     let mut sizes = [0u8; 256];
@@ -0,0 +1,246 @@
+/*
+ * Ported from libjpeg's jidctint.c to rust
+ *
+ * This file was part of the Independent JPEG Group's software:
+ * Copyright (C) 1991-1998, Thomas G. Lane.
+ *
+ * The same conditions as for the forward DCT in fdct.rs apply.
+ *
+ * This file contains a slower but more accurate integer implementation of the
+ * inverse DCT (Discrete Cosine Transform). It uses the same algorithm as the
+ * forward DCT by Loeffler, Ligtenberg and Moschytz.
+ *
+ * The intermediate values are kept in 64 bit integers so that the coefficients
+ * of differential frames, which aren't limited to the range of 8 bit samples,
+ * can't overflow.
+ */
+
+const CONST_BITS: i32 = 13;
+const PASS1_BITS: i32 = 2;
+
+const FIX_0_298631336: i64 = 2446;
+const FIX_0_390180644: i64 = 3196;
+const FIX_0_541196100: i64 = 4433;
+const FIX_0_765366865: i64 = 6270;
+const FIX_0_899976223: i64 = 7373;
+const FIX_1_175875602: i64 = 9633;
+const FIX_1_501321110: i64 = 12299;
+const FIX_1_847759065: i64 = 15137;
+const FIX_1_961570560: i64 = 16069;
+const FIX_2_053119869: i64 = 16819;
+const FIX_2_562915447: i64 = 20995;
+const FIX_3_072711026: i64 = 25172;
+
+#[inline(always)]
+fn descale(x: i64, n: i32) -> i64 {
+    // right shift with rounding
+    (x + (1 << (n - 1))) >> n
+}
+
+/// One dimensional inverse DCT of 8 values with a distance of `stride`
+///
+/// Returns the even and odd parts before the final butterfly.
+#[inline(always)]
+fn idct_1d(data: &[i64], offset: usize, stride: usize) -> ([i64; 4], [i64; 4]) {
+    let value = |i: usize| data[offset + i * stride];
+
+    /* Even part: reverse the even part of the forward DCT. */
+    /* The rotator is sqrt(2)*c(-6). */
+
+    let z2 = value(2);
+    let z3 = value(6);
+
+    let z1 = (z2 + z3) * FIX_0_541196100;
+    let tmp2 = z1 + z3 * -FIX_1_847759065;
+    let tmp3 = z1 + z2 * FIX_0_765366865;
+
+    let tmp0 = (value(0) + value(4)) << CONST_BITS;
+    let tmp1 = (value(0) - value(4)) << CONST_BITS;
+
+    let even = [tmp0 + tmp3, tmp1 + tmp2, tmp1 - tmp2, tmp0 - tmp3];
+
+    /* Odd part per figure 8; the matrix is unitary and hence its
+     * transpose is its inverse. i0..i3 are y7,y5,y3,y1 respectively.
+     */
+
+    let tmp0 = value(7);
+    let tmp1 = value(5);
+    let tmp2 = value(3);
+    let tmp3 = value(1);
+
+    let z1 = tmp0 + tmp3;
+    let z2 = tmp1 + tmp2;
+    let z3 = tmp0 + tmp2;
+    let z4 = tmp1 + tmp3;
+    let z5 = (z3 + z4) * FIX_1_175875602; /* sqrt(2) * c3 */
+
+    let tmp0 = tmp0 * FIX_0_298631336; /* sqrt(2) * (-c1+c3+c5-c7) */
+    let tmp1 = tmp1 * FIX_2_053119869; /* sqrt(2) * ( c1+c3-c5+c7) */
+    let tmp2 = tmp2 * FIX_3_072711026; /* sqrt(2) * ( c1+c3+c5-c7) */
+    let tmp3 = tmp3 * FIX_1_501321110; /* sqrt(2) * ( c1+c3-c5-c7) */
+    let z1 = z1 * -FIX_0_899976223; /* sqrt(2) * ( c7-c3) */
+    let z2 = z2 * -FIX_2_562915447; /* sqrt(2) * (-c1-c3) */
+    let z3 = z3 * -FIX_1_961570560 + z5; /* sqrt(2) * (-c3-c5) */
+    let z4 = z4 * -FIX_0_390180644 + z5; /* sqrt(2) * ( c5-c3) */
+
+    let odd = [
+        tmp3 + z1 + z4,
+        tmp2 + z2 + z3,
+        tmp1 + z2 + z4,
+        tmp0 + z1 + z3,
+    ];
+
+    (even, odd)
+}
+
+/// Inverse DCT of a block of dequantized coefficients in natural order
+///
+/// The coefficients are replaced by the samples without level shift, so 128 has to
+/// be added to get the samples of an 8 bit image. Values outside of the range of
+/// 16 bit integers are saturated.
+pub fn idct(data: &mut [i16; 64]) {
+    let mut coefficients = [0i32; 64];
+    for (c, &value) in coefficients.iter_mut().zip(data.iter()) {
+        *c = i32::from(value);
+    }
+
+    for (value, sample) in data.iter_mut().zip(idct_wide(&coefficients)) {
+        *value = sample.clamp(i16::MIN.into(), i16::MAX.into()) as i16;
+    }
+}
+
+/// Inverse DCT of a block of dequantized coefficients in natural order
+///
+/// Returns the samples without level shift. Unlike [idct] this can be used for the
+/// coefficients of differential frames.
+pub(crate) fn idct_wide(coefficients: &[i32; 64]) -> [i32; 64] {
+    let mut workspace = [0i64; 64];
+
+    /* Pass 1: process columns from input, store into work array. */
+    /* Note results are scaled up by sqrt(8) compared to a true IDCT; */
+    /* furthermore, we scale the results by 2**PASS1_BITS. */
+
+    let input: [i64; 64] = {
+        let mut input = [0i64; 64];
+        for (i, &c) in input.iter_mut().zip(coefficients.iter()) {
+            *i = i64::from(c);
+        }
+        input
+    };
+
+    for x in 0..8 {
+        let (even, odd) = idct_1d(&input, x, 8);
+
+        for i in 0..4 {
+            workspace[i * 8 + x] = descale(even[i] + odd[i], CONST_BITS - PASS1_BITS);
+            workspace[(7 - i) * 8 + x] = descale(even[i] - odd[i], CONST_BITS - PASS1_BITS);
+        }
+    }
+
+    /* Pass 2: process rows from work array, store into output array. */
+    /* Note that we must descale the results by a factor of 8 == 2**3, */
+    /* and also undo the PASS1_BITS scaling. */
+
+    let mut out = [0i32; 64];
+
+    for y in 0..8 {
+        let (even, odd) = idct_1d(&workspace, y * 8, 1);
+
+        for i in 0..4 {
+            out[y * 8 + i] = descale(even[i] + odd[i], CONST_BITS + PASS1_BITS + 3) as i32;
+            out[y * 8 + 7 - i] = descale(even[i] - odd[i], CONST_BITS + PASS1_BITS + 3) as i32;
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{idct, idct_wide};
+    use crate::fdct::fdct;
+
+    #[test]
+    fn test_idct_inverts_fdct() {
+        let mut data = [0i16; 64];
+        for (i, v) in data.iter_mut().enumerate() {
+            *v = ((i * 37 + (i / 8) * 11) % 256) as i16 - 128;
+        }
+
+        let mut coefficients = data;
+        fdct(&mut coefficients);
+
+        // The forward DCT scales the results by 8
+        let mut dequantized = [0i32; 64];
+        for (d, &c) in dequantized.iter_mut().zip(coefficients.iter()) {
+            *d = (i32::from(c) + 4) >> 3;
+        }
+
+        let samples = idct_wide(&dequantized);
+
+        for (&sample, &expected) in samples.iter().zip(data.iter()) {
+            assert!((sample - i32::from(expected)).abs() <= 1);
+        }
+    }
+
+    #[test]
+    fn test_idct_dc() {
+        let mut coefficients = [0i32; 64];
+        coefficients[0] = 80;
+
+        assert_eq!(idct_wide(&coefficients), [10; 64]);
+    }
+
+    /// Dequantized coefficients of a block with a coarse quantization
+    fn create_coefficients() -> [i16; 64] {
+        let mut data = [0i16; 64];
+        for (i, v) in data.iter_mut().enumerate() {
+            *v = ((i * 53 + (i / 8) * 29) % 256) as i16 - 128;
+        }
+
+        fdct(&mut data);
+
+        for (i, v) in data.iter_mut().enumerate() {
+            let q = 4 + i as i16;
+            *v = ((*v + 4) >> 3) / q * q;
+        }
+
+        data
+    }
+
+    #[test]
+    fn test_idct() {
+        let coefficients = create_coefficients();
+
+        let mut wide = [0i32; 64];
+        for (w, &c) in wide.iter_mut().zip(coefficients.iter()) {
+            *w = i32::from(c);
+        }
+
+        let mut samples = coefficients;
+        idct(&mut samples);
+
+        for (&sample, expected) in samples.iter().zip(idct_wide(&wide)) {
+            assert_eq!(i32::from(sample), expected);
+        }
+    }
+
+    #[cfg(all(feature = "simd", any(target_arch = "x86", target_arch = "x86_64")))]
+    #[test]
+    fn test_idct_avx2() {
+        if !std::is_x86_feature_detected!("avx2") {
+            return;
+        }
+
+        let mut data = create_coefficients();
+        data[0] = 1016;
+        data[63] = -900;
+
+        let mut expected = data;
+        idct(&mut expected);
+
+        crate::avx2::idct_avx2(&mut data);
+
+        assert_eq!(data, expected);
+    }
+}
@@ -2,7 +2,9 @@
 
 use alloc::vec::Vec;
 
+use crate::dithering::round_sample;
 use crate::encoder::JpegColorType;
+use crate::{AlphaPolicy, ColorMatrix};
 
 /// Conversion from RGB to YCbCr
 #[inline]
@@ -93,11 +95,78 @@ pub trait ImageBuffer {
     /// Height of the image
     fn height(&self) -> u16;
 
+    /// Number of bits per sample of the image data
+    ///
+    /// Buffers with more than 8 bits per sample should return their precision, so that the
+    /// encoder reads the samples with [fill_buffers_with_precision](ImageBuffer::fill_buffers_with_precision)
+    /// and reduces them to 8 bits with the configured [dithering](crate::Encoder::set_dithering).
+    fn sample_precision(&self) -> u8 {
+        8
+    }
+
     /// Add color values for the row to color component buffers
     fn fill_buffers(&self, y: u16, buffers: &mut [Vec<u8>; 4]);
+
+    /// Add color values with the given precision in bits for the row to color component buffers
+    ///
+    /// This is used for lossless encoding. The default implementation scales the values
+    /// of [fill_buffers](ImageBuffer::fill_buffers) from 8 bits to the requested precision.
+    /// Buffers with more than 8 bits per sample should override this function.
+    fn fill_buffers_with_precision(&self, y: u16, precision: u8, buffers: &mut [Vec<u16>; 4]) {
+        let mut buffers_u8: [Vec<u8>; 4] = Default::default();
+
+        self.fill_buffers(y, &mut buffers_u8);
+
+        for (buffer, values) in buffers.iter_mut().zip(&buffers_u8) {
+            buffer.extend(
+                values
+                    .iter()
+                    .map(|&value| scale_sample(value.into(), 8, precision)),
+            );
+        }
+    }
+}
+
+/// Scales a sample value with a precision of `from` bits to `to` bits
+#[inline]
+fn scale_sample(value: u16, from: u8, to: u8) -> u16 {
+    if to >= from {
+        value << (to - from)
+    } else {
+        value >> (from - to)
+    }
+}
+
+/// Image buffer which forwards to an image buffer of unknown type
+pub(crate) struct DynImageBuffer<'a>(pub &'a (dyn ImageBuffer + Sync));
+
+impl<'a> ImageBuffer for DynImageBuffer<'a> {
+    fn get_jpeg_color_type(&self) -> JpegColorType {
+        self.0.get_jpeg_color_type()
+    }
+
+    fn width(&self) -> u16 {
+        self.0.width()
+    }
+
+    fn height(&self) -> u16 {
+        self.0.height()
+    }
+
+    fn sample_precision(&self) -> u8 {
+        self.0.sample_precision()
+    }
+
+    fn fill_buffers(&self, y: u16, buffers: &mut [Vec<u8>; 4]) {
+        self.0.fill_buffers(y, buffers);
+    }
+
+    fn fill_buffers_with_precision(&self, y: u16, precision: u8, buffers: &mut [Vec<u16>; 4]) {
+        self.0.fill_buffers_with_precision(y, precision, buffers);
+    }
 }
 
-pub(crate) struct GrayImage<'a>(pub &'a [u8], pub u16, pub u16);
+pub(crate) struct GrayImage<'a>(pub &'a [u8], pub u16, pub u16, pub usize);
 
 impl<'a> ImageBuffer for GrayImage<'a> {
     fn get_jpeg_color_type(&self) -> JpegColorType {
@@ -113,7 +182,7 @@ impl<'a> ImageBuffer for GrayImage<'a> {
     }
 
     fn fill_buffers(&self, y: u16, buffers: &mut [Vec<u8>; 4]) {
-        let line = get_line(self.0, y, self.width(), 1);
+        let line = get_line(self.0, y, self.3, self.width(), 1);
 
         for &pixel in line {
             buffers[0].push(pixel);
@@ -121,21 +190,114 @@ impl<'a> ImageBuffer for GrayImage<'a> {
     }
 }
 
+pub(crate) struct GrayImage16<'a>(pub &'a [u8], pub u16, pub u16, pub usize);
+
+impl<'a> ImageBuffer for GrayImage16<'a> {
+    fn get_jpeg_color_type(&self) -> JpegColorType {
+        JpegColorType::Luma
+    }
+
+    fn width(&self) -> u16 {
+        self.1
+    }
+
+    fn height(&self) -> u16 {
+        self.2
+    }
+
+    fn sample_precision(&self) -> u8 {
+        16
+    }
+
+    fn fill_buffers(&self, y: u16, buffers: &mut [Vec<u8>; 4]) {
+        let line = get_line(self.0, y, self.3, self.width(), 2);
+
+        for pixel in line.chunks_exact(2) {
+            buffers[0].push(round_sample(get_u16(pixel, 0)));
+        }
+    }
+
+    fn fill_buffers_with_precision(&self, y: u16, precision: u8, buffers: &mut [Vec<u16>; 4]) {
+        let line = get_line(self.0, y, self.3, self.width(), 2);
+
+        for pixel in line.chunks_exact(2) {
+            buffers[0].push(scale_sample(get_u16(pixel, 0), 16, precision));
+        }
+    }
+}
+
+/// Reads the native endian u16 value with the given index
 #[inline(always)]
-fn get_line(data: &[u8], y: u16, width:u16, num_colors: usize) -> &[u8] {
-    let width= usize::from(width);
+fn get_u16(data: &[u8], index: usize) -> u16 {
+    u16::from_ne_bytes([data[index * 2], data[index * 2 + 1]])
+}
+
+/// Reads the native endian f32 value with the given index and scales it to 16 bits
+///
+/// Values outside of 0.0..=1.0 are clamped and NaN is read as 0.
+#[inline(always)]
+fn get_f32(data: &[u8], index: usize) -> u16 {
+    let value = f32::from_ne_bytes([
+        data[index * 4],
+        data[index * 4 + 1],
+        data[index * 4 + 2],
+        data[index * 4 + 3],
+    ]);
+
+    (value.clamp(0.0, 1.0) * 65535.0 + 0.5) as u16
+}
+
+/// Returns the samples of row `y`, which starts `stride` bytes after the previous row
+#[inline(always)]
+fn get_line(data: &[u8], y: u16, stride: usize, width: u16, num_colors: usize) -> &[u8] {
+    let width = usize::from(width);
     let y = usize::from(y);
 
-    let start = y *width * num_colors;
+    let start = y * stride;
     let end = start + width * num_colors;
 
     &data[start..end]
 }
 
 macro_rules! ycbcr_image {
-    ($name:ident, $num_colors:expr, $o1:expr, $o2:expr, $o3:expr) => {
-        pub(crate) struct $name<'a>(pub &'a [u8], pub u16, pub u16);
+    ($name:ident, 3, $o1:expr, $o2:expr, $o3:expr) => {
+        pub(crate) struct $name<'a>(pub &'a [u8], pub u16, pub u16, pub usize, pub ColorMatrix);
+
+        impl<'a> $name<'a> {
+            #[inline(always)]
+            fn get_rgb(&self, pixel: &[u8]) -> (u8, u8, u8) {
+                (pixel[$o1], pixel[$o2], pixel[$o3])
+            }
+        }
 
+        ycbcr_image!(@impl $name, 3);
+    };
+    ($name:ident, 4, $o1:expr, $o2:expr, $o3:expr, $oa:expr, $premultiplied:expr) => {
+        pub(crate) struct $name<'a>(
+            pub &'a [u8],
+            pub u16,
+            pub u16,
+            pub usize,
+            pub ColorMatrix,
+            pub AlphaPolicy,
+        );
+
+        impl<'a> $name<'a> {
+            #[inline(always)]
+            fn get_rgb(&self, pixel: &[u8]) -> (u8, u8, u8) {
+                let (r, g, b, a) = (pixel[$o1], pixel[$o2], pixel[$o3], pixel[$oa]);
+
+                if $premultiplied {
+                    self.5.apply_premultiplied(r, g, b, a)
+                } else {
+                    self.5.apply(r, g, b, a)
+                }
+            }
+        }
+
+        ycbcr_image!(@impl $name, 4);
+    };
+    (@impl $name:ident, $num_colors:expr) => {
         impl<'a> ImageBuffer for $name<'a> {
             fn get_jpeg_color_type(&self) -> JpegColorType {
                 JpegColorType::Ycbcr
@@ -151,14 +313,12 @@ macro_rules! ycbcr_image {
 
             #[inline(always)]
             fn fill_buffers(&self, y: u16, buffers: &mut [Vec<u8>; 4]) {
-                let line = get_line(self.0, y, self.width(), $num_colors);
+                let line = get_line(self.0, y, self.3, self.width(), $num_colors);
+                let coefficients = self.4.coefficients();
 
                 for pixel in line.chunks_exact($num_colors) {
-                    let (y, cb, cr) = rgb_to_ycbcr(
-                        pixel[$o1],
-                        pixel[$o2],
-                        pixel[$o3],
-                    );
+                    let (r, g, b) = self.get_rgb(pixel);
+                    let (y, cb, cr) = coefficients.rgb_to_ycbcr(r, g, b);
 
                     buffers[0].push(y);
                     buffers[1].push(cb);
@@ -170,11 +330,285 @@ macro_rules! ycbcr_image {
 }
 
 ycbcr_image!(RgbImage, 3, 0, 1, 2);
-ycbcr_image!(RgbaImage, 4, 0, 1, 2);
+ycbcr_image!(RgbaImage, 4, 0, 1, 2, 3, false);
 ycbcr_image!(BgrImage, 3, 2, 1, 0);
-ycbcr_image!(BgraImage, 4, 2, 1, 0);
+ycbcr_image!(BgraImage, 4, 2, 1, 0, 3, false);
+ycbcr_image!(ArgbImage, 4, 1, 2, 3, 0, false);
+ycbcr_image!(AbgrImage, 4, 3, 2, 1, 0, false);
+ycbcr_image!(RgbaPremultipliedImage, 4, 0, 1, 2, 3, true);
+ycbcr_image!(BgraPremultipliedImage, 4, 2, 1, 0, 3, true);
+ycbcr_image!(ArgbPremultipliedImage, 4, 1, 2, 3, 0, true);
+ycbcr_image!(AbgrPremultipliedImage, 4, 3, 2, 1, 0, true);
+
+pub(crate) struct Rgb16Image<'a>(pub &'a [u8], pub u16, pub u16, pub usize, pub ColorMatrix);
+
+impl<'a> Rgb16Image<'a> {
+    #[inline(always)]
+    fn get_rgb(&self, pixel: &[u8]) -> (u16, u16, u16) {
+        (get_u16(pixel, 0), get_u16(pixel, 1), get_u16(pixel, 2))
+    }
+}
+
+pub(crate) struct Rgba16Image<'a>(
+    pub &'a [u8],
+    pub u16,
+    pub u16,
+    pub usize,
+    pub ColorMatrix,
+    pub AlphaPolicy,
+);
+
+impl<'a> Rgba16Image<'a> {
+    #[inline(always)]
+    fn get_rgb(&self, pixel: &[u8]) -> (u16, u16, u16) {
+        let (r, g, b, a) = (
+            get_u16(pixel, 0),
+            get_u16(pixel, 1),
+            get_u16(pixel, 2),
+            get_u16(pixel, 3),
+        );
+
+        self.5.apply_16(r, g, b, a)
+    }
+}
+
+pub(crate) struct RgbF32Image<'a>(pub &'a [u8], pub u16, pub u16, pub usize, pub ColorMatrix);
+
+impl<'a> RgbF32Image<'a> {
+    #[inline(always)]
+    fn get_rgb(&self, pixel: &[u8]) -> (u16, u16, u16) {
+        (get_f32(pixel, 0), get_f32(pixel, 1), get_f32(pixel, 2))
+    }
+}
+
+/// Implements [ImageBuffer] for RGB images with 16 bit values which are converted to YCbCr
+macro_rules! ycbcr_image_16 {
+    ($name:ident, $bytes_per_pixel:expr) => {
+        impl<'a> ImageBuffer for $name<'a> {
+            fn get_jpeg_color_type(&self) -> JpegColorType {
+                JpegColorType::Ycbcr
+            }
+
+            fn width(&self) -> u16 {
+                self.1
+            }
+
+            fn height(&self) -> u16 {
+                self.2
+            }
+
+            fn sample_precision(&self) -> u8 {
+                16
+            }
+
+            fn fill_buffers(&self, y: u16, buffers: &mut [Vec<u8>; 4]) {
+                let line = get_line(self.0, y, self.3, self.width(), $bytes_per_pixel);
+                let coefficients = self.4.coefficients();
+
+                for pixel in line.chunks_exact($bytes_per_pixel) {
+                    let (r, g, b) = self.get_rgb(pixel);
+                    let (y, cb, cr) = coefficients.rgb_to_ycbcr_with_precision(r, g, b, 16);
+
+                    buffers[0].push(round_sample(y));
+                    buffers[1].push(round_sample(cb));
+                    buffers[2].push(round_sample(cr));
+                }
+            }
+
+            fn fill_buffers_with_precision(
+                &self,
+                y: u16,
+                precision: u8,
+                buffers: &mut [Vec<u16>; 4],
+            ) {
+                let line = get_line(self.0, y, self.3, self.width(), $bytes_per_pixel);
+                let coefficients = self.4.coefficients();
+
+                for pixel in line.chunks_exact($bytes_per_pixel) {
+                    let (r, g, b) = self.get_rgb(pixel);
+
+                    let (y, cb, cr) = coefficients.rgb_to_ycbcr_with_precision(
+                        scale_sample(r, 16, precision),
+                        scale_sample(g, 16, precision),
+                        scale_sample(b, 16, precision),
+                        precision,
+                    );
+
+                    buffers[0].push(y);
+                    buffers[1].push(cb);
+                    buffers[2].push(cr);
+                }
+            }
+        }
+    };
+}
+
+ycbcr_image_16!(Rgb16Image, 6);
+ycbcr_image_16!(Rgba16Image, 8);
+ycbcr_image_16!(RgbF32Image, 12);
+
+macro_rules! rgb_image {
+    ($name:ident, 3, $o1:expr, $o2:expr, $o3:expr) => {
+        pub(crate) struct $name<'a>(pub &'a [u8], pub u16, pub u16, pub usize);
+
+        impl<'a> $name<'a> {
+            #[inline(always)]
+            fn get_rgb(&self, pixel: &[u8]) -> (u8, u8, u8) {
+                (pixel[$o1], pixel[$o2], pixel[$o3])
+            }
+        }
+
+        rgb_image!(@impl $name, 3);
+    };
+    ($name:ident, 4, $o1:expr, $o2:expr, $o3:expr, $oa:expr, $premultiplied:expr) => {
+        pub(crate) struct $name<'a>(pub &'a [u8], pub u16, pub u16, pub usize, pub AlphaPolicy);
+
+        impl<'a> $name<'a> {
+            #[inline(always)]
+            fn get_rgb(&self, pixel: &[u8]) -> (u8, u8, u8) {
+                let (r, g, b, a) = (pixel[$o1], pixel[$o2], pixel[$o3], pixel[$oa]);
+
+                if $premultiplied {
+                    self.4.apply_premultiplied(r, g, b, a)
+                } else {
+                    self.4.apply(r, g, b, a)
+                }
+            }
+        }
+
+        rgb_image!(@impl $name, 4);
+    };
+    (@impl $name:ident, $num_colors:expr) => {
+        impl<'a> ImageBuffer for $name<'a> {
+            fn get_jpeg_color_type(&self) -> JpegColorType {
+                JpegColorType::Rgb
+            }
+
+            fn width(&self) -> u16 {
+                self.1
+            }
+
+            fn height(&self) -> u16 {
+                self.2
+            }
+
+            fn fill_buffers(&self, y: u16, buffers: &mut [Vec<u8>; 4]) {
+                let line = get_line(self.0, y, self.3, self.width(), $num_colors);
+
+                for pixel in line.chunks_exact($num_colors) {
+                    let (r, g, b) = self.get_rgb(pixel);
+
+                    buffers[0].push(r);
+                    buffers[1].push(g);
+                    buffers[2].push(b);
+                }
+            }
+        }
+    };
+}
+
+rgb_image!(RgbAsRgbImage, 3, 0, 1, 2);
+rgb_image!(RgbaAsRgbImage, 4, 0, 1, 2, 3, false);
+rgb_image!(BgrAsRgbImage, 3, 2, 1, 0);
+rgb_image!(BgraAsRgbImage, 4, 2, 1, 0, 3, false);
+rgb_image!(ArgbAsRgbImage, 4, 1, 2, 3, 0, false);
+rgb_image!(AbgrAsRgbImage, 4, 3, 2, 1, 0, false);
+rgb_image!(RgbaPremultipliedAsRgbImage, 4, 0, 1, 2, 3, true);
+rgb_image!(BgraPremultipliedAsRgbImage, 4, 2, 1, 0, 3, true);
+rgb_image!(ArgbPremultipliedAsRgbImage, 4, 1, 2, 3, 0, true);
+rgb_image!(AbgrPremultipliedAsRgbImage, 4, 3, 2, 1, 0, true);
+
+pub(crate) struct Rgb16AsRgbImage<'a>(pub &'a [u8], pub u16, pub u16, pub usize);
+
+impl<'a> Rgb16AsRgbImage<'a> {
+    #[inline(always)]
+    fn get_rgb(&self, pixel: &[u8]) -> (u16, u16, u16) {
+        (get_u16(pixel, 0), get_u16(pixel, 1), get_u16(pixel, 2))
+    }
+}
+
+pub(crate) struct Rgba16AsRgbImage<'a>(pub &'a [u8], pub u16, pub u16, pub usize, pub AlphaPolicy);
+
+impl<'a> Rgba16AsRgbImage<'a> {
+    #[inline(always)]
+    fn get_rgb(&self, pixel: &[u8]) -> (u16, u16, u16) {
+        let (r, g, b, a) = (
+            get_u16(pixel, 0),
+            get_u16(pixel, 1),
+            get_u16(pixel, 2),
+            get_u16(pixel, 3),
+        );
+
+        self.4.apply_16(r, g, b, a)
+    }
+}
+
+pub(crate) struct RgbF32AsRgbImage<'a>(pub &'a [u8], pub u16, pub u16, pub usize);
+
+impl<'a> RgbF32AsRgbImage<'a> {
+    #[inline(always)]
+    fn get_rgb(&self, pixel: &[u8]) -> (u16, u16, u16) {
+        (get_f32(pixel, 0), get_f32(pixel, 1), get_f32(pixel, 2))
+    }
+}
+
+/// Implements [ImageBuffer] for RGB images with 16 bit values which are stored as RGB
+macro_rules! rgb_image_16 {
+    ($name:ident, $bytes_per_pixel:expr) => {
+        impl<'a> ImageBuffer for $name<'a> {
+            fn get_jpeg_color_type(&self) -> JpegColorType {
+                JpegColorType::Rgb
+            }
+
+            fn width(&self) -> u16 {
+                self.1
+            }
+
+            fn height(&self) -> u16 {
+                self.2
+            }
+
+            fn sample_precision(&self) -> u8 {
+                16
+            }
+
+            fn fill_buffers(&self, y: u16, buffers: &mut [Vec<u8>; 4]) {
+                let line = get_line(self.0, y, self.3, self.width(), $bytes_per_pixel);
+
+                for pixel in line.chunks_exact($bytes_per_pixel) {
+                    let (r, g, b) = self.get_rgb(pixel);
+
+                    buffers[0].push(round_sample(r));
+                    buffers[1].push(round_sample(g));
+                    buffers[2].push(round_sample(b));
+                }
+            }
+
+            fn fill_buffers_with_precision(
+                &self,
+                y: u16,
+                precision: u8,
+                buffers: &mut [Vec<u16>; 4],
+            ) {
+                let line = get_line(self.0, y, self.3, self.width(), $bytes_per_pixel);
+
+                for pixel in line.chunks_exact($bytes_per_pixel) {
+                    let (r, g, b) = self.get_rgb(pixel);
+
+                    buffers[0].push(scale_sample(r, 16, precision));
+                    buffers[1].push(scale_sample(g, 16, precision));
+                    buffers[2].push(scale_sample(b, 16, precision));
+                }
+            }
+        }
+    };
+}
 
-pub(crate) struct YCbCrImage<'a>(pub &'a [u8], pub u16, pub u16);
+rgb_image_16!(Rgb16AsRgbImage, 6);
+rgb_image_16!(Rgba16AsRgbImage, 8);
+rgb_image_16!(RgbF32AsRgbImage, 12);
+
+pub(crate) struct YCbCrImage<'a>(pub &'a [u8], pub u16, pub u16, pub usize);
 
 impl<'a> ImageBuffer for YCbCrImage<'a> {
     fn get_jpeg_color_type(&self) -> JpegColorType {
@@ -190,7 +624,7 @@ impl<'a> ImageBuffer for YCbCrImage<'a> {
     }
 
     fn fill_buffers(&self, y: u16, buffers: &mut [Vec<u8>; 4]) {
-        let line = get_line(self.0, y, self.width(), 3);
+        let line = get_line(self.0, y, self.3, self.width(), 3);
 
         for pixel in line.chunks_exact(3) {
             buffers[0].push(pixel[0]);
@@ -200,7 +634,7 @@ impl<'a> ImageBuffer for YCbCrImage<'a> {
     }
 }
 
-pub(crate) struct CmykImage<'a>(pub &'a [u8], pub u16, pub u16);
+pub(crate) struct CmykImage<'a>(pub &'a [u8], pub u16, pub u16, pub usize);
 
 impl<'a> ImageBuffer for CmykImage<'a> {
     fn get_jpeg_color_type(&self) -> JpegColorType {
@@ -216,7 +650,7 @@ impl<'a> ImageBuffer for CmykImage<'a> {
     }
 
     fn fill_buffers(&self, y: u16, buffers: &mut [Vec<u8>; 4]) {
-        let line = get_line(self.0, y, self.width(), 4);
+        let line = get_line(self.0, y, self.3, self.width(), 4);
 
         for pixel in line.chunks_exact(4) {
             buffers[0].push(255 - pixel[0]);
@@ -227,7 +661,7 @@ impl<'a> ImageBuffer for CmykImage<'a> {
     }
 }
 
-pub(crate) struct CmykAsYcckImage<'a>(pub &'a [u8], pub u16, pub u16);
+pub(crate) struct CmykAsYcckImage<'a>(pub &'a [u8], pub u16, pub u16, pub usize);
 
 impl<'a> ImageBuffer for CmykAsYcckImage<'a> {
     fn get_jpeg_color_type(&self) -> JpegColorType {
@@ -243,7 +677,7 @@ impl<'a> ImageBuffer for CmykAsYcckImage<'a> {
     }
 
     fn fill_buffers(&self, y: u16, buffers: &mut [Vec<u8>; 4]) {
-        let line = get_line(self.0, y, self.width(), 4);
+        let line = get_line(self.0, y, self.3, self.width(), 4);
 
         for pixel in line.chunks_exact(4) {
 
@@ -262,7 +696,7 @@ impl<'a> ImageBuffer for CmykAsYcckImage<'a> {
     }
 }
 
-pub(crate) struct YcckImage<'a>(pub &'a [u8], pub u16, pub u16);
+pub(crate) struct YcckImage<'a>(pub &'a [u8], pub u16, pub u16, pub usize);
 
 impl<'a> ImageBuffer for YcckImage<'a> {
     fn get_jpeg_color_type(&self) -> JpegColorType {
@@ -278,7 +712,7 @@ impl<'a> ImageBuffer for YcckImage<'a> {
     }
 
     fn fill_buffers(&self, y: u16, buffers: &mut [Vec<u8>; 4]) {
-        let line = get_line(self.0, y, self.width(), 4);
+        let line = get_line(self.0, y, self.3, self.width(), 4);
 
         for pixel in line.chunks_exact(4) {
 
@@ -292,7 +726,10 @@ impl<'a> ImageBuffer for YcckImage<'a> {
 
 #[cfg(test)]
 mod tests {
-    use crate::rgb_to_ycbcr;
+    use alloc::vec::Vec;
+
+    use super::{AbgrPremultipliedImage, ArgbImage, RgbImage, RgbaImage};
+    use crate::{rgb_to_ycbcr, AlphaPolicy, ColorMatrix, ImageBuffer};
 
     fn assert_rgb_to_ycbcr(rgb: [u8; 3], ycbcr: [u8; 3]) {
         let (y, cb, cr) = rgb_to_ycbcr(rgb[0], rgb[1], rgb[2]);
@@ -399,4 +836,194 @@ mod tests {
         assert_rgb_to_ycbcr([144, 193, 75], [165, 77, 113]);
         assert_rgb_to_ycbcr([49, 94, 1], [70, 89, 113]);
     }
+
+    #[test]
+    fn test_rgb_to_ycbcr_with_precision() {
+        let rgb_to_ycbcr_with_precision = |r, g, b, precision| {
+            ColorMatrix::Bt601Full
+                .coefficients()
+                .rgb_to_ycbcr_with_precision(r, g, b, precision)
+        };
+
+        for v in (0..=255).step_by(5) {
+            let (r, g, b) = (v, 255 - v, (v * 7) % 256);
+
+            let (y, cb, cr) = rgb_to_ycbcr(r as u8, g as u8, b as u8);
+            let ycbcr = rgb_to_ycbcr_with_precision(r, g, b, 8);
+
+            assert_eq!(ycbcr, (y.into(), cb.into(), cr.into()));
+        }
+
+        assert_eq!(rgb_to_ycbcr_with_precision(0, 0, 0, 12), (0, 2048, 2048));
+        assert_eq!(
+            rgb_to_ycbcr_with_precision(4095, 4095, 4095, 12),
+            (4095, 2048, 2048)
+        );
+        assert_eq!(
+            rgb_to_ycbcr_with_precision(0, 0, 4095, 12),
+            (467, 4095, 1715)
+        );
+    }
+
+    const MATRICES: [ColorMatrix; 6] = [
+        ColorMatrix::Bt601Full,
+        ColorMatrix::Bt601Limited,
+        ColorMatrix::Bt709Full,
+        ColorMatrix::Bt709Limited,
+        ColorMatrix::Bt2020Full,
+        ColorMatrix::Bt2020Limited,
+    ];
+
+    fn fill_buffers<I: ImageBuffer>(image: &I) -> [Vec<u8>; 4] {
+        let mut buffers: [Vec<u8>; 4] = Default::default();
+
+        for y in 0..image.height() {
+            image.fill_buffers(y, &mut buffers);
+        }
+
+        buffers
+    }
+
+    #[test]
+    fn test_color_matrix() {
+        let data: Vec<u8> = (0..19 * 3 * 4).map(|i| (i * 37 % 256) as u8).collect();
+
+        for matrix in MATRICES {
+            let buffers = fill_buffers(&RgbImage(&data, 19, 4, 19 * 3, matrix));
+
+            for (i, pixel) in data.chunks_exact(3).enumerate() {
+                let (y, cb, cr) = matrix.rgb_to_ycbcr(pixel[0], pixel[1], pixel[2]);
+                assert_eq!([buffers[0][i], buffers[1][i], buffers[2][i]], [y, cb, cr]);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "simd", any(target_arch = "x86", target_arch = "x86_64")))]
+    fn test_color_matrix_avx2() {
+        use super::BgraImage;
+        use crate::avx2::{BgraImageAVX2, RgbImageAVX2};
+
+        if !std::is_x86_feature_detected!("avx2") {
+            return;
+        }
+
+        // Widths which aren't a multiple of 8 are partially converted without AVX2
+        let data: Vec<u8> = (0..21 * 4 * 4).map(|i| (i * 37 % 256) as u8).collect();
+
+        let alpha = AlphaPolicy::Ignore;
+
+        for matrix in MATRICES {
+            assert_eq!(
+                fill_buffers(&RgbImageAVX2(&data, 21, 5, 21 * 3, matrix)),
+                fill_buffers(&RgbImage(&data, 21, 5, 21 * 3, matrix))
+            );
+            assert_eq!(
+                fill_buffers(&BgraImageAVX2(&data, 21, 4, 21 * 4, matrix, alpha)),
+                fill_buffers(&BgraImage(&data, 21, 4, 21 * 4, matrix, alpha))
+            );
+        }
+    }
+
+    const ALPHA_POLICIES: [AlphaPolicy; 3] = [
+        AlphaPolicy::Ignore,
+        AlphaPolicy::Composite([255, 128, 0]),
+        AlphaPolicy::Unpremultiply,
+    ];
+
+    #[test]
+    fn test_alpha_policy() {
+        let data: Vec<u8> = (0..19 * 4 * 4).map(|i| (i * 37 % 256) as u8).collect();
+
+        for policy in ALPHA_POLICIES {
+            let matrix = ColorMatrix::Bt601Full;
+            let buffers = fill_buffers(&RgbaImage(&data, 19, 4, 19 * 4, matrix, policy));
+
+            for (i, pixel) in data.chunks_exact(4).enumerate() {
+                let (r, g, b) = policy.apply(pixel[0], pixel[1], pixel[2], pixel[3]);
+                let (y, cb, cr) = rgb_to_ycbcr(r, g, b);
+                assert_eq!([buffers[0][i], buffers[1][i], buffers[2][i]], [y, cb, cr]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_alpha_layouts() {
+        let rgba: Vec<u8> = (0..19 * 4 * 4).map(|i| (i * 37 % 256) as u8).collect();
+
+        let argb: Vec<u8> = rgba
+            .chunks_exact(4)
+            .flat_map(|p| [p[3], p[0], p[1], p[2]])
+            .collect();
+        let abgr: Vec<u8> = rgba
+            .chunks_exact(4)
+            .flat_map(|p| [p[3], p[2], p[1], p[0]])
+            .collect();
+
+        let matrix = ColorMatrix::Bt601Full;
+
+        for policy in ALPHA_POLICIES {
+            assert_eq!(
+                fill_buffers(&ArgbImage(&argb, 19, 4, 19 * 4, matrix, policy)),
+                fill_buffers(&RgbaImage(&rgba, 19, 4, 19 * 4, matrix, policy))
+            );
+
+            let image = AbgrPremultipliedImage(&abgr, 19, 4, 19 * 4, matrix, policy);
+            let buffers = fill_buffers(&image);
+
+            for (i, pixel) in rgba.chunks_exact(4).enumerate() {
+                let (r, g, b) = policy.apply_premultiplied(pixel[0], pixel[1], pixel[2], pixel[3]);
+                let (y, cb, cr) = rgb_to_ycbcr(r, g, b);
+                assert_eq!([buffers[0][i], buffers[1][i], buffers[2][i]], [y, cb, cr]);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "simd", any(target_arch = "x86", target_arch = "x86_64")))]
+    fn test_alpha_policy_avx2() {
+        use super::{AbgrImage, ArgbPremultipliedImage, BgraImage, RgbaPremultipliedImage};
+        use crate::avx2::{
+            AbgrImageAVX2, ArgbPremultipliedImageAVX2, BgraImageAVX2, RgbaImageAVX2,
+            RgbaPremultipliedImageAVX2,
+        };
+
+        if !std::is_x86_feature_detected!("avx2") {
+            return;
+        }
+
+        // Contains all combinations of color values and alpha
+        let data: Vec<u8> = (0..256 * 256)
+            .flat_map(|i| {
+                let alpha = (i / 256) as u8;
+                [(i % 256) as u8, alpha, (i * 7 % 256) as u8, alpha]
+            })
+            .collect();
+
+        for policy in ALPHA_POLICIES {
+            let matrix = ColorMatrix::Bt601Full;
+
+            assert_eq!(
+                fill_buffers(&RgbaImageAVX2(&data, 1021, 64, 1021 * 4, matrix, policy)),
+                fill_buffers(&RgbaImage(&data, 1021, 64, 1021 * 4, matrix, policy))
+            );
+            assert_eq!(
+                fill_buffers(&BgraImageAVX2(&data, 21, 4, 21 * 4, matrix, policy)),
+                fill_buffers(&BgraImage(&data, 21, 4, 21 * 4, matrix, policy))
+            );
+            assert_eq!(
+                fill_buffers(&AbgrImageAVX2(&data, 21, 4, 21 * 4, matrix, policy)),
+                fill_buffers(&AbgrImage(&data, 21, 4, 21 * 4, matrix, policy))
+            );
+
+            // Includes invalid premultiplied values which are larger than alpha
+            let avx2 = RgbaPremultipliedImageAVX2(&data, 1021, 64, 1021 * 4, matrix, policy);
+            let scalar = RgbaPremultipliedImage(&data, 1021, 64, 1021 * 4, matrix, policy);
+            assert_eq!(fill_buffers(&avx2), fill_buffers(&scalar));
+
+            let avx2 = ArgbPremultipliedImageAVX2(&data, 21, 4, 21 * 4, matrix, policy);
+            let scalar = ArgbPremultipliedImage(&data, 21, 4, 21 * 4, matrix, policy);
+            assert_eq!(fill_buffers(&avx2), fill_buffers(&scalar));
+        }
+    }
 }
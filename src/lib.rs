@@ -31,32 +31,78 @@ extern crate std;
 extern crate alloc;
 extern crate core;
 
+mod alpha;
+#[cfg(feature = "arithmetic")]
+mod arithmetic;
 #[cfg(all(feature = "simd", any(target_arch = "x86", target_arch = "x86_64")))]
 mod avx2;
+#[cfg(test)]
+mod coefficient_decoder;
+mod color_matrix;
+mod dithering;
+mod downsampling;
 mod encoder;
 mod error;
 mod fdct;
+mod hierarchical;
 mod huffman;
+mod idct;
 mod image_buffer;
+#[cfg(feature = "std")]
+mod linear_light;
+mod lossless;
 mod marker;
+#[cfg(feature = "std")]
+mod metrics;
+mod parallel;
 mod quantization;
+mod scan_script;
+mod sharp_yuv;
+mod streaming;
+mod trellis;
 mod writer;
-
+mod yuv;
+
+pub use alpha::AlphaPolicy;
+#[cfg(feature = "arithmetic")]
+pub use arithmetic::ArithmeticConditioning;
+pub use color_matrix::ColorMatrix;
+pub use dithering::Dithering;
+pub use downsampling::DownsamplingFilter;
 pub use encoder::{ColorType, Encoder, JpegColorType, SamplingFactor};
 pub use error::EncodingError;
+pub use idct::idct;
 pub use image_buffer::{cmyk_to_ycck, rgb_to_ycbcr, ImageBuffer};
+#[cfg(feature = "std")]
+pub use linear_light::TransferFunction;
+pub use lossless::Predictor;
+#[cfg(feature = "std")]
+pub use metrics::QualityTarget;
 pub use quantization::QuantizationTableType;
+pub use scan_script::{ScanInfo, ScanScript};
+pub use streaming::StreamingEncoder;
 pub use writer::{Density, JfifWrite};
+pub use yuv::YuvFormat;
 
 #[cfg(feature = "benchmark")]
 pub use fdct::fdct;
 #[cfg(all(feature = "benchmark", feature = "simd", any(target_arch = "x86", target_arch = "x86_64")))]
-pub use avx2::fdct_avx2;
+pub use avx2::{fdct_avx2, idct_avx2};
 
 #[cfg(test)]
 mod tests {
+    use crate::hierarchical::Plane;
     use crate::image_buffer::rgb_to_ycbcr;
-    use crate::{ColorType, Encoder, QuantizationTableType, SamplingFactor};
+    #[cfg(feature = "std")]
+    use crate::{
+        metrics::{get_psnr, get_ssim},
+        QualityTarget,
+    };
+    use crate::{
+        AlphaPolicy, ColorMatrix, ColorType, Dithering, DownsamplingFilter, Encoder, EncodingError,
+        JpegColorType, Predictor, QuantizationTableType, SamplingFactor, ScanInfo, ScanScript,
+        YuvFormat,
+    };
     use jpeg_decoder::{Decoder, ImageInfo, PixelFormat};
 
     use alloc::boxed::Box;
@@ -144,6 +190,7 @@ mod tests {
         (decoder.decode().unwrap(), decoder.info().unwrap())
     }
 
+    #[allow(clippy::ptr_arg, clippy::needless_borrow)]
     fn check_result(
         data: Vec<u8>,
         width: u16,
@@ -348,6 +395,63 @@ mod tests {
         check_result(data, width, height, &mut result, PixelFormat::RGB24);
     }
 
+    #[test]
+    fn test_rgb_trellis() {
+        let (data, width, height) = create_test_img_rgb();
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 90);
+        encoder.set_trellis_quantization(true);
+
+        encoder
+            .encode(&data, width, height, ColorType::Rgb)
+            .unwrap();
+
+        let mut reference = Vec::new();
+        let encoder = Encoder::new(&mut reference, 90);
+
+        encoder
+            .encode(&data, width, height, ColorType::Rgb)
+            .unwrap();
+
+        assert!(result.len() < reference.len());
+
+        check_result(data, width, height, &mut result, PixelFormat::RGB24);
+    }
+
+    #[test]
+    fn test_rgb_trellis_optimized() {
+        let (data, width, height) = create_test_img_rgb();
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 90);
+        encoder.set_sampling_factor(SamplingFactor::F_2_2);
+        encoder.set_optimized_huffman_tables(true);
+        encoder.set_trellis_quantization(true);
+
+        encoder
+            .encode(&data, width, height, ColorType::Rgb)
+            .unwrap();
+
+        check_result(data, width, height, &mut result, PixelFormat::RGB24);
+    }
+
+    #[test]
+    fn test_rgb_trellis_progressive() {
+        let (data, width, height) = create_test_img_rgb();
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 90);
+        encoder.set_progressive(true);
+        encoder.set_trellis_quantization(true);
+
+        encoder
+            .encode(&data, width, height, ColorType::Rgb)
+            .unwrap();
+
+        check_result(data, width, height, &mut result, PixelFormat::RGB24);
+    }
+
     #[test]
     fn test_rgb_optimized_progressive() {
         let (data, width, height) = create_test_img_rgb();
@@ -365,6 +469,190 @@ mod tests {
         check_result(data, width, height, &mut result, PixelFormat::RGB24);
     }
 
+    #[test]
+    fn test_rgb_successive_approximation() {
+        let (data, width, height) = create_test_img_rgb();
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 100);
+        encoder.set_sampling_factor(SamplingFactor::F_2_2);
+        encoder.set_progressive(true);
+        encoder.set_successive_approximation(true);
+
+        encoder
+            .encode(&data, width, height, ColorType::Rgb)
+            .unwrap();
+
+        check_result(data, width, height, &mut result, PixelFormat::RGB24);
+    }
+
+    #[test]
+    fn test_rgb_optimized_successive_approximation() {
+        let (data, width, height) = create_test_img_rgb();
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 80);
+        encoder.set_progressive_scans(6);
+        encoder.set_successive_approximation(true);
+        encoder.set_optimized_huffman_tables(true);
+        encoder.set_restart_interval(7);
+
+        encoder
+            .encode(&data, width, height, ColorType::Rgb)
+            .unwrap();
+
+        check_result(data, width, height, &mut result, PixelFormat::RGB24);
+    }
+
+    #[test]
+    fn test_gray_successive_approximation() {
+        let (data, width, height) = create_test_img_gray();
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 90);
+        encoder.set_progressive(true);
+        encoder.set_successive_approximation(true);
+        encoder.set_restart_interval(32);
+
+        encoder
+            .encode(&data, width, height, ColorType::Luma)
+            .unwrap();
+
+        check_result(data, width, height, &mut result, PixelFormat::L8);
+    }
+
+    #[test]
+    fn test_rgb_scan_script() {
+        let (data, width, height) = create_test_img_rgb();
+
+        let script = ScanScript::from(vec![
+            ScanInfo::new(&[0], 0, 0, 0, 1),
+            ScanInfo::new(&[1], 0, 0, 0, 0),
+            ScanInfo::new(&[2], 0, 0, 0, 0),
+            ScanInfo::new(&[0], 1, 5, 0, 2),
+            ScanInfo::new(&[2], 1, 63, 0, 0),
+            ScanInfo::new(&[1], 1, 63, 0, 0),
+            ScanInfo::new(&[0], 6, 63, 0, 2),
+            ScanInfo::new(&[0], 1, 63, 2, 1),
+            ScanInfo::new(&[0], 0, 0, 1, 0),
+            ScanInfo::new(&[0], 1, 63, 1, 0),
+        ]);
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 90);
+        encoder.set_sampling_factor(SamplingFactor::F_2_2);
+        encoder.set_scan_script(script);
+
+        encoder
+            .encode(&data, width, height, ColorType::Rgb)
+            .unwrap();
+
+        check_result(data, width, height, &mut result, PixelFormat::RGB24);
+    }
+
+    #[test]
+    fn test_rgb_interleaved_dc_scan() {
+        let (data, width, height) = create_test_img_rgb();
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 90);
+        encoder.set_sampling_factor(SamplingFactor::F_2_1);
+        encoder.set_progressive(true);
+        encoder.set_successive_approximation(true);
+        encoder.set_restart_interval(5);
+
+        encoder
+            .encode(&data, width, height, ColorType::Rgb)
+            .unwrap();
+
+        check_result(data, width, height, &mut result, PixelFormat::RGB24);
+    }
+
+    #[test]
+    fn test_rgb_non_interleaved_dc_scan() {
+        let (data, width, height) = create_test_img_rgb();
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 90);
+        encoder.set_sampling_factor(SamplingFactor::F_2_2);
+        encoder.set_progressive(true);
+        encoder.set_interleaved_dc_scan(false);
+        encoder.set_restart_interval(5);
+
+        encoder
+            .encode(&data, width, height, ColorType::Rgb)
+            .unwrap();
+
+        check_result(data, width, height, &mut result, PixelFormat::RGB24);
+    }
+
+    #[test]
+    fn test_cmyk_interleaved_dc_scan() {
+        let (data, width, height) = create_test_img_cmyk();
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 100);
+        encoder.set_progressive(true);
+        encoder.set_restart_interval(9);
+
+        encoder
+            .encode(&data, width, height, ColorType::Cmyk)
+            .unwrap();
+
+        check_result(data, width, height, &mut result, PixelFormat::CMYK32);
+    }
+
+    #[test]
+    fn test_invalid_scan_script() {
+        let (data, width, height) = create_test_img_rgb();
+
+        // Missing scans for the chroma components
+        let script = ScanScript::from(vec![
+            ScanInfo::new(&[0], 0, 0, 0, 0),
+            ScanInfo::new(&[0], 1, 63, 0, 0),
+        ]);
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 90);
+        encoder.set_scan_script(script);
+
+        let res = encoder.encode(&data, width, height, ColorType::Rgb);
+
+        assert!(matches!(
+            res,
+            Err(EncodingError::InvalidScanScript { scan: None, .. })
+        ));
+        assert!(result.is_empty());
+    }
+
+    #[test]
+    fn test_progressive_eobrun() {
+        // Mostly flat image with long runs of empty blocks in the AC scans
+        let width = 512;
+        let height = 256;
+
+        let mut data = vec![128u8; width * height];
+
+        for y in 100..120 {
+            for x in 300..340 {
+                data[y * width + x] = (x + y) as u8;
+            }
+        }
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 90);
+        encoder.set_progressive_scans(8);
+        encoder.set_restart_interval(100);
+
+        encoder
+            .encode(&data, width as u16, height as u16, ColorType::Luma)
+            .unwrap();
+
+        assert!(result.len() < 4096, "Unexpected file size: {}", result.len());
+
+        check_result(data, width as u16, height as u16, &mut result, PixelFormat::L8);
+    }
+
     #[test]
     fn test_cmyk() {
         let (data, width, height) = create_test_img_cmyk();
@@ -528,4 +816,2140 @@ mod tests {
 
         check_result(data, 1, 1, &mut result, PixelFormat::RGB24);
     }
+
+    // Returns the markers of a JPEG file without the restart markers
+    // and the number of restart markers
+    fn get_markers(data: &[u8]) -> (Vec<u8>, usize) {
+        let mut markers = Vec::new();
+        let mut restarts = 0;
+
+        let mut i = 0;
+        while i + 1 < data.len() {
+            if data[i] != 0xFF || data[i + 1] == 0 || data[i + 1] == 0xFF {
+                i += 1;
+                continue;
+            }
+
+            let marker = data[i + 1];
+            i += 2;
+
+            match marker {
+                0xD0..=0xD7 => restarts += 1,
+                0xD8 | 0xD9 => markers.push(marker),
+                _ => {
+                    markers.push(marker);
+                    i += usize::from(u16::from_be_bytes([data[i], data[i + 1]]));
+                }
+            }
+        }
+
+        (markers, restarts)
+    }
+
+    // Returns the data of the first segment with the given marker
+    fn get_segment(data: &[u8], marker: u8) -> Option<&[u8]> {
+        let start = data.windows(2).position(|m| m == [0xFF, marker])? + 2;
+        let length = usize::from(u16::from_be_bytes([data[start], data[start + 1]]));
+
+        Some(&data[start + 2..start + length])
+    }
+
+    #[test]
+    #[cfg(feature = "arithmetic")]
+    fn test_arithmetic_sequential() {
+        let (data, width, height) = create_test_img_rgb();
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 90);
+        encoder.set_sampling_factor(SamplingFactor::F_2_2);
+        encoder.set_arithmetic_coding(true);
+        encoder.set_restart_interval(4);
+
+        encoder
+            .encode(&data, width, height, ColorType::Rgb)
+            .unwrap();
+
+        let (markers, restarts) = get_markers(&result);
+
+        assert!(markers.contains(&0xC9), "Missing SOF9 marker");
+        assert!(markers.contains(&0xCC), "Missing DAC marker");
+        assert!(!markers.contains(&0xC4), "Unexpected DHT marker");
+        assert_eq!(markers.iter().filter(|&&m| m == 0xDA).count(), 1);
+
+        // 17x8 MCUs with a restart every 4 MCUs
+        assert_eq!(restarts, 33);
+
+        let mut huffman_result = Vec::new();
+        let mut encoder = Encoder::new(&mut huffman_result, 90);
+        encoder.set_sampling_factor(SamplingFactor::F_2_2);
+        encoder.set_optimized_huffman_tables(true);
+        encoder.set_restart_interval(4);
+
+        encoder
+            .encode(&data, width, height, ColorType::Rgb)
+            .unwrap();
+
+        assert!(result.len() < huffman_result.len());
+    }
+
+    #[test]
+    #[cfg(feature = "arithmetic")]
+    fn test_arithmetic_progressive() {
+        let (data, width, height) = create_test_img_rgb();
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 90);
+        encoder.set_sampling_factor(SamplingFactor::F_2_1);
+        encoder.set_progressive(true);
+        encoder.set_successive_approximation(true);
+        encoder.set_arithmetic_coding(true);
+
+        encoder
+            .encode(&data, width, height, ColorType::Rgb)
+            .unwrap();
+
+        let (markers, restarts) = get_markers(&result);
+
+        assert!(markers.contains(&0xCA), "Missing SOF10 marker");
+        assert!(markers.contains(&0xCC), "Missing DAC marker");
+        assert!(!markers.contains(&0xC4), "Unexpected DHT marker");
+        assert_eq!(restarts, 0);
+
+        // Interleaved DC scan, 3 AC scans per component and the refinement scans
+        assert_eq!(markers.iter().filter(|&&m| m == 0xDA).count(), 14);
+    }
+
+    /// Returns quantized blocks in natural order with the given number of blocks per component
+    ///
+    /// The blocks contain runs of zeros, small and large values up to the limits of 8 bit
+    /// images and the largest possible DC differences.
+    fn create_test_coefficients(num_blocks: &[usize]) -> Vec<Vec<[i16; 64]>> {
+        let mut state = 0x2545_F491u32;
+        let mut random = move |max: u32| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state % max
+        };
+
+        num_blocks
+            .iter()
+            .map(|&num_blocks| {
+                (0..num_blocks)
+                    .map(|i| {
+                        let mut block = [0i16; 64];
+
+                        block[0] = match i % 4 {
+                            0 => -1024,
+                            1 => 1023,
+                            _ => random(2048) as i16 - 1024,
+                        };
+
+                        // Some blocks only have a DC coefficient
+                        if i % 5 == 3 {
+                            return block;
+                        }
+
+                        let density = 1 + random(8);
+
+                        for (k, value) in block.iter_mut().enumerate().skip(1) {
+                            if random(8) >= density {
+                                continue;
+                            }
+
+                            let magnitude = match random(16) {
+                                0 => 1 + random(1023),
+                                1..=4 => 1 + random(31),
+                                _ => 1 + random(3),
+                            } as i16;
+
+                            *value = if (k + i) % 3 == 0 {
+                                -magnitude
+                            } else {
+                                magnitude
+                            };
+                        }
+
+                        if i % 7 == 0 {
+                            block[63] = -1023;
+                        }
+
+                        block
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Encodes test coefficients and checks that they are decoded unchanged
+    ///
+    /// Returns the SOF marker of the encoded frame.
+    fn check_coefficients_roundtrip(
+        sampling_factor: SamplingFactor,
+        configure: &dyn Fn(&mut Encoder<&mut Vec<u8>>),
+    ) -> u8 {
+        use crate::coefficient_decoder::decode_coefficients;
+
+        let width = 45;
+        let height = 37;
+
+        let mut luma_table = [0u16; 64];
+        let mut chroma_table = [0u16; 64];
+        for i in 0..64 {
+            luma_table[i] = 1 + i as u16;
+            chroma_table[i] = 64 - i as u16;
+        }
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 80);
+        encoder.set_sampling_factor(sampling_factor);
+        encoder.set_quantization_tables(
+            QuantizationTableType::Custom(Box::new(luma_table)),
+            QuantizationTableType::Custom(Box::new(chroma_table)),
+        );
+        configure(&mut encoder);
+
+        let progressive = encoder.is_progressive();
+
+        let (h, v) = sampling_factor.get_sampling_factors();
+        let cols = (usize::from(width) + 7) / 8;
+        let rows = (usize::from(height) + 7) / 8;
+        let chroma_cols = (cols + usize::from(h) - 1) / usize::from(h);
+        let chroma_rows = (rows + usize::from(v) - 1) / usize::from(v);
+
+        let coefficients = create_test_coefficients(&[
+            cols * rows,
+            chroma_cols * chroma_rows,
+            chroma_cols * chroma_rows,
+        ]);
+
+        encoder
+            .encode_coefficients(&coefficients, width, height, JpegColorType::Ycbcr)
+            .unwrap();
+
+        let frames = decode_coefficients(&result);
+        assert_eq!(frames.len(), 1);
+
+        let frame = &frames[0];
+        assert_eq!(frame.marker & 0x03 == 2, progressive);
+        assert_eq!(frame.precision, 8);
+        assert_eq!((frame.width, frame.height), (width, height));
+        assert_eq!(frame.components.len(), 3);
+
+        for (i, component) in frame.components.iter().enumerate() {
+            assert_eq!(usize::from(component.id), i);
+        }
+
+        let luma = &frame.components[0];
+        assert_eq!(luma.horizontal_sampling_factor, h);
+        assert_eq!(luma.vertical_sampling_factor, v);
+        assert_eq!(luma.quantization_table, luma_table);
+        assert_eq!((luma.cols, luma.rows), (cols, rows));
+
+        for chroma in &frame.components[1..] {
+            assert_eq!(chroma.horizontal_sampling_factor, 1);
+            assert_eq!(chroma.vertical_sampling_factor, 1);
+            assert_eq!(chroma.quantization_table, chroma_table);
+            assert_eq!((chroma.cols, chroma.rows), (chroma_cols, chroma_rows));
+        }
+
+        for (component, expected) in frame.components.iter().zip(&coefficients) {
+            assert_eq!(component.blocks.len(), expected.len());
+
+            for (block, expected) in component.blocks.iter().zip(expected) {
+                assert_eq!(block, &expected.map(i32::from));
+            }
+        }
+
+        frame.marker
+    }
+
+    /// Checks the roundtrip of coefficients for sequential and progressive scans
+    ///
+    /// `coding` selects the entropy coding, which must use `markers` for sequential
+    /// and progressive frames.
+    fn check_scans_roundtrip(coding: &dyn Fn(&mut Encoder<&mut Vec<u8>>), markers: (u8, u8)) {
+        let check = |sampling_factor, configure: &dyn Fn(&mut Encoder<&mut Vec<u8>>)| {
+            let marker = check_coefficients_roundtrip(sampling_factor, &|encoder| {
+                coding(encoder);
+                configure(encoder);
+            });
+
+            assert!(marker == markers.0 || marker == markers.1);
+        };
+
+        for sampling_factor in [
+            SamplingFactor::F_1_1,
+            SamplingFactor::F_2_2,
+            SamplingFactor::F_4_1,
+        ] {
+            // Sequential with and without restart intervals
+            check(sampling_factor, &|_| {});
+            check(sampling_factor, &|encoder| encoder.set_restart_interval(3));
+
+            // DC first, AC first and refinement scans
+            check(sampling_factor, &|encoder| {
+                encoder.set_progressive(true);
+                encoder.set_successive_approximation(true);
+            });
+            check(sampling_factor, &|encoder| {
+                encoder.set_progressive(true);
+                encoder.set_successive_approximation(true);
+                encoder.set_restart_interval(2);
+            });
+        }
+
+        // Multiple refinement steps with restart intervals
+        let mut script = ScanScript::new();
+        script.push(ScanInfo::new(&[0, 1, 2], 0, 0, 0, 3));
+        for component in 0..3 {
+            script.push(ScanInfo::new(&[component], 1, 9, 0, 4));
+            script.push(ScanInfo::new(&[component], 10, 63, 0, 2));
+        }
+        script.push(ScanInfo::new(&[0, 1, 2], 0, 0, 3, 2));
+        script.push(ScanInfo::new(&[0, 1, 2], 0, 0, 2, 1));
+        for component in 0..3 {
+            for al in (0..4).rev() {
+                script.push(ScanInfo::new(&[component], 1, 9, al + 1, al));
+            }
+            script.push(ScanInfo::new(&[component], 10, 63, 2, 1));
+            script.push(ScanInfo::new(&[component], 10, 63, 1, 0));
+        }
+        script.push(ScanInfo::new(&[0, 1, 2], 0, 0, 1, 0));
+
+        check(SamplingFactor::F_2_1, &|encoder| {
+            encoder.set_scan_script(script.clone());
+            encoder.set_restart_interval(5);
+        });
+    }
+
+    #[test]
+    fn test_huffman_roundtrip() {
+        check_scans_roundtrip(&|_| {}, (0xC0, 0xC2));
+
+        check_scans_roundtrip(
+            &|encoder| encoder.set_optimized_huffman_tables(true),
+            (0xC0, 0xC2),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "arithmetic")]
+    fn test_arithmetic_roundtrip() {
+        use crate::ArithmeticConditioning;
+
+        check_scans_roundtrip(&|encoder| encoder.set_arithmetic_coding(true), (0xC9, 0xCA));
+
+        // Conditioning with other bins for the DC and AC magnitude categories
+        for (luma, chroma) in [((0, 0, 1), (15, 15, 63)), ((2, 6, 10), (1, 3, 30))] {
+            let luma = ArithmeticConditioning::new(luma.0, luma.1, luma.2).unwrap();
+            let chroma = ArithmeticConditioning::new(chroma.0, chroma.1, chroma.2).unwrap();
+
+            for progressive in [false, true] {
+                let marker = check_coefficients_roundtrip(SamplingFactor::F_2_2, &|encoder| {
+                    encoder.set_arithmetic_coding(true);
+                    encoder.set_arithmetic_conditioning(luma, chroma);
+                    encoder.set_progressive(progressive);
+                    encoder.set_successive_approximation(true);
+                });
+
+                assert_eq!(marker, if progressive { 0xCA } else { 0xC9 });
+            }
+        }
+    }
+
+    fn create_test_img_gray16() -> (Vec<u8>, u16, u16) {
+        let width = 258;
+        let height = 128;
+
+        let mut data = Vec::with_capacity(width * height * 2);
+
+        for y in 0..height {
+            for x in 0..width {
+                let value = (x * 251 + y * 509 + (x * y) % 97) as u16;
+                data.extend_from_slice(&value.to_ne_bytes());
+            }
+        }
+
+        (data, width as u16, height as u16)
+    }
+
+    #[test]
+    fn test_lossless_gray() {
+        use Predictor::*;
+
+        let (data, width, height) = create_test_img_gray();
+
+        for predictor in [
+            Ra,
+            Rb,
+            Rc,
+            RaPlusRbMinusRc,
+            RaPlusHalfRbMinusRc,
+            RbPlusHalfRaMinusRc,
+            AverageRaRb,
+        ] {
+            let mut result = Vec::new();
+            let mut encoder = Encoder::new(&mut result, 80);
+            encoder.set_lossless(Some(predictor));
+
+            encoder
+                .encode(&data, width, height, ColorType::Luma)
+                .unwrap();
+
+            let (img, info) = decode(&result);
+
+            assert_eq!(info.pixel_format, PixelFormat::L8);
+            assert_eq!(img, data);
+        }
+    }
+
+    #[test]
+    fn test_lossless_rgb() {
+        let (data, width, height) = create_test_img_rgb();
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 80);
+        encoder.set_lossless(Some(Predictor::RaPlusHalfRbMinusRc));
+
+        encoder
+            .encode(&data, width, height, ColorType::Rgb)
+            .unwrap();
+
+        let (img, info) = decode(&result);
+
+        assert_eq!(info.pixel_format, PixelFormat::RGB24);
+        assert_eq!(img, data);
+    }
+
+    #[test]
+    fn test_lossless_gray16() {
+        let (data, width, height) = create_test_img_gray16();
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 80);
+        encoder.set_lossless(Some(Predictor::RaPlusRbMinusRc));
+        encoder.set_sample_precision(16);
+
+        encoder
+            .encode(&data, width, height, ColorType::Luma16)
+            .unwrap();
+
+        let (img, info) = decode(&result);
+
+        assert_eq!(info.pixel_format, PixelFormat::L16);
+        assert_eq!(img, data);
+    }
+
+    #[test]
+    fn test_lossless_invalid_settings() {
+        let (data, width, height) = create_test_img_gray();
+
+        let mut encoder = Encoder::new(Vec::new(), 80);
+        encoder.set_lossless(Some(Predictor::Ra));
+        encoder.set_sample_precision(17);
+
+        assert!(matches!(
+            encoder.encode(&data, width, height, ColorType::Luma),
+            Err(EncodingError::UnsupportedSamplePrecision(17))
+        ));
+
+        let mut encoder = Encoder::new(Vec::new(), 80);
+        encoder.set_lossless(Some(Predictor::Ra));
+        encoder.set_sample_precision(12);
+        encoder.set_point_transform(12);
+
+        assert!(matches!(
+            encoder.encode(&data, width, height, ColorType::Luma),
+            Err(EncodingError::InvalidPointTransform {
+                point_transform: 12,
+                precision: 12
+            })
+        ));
+
+        let mut encoder = Encoder::new(Vec::new(), 80);
+        encoder.set_sample_precision(10);
+
+        assert!(matches!(
+            encoder.encode(&data, width, height, ColorType::Luma),
+            Err(EncodingError::UnsupportedSamplePrecision(10))
+        ));
+    }
+
+    #[test]
+    fn test_gray16() {
+        let (data, width, height) = create_test_img_gray16();
+
+        let mut result = Vec::new();
+        let encoder = Encoder::new(&mut result, 100);
+
+        encoder
+            .encode(&data, width, height, ColorType::Luma16)
+            .unwrap();
+
+        let data = data
+            .chunks_exact(2)
+            .map(|v| (u16::from_ne_bytes([v[0], v[1]]) >> 8) as u8)
+            .collect();
+
+        check_result(data, width, height, &mut result, PixelFormat::L8);
+    }
+
+    #[test]
+    fn test_rgba16_and_rgb_f32() {
+        let width = 67;
+        let height = 19;
+
+        let rgb: Vec<u16> = (0..width * height * 3)
+            .map(|i| (i * 4099 % 65536) as u16)
+            .collect();
+
+        let encode = |data: &[u8], color_type, policy| {
+            let mut result = Vec::new();
+            let mut encoder = Encoder::new(&mut result, 95);
+            encoder.set_alpha_policy(policy);
+            encoder
+                .encode(data, width as u16, height as u16, color_type)
+                .unwrap();
+            result
+        };
+
+        let rgb16: Vec<u8> = rgb.iter().flat_map(|v| v.to_ne_bytes()).collect();
+        let expected = encode(&rgb16, ColorType::Rgb16, AlphaPolicy::Ignore);
+
+        let rgb_f32: Vec<u8> = rgb
+            .iter()
+            .flat_map(|&v| (f32::from(v) / 65535.0).to_ne_bytes())
+            .collect();
+        assert_eq!(
+            encode(&rgb_f32, ColorType::RgbF32, AlphaPolicy::Ignore),
+            expected
+        );
+
+        let rgba16: Vec<u8> = rgb
+            .chunks_exact(3)
+            .flat_map(|p| [p[0], p[1], p[2], u16::MAX])
+            .flat_map(|v| v.to_ne_bytes())
+            .collect();
+        assert_eq!(
+            encode(&rgba16, ColorType::Rgba16, AlphaPolicy::Ignore),
+            expected
+        );
+
+        // Values outside of 0.0..=1.0 are clamped
+        for (pixel, expected) in [
+            ([-1.0f32, 0.5, 2.0], [0u8, 128, 255]),
+            ([f32::NAN, 1.0, 0.0], [0, 255, 0]),
+        ] {
+            let data: Vec<u8> = pixel
+                .repeat(width * height)
+                .iter()
+                .flat_map(|v| v.to_ne_bytes())
+                .collect();
+
+            let result = encode(&data, ColorType::RgbF32, AlphaPolicy::Ignore);
+            let (decoded, _) = decode(&result);
+
+            for pixel in decoded.chunks_exact(3) {
+                for (&v1, v2) in pixel.iter().zip(expected) {
+                    assert!((i16::from(v1) - i16::from(v2)).abs() < 4);
+                }
+            }
+        }
+
+        // Transparent pixels are composited onto the background
+        let transparent: Vec<u8> = [0u16, 0, 0, 0]
+            .repeat(width * height)
+            .iter()
+            .flat_map(|v| v.to_ne_bytes())
+            .collect();
+
+        let result = encode(
+            &transparent,
+            ColorType::Rgba16,
+            AlphaPolicy::Composite([255, 255, 255]),
+        );
+
+        let (decoded, _) = decode(&result);
+        assert!(decoded.iter().all(|&v| v >= 254));
+    }
+
+    /// Returns a 16 bit gray image with a gradient between two 8 bit values and the
+    /// gradient as 8 bit values without rounding
+    fn create_test_img_gray16_gradient() -> (Vec<u8>, Vec<f32>, u16, u16) {
+        let width = 256;
+        let height = 64;
+
+        let values: Vec<f32> = (0..width * height)
+            .map(|i| 100.0 + (i % width) as f32 / width as f32)
+            .collect();
+
+        let data = values
+            .iter()
+            .flat_map(|&v| ((v * 257.0).round() as u16).to_ne_bytes())
+            .collect();
+
+        (data, values, width as u16, height as u16)
+    }
+
+    /// Returns the mean squared error of the averages of all 8x8 blocks
+    fn get_block_average_error(decoded: &[u8], values: &[f32], width: usize) -> f32 {
+        let mut sum = 0.0;
+        let mut count = 0;
+
+        for block_y in (0..values.len() / width).step_by(8) {
+            for block_x in (0..width).step_by(8) {
+                let mut diff = 0.0;
+
+                for y in block_y..block_y + 8 {
+                    for x in block_x..block_x + 8 {
+                        diff += f32::from(decoded[y * width + x]) - values[y * width + x];
+                    }
+                }
+
+                sum += (diff / 64.0) * (diff / 64.0);
+                count += 1;
+            }
+        }
+
+        sum / count as f32
+    }
+
+    #[test]
+    fn test_dithering() {
+        let (data, values, width, height) = create_test_img_gray16_gradient();
+
+        let encode = |dithering| {
+            let mut result = Vec::new();
+            let mut encoder = Encoder::new(&mut result, 100);
+            encoder.set_dithering(dithering);
+            assert_eq!(encoder.dithering(), dithering);
+
+            encoder
+                .encode(&data, width, height, ColorType::Luma16)
+                .unwrap();
+
+            result
+        };
+
+        let get_error = |result: &[u8]| {
+            let (decoded, _) = decode(result);
+            get_block_average_error(&decoded, &values, usize::from(width))
+        };
+
+        let rounded = get_error(&encode(Dithering::Round));
+        let ordered = encode(Dithering::Ordered);
+
+        // Dithering keeps the average brightness of areas with fractional values
+        assert!(get_error(&ordered) < rounded / 4.0);
+        assert!(get_error(&encode(Dithering::ErrorDiffusion)) < rounded / 4.0);
+
+        // The dither pattern continues across rows written separately
+        let mut streamed = Vec::new();
+        let mut encoder = Encoder::new(&mut streamed, 100);
+        encoder.set_dithering(Dithering::Ordered);
+
+        let mut streaming = encoder
+            .into_streaming(width, height, ColorType::Luma16)
+            .unwrap();
+
+        for rows in data.chunks(usize::from(width) * 2 * 3) {
+            streaming.write_rows(rows).unwrap();
+        }
+        streaming.finish().unwrap();
+
+        assert_eq!(streamed, ordered);
+
+        let mut encoder = Encoder::new(Vec::new(), 100);
+        encoder.set_dithering(Dithering::ErrorDiffusion);
+
+        assert!(matches!(
+            encoder.into_streaming(width, height, ColorType::Luma16),
+            Err(EncodingError::UnsupportedStreamingSettings(
+                "error diffusion dithering"
+            ))
+        ));
+
+        // Images with 8 bits per sample aren't affected
+        let mut encoder = Encoder::new(Vec::new(), 100);
+        encoder.set_dithering(Dithering::ErrorDiffusion);
+        let streaming = encoder.into_streaming(width, height, ColorType::Luma);
+        assert!(streaming.is_ok());
+    }
+
+    #[test]
+    fn test_high_precision_fdct() {
+        let (data, values, width, height) = create_test_img_gray16_gradient();
+
+        let encode = |high_precision_fdct| {
+            let mut result = Vec::new();
+            let mut encoder = Encoder::new(&mut result, 100);
+            encoder.set_high_precision_fdct(high_precision_fdct);
+            assert_eq!(encoder.high_precision_fdct(), high_precision_fdct);
+
+            encoder
+                .encode(&data, width, height, ColorType::Luma16)
+                .unwrap();
+
+            result
+        };
+
+        let result = encode(true);
+
+        // The image is still a baseline image with 8 bit samples
+        let frame = get_segment(&result, 0xC0).unwrap();
+        assert_eq!(frame[0], 8);
+
+        let get_error = |result: &[u8]| {
+            let (decoded, _) = decode(result);
+            get_block_average_error(&decoded, &values, usize::from(width))
+        };
+
+        // The decoder rounds every sample, so the error is at least the error of rounding
+        let rounded = encode(false);
+        assert_ne!(result, rounded);
+        assert!(get_error(&result) < get_error(&rounded) * 1.1);
+
+        let rgb: Vec<u8> = data
+            .chunks_exact(2)
+            .flat_map(|v| [v[0], v[1], v[0], v[1], v[0], v[1]])
+            .collect();
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 90);
+        encoder.set_high_precision_fdct(true);
+        encoder
+            .encode(&rgb, width, height, ColorType::Rgb16)
+            .unwrap();
+
+        let expected: Vec<u8> = values.iter().flat_map(|&v| [v.round() as u8; 3]).collect();
+        check_result(expected, width, height, &mut result, PixelFormat::RGB24);
+
+        let mut encoder = Encoder::new(Vec::new(), 100);
+        encoder.set_high_precision_fdct(true);
+
+        assert!(matches!(
+            encoder.into_streaming(width, height, ColorType::Rgb16),
+            Err(EncodingError::UnsupportedStreamingSettings(
+                "high precision FDCT"
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_12bit_sequential() {
+        let (data, width, height) = create_test_img_gray16();
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 90);
+        encoder.set_sample_precision(12);
+        encoder.set_quantization_tables(
+            QuantizationTableType::Custom(Box::new([300; 64])),
+            QuantizationTableType::Default,
+        );
+
+        encoder
+            .encode(&data, width, height, ColorType::Luma16)
+            .unwrap();
+
+        let (markers, _) = get_markers(&result);
+        assert!(!markers.contains(&0xC0));
+        assert!(markers.contains(&0xC1));
+
+        let frame = get_segment(&result, 0xC1).unwrap();
+        assert_eq!(frame[0], 12);
+
+        // 16 bit quantization table
+        let table = get_segment(&result, 0xDB).unwrap();
+        assert_eq!(table.len(), 1 + 64 * 2);
+        assert_eq!(table[0], 0x10);
+        assert_eq!(&table[1..3], &300u16.to_be_bytes());
+
+        let expected: Vec<i32> = data
+            .chunks_exact(2)
+            .map(|v| i32::from(u16::from_ne_bytes([v[0], v[1]]) >> 4))
+            .collect();
+
+        // The errors of the coarse quantization stay below a quantization step
+        check_12bit_reconstruction(&result, &[expected], (300, 20));
+    }
+
+    #[test]
+    fn test_12bit_progressive() {
+        let (data, width, height) = create_test_img_rgb();
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 90);
+        encoder.set_sample_precision(12);
+        encoder.set_progressive(true);
+        encoder.set_successive_approximation(true);
+        encoder.set_sampling_factor(SamplingFactor::F_1_1);
+
+        encoder
+            .encode(&data, width, height, ColorType::Rgb)
+            .unwrap();
+
+        let frame = get_segment(&result, 0xC2).unwrap();
+        assert_eq!(frame[0], 12);
+
+        let table = get_segment(&result, 0xDB).unwrap();
+        assert_eq!(table[0], 0x00);
+
+        let mut expected = vec![Vec::new(), Vec::new(), Vec::new()];
+
+        for pixel in data.chunks_exact(3) {
+            let (y, cb, cr) = rgb_to_ycbcr(pixel[0], pixel[1], pixel[2]);
+
+            expected[0].push(i32::from(y) << 4);
+            expected[1].push(i32::from(cb) << 4);
+            expected[2].push(i32::from(cr) << 4);
+        }
+
+        // Errors of about one step of 8 bit samples
+        check_12bit_reconstruction(&result, &expected, (16, 3));
+    }
+
+    /// Decodes a 12 bit image and checks the samples of all components against the expected samples
+    ///
+    /// `max_errors` are the limits of the maximum and mean absolute error of the samples.
+    fn check_12bit_reconstruction(result: &[u8], expected: &[Vec<i32>], max_errors: (i32, i32)) {
+        use crate::coefficient_decoder::decode_coefficients;
+
+        let frames = decode_coefficients(result);
+        assert_eq!(frames.len(), 1);
+
+        let frame = &frames[0];
+        assert_eq!(frame.precision, 12);
+        assert_eq!(frame.components.len(), expected.len());
+
+        let width = usize::from(frame.width);
+        let height = usize::from(frame.height);
+
+        for (component, expected) in frame.components.iter().zip(expected) {
+            let plane = component.reconstruct(frame.precision, width, height);
+            check_sample_errors(&plane, expected, max_errors);
+        }
+    }
+
+    /// Checks the maximum and mean absolute error of the samples of a plane
+    fn check_sample_errors(plane: &Plane, expected: &[i32], max_errors: (i32, i32)) {
+        assert_eq!(plane.samples.len(), expected.len());
+
+        let mut max_error = 0;
+        let mut sum = 0;
+
+        for (&sample, &expected) in plane.samples.iter().zip(expected) {
+            let error = (i32::from(sample) - expected).abs();
+            max_error = max_error.max(error);
+            sum += error;
+        }
+
+        let mean_error = sum / expected.len() as i32;
+
+        assert!(max_error <= max_errors.0, "Maximum error: {}", max_error);
+        assert!(mean_error <= max_errors.1, "Mean error: {}", mean_error);
+    }
+
+    #[test]
+    fn test_8bit_quantization_table_limit() {
+        let (data, width, height) = create_test_img_gray();
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 90);
+        encoder.set_quantization_tables(
+            QuantizationTableType::Custom(Box::new([300; 64])),
+            QuantizationTableType::Default,
+        );
+
+        encoder
+            .encode(&data, width, height, ColorType::Luma)
+            .unwrap();
+
+        let table = get_segment(&result, 0xDB).unwrap();
+        assert_eq!(table.len(), 1 + 64);
+        assert_eq!(table[1], 255);
+
+        let (img, _) = decode(&result);
+        assert_eq!(img.len(), data.len());
+    }
+
+    #[test]
+    fn test_encode_coefficients() {
+        let width = 20;
+        let height = 12;
+
+        // Flat blocks with a DC coefficient of 8 * (value - 128) for each value
+        let values = [20u8, 70, 120, 170, 220, 250];
+        let coefficients: Vec<[i16; 64]> = values
+            .iter()
+            .map(|&v| {
+                let mut block = [0i16; 64];
+                block[0] = 8 * (i16::from(v) - 128);
+                block
+            })
+            .collect();
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 80);
+        encoder.set_quantization_tables(
+            QuantizationTableType::Custom(Box::new([1; 64])),
+            QuantizationTableType::Custom(Box::new([1; 64])),
+        );
+        encoder.set_progressive(true);
+
+        encoder
+            .encode_coefficients(&[coefficients], width, height, JpegColorType::Luma)
+            .unwrap();
+
+        let (img, info) = decode(&result);
+        assert_eq!(info.width, width);
+        assert_eq!(info.height, height);
+
+        for (i, &v) in img.iter().enumerate() {
+            let block = (i / usize::from(width)) / 8 * 3 + (i % usize::from(width)) / 8;
+            assert_eq!(v, values[block]);
+        }
+    }
+
+    #[test]
+    fn test_encode_coefficients_invalid_data() {
+        let encoder = Encoder::new(Vec::new(), 80);
+
+        assert!(matches!(
+            encoder.encode_coefficients(&[vec![[0; 64]; 4]], 16, 16, JpegColorType::Ycbcr),
+            Err(EncodingError::BadCoefficientData {
+                component: None,
+                length: 1,
+                required: 3
+            })
+        ));
+
+        // 2x2 subsampled chroma components have a single block
+        let mut encoder = Encoder::new(Vec::new(), 80);
+        encoder.set_sampling_factor(SamplingFactor::F_2_2);
+
+        let coefficients = [vec![[0; 64]; 4], vec![[0; 64]; 1], vec![[0; 64]; 4]];
+
+        assert!(matches!(
+            encoder.encode_coefficients(&coefficients, 16, 16, JpegColorType::Ycbcr),
+            Err(EncodingError::BadCoefficientData {
+                component: Some(2),
+                length: 4,
+                required: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn test_encode_coefficients_out_of_range() {
+        let encode = |precision: u8, index: usize, value: i16| {
+            let mut block = [0i16; 64];
+            block[index] = value;
+
+            let mut result = Vec::new();
+            let mut encoder = Encoder::new(&mut result, 80);
+            encoder.set_sample_precision(precision);
+
+            // The largest DC difference between neighboring blocks
+            let mut blocks = vec![block; 4];
+            if index == 0 {
+                blocks[1][0] = -value - 1;
+            }
+
+            let res = encoder.encode_coefficients(&[blocks], 16, 16, JpegColorType::Luma);
+            (res, result)
+        };
+
+        for (precision, limit) in [(8, 1024), (12, 16384)] {
+            for (index, value) in [(0, limit), (0, -limit - 1), (1, limit), (63, -limit)] {
+                let (res, result) = encode(precision, index, value);
+
+                assert!(matches!(
+                    res,
+                    Err(EncodingError::CoefficientOutOfRange {
+                        component: 0,
+                        block: 0,
+                        index: i,
+                        value: v,
+                    }) if i == index && v == value
+                ));
+                assert!(result.is_empty());
+            }
+
+            let valid = [(0, limit - 1), (0, -limit), (1, limit - 1), (63, 1 - limit)];
+
+            for (index, value) in valid {
+                let (res, result) = encode(precision, index, value);
+                res.unwrap();
+
+                if precision == 8 {
+                    decode(&result);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_hierarchical() {
+        use crate::coefficient_decoder::decode_coefficients;
+        use crate::encoder::{reconstruct_plane, DefaultOperations};
+        use crate::quantization::QuantizationTable;
+        use crate::writer::ZIGZAG;
+
+        let (data, width, height) = create_test_img_rgb();
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 80);
+        encoder.set_hierarchical_levels(3);
+        encoder.set_restart_interval(10);
+
+        encoder
+            .encode(&data, width, height, ColorType::Rgb)
+            .unwrap();
+
+        let (markers, restarts) = get_markers(&result);
+
+        let position = |marker| markers.iter().position(|&m| m == marker).unwrap();
+        let count = |marker| markers.iter().filter(|&&m| m == marker).count();
+
+        assert!(position(0xDE) < position(0xC1));
+        assert!(position(0xC1) < position(0xDF));
+        assert!(position(0xDF) < position(0xC5));
+        assert_eq!(count(0xDE), 1);
+        assert_eq!(count(0xC1), 1);
+        assert_eq!(count(0xC5), 2);
+        assert_eq!(count(0xDF), 2);
+        assert_eq!(count(0xDA), 3 * 3);
+        assert!(!markers.contains(&0xC0));
+        assert!(restarts > 0);
+
+        // The DHP segment contains the size of the whole image
+        let dhp = get_segment(&result, 0xDE).unwrap();
+        assert_eq!(&dhp[1..5], &[0, 128, 1, 2]);
+        assert_eq!(dhp[7], 0x11);
+
+        // The first frame contains the image downscaled by 4
+        let frame = get_segment(&result, 0xC1).unwrap();
+        assert_eq!(&frame[1..5], &[0, 32, 0, 65]);
+
+        let exp = get_segment(&result, 0xDF).unwrap();
+        assert_eq!(exp, &[0x11]);
+
+        let frames = decode_coefficients(&result);
+        let markers: Vec<_> = frames.iter().map(|frame| frame.marker).collect();
+        assert_eq!(markers, [0xC1, 0xC5, 0xC5]);
+
+        // Add up the upsampled frames like a decoder
+        let mut reference: Option<Vec<Plane>> = None;
+
+        for frame in &frames {
+            let width = usize::from(frame.width);
+            let height = usize::from(frame.height);
+
+            let planes = frame
+                .components
+                .iter()
+                .enumerate()
+                .map(|(i, component)| {
+                    let table = QuantizationTable::new_with_quality(
+                        &QuantizationTableType::Custom(Box::new(component.quantization_table)),
+                        100,
+                        i == 0,
+                        8,
+                    );
+
+                    let blocks: Vec<_> = component
+                        .blocks
+                        .iter()
+                        .map(|block| ZIGZAG.map(|z| block[usize::from(z & 0x3f)] as i16))
+                        .collect();
+
+                    let reference = reference
+                        .as_ref()
+                        .map(|reference| reference[i].upsample(width, height));
+
+                    reconstruct_plane::<DefaultOperations>(
+                        &blocks,
+                        &table,
+                        width,
+                        height,
+                        reference.as_ref(),
+                    )
+                })
+                .collect();
+
+            reference = Some(planes);
+        }
+
+        let planes = reference.unwrap();
+        let mut expected = vec![Vec::new(), Vec::new(), Vec::new()];
+
+        for pixel in data.chunks_exact(3) {
+            let (y, cb, cr) = rgb_to_ycbcr(pixel[0], pixel[1], pixel[2]);
+
+            expected[0].push(i32::from(y));
+            expected[1].push(i32::from(cb));
+            expected[2].push(i32::from(cr));
+        }
+
+        // Only the quantization errors of the last frame remain
+        for (plane, expected) in planes.iter().zip(&expected) {
+            check_sample_errors(plane, expected, (4, 1));
+        }
+    }
+
+    #[test]
+    fn test_hierarchical_invalid_settings() {
+        let (data, width, height) = create_test_img_gray();
+
+        let mut encoder = Encoder::new(Vec::new(), 80);
+        encoder.set_hierarchical_levels(2);
+        encoder.set_sample_precision(12);
+
+        assert!(matches!(
+            encoder.encode(&data, width, height, ColorType::Luma),
+            Err(EncodingError::UnsupportedSamplePrecision(12))
+        ));
+    }
+
+    #[test]
+    fn test_streaming() {
+        let (data, width, height) = create_test_img_rgb();
+
+        let mut expected = Vec::new();
+        let mut encoder = Encoder::new(&mut expected, 80);
+        encoder.set_restart_interval(3);
+        encoder
+            .encode(&data, width, height, ColorType::Rgb)
+            .unwrap();
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 80);
+        encoder.set_restart_interval(3);
+
+        let mut streaming = encoder
+            .into_streaming(width, height, ColorType::Rgb)
+            .unwrap();
+
+        // Write chunks of rows which don't line up with the MCUs
+        let row_length = usize::from(width) * 3;
+        for (i, chunk) in data.chunks(row_length * 5).enumerate() {
+            if i % 2 == 0 {
+                streaming.write_rows(chunk).unwrap();
+            } else {
+                for row in chunk.chunks(row_length) {
+                    streaming.write_rows(row).unwrap();
+                }
+            }
+        }
+
+        assert_eq!(streaming.rows_written(), usize::from(height));
+        streaming.finish().unwrap();
+
+        assert_eq!(result, expected);
+        check_result(data, width, height, &mut result, PixelFormat::RGB24);
+    }
+
+    #[test]
+    fn test_streaming_partial_mcu() {
+        let (data, width, _) = create_test_img_gray();
+        let height = 61;
+        let data = &data[..usize::from(width) * usize::from(height)];
+
+        let mut expected = Vec::new();
+        let encoder = Encoder::new(&mut expected, 90);
+        encoder
+            .encode(data, width, height, ColorType::Luma)
+            .unwrap();
+
+        let mut result = Vec::new();
+        let encoder = Encoder::new(&mut result, 90);
+
+        let mut streaming = encoder
+            .into_streaming(width, height, ColorType::Luma)
+            .unwrap();
+        streaming.write_rows(data).unwrap();
+        streaming.finish().unwrap();
+
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_streaming_invalid() {
+        let (data, width, height) = create_test_img_gray();
+
+        let mut encoder = Encoder::new(Vec::new(), 80);
+        encoder.set_progressive(true);
+
+        assert!(matches!(
+            encoder.into_streaming(width, height, ColorType::Luma),
+            Err(EncodingError::UnsupportedStreamingSettings(_))
+        ));
+
+        let encoder = Encoder::new(Vec::new(), 80);
+        let mut streaming = encoder
+            .into_streaming(width, height, ColorType::Luma)
+            .unwrap();
+
+        assert!(matches!(
+            streaming.write_rows(&data[..usize::from(width) + 1]),
+            Err(EncodingError::BadImageData { .. })
+        ));
+
+        streaming.write_rows(&data[..usize::from(width)]).unwrap();
+
+        assert!(matches!(
+            streaming.write_rows(&data),
+            Err(EncodingError::InvalidRowCount { rows: 129, .. })
+        ));
+
+        assert!(matches!(
+            streaming.finish(),
+            Err(EncodingError::InvalidRowCount { rows: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_rgb_stride() {
+        let (data, width, _) = create_test_img_rgb();
+
+        // Encode a rectangle of the image which starts at x = 3, y = 2
+        let stride = usize::from(width) * 3;
+        let (x, y, sub_width, sub_height) = (3, 2, 201, 100);
+
+        let start = y * stride + x * 3;
+        let end = start + (sub_height - 1) * stride + sub_width * 3;
+
+        let mut packed = Vec::with_capacity(sub_width * sub_height * 3);
+        for row in data[start..].chunks(stride).take(sub_height) {
+            packed.extend_from_slice(&row[..sub_width * 3]);
+        }
+
+        let mut expected = Vec::new();
+        let encoder = Encoder::new(&mut expected, 80);
+        encoder
+            .encode(&packed, sub_width as u16, sub_height as u16, ColorType::Rgb)
+            .unwrap();
+
+        let mut result = Vec::new();
+        let encoder = Encoder::new(&mut result, 80);
+        encoder
+            .encode_with_stride(
+                &data[start..end],
+                sub_width as u16,
+                sub_height as u16,
+                stride,
+                ColorType::Rgb,
+            )
+            .unwrap();
+
+        assert_eq!(result, expected);
+        check_result(
+            packed,
+            sub_width as u16,
+            sub_height as u16,
+            &mut result,
+            PixelFormat::RGB24,
+        );
+    }
+
+    #[test]
+    fn test_invalid_stride() {
+        let (data, width, height) = create_test_img_gray();
+
+        let encoder = Encoder::new(Vec::new(), 80);
+
+        assert!(matches!(
+            encoder.encode_with_stride(
+                &data,
+                width,
+                height,
+                usize::from(width) - 1,
+                ColorType::Luma
+            ),
+            Err(EncodingError::InvalidRowStride { .. })
+        ));
+
+        let encoder = Encoder::new(Vec::new(), 80);
+
+        assert!(matches!(
+            encoder.encode_with_stride(
+                &data,
+                width,
+                height,
+                usize::from(width) + 1,
+                ColorType::Luma
+            ),
+            Err(EncodingError::BadImageData { .. })
+        ));
+    }
+
+    fn create_test_img_yuv(format: YuvFormat) -> (Vec<u8>, Vec<u8>, u16, u16) {
+        // Use odd dimensions, so that the padding of the chroma planes matches the
+        // padding of full resolution images
+        let width = 257;
+        let height = 127;
+
+        let mut ycbcr = Vec::with_capacity(width * height * 3);
+
+        for y in 0..height {
+            for x in 0..width {
+                let x = x.min(255);
+                let (y, cb, cr) = rgb_to_ycbcr(x as u8, (y * 2) as u8, ((x + y * 2) / 2) as u8);
+                ycbcr.extend_from_slice(&[y, cb, cr]);
+            }
+        }
+
+        let sample = |x: usize, y: usize, c: usize| ycbcr[(y * width + x) * 3 + c];
+
+        let mut yuv = Vec::new();
+
+        match format {
+            YuvFormat::I420 | YuvFormat::Nv12 => {
+                for y in 0..height {
+                    for x in 0..width {
+                        yuv.push(sample(x, y, 0));
+                    }
+                }
+
+                if format == YuvFormat::I420 {
+                    for c in 1..3 {
+                        for y in (0..height).step_by(2) {
+                            for x in (0..width).step_by(2) {
+                                yuv.push(sample(x, y, c));
+                            }
+                        }
+                    }
+                } else {
+                    for y in (0..height).step_by(2) {
+                        for x in (0..width).step_by(2) {
+                            yuv.push(sample(x, y, 1));
+                            yuv.push(sample(x, y, 2));
+                        }
+                    }
+                }
+            }
+            YuvFormat::Yuy2 => {
+                for y in 0..height {
+                    for x in (0..width).step_by(2) {
+                        yuv.push(sample(x, y, 0));
+                        yuv.push(sample(x, y, 1));
+                        yuv.push(sample((x + 1).min(width - 1), y, 0));
+                        yuv.push(sample(x, y, 2));
+                    }
+                }
+            }
+        }
+
+        (ycbcr, yuv, width as u16, height as u16)
+    }
+
+    fn check_yuv(
+        format: YuvFormat,
+        sampling: SamplingFactor,
+        configure: impl Fn(&mut Encoder<&mut Vec<u8>>),
+    ) {
+        let (ycbcr, yuv, width, height) = create_test_img_yuv(format);
+
+        // The pre-subsampled components are point sampled from the full resolution image
+        let mut expected = Vec::new();
+        let mut encoder = Encoder::new(&mut expected, 80);
+        encoder.set_sampling_factor(sampling);
+        encoder.set_downsampling_filter(DownsamplingFilter::Point);
+        configure(&mut encoder);
+        encoder
+            .encode(&ycbcr, width, height, ColorType::Ycbcr)
+            .unwrap();
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 80);
+        configure(&mut encoder);
+        encoder.encode_yuv(&yuv, width, height, format).unwrap();
+
+        assert!(result == expected);
+    }
+
+    #[test]
+    fn test_yuv() {
+        let (ycbcr, yuv, width, height) = create_test_img_yuv(YuvFormat::I420);
+
+        let mut result = Vec::new();
+        let encoder = Encoder::new(&mut result, 90);
+        encoder
+            .encode_yuv(&yuv, width, height, YuvFormat::I420)
+            .unwrap();
+
+        let (img, info) = decode(&result);
+        assert_eq!(info.width, width);
+        assert_eq!(info.height, height);
+
+        let mut decoded = Vec::new();
+        for pixel in img.chunks_exact(3) {
+            let (y, cb, cr) = rgb_to_ycbcr(pixel[0], pixel[1], pixel[2]);
+            decoded.extend_from_slice(&[y, cb, cr]);
+        }
+
+        for (i, (&v1, &v2)) in ycbcr.iter().zip(decoded.iter()).enumerate() {
+            let diff = (v1 as i16 - v2 as i16).abs();
+            assert!(diff < 20, "Large diff at index: {}: {} vs {}", i, v1, v2);
+        }
+
+        check_yuv(YuvFormat::I420, SamplingFactor::F_2_2, |encoder| {
+            encoder.set_optimized_huffman_tables(true)
+        });
+        check_yuv(YuvFormat::Nv12, SamplingFactor::F_2_2, |encoder| {
+            encoder.set_progressive(true)
+        });
+        check_yuv(YuvFormat::Yuy2, SamplingFactor::F_2_1, |encoder| {
+            encoder.set_progressive(true)
+        });
+    }
+
+    #[test]
+    fn test_yuv_invalid_data() {
+        let (_, yuv, width, height) = create_test_img_yuv(YuvFormat::I420);
+
+        let encoder = Encoder::new(Vec::new(), 80);
+
+        assert!(matches!(
+            encoder.encode_yuv(&yuv[..yuv.len() - 1], width, height, YuvFormat::I420),
+            Err(EncodingError::BadImageData { .. })
+        ));
+    }
+
+    fn check_target_size(
+        target_size: usize,
+        configure: impl Fn(&mut Encoder<&mut Vec<u8>>),
+        sampling: Option<SamplingFactor>,
+    ) {
+        let (data, width, height) = create_test_img_rgb();
+
+        let encode = |quality| {
+            let mut result = Vec::new();
+            let mut encoder = Encoder::new(&mut result, quality);
+            if let Some(sampling) = sampling {
+                encoder.set_sampling_factor(sampling);
+            }
+            configure(&mut encoder);
+            encoder
+                .encode(&data, width, height, ColorType::Rgb)
+                .unwrap();
+            result
+        };
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 95);
+        if let Some(sampling) = sampling {
+            encoder.set_sampling_factor(sampling);
+        } else {
+            encoder.set_target_size_subsampling(true);
+        }
+        configure(&mut encoder);
+        encoder.set_target_size(target_size);
+        encoder
+            .encode(&data, width, height, ColorType::Rgb)
+            .unwrap();
+
+        assert!(result.len() <= target_size);
+
+        // The result is the output of a quality whose next higher quality doesn't fit
+        let quality = (1..=95).find(|&quality| encode(quality) == result).unwrap();
+        assert!(quality == 95 || encode(quality + 1).len() > target_size);
+    }
+
+    #[test]
+    fn test_target_size() {
+        check_target_size(4_000, |_| {}, Some(SamplingFactor::F_1_1));
+        check_target_size(2_500, |_| {}, None);
+        check_target_size(
+            2_000,
+            |encoder| encoder.set_optimized_huffman_tables(true),
+            Some(SamplingFactor::F_2_1),
+        );
+        check_target_size(1_800, |encoder| encoder.set_progressive(true), None);
+        check_target_size(1_000_000, |_| {}, None);
+    }
+
+    #[test]
+    fn test_target_size_too_small() {
+        let (data, width, height) = create_test_img_rgb();
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 80);
+        encoder.set_target_size(500);
+
+        assert!(matches!(
+            encoder.encode(&data, width, height, ColorType::Rgb),
+            Err(EncodingError::TargetSizeTooSmall {
+                target_size: 500,
+                ..
+            })
+        ));
+        assert!(result.is_empty());
+
+        let mut encoder = Encoder::new(Vec::new(), 80);
+        encoder.set_target_size(500);
+
+        assert!(matches!(
+            encoder.into_streaming(width, height, ColorType::Rgb),
+            Err(EncodingError::UnsupportedStreamingSettings(_))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_quality_target() {
+        let (data, width, height) = create_test_img_rgb();
+
+        let encode = |quality_target| {
+            let mut result = Vec::new();
+            let mut encoder = Encoder::new(&mut result, 100);
+            encoder.set_sampling_factor(SamplingFactor::F_2_2);
+            encoder.set_quality_target(quality_target);
+            encoder
+                .encode(&data, width, height, ColorType::Rgb)
+                .unwrap();
+            result
+        };
+
+        let get_luma = |data: &[u8]| {
+            let samples = data
+                .chunks_exact(3)
+                .map(|pixel| i16::from(rgb_to_ycbcr(pixel[0], pixel[1], pixel[2]).0))
+                .collect();
+
+            Plane::new(usize::from(width), usize::from(height), samples)
+        };
+
+        let original = get_luma(&data);
+        let full = encode(None);
+
+        for quality_target in [QualityTarget::Psnr(38.0), QualityTarget::Ssim(0.95)] {
+            let result = encode(Some(quality_target));
+            assert!(result.len() < full.len());
+
+            // The decoder and the color conversion cause small differences
+            let decoded = get_luma(&decode(&result).0);
+
+            match quality_target {
+                QualityTarget::Psnr(psnr) => assert!(get_psnr(&original, &decoded) > psnr - 0.5),
+                QualityTarget::Ssim(ssim) => assert!(get_ssim(&original, &decoded) > ssim - 0.01),
+            }
+        }
+
+        assert!(
+            encode(Some(QualityTarget::Psnr(30.0))).len()
+                < encode(Some(QualityTarget::Psnr(38.0))).len()
+        );
+
+        // Unreachable targets use the quality of the encoder
+        assert!(encode(Some(QualityTarget::Psnr(200.0))) == full);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_quality_target_all_components() {
+        // A flat first component must not hide the errors of the others
+        let (width, height) = (64, 48);
+        let data: Vec<u8> = (0..64 * 48)
+            .flat_map(|i| [128, (i * 37 % 251) as u8, (i * 11 % 247) as u8])
+            .collect();
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 100);
+        encoder.set_rgb_without_transform(true);
+        encoder.set_quality_target(Some(QualityTarget::Psnr(30.0)));
+        encoder
+            .encode(&data, width, height, ColorType::Rgb)
+            .unwrap();
+
+        let get_plane = |data: &[u8]| {
+            let samples = data.iter().map(|&value| i16::from(value)).collect();
+            Plane::new(data.len(), 1, samples)
+        };
+
+        let original = get_plane(&data);
+        let decoded = get_plane(&decode(&result).0);
+
+        assert!(get_psnr(&original, &decoded) > 29.5);
+    }
+
+    #[test]
+    fn test_downsampling_filters() {
+        let (data, width, height) = create_test_img_rgb();
+
+        for filter in [
+            DownsamplingFilter::Point,
+            DownsamplingFilter::Box,
+            DownsamplingFilter::Triangle,
+            DownsamplingFilter::Lanczos,
+        ] {
+            for sampling_factor in [SamplingFactor::F_2_2, SamplingFactor::F_4_1] {
+                let mut result = Vec::new();
+                let mut encoder = Encoder::new(&mut result, 90);
+                encoder.set_sampling_factor(sampling_factor);
+                encoder.set_downsampling_filter(filter);
+                assert_eq!(encoder.downsampling_filter(), filter);
+
+                encoder
+                    .encode(&data, width, height, ColorType::Rgb)
+                    .unwrap();
+
+                check_result(data.clone(), width, height, &mut result, PixelFormat::RGB24);
+            }
+        }
+
+        let mut encoder = Encoder::new(Vec::new(), 80);
+        encoder.set_sampling_factor(SamplingFactor::F_2_2);
+        encoder.set_downsampling_filter(DownsamplingFilter::Lanczos);
+
+        assert!(matches!(
+            encoder.into_streaming(width, height, ColorType::Rgb),
+            Err(EncodingError::UnsupportedStreamingSettings(_))
+        ));
+    }
+
+    #[test]
+    fn test_sharp_yuv() {
+        let width = 64;
+        let height = 48;
+
+        // Saturated red lines on white
+        let mut data = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            for x in 0..width {
+                if (x + y / 8) % 8 < 3 {
+                    data.extend_from_slice(&[255, 0, 0]);
+                } else {
+                    data.extend_from_slice(&[255, 255, 255]);
+                }
+            }
+        }
+
+        let encode = |sharp_yuv| {
+            let mut result = Vec::new();
+            let mut encoder = Encoder::new(&mut result, 100);
+            encoder.set_sampling_factor(SamplingFactor::F_2_2);
+            encoder.set_sharp_yuv(sharp_yuv);
+            assert_eq!(encoder.sharp_yuv(), sharp_yuv);
+
+            encoder
+                .encode(&data, width as u16, height as u16, ColorType::Rgb)
+                .unwrap();
+
+            let (decoded, _) = decode(&result);
+
+            decoded
+                .iter()
+                .zip(data.iter())
+                .map(|(&v1, &v2)| {
+                    let diff = i64::from(v1) - i64::from(v2);
+                    diff * diff
+                })
+                .sum::<i64>()
+        };
+
+        assert!(encode(true) < encode(false));
+
+        let mut encoder = Encoder::new(Vec::new(), 80);
+        encoder.set_sampling_factor(SamplingFactor::F_2_2);
+        encoder.set_sharp_yuv(true);
+
+        assert!(matches!(
+            encoder.into_streaming(width as u16, height as u16, ColorType::Rgb),
+            Err(EncodingError::UnsupportedStreamingSettings("sharp YUV"))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_linear_light_downsampling() {
+        use crate::TransferFunction;
+
+        let width = 64;
+        let height = 48;
+
+        // Thin red lines on white
+        let mut data = Vec::with_capacity(width * height * 3);
+        for _ in 0..height {
+            for x in 0..width {
+                if x % 4 == 0 {
+                    data.extend_from_slice(&[255, 0, 0]);
+                } else {
+                    data.extend_from_slice(&[255, 255, 255]);
+                }
+            }
+        }
+
+        let to_linear = |v: u8| (f64::from(v) / 255.0).powf(2.2);
+
+        // Average light of each channel
+        let get_light = |data: &[u8]| {
+            let mut light = [0f64; 3];
+            for (i, &v) in data.iter().enumerate() {
+                light[i % 3] += to_linear(v);
+            }
+            light.map(|l| l / (width * height) as f64)
+        };
+
+        let encode = |transfer_function| {
+            let mut result = Vec::new();
+            let mut encoder = Encoder::new(&mut result, 100);
+            encoder.set_sampling_factor(SamplingFactor::F_2_2);
+            encoder.set_linear_light_downsampling(transfer_function);
+            assert_eq!(encoder.linear_light_downsampling(), transfer_function);
+
+            encoder
+                .encode(&data, width as u16, height as u16, ColorType::Rgb)
+                .unwrap();
+
+            let (decoded, _) = decode(&result);
+
+            let light = get_light(&decoded);
+            light
+                .iter()
+                .zip(get_light(&data).iter())
+                .map(|(l1, l2)| (l1 - l2).abs())
+                .sum::<f64>()
+        };
+
+        assert!(encode(Some(TransferFunction::Srgb)) < encode(None) * 0.9);
+
+        let mut encoder = Encoder::new(Vec::new(), 80);
+        encoder.set_sampling_factor(SamplingFactor::F_2_2);
+        encoder.set_linear_light_downsampling(Some(TransferFunction::default()));
+
+        assert!(matches!(
+            encoder.into_streaming(width as u16, height as u16, ColorType::Rgb),
+            Err(EncodingError::UnsupportedStreamingSettings(
+                "linear light downsampling"
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_color_matrix() {
+        let width = 16;
+        let height = 16;
+
+        // Black and white halves
+        let mut data = Vec::with_capacity(width * height * 3);
+        for _ in 0..height {
+            data.extend_from_slice(&[0; 24]);
+            data.extend_from_slice(&[255; 24]);
+        }
+
+        for (matrix, range) in [
+            (ColorMatrix::Bt601Full, (0, 255)),
+            (ColorMatrix::Bt709Full, (0, 255)),
+            (ColorMatrix::Bt709Limited, (16, 235)),
+            (ColorMatrix::Bt2020Limited, (16, 235)),
+        ] {
+            let mut result = Vec::new();
+            let mut encoder = Encoder::new(&mut result, 100);
+            encoder.set_color_matrix(matrix);
+            assert_eq!(encoder.color_matrix(), matrix);
+
+            encoder
+                .encode(&data, width as u16, height as u16, ColorType::Rgb)
+                .unwrap();
+
+            // The decoder assumes full range BT.601, which keeps the limited range
+            let (decoded, _) = decode(&result);
+
+            for y in 0..height {
+                let row = &decoded[y * width * 3..(y + 1) * width * 3];
+
+                assert!(row[..3].iter().all(|&v| v.abs_diff(range.0) <= 1));
+                assert!(row[45..].iter().all(|&v| v.abs_diff(range.1) <= 1));
+            }
+        }
+    }
+
+    #[test]
+    fn test_rgb_without_transform() {
+        let (data, width, height) = create_test_img_rgb();
+
+        let encode = |rgb_without_transform| {
+            let mut result = Vec::new();
+            let mut encoder = Encoder::new(&mut result, 100);
+            encoder.set_sampling_factor(SamplingFactor::F_2_2);
+            encoder.set_rgb_without_transform(rgb_without_transform);
+            assert_eq!(encoder.rgb_without_transform(), rgb_without_transform);
+
+            encoder
+                .encode(&data, width, height, ColorType::Rgb)
+                .unwrap();
+
+            result
+        };
+
+        let get_error = |result: &[u8]| {
+            let (decoded, info) = decode(result);
+            assert_eq!(info.pixel_format, PixelFormat::RGB24);
+
+            decoded
+                .iter()
+                .zip(data.iter())
+                .map(|(&v1, &v2)| {
+                    let diff = i64::from(v1) - i64::from(v2);
+                    diff * diff
+                })
+                .sum::<i64>()
+        };
+
+        let mut result = encode(true);
+
+        // No JFIF header and an Adobe segment with transform 0
+        assert!(!result.windows(4).any(|w| w == b"JFIF"));
+        assert!(result.windows(12).any(|w| w == b"Adobe\0\0\0\0\0\0\0"));
+
+        assert!(get_error(&result) < get_error(&encode(false)));
+        check_result(data.clone(), width, height, &mut result, PixelFormat::RGB24);
+
+        let mut streamed = Vec::new();
+        let mut encoder = Encoder::new(&mut streamed, 100);
+        encoder.set_sampling_factor(SamplingFactor::F_2_2);
+        encoder.set_rgb_without_transform(true);
+
+        let mut streaming = encoder
+            .into_streaming(width, height, ColorType::Rgb)
+            .unwrap();
+        streaming.write_rows(&data).unwrap();
+        streaming.finish().unwrap();
+
+        assert_eq!(streamed, result);
+    }
+
+    #[test]
+    fn test_alpha_policy() {
+        let (data, width, height) = create_test_img_rgba();
+
+        for policy in [
+            AlphaPolicy::Composite([255, 255, 255]),
+            AlphaPolicy::Unpremultiply,
+        ] {
+            let mut result = Vec::new();
+            let mut encoder = Encoder::new(&mut result, 100);
+            encoder.set_alpha_policy(policy);
+            assert_eq!(encoder.alpha_policy(), policy);
+
+            encoder
+                .encode(&data, width, height, ColorType::Rgba)
+                .unwrap();
+
+            let expected: Vec<u8> = data
+                .chunks_exact(4)
+                .flat_map(|pixel| {
+                    let (r, g, b) = policy.apply(pixel[0], pixel[1], pixel[2], pixel[3]);
+                    [r, g, b]
+                })
+                .collect();
+
+            check_result(expected, width, height, &mut result, PixelFormat::RGB24);
+
+            let mut streamed = Vec::new();
+            let mut encoder = Encoder::new(&mut streamed, 100);
+            encoder.set_alpha_policy(policy);
+
+            let mut streaming = encoder
+                .into_streaming(width, height, ColorType::Rgba)
+                .unwrap();
+            streaming.write_rows(&data).unwrap();
+            streaming.finish().unwrap();
+
+            assert_eq!(streamed, result);
+        }
+
+        // Fully transparent pixels are composited onto the background
+        let data = [0, 0, 0, 0].repeat(16 * 16);
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 100);
+        encoder.set_alpha_policy(AlphaPolicy::Composite([255, 255, 255]));
+        encoder.encode(&data, 16, 16, ColorType::Bgra).unwrap();
+
+        let (decoded, _) = decode(&result);
+        assert!(decoded.iter().all(|&v| v >= 254));
+    }
+
+    #[test]
+    fn test_alpha_color_types() {
+        let (data, width, height) = create_test_img_rgba();
+
+        let encode = |data: &[u8], color_type, policy| {
+            let mut result = Vec::new();
+            let mut encoder = Encoder::new(&mut result, 100);
+            encoder.set_alpha_policy(policy);
+            encoder.encode(data, width, height, color_type).unwrap();
+            result
+        };
+
+        let white = AlphaPolicy::Composite([255, 255, 255]);
+        let expected = encode(&data, ColorType::Rgba, white);
+
+        let argb: Vec<u8> = data
+            .chunks_exact(4)
+            .flat_map(|p| [p[3], p[0], p[1], p[2]])
+            .collect();
+        assert_eq!(encode(&argb, ColorType::Argb, white), expected);
+
+        let premultiplied: Vec<u8> = data
+            .chunks_exact(4)
+            .flat_map(|p| {
+                let a = u16::from(p[3]);
+                let [r, g, b] = [p[0], p[1], p[2]].map(|v| ((u16::from(v) * a + 127) / 255) as u8);
+                [p[3], b, g, r]
+            })
+            .collect();
+
+        let mut result = encode(&premultiplied, ColorType::AbgrPremultiplied, white);
+
+        let (expected, _) = decode(&expected);
+        check_result(expected, width, height, &mut result, PixelFormat::RGB24);
+
+        // Premultiplied values are kept as they are by default
+        let rgb: Vec<u8> = premultiplied
+            .chunks_exact(4)
+            .flat_map(|p| [p[3], p[2], p[1]])
+            .collect();
+
+        let result = encode(
+            &premultiplied,
+            ColorType::AbgrPremultiplied,
+            AlphaPolicy::Ignore,
+        );
+        assert_eq!(result, encode(&rgb, ColorType::Rgb, AlphaPolicy::Ignore));
+
+        let mut streamed = Vec::new();
+        let mut encoder = Encoder::new(&mut streamed, 100);
+        encoder.set_alpha_policy(white);
+
+        let mut streaming = encoder
+            .into_streaming(width, height, ColorType::AbgrPremultiplied)
+            .unwrap();
+        streaming.write_rows(&premultiplied).unwrap();
+        streaming.finish().unwrap();
+
+        assert_eq!(
+            streamed,
+            encode(&premultiplied, ColorType::AbgrPremultiplied, white)
+        );
+    }
+
+    #[test]
+    fn test_reconstruct() {
+        let (data, width, height) = create_test_img_gray();
+
+        for quality in [30, 90] {
+            let mut result = Vec::new();
+            let encoder = Encoder::new(&mut result, quality);
+
+            let reconstructed = encoder
+                .reconstruct(&data, width, height, ColorType::Luma)
+                .unwrap();
+
+            encoder
+                .encode(&data, width, height, ColorType::Luma)
+                .unwrap();
+
+            let (decoded, _) = decode(&result);
+
+            assert_eq!(reconstructed.len(), decoded.len());
+
+            for (&v1, &v2) in reconstructed.iter().zip(decoded.iter()) {
+                assert!((i16::from(v1) - i16::from(v2)).abs() <= 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_reconstruct_subsampled() {
+        let (data, width, height) = create_test_img_rgb();
+
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, 80);
+        encoder.set_sampling_factor(SamplingFactor::F_2_2);
+        encoder.set_progressive(true);
+
+        let reconstructed = encoder
+            .reconstruct(&data, width, height, ColorType::Rgb)
+            .unwrap();
+
+        encoder
+            .encode(&data, width, height, ColorType::Rgb)
+            .unwrap();
+
+        let (decoded, _) = decode(&result);
+
+        assert_eq!(reconstructed.len(), decoded.len());
+
+        // The decoder returns RGB and upsamples the chroma with a filter
+        for (ycbcr, rgb) in reconstructed.chunks_exact(3).zip(decoded.chunks_exact(3)) {
+            let (y, _, _) = rgb_to_ycbcr(rgb[0], rgb[1], rgb[2]);
+            assert!((i16::from(ycbcr[0]) - i16::from(y)).abs() <= 3);
+        }
+    }
+
+    #[test]
+    fn test_reconstruct_unsupported() {
+        let (data, width, height) = create_test_img_gray();
+
+        let mut encoder = Encoder::new(Vec::new(), 80);
+        encoder.set_lossless(Some(Predictor::Ra));
+
+        assert!(matches!(
+            encoder.reconstruct(&data, width, height, ColorType::Luma),
+            Err(EncodingError::UnsupportedReconstructionSettings("lossless"))
+        ));
+
+        let mut encoder = Encoder::new(Vec::new(), 80);
+        encoder.set_hierarchical_levels(2);
+
+        assert!(matches!(
+            encoder.reconstruct(&data, width, height, ColorType::Luma),
+            Err(EncodingError::UnsupportedReconstructionSettings(
+                "hierarchical"
+            ))
+        ));
+
+        let mut encoder = Encoder::new(Vec::new(), 80);
+        encoder.set_sample_precision(12);
+
+        assert!(matches!(
+            encoder.reconstruct(&data, width, height, ColorType::Luma),
+            Err(EncodingError::UnsupportedSamplePrecision(12))
+        ));
+
+        let encoder = Encoder::new(Vec::new(), 80);
+
+        assert!(matches!(
+            encoder.reconstruct(&data[1..], width, height, ColorType::Luma),
+            Err(EncodingError::BadImageData { .. })
+        ));
+    }
+
+    /// Checks that the output doesn't depend on the number of threads and returns it
+    #[cfg(feature = "parallel")]
+    fn check_parallel(
+        data: &[u8],
+        width: u16,
+        height: u16,
+        color_type: ColorType,
+        configure: impl Fn(&mut Encoder<&mut Vec<u8>>),
+    ) -> Vec<u8> {
+        let encode = |threads: usize| {
+            let mut result = Vec::new();
+            let mut encoder = Encoder::new(&mut result, 80);
+            encoder.set_num_threads(threads);
+            configure(&mut encoder);
+            encoder.encode(data, width, height, color_type).unwrap();
+            result
+        };
+
+        let expected = encode(1);
+
+        for threads in [2, 3, 4, 7] {
+            assert!(encode(threads) == expected, "{} threads", threads);
+        }
+
+        expected
+    }
+
+    #[test]
+    #[cfg(feature = "parallel")]
+    fn test_parallel() {
+        let (data, width, height) = create_test_img_rgb();
+
+        check_parallel(&data, width, height, ColorType::Rgb, |_| {});
+
+        let result = check_parallel(&data, width, height, ColorType::Rgb, |encoder| {
+            encoder.set_restart_interval(3);
+        });
+        let (markers, restarts) = get_markers(&result);
+        assert_eq!(markers.iter().filter(|&&marker| marker == 0xDA).count(), 1);
+        assert!(restarts > 8);
+
+        let result = check_parallel(&data, width, height, ColorType::Rgb, |encoder| {
+            encoder.set_sampling_factor(SamplingFactor::F_2_1);
+            encoder.set_optimized_huffman_tables(true);
+            encoder.set_restart_interval(7);
+        });
+        let (_, restarts) = get_markers(&result);
+        assert!(restarts > 8);
+
+        check_parallel(&data, width, height, ColorType::Rgb, |encoder| {
+            encoder.set_progressive(true);
+        });
+
+        check_parallel(&data, width, height, ColorType::Rgb, |encoder| {
+            encoder.set_trellis_quantization(true);
+            encoder.set_restart_interval(2);
+        });
+
+        check_parallel(&data, width, height, ColorType::Rgb, |encoder| {
+            encoder.set_trellis_quantization(true);
+            encoder.set_optimized_huffman_tables(true);
+            encoder.set_restart_interval(3);
+        });
+
+        check_parallel(&data, width, height, ColorType::Rgb, |encoder| {
+            encoder.set_hierarchical_levels(2);
+            encoder.set_restart_interval(5);
+        });
+
+        // Images with many rows of MCUs are coded in batches of rows, which restart intervals
+        // can cross
+        let (width, height) = (45, 700);
+        let data: Vec<u8> = (0..45 * 700 * 3).map(|i| (i * 7 % 251) as u8).collect();
+
+        for restart_interval in [0, 5, 40] {
+            for sampling_factor in [SamplingFactor::F_1_1, SamplingFactor::F_2_2] {
+                let result = check_parallel(&data, width, height, ColorType::Rgb, |encoder| {
+                    encoder.set_sampling_factor(sampling_factor);
+                    encoder.set_restart_interval(restart_interval);
+                });
+                assert!(decode(&result).0.len() == data.len());
+            }
+        }
+
+        let (data, width, height) = create_test_img_gray16();
+
+        check_parallel(&data, width, height, ColorType::Luma16, |encoder| {
+            encoder.set_sample_precision(12);
+            encoder.set_restart_interval(2);
+        });
+
+        for dithering in [Dithering::Ordered, Dithering::ErrorDiffusion] {
+            check_parallel(&data, width, height, ColorType::Luma16, |encoder| {
+                encoder.set_dithering(dithering);
+            });
+        }
+
+        for format in [YuvFormat::I420, YuvFormat::Yuy2] {
+            let (_, yuv, width, height) = create_test_img_yuv(format);
+
+            let mut expected = Vec::new();
+            let mut encoder = Encoder::new(&mut expected, 80);
+            encoder.set_num_threads(1);
+            encoder.set_restart_interval(4);
+            encoder.encode_yuv(&yuv, width, height, format).unwrap();
+
+            let mut result = Vec::new();
+            let mut encoder = Encoder::new(&mut result, 80);
+            encoder.set_num_threads(3);
+            encoder.set_restart_interval(4);
+            encoder.encode_yuv(&yuv, width, height, format).unwrap();
+
+            assert!(result == expected);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "parallel")]
+    #[should_panic(expected = "Number of threads must be greater than zero")]
+    fn test_parallel_zero_threads() {
+        let mut encoder = Encoder::new(Vec::new(), 80);
+        encoder.set_num_threads(0);
+    }
 }
@@ -0,0 +1,258 @@
+/*
+ * Downsampling of the chroma in linear light
+ *
+ * Averaging gamma encoded samples darkens thin saturated features, because the average
+ * of the encoded values is darker than the encoded average of the light. The colors of
+ * each area are therefore converted to linear light, filtered and encoded again before
+ * the chroma is computed from them.
+ */
+
+use alloc::vec::Vec;
+
+use crate::{ColorMatrix, DownsamplingFilter};
+
+/// # Transfer functions between encoded samples and linear light
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TransferFunction {
+    /// The sRGB transfer function of IEC 61966-2-1
+    Srgb,
+
+    /// The transfer function of BT.709, which is also used by BT.2020
+    Bt709,
+
+    /// A pure power function with the given exponent, e.g. 2.2
+    Gamma(f32),
+}
+
+impl Default for TransferFunction {
+    fn default() -> Self {
+        TransferFunction::Srgb
+    }
+}
+
+impl TransferFunction {
+    /// Converts an encoded value between 0 and 1 to linear light
+    fn to_linear(self, value: f32) -> f32 {
+        match self {
+            TransferFunction::Srgb => {
+                if value <= 0.040_45 {
+                    value / 12.92
+                } else {
+                    ((value + 0.055) / 1.055).powf(2.4)
+                }
+            }
+            TransferFunction::Bt709 => {
+                if value < 0.081 {
+                    value / 4.5
+                } else {
+                    ((value + 0.099) / 1.099).powf(1.0 / 0.45)
+                }
+            }
+            TransferFunction::Gamma(gamma) => value.powf(gamma),
+        }
+    }
+
+    /// Converts linear light between 0 and 1 to an encoded value
+    fn to_encoded(self, value: f32) -> f32 {
+        match self {
+            TransferFunction::Srgb => {
+                if value <= 0.003_130_8 {
+                    value * 12.92
+                } else {
+                    1.055 * value.powf(1.0 / 2.4) - 0.055
+                }
+            }
+            TransferFunction::Bt709 => {
+                if value < 0.018 {
+                    value * 4.5
+                } else {
+                    1.099 * value.powf(0.45) - 0.099
+                }
+            }
+            TransferFunction::Gamma(gamma) => value.powf(1.0 / gamma),
+        }
+    }
+}
+
+/// Downsamples the chroma of full resolution YCbCr rows in linear light
+///
+/// `rows` contains the Y, Cb and Cr samples of all rows with a width of `width`, which
+/// must be a multiple of `h_scale`. The number of rows must be a multiple of `v_scale`.
+/// The RGB colors are filtered in linear light with the weights of `filter` and every
+/// sample of the chroma components is replaced by the chroma of the filtered color of
+/// the area it belongs to. The luma isn't changed. The samples are converted from and to
+/// RGB with `matrix`.
+pub(crate) fn linear_light_downsample(
+    rows: &mut [Vec<u8>; 4],
+    width: usize,
+    h_scale: usize,
+    v_scale: usize,
+    filter: DownsamplingFilter,
+    transfer_function: TransferFunction,
+    matrix: ColorMatrix,
+) {
+    let height = rows[0].len() / width;
+
+    debug_assert!(width % h_scale == 0);
+    debug_assert!(height % v_scale == 0);
+
+    let mut linear = [
+        Vec::with_capacity(width * height),
+        Vec::with_capacity(width * height),
+        Vec::with_capacity(width * height),
+    ];
+
+    // The RGB values are rounded to look the linear values up
+    let table: Vec<f32> = (0..=255)
+        .map(|v| transfer_function.to_linear(f32::from(v as u8) / 255.0))
+        .collect();
+
+    for ((&y, &cb), &cr) in rows[0].iter().zip(rows[1].iter()).zip(rows[2].iter()) {
+        let rgb = matrix.ycbcr_to_rgb_f32(f32::from(y), f32::from(cb), f32::from(cr));
+
+        for (plane, value) in linear.iter_mut().zip(rgb) {
+            plane.push(table[(value + 0.5) as usize]);
+        }
+    }
+
+    let [r, g, b] = linear.map(|plane| downsample(&plane, width, height, h_scale, v_scale, filter));
+
+    let encode = |value: f32| 255.0 * transfer_function.to_encoded(value.clamp(0.0, 1.0));
+
+    let chroma_width = width / h_scale;
+
+    let chroma: Vec<(u8, u8)> = r
+        .iter()
+        .zip(g.iter())
+        .zip(b.iter())
+        .map(|((&r, &g), &b)| {
+            let [_, cb, cr] = matrix.rgb_to_ycbcr_f32([encode(r), encode(g), encode(b)]);
+
+            (to_sample(cb), to_sample(cr))
+        })
+        .collect();
+
+    let [_, cb_row, cr_row, _] = rows;
+
+    for (i, (cb, cr)) in cb_row.iter_mut().zip(cr_row.iter_mut()).enumerate() {
+        let x = (i % width) / h_scale;
+        let y = (i / width) / v_scale;
+
+        (*cb, *cr) = chroma[y * chroma_width + x];
+    }
+}
+
+#[inline]
+fn to_sample(value: f32) -> u8 {
+    (value.clamp(0.0, 255.0) + 0.5) as u8
+}
+
+/// Downsamples a plane with the weights of the filter
+fn downsample(
+    plane: &[f32],
+    width: usize,
+    height: usize,
+    h_scale: usize,
+    v_scale: usize,
+    filter: DownsamplingFilter,
+) -> Vec<f32> {
+    let (h_offset, h_taps) = filter.get_taps(h_scale);
+    let (v_offset, v_taps) = filter.get_taps(v_scale);
+
+    let total = h_taps.iter().sum::<i32>() as f32 * v_taps.iter().sum::<i32>() as f32;
+
+    let mut downsampled = Vec::with_capacity((width / h_scale) * (height / v_scale));
+
+    for y in (0..height).step_by(v_scale) {
+        for x in (0..width).step_by(h_scale) {
+            let mut sum = 0.0;
+
+            for (dy, &v_weight) in v_taps.iter().enumerate() {
+                let iy = (y as isize + v_offset + dy as isize).clamp(0, height as isize - 1);
+                let row = &plane[iy as usize * width..(iy as usize + 1) * width];
+
+                let mut row_sum = 0.0;
+                for (dx, &h_weight) in h_taps.iter().enumerate() {
+                    let ix = (x as isize + h_offset + dx as isize).clamp(0, width as isize - 1);
+                    row_sum += h_weight as f32 * row[ix as usize];
+                }
+
+                sum += v_weight as f32 * row_sum;
+            }
+
+            downsampled.push(sum / total);
+        }
+    }
+
+    downsampled
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::{linear_light_downsample, TransferFunction};
+    use crate::{rgb_to_ycbcr, ColorMatrix, DownsamplingFilter};
+
+    #[test]
+    fn test_transfer_functions() {
+        for transfer_function in [
+            TransferFunction::Srgb,
+            TransferFunction::Bt709,
+            TransferFunction::Gamma(2.2),
+        ] {
+            for i in 0..=10 {
+                let value = i as f32 / 10.0;
+                let linear = transfer_function.to_linear(value);
+
+                assert!(linear <= value + 1e-6);
+                assert!((transfer_function.to_encoded(linear) - value).abs() < 1e-4);
+            }
+        }
+
+        assert!((TransferFunction::Srgb.to_linear(0.5) - 0.214).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_linear_light_downsample() {
+        // Alternating red and green columns
+        let mut rows: [Vec<u8>; 4] = Default::default();
+
+        for i in 0..8 * 2 {
+            let (y, cb, cr) = if i % 2 == 0 {
+                rgb_to_ycbcr(255, 0, 0)
+            } else {
+                rgb_to_ycbcr(0, 255, 0)
+            };
+
+            rows[0].push(y);
+            rows[1].push(cb);
+            rows[2].push(cr);
+        }
+
+        let luma = rows[0].clone();
+
+        linear_light_downsample(
+            &mut rows,
+            8,
+            2,
+            2,
+            DownsamplingFilter::Box,
+            TransferFunction::Srgb,
+            ColorMatrix::Bt601Full,
+        );
+
+        assert_eq!(rows[0], luma);
+
+        // The average of the light is (0.5, 0.5, 0) in linear light, which is
+        // encoded as 188 and brighter than the average of the encoded values
+        let (_, cb, cr) = rgb_to_ycbcr(188, 188, 0);
+
+        assert!(rows[1]
+            .iter()
+            .all(|&v| (i16::from(v) - i16::from(cb)).abs() <= 1));
+        assert!(rows[2]
+            .iter()
+            .all(|&v| (i16::from(v) - i16::from(cr)).abs() <= 1));
+    }
+}
@@ -0,0 +1,224 @@
+/*
+ * Lossless encoding as described in Annex H
+ */
+
+use alloc::vec::Vec;
+
+/// # Predictors used in lossless encoding
+///
+/// Selection values of Table H.1 with the reconstructed samples `Ra` left of,
+/// `Rb` above and `Rc` diagonally above left of the predicted sample.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Predictor {
+    /// Px = Ra
+    Ra = 1,
+
+    /// Px = Rb
+    Rb = 2,
+
+    /// Px = Rc
+    Rc = 3,
+
+    /// Px = Ra + Rb - Rc
+    RaPlusRbMinusRc = 4,
+
+    /// Px = Ra + ((Rb - Rc) / 2)
+    RaPlusHalfRbMinusRc = 5,
+
+    /// Px = Rb + ((Ra - Rc) / 2)
+    RbPlusHalfRaMinusRc = 6,
+
+    /// Px = (Ra + Rb) / 2
+    AverageRaRb = 7,
+}
+
+impl Predictor {
+    #[inline]
+    fn predict(self, ra: i32, rb: i32, rc: i32) -> i32 {
+        use Predictor::*;
+
+        match self {
+            Ra => ra,
+            Rb => rb,
+            Rc => rc,
+            RaPlusRbMinusRc => ra + rb - rc,
+            RaPlusHalfRbMinusRc => ra + ((rb - rc) >> 1),
+            RbPlusHalfRaMinusRc => rb + ((ra - rc) >> 1),
+            AverageRaRb => (ra + rb) >> 1,
+        }
+    }
+}
+
+/// Computes the differences of the samples of a component to their prediction (H.1.2)
+///
+/// The samples must already be point transformed to `precision` bits.
+/// The first row of the image and of each restart interval is predicted by `Ra`
+/// and the first sample of every other row by `Rb`.
+/// `restart_rows` is the number of rows in a restart interval or 0 without restarts.
+///
+/// The differences are calculated modulo 2^16.
+pub(crate) fn compute_differences(
+    samples: &[u16],
+    width: usize,
+    predictor: Predictor,
+    precision: u8,
+    restart_rows: usize,
+) -> Vec<u16> {
+    debug_assert!(width > 0);
+    debug_assert!(samples.len() % width == 0);
+
+    debug_assert!(precision > 0);
+
+    let initial_prediction = 1 << (precision - 1);
+
+    let mut differences = Vec::with_capacity(samples.len());
+
+    for (y, row) in samples.chunks_exact(width).enumerate() {
+        let first_row = y == 0 || (restart_rows > 0 && y % restart_rows == 0);
+
+        for (x, &sample) in row.iter().enumerate() {
+            let prediction = if x == 0 {
+                if first_row {
+                    initial_prediction
+                } else {
+                    i32::from(samples[(y - 1) * width])
+                }
+            } else if first_row {
+                i32::from(row[x - 1])
+            } else {
+                let ra = i32::from(row[x - 1]);
+                let rb = i32::from(samples[(y - 1) * width + x]);
+                let rc = i32::from(samples[(y - 1) * width + x - 1]);
+
+                predictor.predict(ra, rb, rc)
+            };
+
+            differences.push((i32::from(sample) - prediction) as u16);
+        }
+    }
+
+    differences
+}
+
+/// Returns the magnitude category of a difference (Table H.2)
+#[inline]
+pub(crate) fn get_difference_category(difference: u16) -> u8 {
+    if difference == 0x8000 {
+        16
+    } else {
+        (16 - (difference as i16).unsigned_abs().leading_zeros()) as u8
+    }
+}
+
+/// Returns the restart interval for a lossless scan
+///
+/// Restart intervals in lossless mode are rounded to a multiple of the MCUs in a row
+/// so that each restart interval starts at the beginning of a row.
+pub(crate) fn get_restart_interval(restart_interval: u16, width: u16) -> u16 {
+    let width = u32::from(width);
+    let rows = (u32::from(restart_interval) + width - 1) / width;
+
+    let interval = (rows * width).min(u32::from(u16::MAX) / width * width);
+
+    interval as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    fn reconstruct(
+        differences: &[u16],
+        width: usize,
+        predictor: Predictor,
+        precision: u8,
+        restart_rows: usize,
+    ) -> Vec<u16> {
+        let mut samples: Vec<u16> = Vec::with_capacity(differences.len());
+
+        for (i, &diff) in differences.iter().enumerate() {
+            let (x, y) = (i % width, i / width);
+            let first_row = y == 0 || (restart_rows > 0 && y % restart_rows == 0);
+
+            let prediction = if x == 0 && first_row {
+                1 << (precision - 1)
+            } else if x == 0 {
+                i32::from(samples[i - width])
+            } else if first_row {
+                i32::from(samples[i - 1])
+            } else {
+                predictor.predict(
+                    i32::from(samples[i - 1]),
+                    i32::from(samples[i - width]),
+                    i32::from(samples[i - width - 1]),
+                )
+            };
+
+            samples.push((prediction as u16).wrapping_add(diff));
+        }
+
+        samples
+    }
+
+    #[test]
+    fn test_differences() {
+        use Predictor::*;
+
+        let width = 7;
+        let height = 9;
+
+        let samples: Vec<u16> = (0..width * height)
+            .map(|i| ((i * 7919 + (i / width) * 104729) % 65536) as u16)
+            .collect();
+
+        for predictor in [
+            Ra,
+            Rb,
+            Rc,
+            RaPlusRbMinusRc,
+            RaPlusHalfRbMinusRc,
+            RbPlusHalfRaMinusRc,
+            AverageRaRb,
+        ] {
+            for restart_rows in [0, 1, 4] {
+                let differences = compute_differences(&samples, width, predictor, 16, restart_rows);
+                assert_eq!(
+                    reconstruct(&differences, width, predictor, 16, restart_rows),
+                    samples
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_first_row_prediction() {
+        let samples = vec![10, 12, 11, 10, 15, 20];
+
+        let differences = compute_differences(&samples, 3, Predictor::Rc, 4, 0);
+        assert_eq!(differences, vec![2, 2, (-1i16) as u16, 0, 5, 8]);
+    }
+
+    #[test]
+    fn test_difference_category() {
+        assert_eq!(get_difference_category(0), 0);
+        assert_eq!(get_difference_category(1), 1);
+        assert_eq!(get_difference_category((-1i16) as u16), 1);
+        assert_eq!(get_difference_category(255), 8);
+        assert_eq!(get_difference_category((-256i16) as u16), 9);
+        assert_eq!(get_difference_category(32767), 15);
+        assert_eq!(get_difference_category((-32767i16) as u16), 15);
+        assert_eq!(get_difference_category(0x8000), 16);
+    }
+
+    #[test]
+    fn test_restart_interval() {
+        assert_eq!(get_restart_interval(1, 100), 100);
+        assert_eq!(get_restart_interval(100, 100), 100);
+        assert_eq!(get_restart_interval(101, 100), 200);
+        assert_eq!(get_restart_interval(65535, 1000), 65000);
+        assert_eq!(get_restart_interval(10, 65535), 65535);
+    }
+}
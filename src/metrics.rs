@@ -0,0 +1,194 @@
+/*
+ * Objective image quality metrics used to choose the quality of the encoder
+ */
+
+use crate::hierarchical::Plane;
+
+/// # Minimum image quality measured by an objective metric
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum QualityTarget {
+    /// Peak signal-to-noise ratio in decibels
+    Psnr(f64),
+
+    /// Mean structural similarity (SSIM) between 0 and 1
+    Ssim(f64),
+}
+
+impl QualityTarget {
+    /// Returns if the reconstructed planes of all components reach the target
+    ///
+    /// The PSNR is computed from the errors of the samples of all planes and the SSIM is
+    /// the mean SSIM of the planes.
+    pub(crate) fn is_reached(self, original: &[Plane], reconstructed: &[Plane]) -> bool {
+        debug_assert_eq!(original.len(), reconstructed.len());
+
+        match self {
+            QualityTarget::Psnr(psnr) => get_combined_psnr(original, reconstructed) >= psnr,
+            QualityTarget::Ssim(ssim) => {
+                let sum: f64 = original
+                    .iter()
+                    .zip(reconstructed.iter())
+                    .map(|(a, b)| get_ssim(a, b))
+                    .sum();
+
+                sum / original.len() as f64 >= ssim
+            }
+        }
+    }
+}
+
+/// Returns the PSNR of 8 bit samples, which is infinite for identical planes
+#[cfg(test)]
+pub(crate) fn get_psnr(a: &Plane, b: &Plane) -> f64 {
+    get_combined_psnr(core::slice::from_ref(a), core::slice::from_ref(b))
+}
+
+/// Returns the PSNR of the 8 bit samples of all planes, which is infinite for identical planes
+fn get_combined_psnr(a: &[Plane], b: &[Plane]) -> f64 {
+    let mut sum = 0u64;
+    let mut count = 0;
+
+    for (a, b) in a.iter().zip(b.iter()) {
+        debug_assert_eq!(a.samples.len(), b.samples.len());
+
+        sum += a
+            .samples
+            .iter()
+            .zip(b.samples.iter())
+            .map(|(&a, &b)| {
+                let diff = i64::from(a) - i64::from(b);
+                (diff * diff) as u64
+            })
+            .sum::<u64>();
+
+        count += a.samples.len();
+    }
+
+    if sum == 0 {
+        return f64::INFINITY;
+    }
+
+    let mse = sum as f64 / count as f64;
+
+    10.0 * (255.0 * 255.0 / mse).log10()
+}
+
+/// Returns the mean SSIM of 8 bit samples
+///
+/// The SSIM is computed for windows of 8x8 samples with uniform weights, which overlap
+/// by 4 samples. Smaller planes are measured with a single window.
+pub(crate) fn get_ssim(a: &Plane, b: &Plane) -> f64 {
+    debug_assert_eq!(a.width, b.width);
+    debug_assert_eq!(a.height, b.height);
+
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let window_width = a.width.min(8);
+    let window_height = a.height.min(8);
+    let count = (window_width * window_height) as f64;
+
+    let mut sum = 0.0;
+    let mut windows = 0;
+
+    for start_y in (0..=a.height - window_height).step_by(4) {
+        for start_x in (0..=a.width - window_width).step_by(4) {
+            let mut sum_a = 0.0;
+            let mut sum_b = 0.0;
+            let mut sum_aa = 0.0;
+            let mut sum_bb = 0.0;
+            let mut sum_ab = 0.0;
+
+            for y in start_y..start_y + window_height {
+                for x in start_x..start_x + window_width {
+                    let a = f64::from(a.samples[y * a.width + x]);
+                    let b = f64::from(b.samples[y * b.width + x]);
+
+                    sum_a += a;
+                    sum_b += b;
+                    sum_aa += a * a;
+                    sum_bb += b * b;
+                    sum_ab += a * b;
+                }
+            }
+
+            let mean_a = sum_a / count;
+            let mean_b = sum_b / count;
+            let var_a = sum_aa / count - mean_a * mean_a;
+            let var_b = sum_bb / count - mean_b * mean_b;
+            let covar = sum_ab / count - mean_a * mean_b;
+
+            sum += ((2.0 * mean_a * mean_b + C1) * (2.0 * covar + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            windows += 1;
+        }
+    }
+
+    sum / f64::from(windows)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::{get_combined_psnr, get_psnr, get_ssim, QualityTarget};
+    use crate::hierarchical::Plane;
+
+    fn create_plane(width: usize, height: usize, f: impl Fn(usize, usize) -> i16) -> Plane {
+        let samples: Vec<i16> = (0..width * height)
+            .map(|i| f(i % width, i / width))
+            .collect();
+
+        Plane::new(width, height, samples)
+    }
+
+    #[test]
+    fn test_psnr() {
+        let a = create_plane(20, 10, |x, y| (x * 10 + y) as i16);
+        let b = create_plane(20, 10, |x, y| (x * 10 + y + 1) as i16);
+
+        assert_eq!(get_psnr(&a, &a), f64::INFINITY);
+        assert!((get_psnr(&a, &b) - 48.1308).abs() < 0.001);
+
+        let a = [a];
+        let b = [b];
+
+        assert!(QualityTarget::Psnr(48.0).is_reached(&a, &b));
+        assert!(!QualityTarget::Psnr(49.0).is_reached(&a, &b));
+
+        // The errors of all planes are combined
+        let a = [a[0].clone(), a[0].clone()];
+        let b = [a[0].clone(), b[0].clone()];
+
+        assert!((get_combined_psnr(&a, &b) - 51.1411).abs() < 0.001);
+        assert!(QualityTarget::Psnr(51.0).is_reached(&a, &b));
+        assert!(!QualityTarget::Psnr(52.0).is_reached(&a, &b));
+    }
+
+    #[test]
+    fn test_ssim() {
+        let a = create_plane(20, 10, |x, y| (x * 10 + y) as i16);
+        let noisy = create_plane(20, 10, |x, y| (x * 10 + y + (x * 7 + y * 3) % 11) as i16);
+        let flat = create_plane(20, 10, |_, _| 100);
+
+        assert!((get_ssim(&a, &a) - 1.0).abs() < 1e-9);
+
+        let ssim_noisy = get_ssim(&a, &noisy);
+        let ssim_flat = get_ssim(&a, &flat);
+
+        assert!(ssim_noisy < 1.0);
+        assert!(ssim_flat < ssim_noisy);
+
+        // The mean of the planes
+        let original = [a.clone(), a.clone()];
+        let reconstructed = [a.clone(), noisy];
+        let ssim = (1.0 + ssim_noisy) / 2.0;
+
+        assert!(QualityTarget::Ssim(ssim - 1e-6).is_reached(&original, &reconstructed));
+        assert!(!QualityTarget::Ssim(ssim + 1e-6).is_reached(&original, &reconstructed));
+
+        // Planes smaller than a window
+        let small = create_plane(3, 2, |x, y| (x + y) as i16);
+        assert!((get_ssim(&small, &small) - 1.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,83 @@
+/*
+ * Distribution of independent work to multiple threads
+ *
+ * Without the `parallel` feature all work is done on the calling thread.
+ */
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+/// Returns the number of threads available to the process
+#[cfg(feature = "parallel")]
+pub(crate) fn available_threads() -> usize {
+    std::thread::available_parallelism()
+        .map(|threads| threads.get())
+        .unwrap_or(1)
+}
+
+/// Splits `0..len` into up to `threads` ranges of similar size and maps each range with `f`
+///
+/// Returns the results in the order of the ranges.
+// Scoped threads are only used with the `parallel` feature, which requires Rust 1.63
+#[clippy::msrv = "1.63"]
+pub(crate) fn map_ranges<R, F>(len: usize, threads: usize, f: F) -> Vec<R>
+where
+    R: Send,
+    F: Fn(Range<usize>) -> R + Sync,
+{
+    #[cfg(feature = "parallel")]
+    if threads > 1 && len > 1 {
+        let chunk_size = (len + threads - 1) / threads;
+        let f = &f;
+
+        return std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..len)
+                .step_by(chunk_size)
+                .map(|start| scope.spawn(move || f(start..(start + chunk_size).min(len))))
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|err| std::panic::resume_unwind(err))
+                })
+                .collect()
+        });
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    let _ = threads;
+
+    vec![f(0..len)]
+}
+
+/// Concatenates the results of [map_ranges]
+pub(crate) fn concat<T: Copy>(mut chunks: Vec<Vec<T>>) -> Vec<T> {
+    if chunks.len() == 1 {
+        chunks.pop().unwrap()
+    } else {
+        chunks.concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::{concat, map_ranges};
+
+    #[test]
+    fn test_map_ranges() {
+        for threads in 1..6 {
+            for len in 0..10 {
+                let ranges = map_ranges(len, threads, |range| range.collect::<Vec<_>>());
+
+                assert!(ranges.len() <= threads.max(1));
+                assert_eq!(concat(ranges), (0..len).collect::<Vec<_>>());
+            }
+        }
+    }
+}
@@ -217,9 +217,10 @@ impl QuantizationTable {
         table: &QuantizationTableType,
         quality: u8,
         luma: bool,
+        precision: u8,
     ) -> QuantizationTable {
         let table = match table {
-            QuantizationTableType::Custom(table) => Self::get_user_table(table),
+            QuantizationTableType::Custom(table) => Self::get_user_table(table, precision),
             table => {
                 let table = if luma {
                     &DEFAULT_LUMA_TABLES[table.index()]
@@ -247,10 +248,13 @@ impl QuantizationTable {
         }
     }
 
-    fn get_user_table(table: &[u16; 64]) -> [NonZeroU16; 64] {
+    fn get_user_table(table: &[u16; 64], precision: u8) -> [NonZeroU16; 64] {
+        // 16 bit table values are only allowed for 12 bit samples
+        let max_value = if precision == 8 { 255 } else { 2 << 10 };
+
         let mut q_table = [NonZeroU16::new(1).unwrap(); 64];
         for (i, &v) in table.iter().enumerate() {
-            q_table[i] = match NonZeroU16::new(v.clamp(1, 2 << 10) << 3) {
+            q_table[i] = match NonZeroU16::new(v.clamp(1, max_value) << 3) {
                 Some(v) => v,
                 None => panic!("Invalid quantization table value: {}", v),
             };
@@ -283,8 +287,13 @@ impl QuantizationTable {
     }
 
     #[inline]
-    pub fn get(&self, index: usize) -> u8 {
-        (self.table[index].get() >> 3) as u8
+    pub fn get(&self, index: usize) -> u16 {
+        self.table[index].get() >> 3
+    }
+
+    /// Returns if the table contains values that need a 16 bit precision
+    pub fn is_16bit(&self) -> bool {
+        self.table.iter().any(|v| (v.get() >> 3) > 255)
     }
 
     #[inline]
@@ -305,22 +314,44 @@ impl QuantizationTable {
 
         product as i16
     }
+
+    /// Quantizes values outside the range of an i16 as produced for 12 bit samples
+    #[inline]
+    pub fn quantize_wide(&self, value: i32, index: usize) -> i16 {
+        self.quantize_scaled(value, index, 0)
+    }
+
+    /// Quantizes values of samples with `extra_bits` more bits than the precision of the table
+    #[inline]
+    pub fn quantize_scaled(&self, value: i32, index: usize, extra_bits: u8) -> i16 {
+        let divisor = i32::from(self.table[index].get()) << extra_bits;
+
+        let product = (value.abs() + divisor / 2) / divisor;
+
+        if value < 0 {
+            -product as i16
+        } else {
+            product as i16
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use alloc::boxed::Box;
+
     use crate::quantization::{QuantizationTable, QuantizationTableType};
 
     #[test]
     fn test_new_100() {
-        let q = QuantizationTable::new_with_quality(&QuantizationTableType::Default, 100, true);
+        let q = QuantizationTable::new_with_quality(&QuantizationTableType::Default, 100, true, 8);
 
         for &v in &q.table {
             let v = v.get();
             assert_eq!(v, 1 << 3);
         }
 
-        let q = QuantizationTable::new_with_quality(&QuantizationTableType::Default, 100, false);
+        let q = QuantizationTable::new_with_quality(&QuantizationTableType::Default, 100, false, 8);
 
         for &v in &q.table {
             let v = v.get();
@@ -330,10 +361,30 @@ mod tests {
 
     #[test]
     fn test_new_100_quantize() {
-        let q = QuantizationTable::new_with_quality(&QuantizationTableType::Default, 100, true);
+        let q = QuantizationTable::new_with_quality(&QuantizationTableType::Default, 100, true, 8);
 
         for i in -255..255 {
             assert_eq!(i, q.quantize(i << 3, 0));
         }
     }
+
+    #[test]
+    fn test_custom_precision() {
+        let table = QuantizationTableType::Custom(Box::new([1000; 64]));
+
+        let q = QuantizationTable::new_with_quality(&table, 100, true, 8);
+        assert_eq!(q.get(0), 255);
+        assert!(!q.is_16bit());
+
+        let q = QuantizationTable::new_with_quality(&table, 100, true, 12);
+        assert_eq!(q.get(0), 1000);
+        assert!(q.is_16bit());
+
+        assert_eq!(q.quantize_wide(1499 << 3, 0), 1);
+        assert_eq!(q.quantize_wide(1501 << 3, 0), 2);
+        assert_eq!(q.quantize_wide(-16384 << 3, 0), -16);
+
+        assert_eq!(q.quantize_scaled(1499 << 7, 0, 4), 1);
+        assert_eq!(q.quantize_scaled(1501 << 7, 0, 4), 2);
+    }
 }
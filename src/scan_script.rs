@@ -0,0 +1,573 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::encoder::Component;
+use crate::EncodingError;
+
+/// Maximum value of the successive approximation bit positions for 8 bit images
+const MAX_APPROXIMATION: u8 = 10;
+
+/// Maximum value of the successive approximation bit positions for 12 bit images
+const MAX_APPROXIMATION_12_BIT: u8 = 13;
+
+/// Maximum number of blocks in a MCU of an interleaved scan (B.2.3)
+const MAX_BLOCKS_IN_MCU: u8 = 10;
+
+/// # A single scan of a progressive image
+///
+/// Equivalent to libjpeg's `jpeg_scan_info`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ScanInfo {
+    /// Indices of the components in this scan
+    ///
+    /// The index is the position of the component in the image, e.g. 0 for Y, 1 for Cb
+    /// and 2 for Cr in YCbCr images.
+    pub components: Vec<u8>,
+
+    /// Index of the first coefficient in zig-zag order (Ss)
+    pub spectral_start: u8,
+
+    /// Index of the last coefficient in zig-zag order (Se)
+    pub spectral_end: u8,
+
+    /// Point transform of the previous scan for these coefficients or 0 for the first scan (Ah)
+    pub approximation_high: u8,
+
+    /// Point transform of this scan (Al)
+    pub approximation_low: u8,
+}
+
+impl ScanInfo {
+    /// Create a new scan
+    pub fn new(
+        components: &[u8],
+        spectral_start: u8,
+        spectral_end: u8,
+        approximation_high: u8,
+        approximation_low: u8,
+    ) -> ScanInfo {
+        ScanInfo {
+            components: components.to_vec(),
+            spectral_start,
+            spectral_end,
+            approximation_high,
+            approximation_low,
+        }
+    }
+
+    pub(crate) fn is_dc(&self) -> bool {
+        self.spectral_start == 0
+    }
+
+    pub(crate) fn is_refinement(&self) -> bool {
+        self.approximation_high > 0
+    }
+}
+
+/// # Scan script for progressive encoding
+///
+/// Lists all scans of a progressive image in the order they are written.
+/// Scripts are validated against the progression rules of Annex G before encoding.
+///
+/// ## Example
+/// ```
+/// use jpeg_encoder::{ScanInfo, ScanScript};
+///
+/// // Script for a grayscale image
+/// let mut script = ScanScript::new();
+/// script.push(ScanInfo::new(&[0], 0, 0, 0, 1));
+/// script.push(ScanInfo::new(&[0], 1, 5, 0, 2));
+/// script.push(ScanInfo::new(&[0], 6, 63, 0, 2));
+/// script.push(ScanInfo::new(&[0], 1, 63, 2, 1));
+/// script.push(ScanInfo::new(&[0], 0, 0, 1, 0));
+/// script.push(ScanInfo::new(&[0], 1, 63, 1, 0));
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ScanScript {
+    scans: Vec<ScanInfo>,
+}
+
+impl ScanScript {
+    /// Create an empty scan script
+    pub fn new() -> ScanScript {
+        ScanScript { scans: Vec::new() }
+    }
+
+    /// Append a scan to the script
+    pub fn push(&mut self, scan: ScanInfo) {
+        self.scans.push(scan);
+    }
+
+    /// Return the scans of this script
+    pub fn scans(&self) -> &[ScanInfo] {
+        &self.scans
+    }
+
+    /// Number of scans
+    pub fn len(&self) -> usize {
+        self.scans.len()
+    }
+
+    /// Returns true if the script contains no scans
+    pub fn is_empty(&self) -> bool {
+        self.scans.is_empty()
+    }
+
+    /// Creates a script which splits the AC coefficients evenly into `scans - 1` scans per component
+    ///
+    /// With successive approximation the DC and AC coefficients are first transferred without their
+    /// least significant bit, followed by refinement scans for all coefficients.
+    ///
+    /// If `interleaved_dc` is set, the DC coefficients of all components are transferred in a
+    /// single interleaved scan.
+    pub(crate) fn spectral_selection(
+        num_components: usize,
+        scans: u8,
+        successive_approximation: bool,
+        interleaved_dc: bool,
+    ) -> ScanScript {
+        let approximation_low = u8::from(successive_approximation);
+
+        let all_components: Vec<u8> = (0..num_components as u8).collect();
+
+        let mut script = ScanScript::new();
+
+        let push_dc_scans =
+            |script: &mut ScanScript, approximation_high: u8, approximation_low: u8| {
+                if interleaved_dc {
+                    script.push(ScanInfo::new(
+                        &all_components,
+                        0,
+                        0,
+                        approximation_high,
+                        approximation_low,
+                    ));
+                } else {
+                    for &component in &all_components {
+                        script.push(ScanInfo::new(
+                            &[component],
+                            0,
+                            0,
+                            approximation_high,
+                            approximation_low,
+                        ));
+                    }
+                }
+            };
+
+        // Phase 1: DC Scan
+        //          Only the DC coefficients can be transfer in the first scans
+        push_dc_scans(&mut script, 0, approximation_low);
+
+        // Phase 2: AC scans
+        let scans = scans as usize - 1;
+
+        for scan in 0..scans {
+            let start = 1 + scan * 63 / scans;
+            let end = 1 + (scan + 1) * 63 / scans;
+
+            for component in 0..num_components {
+                script.push(ScanInfo::new(
+                    &[component as u8],
+                    start as u8,
+                    end as u8 - 1,
+                    0,
+                    approximation_low,
+                ));
+            }
+        }
+
+        // Phase 3: Refinement scans for the least significant bit
+        if successive_approximation {
+            push_dc_scans(&mut script, 1, 0);
+
+            for &component in &all_components {
+                script.push(ScanInfo::new(&[component], 1, 63, 1, 0));
+            }
+        }
+
+        script
+    }
+
+    /// Validate the script against the progression rules of Annex G
+    ///
+    /// - Each scan contains between 1 and 4 components in increasing order
+    /// - Interleaved scans contain at most 10 blocks per MCU
+    /// - DC scans must not contain AC coefficients
+    /// - AC scans contain exactly one component and must follow the first DC scan of this component
+    /// - The first scan of a coefficient has no successive approximation high bit
+    /// - Every following scan of a coefficient refines it by exactly one bit
+    /// - All coefficients of all components are transferred with full precision
+    /// - Successive approximation bit positions don't exceed 10 for 8 bit and 13 for 12 bit images
+    pub(crate) fn validate(
+        &self,
+        components: &[Component],
+        sample_precision: u8,
+    ) -> Result<(), EncodingError> {
+        fn error(scan: usize, reason: &'static str) -> EncodingError {
+            EncodingError::InvalidScanScript {
+                scan: Some(scan),
+                reason,
+            }
+        }
+
+        if self.scans.is_empty() {
+            return Err(EncodingError::InvalidScanScript {
+                scan: None,
+                reason: "Script contains no scans",
+            });
+        }
+
+        let num_components = components.len();
+
+        let max_approximation = if sample_precision > 8 {
+            MAX_APPROXIMATION_12_BIT
+        } else {
+            MAX_APPROXIMATION
+        };
+
+        // Interleaved mode is only supported with h/v sampling factors of 1 or 2.
+        let supports_interleaved = components
+            .iter()
+            .all(|c| c.horizontal_sampling_factor <= 2 && c.vertical_sampling_factor <= 2);
+
+        // Last successive approximation low bit for each coefficient or None if not sent yet
+        let mut last_bit_position = vec![[None; 64]; num_components];
+
+        for (i, scan) in self.scans.iter().enumerate() {
+            if scan.components.is_empty() || scan.components.len() > 4 {
+                return Err(error(i, "Scan must contain between 1 and 4 components"));
+            }
+
+            for (j, &component) in scan.components.iter().enumerate() {
+                if usize::from(component) >= num_components {
+                    return Err(error(i, "Invalid component index"));
+                }
+
+                if j > 0 && component <= scan.components[j - 1] {
+                    return Err(error(i, "Components must be in increasing order"));
+                }
+            }
+
+            if scan.components.len() > 1 {
+                if !supports_interleaved {
+                    return Err(error(
+                        i,
+                        "Interleaved scans are not supported with sampling factors of 4",
+                    ));
+                }
+
+                let blocks_in_mcu: u8 = scan
+                    .components
+                    .iter()
+                    .map(|&c| {
+                        let component = &components[usize::from(c)];
+                        component.horizontal_sampling_factor * component.vertical_sampling_factor
+                    })
+                    .sum();
+
+                if blocks_in_mcu > MAX_BLOCKS_IN_MCU {
+                    return Err(error(i, "Too many blocks in MCU"));
+                }
+            }
+
+            if scan.spectral_start > scan.spectral_end || scan.spectral_end > 63 {
+                return Err(error(i, "Invalid spectral selection"));
+            }
+
+            if scan.spectral_start == 0 && scan.spectral_end != 0 {
+                return Err(error(i, "DC scans must not contain AC coefficients"));
+            }
+
+            if scan.spectral_start > 0 && scan.components.len() != 1 {
+                return Err(error(i, "AC scans must contain exactly one component"));
+            }
+
+            if scan.approximation_high > max_approximation
+                || scan.approximation_low > max_approximation
+            {
+                return Err(error(i, "Invalid successive approximation"));
+            }
+
+            for &component in &scan.components {
+                let bit_positions = &mut last_bit_position[usize::from(component)];
+
+                if scan.spectral_start > 0 && bit_positions[0].is_none() {
+                    return Err(error(
+                        i,
+                        "AC scan precedes the first DC scan of the component",
+                    ));
+                }
+
+                for coefficient in scan.spectral_start..=scan.spectral_end {
+                    let bit_position = &mut bit_positions[usize::from(coefficient)];
+
+                    match *bit_position {
+                        None if scan.approximation_high != 0 => {
+                            return Err(error(i, "First scan of a coefficient must have Ah = 0"));
+                        }
+                        Some(last) if scan.approximation_low >= last => {
+                            return Err(error(
+                                i,
+                                "Coefficients were already transferred with this precision",
+                            ));
+                        }
+                        Some(last) if scan.approximation_high != last => {
+                            return Err(error(i, "Ah must be equal to Al of the previous scan"));
+                        }
+                        Some(_) if scan.approximation_low + 1 != scan.approximation_high => {
+                            return Err(error(i, "Refinement scans must have Al = Ah - 1"));
+                        }
+                        _ => {}
+                    }
+
+                    *bit_position = Some(scan.approximation_low);
+                }
+            }
+        }
+
+        let complete = last_bit_position
+            .iter()
+            .all(|bit_positions| bit_positions.iter().all(|&b| b == Some(0)));
+
+        if !complete {
+            return Err(EncodingError::InvalidScanScript {
+                scan: None,
+                reason: "Not all coefficients are transferred with full precision",
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl From<Vec<ScanInfo>> for ScanScript {
+    fn from(scans: Vec<ScanInfo>) -> Self {
+        ScanScript { scans }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use crate::encoder::Component;
+    use crate::{EncodingError, ScanInfo, ScanScript};
+
+    fn components(num_components: usize, luma_sampling: (u8, u8)) -> Vec<Component> {
+        (0..num_components)
+            .map(|i| {
+                let (h, v) = if i == 0 { luma_sampling } else { (1, 1) };
+                Component {
+                    id: i as u8,
+                    quantization_table: 0,
+                    dc_huffman_table: 0,
+                    ac_huffman_table: 0,
+                    horizontal_sampling_factor: h,
+                    vertical_sampling_factor: v,
+                }
+            })
+            .collect()
+    }
+
+    fn assert_invalid(script: &ScanScript, num_components: usize, scan: Option<usize>) {
+        match script.validate(&components(num_components, (1, 1)), 8) {
+            Err(EncodingError::InvalidScanScript { scan: s, .. }) => assert_eq!(s, scan),
+            other => panic!("Expected invalid scan script error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_spectral_selection_valid() {
+        for num_components in [1, 3, 4] {
+            for scans in 2..=64 {
+                for successive_approximation in [false, true] {
+                    for interleaved_dc in [false, true] {
+                        let script = ScanScript::spectral_selection(
+                            num_components,
+                            scans,
+                            successive_approximation,
+                            interleaved_dc,
+                        );
+                        script
+                            .validate(&components(num_components, (2, 2)), 8)
+                            .unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_valid_script() {
+        let script = ScanScript::from(vec![
+            ScanInfo::new(&[0], 0, 0, 0, 1),
+            ScanInfo::new(&[0], 1, 5, 0, 2),
+            ScanInfo::new(&[0], 6, 63, 0, 2),
+            ScanInfo::new(&[0], 1, 63, 2, 1),
+            ScanInfo::new(&[0], 0, 0, 1, 0),
+            ScanInfo::new(&[0], 1, 63, 1, 0),
+        ]);
+
+        script.validate(&components(1, (1, 1)), 8).unwrap();
+    }
+
+    #[test]
+    fn test_interleaved() {
+        let script = ScanScript::from(vec![
+            ScanInfo::new(&[0, 1, 2], 0, 0, 0, 0),
+            ScanInfo::new(&[0], 1, 63, 0, 0),
+            ScanInfo::new(&[1], 1, 63, 0, 0),
+            ScanInfo::new(&[2], 1, 63, 0, 0),
+        ]);
+
+        script.validate(&components(3, (2, 2)), 8).unwrap();
+
+        // Sampling factors of 4 need non interleaved scans
+        match script.validate(&components(3, (4, 1)), 8) {
+            Err(EncodingError::InvalidScanScript { scan: Some(0), .. }) => {}
+            other => panic!("Expected invalid scan script error, got {:?}", other),
+        }
+
+        // Components in wrong order
+        let script = ScanScript::from(vec![
+            ScanInfo::new(&[1, 0, 2], 0, 0, 0, 0),
+            ScanInfo::new(&[0], 1, 63, 0, 0),
+            ScanInfo::new(&[1], 1, 63, 0, 0),
+            ScanInfo::new(&[2], 1, 63, 0, 0),
+        ]);
+        assert_invalid(&script, 3, Some(0));
+
+        // Interleaved AC scan
+        let script = ScanScript::from(vec![
+            ScanInfo::new(&[0, 1, 2], 0, 0, 0, 0),
+            ScanInfo::new(&[0, 1, 2], 1, 63, 0, 0),
+        ]);
+        assert_invalid(&script, 3, Some(1));
+    }
+
+    #[test]
+    fn test_already_transferred() {
+        let reason = |scans: Vec<ScanInfo>| match ScanScript::from(scans)
+            .validate(&components(1, (1, 1)), 8)
+        {
+            Err(EncodingError::InvalidScanScript { reason, .. }) => reason,
+            other => panic!("Expected invalid scan script error, got {:?}", other),
+        };
+
+        let already_transferred = "Coefficients were already transferred with this precision";
+
+        assert_eq!(
+            reason(vec![
+                ScanInfo::new(&[0], 0, 0, 0, 0),
+                ScanInfo::new(&[0], 0, 0, 0, 0),
+            ]),
+            already_transferred
+        );
+
+        assert_eq!(
+            reason(vec![
+                ScanInfo::new(&[0], 0, 0, 0, 1),
+                ScanInfo::new(&[0], 1, 63, 0, 1),
+                ScanInfo::new(&[0], 1, 10, 1, 1),
+            ]),
+            already_transferred
+        );
+
+        assert_eq!(
+            reason(vec![
+                ScanInfo::new(&[0], 0, 0, 0, 0),
+                ScanInfo::new(&[0], 1, 63, 0, 2),
+                ScanInfo::new(&[0], 1, 63, 2, 0),
+            ]),
+            "Refinement scans must have Al = Ah - 1"
+        );
+    }
+
+    #[test]
+    fn test_approximation_limit() {
+        let script = |al: u8| {
+            let mut scans = vec![ScanInfo::new(&[0], 0, 0, 0, al)];
+            scans.push(ScanInfo::new(&[0], 1, 63, 0, 0));
+
+            for bit in (0..al).rev() {
+                scans.push(ScanInfo::new(&[0], 0, 0, bit + 1, bit));
+            }
+
+            ScanScript::from(scans)
+        };
+
+        script(10).validate(&components(1, (1, 1)), 8).unwrap();
+        assert_invalid(&script(11), 1, Some(0));
+
+        // 12 bit images allow bit positions up to 13
+        script(13).validate(&components(1, (1, 1)), 12).unwrap();
+        assert!(script(14).validate(&components(1, (1, 1)), 12).is_err());
+    }
+
+    #[test]
+    fn test_invalid_scripts() {
+        assert_invalid(&ScanScript::new(), 1, None);
+
+        // Bad component index
+        let script = ScanScript::from(vec![ScanInfo::new(&[1], 0, 0, 0, 0)]);
+        assert_invalid(&script, 1, Some(0));
+
+        // DC and AC coefficients in one scan
+        let script = ScanScript::from(vec![ScanInfo::new(&[0], 0, 63, 0, 0)]);
+        assert_invalid(&script, 1, Some(0));
+
+        // AC before DC
+        let script = ScanScript::from(vec![
+            ScanInfo::new(&[0], 1, 63, 0, 0),
+            ScanInfo::new(&[0], 0, 0, 0, 0),
+        ]);
+        assert_invalid(&script, 1, Some(0));
+
+        // Refinement without first scan
+        let script = ScanScript::from(vec![
+            ScanInfo::new(&[0], 0, 0, 0, 0),
+            ScanInfo::new(&[0], 1, 63, 1, 0),
+        ]);
+        assert_invalid(&script, 1, Some(1));
+
+        // Refinement by more than one bit
+        let script = ScanScript::from(vec![
+            ScanInfo::new(&[0], 0, 0, 0, 0),
+            ScanInfo::new(&[0], 1, 63, 0, 2),
+            ScanInfo::new(&[0], 1, 63, 2, 0),
+        ]);
+        assert_invalid(&script, 1, Some(2));
+
+        // Coefficients sent twice
+        let script = ScanScript::from(vec![
+            ScanInfo::new(&[0], 0, 0, 0, 0),
+            ScanInfo::new(&[0], 1, 63, 0, 0),
+            ScanInfo::new(&[0], 10, 20, 0, 0),
+        ]);
+        assert_invalid(&script, 1, Some(2));
+
+        // Refinement without new bits
+        let script = ScanScript::from(vec![
+            ScanInfo::new(&[0], 0, 0, 0, 0),
+            ScanInfo::new(&[0], 1, 63, 0, 2),
+            ScanInfo::new(&[0], 1, 63, 2, 2),
+        ]);
+        assert_invalid(&script, 1, Some(2));
+
+        // Missing coefficients
+        let script = ScanScript::from(vec![
+            ScanInfo::new(&[0], 0, 0, 0, 0),
+            ScanInfo::new(&[0], 1, 62, 0, 0),
+        ]);
+        assert_invalid(&script, 1, None);
+
+        // Missing refinement
+        let script = ScanScript::from(vec![
+            ScanInfo::new(&[0], 0, 0, 0, 1),
+            ScanInfo::new(&[0], 1, 63, 0, 0),
+        ]);
+        assert_invalid(&script, 1, None);
+    }
+}
@@ -0,0 +1,263 @@
+/*
+ * Iterative chroma downsampling similar to the "sharp YUV" conversion of libwebp
+ *
+ * Averaging the chroma of an area mixes the colors of both sides of an edge, which
+ * shifts the hue and brightness of saturated colors after upsampling in the decoder.
+ * Instead the subsampled chroma and the luma are refined in a few iterations so that
+ * the RGB image reconstructed with linear chroma upsampling matches the source.
+ */
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::ColorMatrix;
+
+const ITERATIONS: usize = 4;
+
+/// Downsamples the chroma of full resolution YCbCr rows with iterative refinement
+///
+/// `rows` contains the Y, Cb and Cr samples of all rows with a width of `width`, which
+/// must be a multiple of `h_scale`. The number of rows must be a multiple of `v_scale`.
+/// The luma is replaced by the adjusted luma and every sample of the chroma components
+/// by the value of the downsampled area it belongs to. The samples are converted from and
+/// to RGB with `matrix`.
+pub(crate) fn sharp_yuv_downsample(
+    rows: &mut [Vec<u8>; 4],
+    width: usize,
+    h_scale: usize,
+    v_scale: usize,
+    matrix: ColorMatrix,
+) {
+    let height = rows[0].len() / width;
+
+    debug_assert!(width % h_scale == 0);
+    debug_assert!(height % v_scale == 0);
+
+    let mut luma: Vec<f32> = rows[0].iter().map(|&v| f32::from(v)).collect();
+
+    // The source converted to RGB and back, to compare it to the reconstructed pixels
+    let original: Vec<[f32; 3]> = (0..width * height)
+        .map(|i| {
+            let rgb =
+                matrix.ycbcr_to_rgb_f32(luma[i], f32::from(rows[1][i]), f32::from(rows[2][i]));
+            matrix.rgb_to_ycbcr_f32(rgb)
+        })
+        .collect();
+
+    let mut chroma = [1, 2].map(|c| {
+        let plane: Vec<f32> = rows[c].iter().map(|&v| f32::from(v)).collect();
+        downsample(&plane, width, height, h_scale, v_scale)
+    });
+
+    let mut errors = [vec![0f32; width * height], vec![0f32; width * height]];
+
+    for _ in 0..ITERATIONS {
+        let cb = upsample(&chroma[0], width, height, h_scale, v_scale);
+        let cr = upsample(&chroma[1], width, height, h_scale, v_scale);
+
+        for (i, original) in original.iter().enumerate() {
+            let reconstructed = matrix.ycbcr_to_rgb_f32(luma[i], cb[i], cr[i]);
+            let reconstructed = matrix.rgb_to_ycbcr_f32(reconstructed);
+
+            luma[i] = (luma[i] + original[0] - reconstructed[0]).clamp(0.0, 255.0);
+            errors[0][i] = original[1] - reconstructed[1];
+            errors[1][i] = original[2] - reconstructed[2];
+        }
+
+        for (chroma, errors) in chroma.iter_mut().zip(errors.iter()) {
+            let correction = downsample(errors, width, height, h_scale, v_scale);
+
+            for (value, correction) in chroma.iter_mut().zip(correction) {
+                *value = (*value + correction).clamp(0.0, 255.0);
+            }
+        }
+    }
+
+    for (value, &adjusted) in rows[0].iter_mut().zip(luma.iter()) {
+        *value = to_sample(adjusted);
+    }
+
+    let chroma_width = width / h_scale;
+
+    for (row, chroma) in rows[1..3].iter_mut().zip(chroma.iter()) {
+        for (i, value) in row.iter_mut().enumerate() {
+            let x = (i % width) / h_scale;
+            let y = (i / width) / v_scale;
+
+            *value = to_sample(chroma[y * chroma_width + x]);
+        }
+    }
+}
+
+#[inline]
+fn to_sample(value: f32) -> u8 {
+    (value.clamp(0.0, 255.0) + 0.5) as u8
+}
+
+/// Averages the samples of each area of `h_scale` x `v_scale` samples
+fn downsample(
+    plane: &[f32],
+    width: usize,
+    height: usize,
+    h_scale: usize,
+    v_scale: usize,
+) -> Vec<f32> {
+    let chroma_width = width / h_scale;
+    let chroma_height = height / v_scale;
+
+    let mut downsampled = vec![0f32; chroma_width * chroma_height];
+
+    for (i, &value) in plane.iter().enumerate() {
+        let x = (i % width) / h_scale;
+        let y = (i / width) / v_scale;
+
+        downsampled[y * chroma_width + x] += value;
+    }
+
+    let count = (h_scale * v_scale) as f32;
+
+    for value in &mut downsampled {
+        *value /= count;
+    }
+
+    downsampled
+}
+
+/// Returns the two nearest downsampled samples of a full resolution sample and the
+/// weight of the second one for linear interpolation
+#[inline]
+fn get_neighbors(i: usize, scale: usize, len: usize) -> (usize, usize, f32) {
+    let position = ((i as f32 + 0.5) / scale as f32 - 0.5).max(0.0);
+
+    let first = (position as usize).min(len - 1);
+    let second = (first + 1).min(len - 1);
+
+    (first, second, position - first as f32)
+}
+
+/// Upsamples the chroma with linear interpolation, like the "fancy" upsampling of libjpeg
+fn upsample(
+    plane: &[f32],
+    width: usize,
+    height: usize,
+    h_scale: usize,
+    v_scale: usize,
+) -> Vec<f32> {
+    let chroma_width = width / h_scale;
+    let chroma_height = height / v_scale;
+
+    let columns: Vec<_> = (0..width)
+        .map(|x| get_neighbors(x, h_scale, chroma_width))
+        .collect();
+
+    let mut upsampled = Vec::with_capacity(width * height);
+
+    for y in 0..height {
+        let (top, bottom, v_weight) = get_neighbors(y, v_scale, chroma_height);
+
+        let top = &plane[top * chroma_width..(top + 1) * chroma_width];
+        let bottom = &plane[bottom * chroma_width..(bottom + 1) * chroma_width];
+
+        for &(left, right, h_weight) in &columns {
+            let top = top[left] + (top[right] - top[left]) * h_weight;
+            let bottom = bottom[left] + (bottom[right] - bottom[left]) * h_weight;
+
+            upsampled.push(top + (bottom - top) * v_weight);
+        }
+    }
+
+    upsampled
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::{downsample, sharp_yuv_downsample, upsample};
+    use crate::{rgb_to_ycbcr, ColorMatrix};
+
+    /// Converts RGB pixels to full resolution YCbCr rows
+    fn create_rows(pixels: &[[u8; 3]]) -> [Vec<u8>; 4] {
+        let mut rows: [Vec<u8>; 4] = Default::default();
+
+        for pixel in pixels {
+            let (y, cb, cr) = rgb_to_ycbcr(pixel[0], pixel[1], pixel[2]);
+            rows[0].push(y);
+            rows[1].push(cb);
+            rows[2].push(cr);
+        }
+
+        rows
+    }
+
+    /// Sum of the squared RGB errors after upsampling the chroma
+    fn get_error(pixels: &[[u8; 3]], rows: &[Vec<u8>; 4], width: usize) -> f32 {
+        let height = pixels.len() / width;
+
+        let chroma = [1, 2].map(|c| {
+            let plane: Vec<f32> = rows[c].iter().map(|&v| f32::from(v)).collect();
+            let downsampled = downsample(&plane, width, height, 2, 2);
+
+            upsample(&downsampled, width, height, 2, 2)
+        });
+
+        pixels
+            .iter()
+            .enumerate()
+            .map(|(i, pixel)| {
+                let rgb = ColorMatrix::Bt601Full.ycbcr_to_rgb_f32(
+                    f32::from(rows[0][i]),
+                    chroma[0][i],
+                    chroma[1][i],
+                );
+
+                (0..3)
+                    .map(|c| {
+                        let diff = rgb[c] - f32::from(pixel[c]);
+                        diff * diff
+                    })
+                    .sum::<f32>()
+            })
+            .sum()
+    }
+
+    #[test]
+    fn test_upsample() {
+        let plane = [0.0, 100.0];
+        let upsampled = upsample(&plane, 4, 2, 2, 2);
+
+        assert_eq!(upsampled[..4], [0.0, 25.0, 75.0, 100.0]);
+        assert_eq!(upsampled[..4], upsampled[4..]);
+    }
+
+    #[test]
+    fn test_sharp_yuv_flat() {
+        let pixels = [[200, 30, 60]; 64];
+        let mut rows = create_rows(&pixels);
+        let expected = rows.clone();
+
+        sharp_yuv_downsample(&mut rows, 8, 2, 2, ColorMatrix::Bt601Full);
+
+        assert_eq!(rows, expected);
+    }
+
+    #[test]
+    fn test_sharp_yuv_edge() {
+        let width = 16;
+
+        // A saturated red line on white
+        let pixels: Vec<[u8; 3]> = (0..width * 16)
+            .map(|i| match i % width {
+                5..=7 => [255, 0, 0],
+                _ => [255, 255, 255],
+            })
+            .collect();
+
+        let rows = create_rows(&pixels);
+
+        let mut sharp = rows.clone();
+        sharp_yuv_downsample(&mut sharp, width, 2, 2, ColorMatrix::Bt601Full);
+
+        assert!(get_error(&pixels, &sharp, width) < get_error(&pixels, &rows, width) * 0.8);
+    }
+}
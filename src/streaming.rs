@@ -0,0 +1,218 @@
+use crate::dithering::fill_buffers_reduced;
+use crate::encoder::{
+    ceil_div, ColorType, DefaultOperations, Encoder, InterleavedState, Operations,
+};
+use crate::quantization::QuantizationTable;
+use crate::{EncodingError, JfifWrite};
+
+use alloc::vec::Vec;
+
+/// # Encoder that takes the image row by row
+///
+/// Created with [Encoder::into_streaming]. Rows are passed with [write_rows](StreamingEncoder::write_rows)
+/// and each row of MCUs is written as soon as it is complete, so only the rows of a single MCU
+/// need to be kept in memory. [finish](StreamingEncoder::finish) must be called after the last row
+/// to complete the image.
+///
+/// ```no_run
+/// # use jpeg_encoder::EncodingError;
+/// # pub fn main() -> Result<(), EncodingError> {
+/// use jpeg_encoder::{ColorType, Encoder};
+///
+/// let encoder = Encoder::new_file("some.jpeg", 90)?;
+/// let mut streaming = encoder.into_streaming(640, 480, ColorType::Rgb)?;
+///
+/// let row = vec![128u8; 640 * 3];
+///
+/// for _ in 0..480 {
+///     streaming.write_rows(&row)?;
+/// }
+///
+/// streaming.finish()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct StreamingEncoder<W: JfifWrite> {
+    encoder: Encoder<W>,
+    width: u16,
+    height: u16,
+    color_type: ColorType,
+    q_tables: [QuantizationTable; 2],
+    row: [Vec<u8>; 4],
+    buffer_width: usize,
+    mcu_height: usize,
+    buffered_lines: usize,
+    rows_written: usize,
+    state: InterleavedState,
+}
+
+impl<W: JfifWrite> StreamingEncoder<W> {
+    pub(crate) fn new(
+        mut encoder: Encoder<W>,
+        width: u16,
+        height: u16,
+        color_type: ColorType,
+        q_tables: [QuantizationTable; 2],
+    ) -> StreamingEncoder<W> {
+        let (max_h_sampling, max_v_sampling) = encoder.get_max_sampling_size();
+
+        let num_cols = ceil_div(usize::from(width), 8 * max_h_sampling);
+        let buffer_width = num_cols * 8 * max_h_sampling;
+        let mcu_height = 8 * max_v_sampling;
+
+        let row = encoder.init_rows(buffer_width * mcu_height);
+        let state = InterleavedState::new(encoder.restart_interval());
+
+        StreamingEncoder {
+            encoder,
+            width,
+            height,
+            color_type,
+            q_tables,
+            row,
+            buffer_width,
+            mcu_height,
+            buffered_lines: 0,
+            rows_written: 0,
+            state,
+        }
+    }
+
+    /// Width of the image
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    /// Height of the image
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    /// Number of rows written so far
+    pub fn rows_written(&self) -> usize {
+        self.rows_written
+    }
+
+    /// Write one or more rows of the image
+    ///
+    /// `data` must contain complete rows in the color type of the encoder.
+    /// Writing more rows than the image height is an error.
+    pub fn write_rows(&mut self, data: &[u8]) -> Result<(), EncodingError> {
+        let row_length = usize::from(self.width) * self.color_type.get_bytes_per_pixel();
+
+        if data.len() % row_length != 0 {
+            return Err(EncodingError::BadImageData {
+                length: data.len(),
+                required: (data.len() / row_length + 1) * row_length,
+            });
+        }
+
+        let num_rows = data.len() / row_length;
+
+        if self.rows_written + num_rows > usize::from(self.height) {
+            return Err(EncodingError::InvalidRowCount {
+                rows: self.rows_written + num_rows,
+                height: self.height,
+            });
+        }
+
+        let image = self.encoder.get_image_buffer(
+            self.color_type,
+            data,
+            self.width,
+            num_rows as u16,
+            row_length,
+        );
+
+        let dithering = self.encoder.dithering();
+
+        for y in 0..num_rows {
+            fill_buffers_reduced(
+                &*image,
+                y as u16,
+                self.rows_written,
+                dithering,
+                &mut self.row,
+            );
+            self.push_line()?;
+        }
+
+        Ok(())
+    }
+
+    /// Finish the image after all rows have been written
+    pub fn finish(mut self) -> Result<(), EncodingError> {
+        if self.rows_written != usize::from(self.height) {
+            return Err(EncodingError::InvalidRowCount {
+                rows: self.rows_written,
+                height: self.height,
+            });
+        }
+
+        if self.buffered_lines > 0 {
+            // Complete the last row of MCUs by repeating the last line
+            while self.buffered_lines < self.mcu_height {
+                for channel in &mut self.row {
+                    if !channel.is_empty() {
+                        let start = channel.len() - self.buffer_width;
+                        channel.extend_from_within(start..);
+                    }
+                }
+
+                self.buffered_lines += 1;
+            }
+
+            self.encode_mcu_row()?;
+        }
+
+        self.encoder.finish_interleaved()
+    }
+
+    /// Pad the last line in the buffers to the buffer width and encode the MCUs if complete
+    fn push_line(&mut self) -> Result<(), EncodingError> {
+        for _ in usize::from(self.width)..self.buffer_width {
+            for channel in &mut self.row {
+                if !channel.is_empty() {
+                    channel.push(channel[channel.len() - 1]);
+                }
+            }
+        }
+
+        self.rows_written += 1;
+        self.buffered_lines += 1;
+
+        if self.buffered_lines == self.mcu_height {
+            self.encode_mcu_row()?;
+        }
+
+        Ok(())
+    }
+
+    fn encode_mcu_row(&mut self) -> Result<(), EncodingError> {
+        #[cfg(all(feature = "simd", any(target_arch = "x86", target_arch = "x86_64")))]
+        {
+            if std::is_x86_feature_detected!("avx2") {
+                return self.encode_mcu_row_with::<crate::avx2::AVX2Operations>();
+            }
+        }
+
+        self.encode_mcu_row_with::<DefaultOperations>()
+    }
+
+    fn encode_mcu_row_with<OP: Operations>(&mut self) -> Result<(), EncodingError> {
+        self.encoder.encode_mcu_row::<OP>(
+            &self.row,
+            self.buffer_width,
+            &self.q_tables,
+            &mut self.state,
+        )?;
+
+        for channel in &mut self.row {
+            channel.clear();
+        }
+
+        self.buffered_lines = 0;
+
+        Ok(())
+    }
+}
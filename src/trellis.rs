@@ -0,0 +1,207 @@
+/*
+ * Rate-distortion optimized quantization of the AC coefficients
+ *
+ * Based on the trellis quantization of mozjpeg: For every coefficient, the quantized value
+ * is chosen from the rounded value and the largest values of the smaller magnitude categories,
+ * or zero. The choice minimizes the number of bits needed for the block plus the
+ * distortion of the coefficients weighted by lambda.
+ */
+
+use crate::huffman::HuffmanTable;
+use crate::quantization::QuantizationTable;
+use crate::writer::ZIGZAG;
+
+// 2^14.75 and 2^16.5, the default lambda scales of mozjpeg
+const LAMBDA_SCALE1: f32 = 27554.5;
+const LAMBDA_SCALE2: f32 = 92681.9;
+
+// Symbols missing in the huffman table are assumed to need the maximum code length
+const MISSING_CODE_LENGTH: f32 = 16.0;
+
+const ZRL: u8 = 0xF0;
+const EOB: u8 = 0x00;
+
+fn code_length(table: &HuffmanTable, symbol: u8) -> f32 {
+    match table.get_code_length(symbol) {
+        0 => MISSING_CODE_LENGTH,
+        size => f32::from(size),
+    }
+}
+
+fn get_num_bits(value: u16) -> u8 {
+    (16 - value.leading_zeros()) as u8
+}
+
+/// Quantizes a block of DCT coefficients in natural order
+///
+/// The DC coefficient is rounded as usual. The AC coefficients are chosen to minimize
+/// the coded size with the given AC huffman table plus the weighted distortion.
+/// Returns the quantized coefficients in zigzag order.
+pub(crate) fn trellis_quantize(
+    block: &[i16; 64],
+    table: &QuantizationTable,
+    ac_table: &HuffmanTable,
+) -> [i16; 64] {
+    let mut q_block = [0i16; 64];
+    q_block[0] = table.quantize(block[0], 0);
+
+    // Blocks with a lot of energy tolerate more distortion
+    let norm = block[1..]
+        .iter()
+        .map(|&v| f32::from(v) * f32::from(v))
+        .sum::<f32>()
+        / 63.0;
+
+    let lambda = LAMBDA_SCALE1 / (LAMBDA_SCALE2 + norm);
+
+    let zrl_length = code_length(ac_table, ZRL);
+
+    // Distortion if all coefficients up to the index are zero
+    let mut accumulated_zero_dist = [0f32; 64];
+
+    // Lowest cost of the coefficients up to the index if the coefficient at the index is the last non zero one
+    let mut accumulated_cost = [f32::INFINITY; 64];
+    accumulated_cost[0] = 0.0;
+
+    // Index of the previous non zero coefficient
+    let mut run_start = [0usize; 64];
+
+    let mut values = [0i16; 64];
+
+    for i in 1..64 {
+        let z = ZIGZAG[i] as usize & 0x3f;
+
+        let quant = f32::from(table.get(z));
+        let weight = lambda / (quant * quant);
+
+        // The DCT coefficients are scaled by 8
+        let q = quant * 8.0;
+        let x = f32::from(block[z]).abs();
+
+        accumulated_zero_dist[i] = accumulated_zero_dist[i - 1] + x * x * weight;
+
+        let rounded = table.quantize(block[z], z).unsigned_abs();
+        let num_bits = get_num_bits(rounded);
+
+        for size in 1..=num_bits {
+            let candidate = if size == num_bits {
+                rounded
+            } else {
+                (1 << size) - 1
+            };
+
+            let delta = x - f32::from(candidate) * q;
+            let dist = delta * delta * weight;
+
+            for j in 0..i {
+                if accumulated_cost[j] == f32::INFINITY {
+                    continue;
+                }
+
+                let run = i - j - 1;
+                let symbol = (((run % 16) as u8) << 4) | size;
+
+                let rate = code_length(ac_table, symbol)
+                    + f32::from(size)
+                    + (run / 16) as f32 * zrl_length;
+
+                let cost = accumulated_cost[j]
+                    + rate
+                    + dist
+                    + (accumulated_zero_dist[i - 1] - accumulated_zero_dist[j]);
+
+                if cost < accumulated_cost[i] {
+                    accumulated_cost[i] = cost;
+                    run_start[i] = j;
+                    values[i] = if block[z] < 0 {
+                        -(candidate as i16)
+                    } else {
+                        candidate as i16
+                    };
+                }
+            }
+        }
+    }
+
+    let eob_length = code_length(ac_table, EOB);
+
+    let mut last = 0;
+    let mut best_cost = f32::INFINITY;
+
+    for (i, &cost) in accumulated_cost.iter().enumerate() {
+        let mut cost = cost + (accumulated_zero_dist[63] - accumulated_zero_dist[i]);
+
+        if i < 63 {
+            cost += eob_length;
+        }
+
+        if cost < best_cost {
+            best_cost = cost;
+            last = i;
+        }
+    }
+
+    while last > 0 {
+        q_block[last] = values[last];
+        last = run_start[last];
+    }
+
+    q_block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::trellis_quantize;
+    use crate::fdct::fdct;
+    use crate::huffman::HuffmanTable;
+    use crate::quantization::{QuantizationTable, QuantizationTableType};
+    use crate::writer::ZIGZAG;
+
+    fn get_block(seed: usize) -> [i16; 64] {
+        let mut block = [0i16; 64];
+        for (i, v) in block.iter_mut().enumerate() {
+            *v = (((i * 37 + seed * 101 + (i / 8) * (i % 8) * 13) % 256) as i16) - 128;
+        }
+
+        fdct(&mut block);
+        block
+    }
+
+    #[test]
+    fn test_trellis_magnitudes() {
+        let table =
+            QuantizationTable::new_with_quality(&QuantizationTableType::Default, 75, true, 8);
+        let ac_table = HuffmanTable::default_luma_ac();
+
+        for seed in 0..16 {
+            let block = get_block(seed);
+            let q_block = trellis_quantize(&block, &table, &ac_table);
+
+            assert_eq!(q_block[0], table.quantize(block[0], 0));
+
+            for i in 1..64 {
+                let z = ZIGZAG[i] as usize & 0x3f;
+                let rounded = table.quantize(block[z], z);
+
+                // Coefficients are never increased and keep their sign
+                assert!(q_block[i].abs() <= rounded.abs());
+                assert!(q_block[i] == 0 || q_block[i].signum() == rounded.signum());
+            }
+        }
+    }
+
+    #[test]
+    fn test_trellis_flat_block() {
+        let table =
+            QuantizationTable::new_with_quality(&QuantizationTableType::Default, 75, true, 8);
+        let ac_table = HuffmanTable::default_luma_ac();
+
+        let mut block = [0i16; 64];
+        block[0] = 800;
+
+        let q_block = trellis_quantize(&block, &table, &ac_table);
+
+        assert_eq!(q_block[0], table.quantize(800, 0));
+        assert_eq!(q_block[1..], [0; 63]);
+    }
+}
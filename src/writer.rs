@@ -1,9 +1,13 @@
+#[cfg(feature = "arithmetic")]
+use crate::arithmetic::ArithmeticConditioning;
 use crate::encoder::Component;
 use crate::huffman::{CodingClass, HuffmanTable};
 use crate::marker::{Marker, SOFType};
 use crate::quantization::QuantizationTable;
 use crate::EncodingError;
 
+use alloc::vec::Vec;
+
 /// Density settings
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Density {
@@ -28,6 +32,11 @@ pub static ZIGZAG: [u8; 64] = [
 
 const BUFFER_SIZE: usize = core::mem::size_of::<usize>() * 8;
 
+/// Maximum number of buffered correction bits in AC refinement scans
+///
+/// Same limit as used by libjpeg
+pub(crate) const MAX_CORRECTION_BITS: usize = 1000;
+
 /// A no_std alternative for `std::io::Write`
 ///
 /// An implementation of a subset of `std::io::Write` necessary to use the encoder without `std`.
@@ -68,6 +77,10 @@ pub(crate) struct JfifWriter<W: JfifWrite> {
     w: W,
     bit_buffer: usize,
     free_bits: i8,
+
+    // State of the end of band run used in progressive AC scans
+    eobrun: u16,
+    correction_bits: Vec<u8>,
 }
 
 impl<W: JfifWrite> JfifWriter<W> {
@@ -76,9 +89,15 @@ impl<W: JfifWrite> JfifWriter<W> {
             w,
             bit_buffer: 0,
             free_bits: BUFFER_SIZE as i8,
+            eobrun: 0,
+            correction_bits: Vec::new(),
         }
     }
 
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+
     #[inline(always)]
     pub fn write(&mut self, buf: &[u8]) -> Result<(), EncodingError> {
         self.w.write_all(buf)
@@ -250,12 +269,45 @@ impl<W: JfifWrite> JfifWriter<W> {
         assert!(destination < 4, "Bad destination: {}", destination);
 
         self.write_marker(Marker::DQT)?;
-        self.write_u16(2 + 1 + 64)?;
 
-        self.write_u8(destination)?;
+        if table.is_16bit() {
+            self.write_u16(2 + 1 + 64 * 2)?;
+
+            self.write_u8(0x10 | destination)?;
+
+            for &v in ZIGZAG.iter() {
+                self.write_u16(table.get(v as usize))?;
+            }
+        } else {
+            self.write_u16(2 + 1 + 64)?;
 
-        for &v in ZIGZAG.iter() {
-            self.write_u8(table.get(v as usize))?;
+            self.write_u8(destination)?;
+
+            for &v in ZIGZAG.iter() {
+                self.write_u8(table.get(v as usize) as u8)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "arithmetic")]
+    pub fn write_arithmetic_conditioning_segment(
+        &mut self,
+        conditioning: &[ArithmeticConditioning],
+    ) -> Result<(), EncodingError> {
+        self.write_marker(Marker::DAC)?;
+
+        self.write_u16(2 + conditioning.len() as u16 * 4)?;
+
+        for (table, conditioning) in conditioning.iter().enumerate() {
+            // DC conditioning
+            self.write_u8(table as u8)?;
+            self.write_u8(conditioning.dc_value())?;
+
+            // AC conditioning
+            self.write_u8(0x10 | table as u8)?;
+            self.write_u8(conditioning.ac_kx())?;
         }
 
         Ok(())
@@ -315,6 +367,24 @@ impl<W: JfifWrite> JfifWriter<W> {
         Ok(())
     }
 
+    /// Write the difference of a sample to its prediction in a lossless scan
+    ///
+    /// Section H.1.2.2
+    pub fn write_lossless_difference(
+        &mut self,
+        difference: u16,
+        table: &HuffmanTable,
+    ) -> Result<(), EncodingError> {
+        // Category 16 has no additional bits
+        if difference == 0x8000 {
+            return self.huffman_encode(16, table);
+        }
+
+        let (size, value) = get_code(difference as i16);
+
+        self.huffman_encode_value(size, size, value, table)
+    }
+
     pub fn write_ac_block(
         &mut self,
         block: &[i16; 64],
@@ -349,23 +419,217 @@ impl<W: JfifWrite> JfifWriter<W> {
         Ok(())
     }
 
+    /// Write the AC coefficients of a block in the first scan of a progressive image
+    ///
+    /// The coefficients are divided by 2^`al` as described in G.1.2.2
+    ///
+    /// Blocks without remaining non-zero coefficients are collected into an end of band run
+    /// which must be written with [write_eobrun](JfifWriter::write_eobrun) at the end of the scan
+    /// or before a restart marker.
+    pub fn write_ac_first(
+        &mut self,
+        block: &[i16; 64],
+        start: usize,
+        end: usize,
+        al: u8,
+        ac_table: &HuffmanTable,
+    ) -> Result<(), EncodingError> {
+        let mut zero_run = 0;
+
+        for &value in &block[start..end] {
+            let abs_value = value.unsigned_abs() >> al;
+
+            if abs_value == 0 {
+                zero_run += 1;
+            } else {
+                self.write_eobrun(ac_table)?;
+
+                while zero_run > 15 {
+                    self.huffman_encode(0xF0, ac_table)?;
+                    zero_run -= 16;
+                }
+
+                let value = if value < 0 {
+                    -(abs_value as i16)
+                } else {
+                    abs_value as i16
+                };
+
+                let (size, value) = get_code(value);
+                let symbol = (zero_run << 4) | size;
+
+                self.huffman_encode_value(size, symbol, value, ac_table)?;
+
+                zero_run = 0;
+            }
+        }
+
+        if zero_run > 0 {
+            self.eobrun += 1;
+
+            if self.eobrun == 0x7FFF {
+                self.write_eobrun(ac_table)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write the next bit of the DC coefficient in a successive approximation refinement scan
+    ///
+    /// Section G.1.2.1
+    pub fn write_dc_refinement(&mut self, value: i16, al: u8) -> Result<(), EncodingError> {
+        self.write_bits(((value >> al) & 1) as u32, 1)
+    }
+
+    /// Write the next bit of the AC coefficients in a successive approximation refinement scan
+    ///
+    /// Section G.1.2.3
+    ///
+    /// Blocks without newly non-zero coefficients are collected into an end of band run
+    /// which must be written with [write_eobrun](JfifWriter::write_eobrun) at the end of the scan
+    /// or before a restart marker.
+    pub fn write_ac_refinement(
+        &mut self,
+        block: &[i16; 64],
+        start: usize,
+        end: usize,
+        al: u8,
+        ac_table: &HuffmanTable,
+    ) -> Result<(), EncodingError> {
+        let mut abs_values = [0u16; 64];
+
+        // Position of the last coefficient that becomes non-zero in this scan
+        let mut eob = 0;
+
+        for (i, &value) in block.iter().enumerate().take(end).skip(start) {
+            let abs_value = value.unsigned_abs() >> al;
+            abs_values[i] = abs_value;
+
+            if abs_value == 1 {
+                eob = i;
+            }
+        }
+
+        let mut zero_run = 0;
+
+        let mut correction_bits = [0u8; 64];
+        let mut num_correction_bits = 0;
+
+        for i in start..end {
+            let abs_value = abs_values[i];
+
+            if abs_value == 0 {
+                zero_run += 1;
+                continue;
+            }
+
+            // ZRL codes are only needed if they can't be folded into the EOB
+            while zero_run > 15 && i <= eob {
+                self.write_eobrun(ac_table)?;
+                self.huffman_encode(0xF0, ac_table)?;
+                zero_run -= 16;
+
+                self.write_correction_bits(&correction_bits[..num_correction_bits])?;
+                num_correction_bits = 0;
+            }
+
+            if abs_value > 1 {
+                // Coefficient was already non-zero and only needs a correction bit
+                correction_bits[num_correction_bits] = (abs_value & 1) as u8;
+                num_correction_bits += 1;
+                continue;
+            }
+
+            self.write_eobrun(ac_table)?;
+
+            let sign = u16::from(block[i] >= 0);
+            self.huffman_encode_value(1, (zero_run << 4) | 1, sign, ac_table)?;
+
+            self.write_correction_bits(&correction_bits[..num_correction_bits])?;
+            num_correction_bits = 0;
+
+            zero_run = 0;
+        }
+
+        if zero_run > 0 || num_correction_bits > 0 {
+            self.eobrun += 1;
+            self.correction_bits
+                .extend_from_slice(&correction_bits[..num_correction_bits]);
+
+            if self.eobrun == 0x7FFF
+                || self.correction_bits.len() > MAX_CORRECTION_BITS - 64 + 1
+            {
+                self.write_eobrun(ac_table)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write a pending end of band run and the correction bits buffered with it
+    pub fn write_eobrun(&mut self, ac_table: &HuffmanTable) -> Result<(), EncodingError> {
+        if self.eobrun > 0 {
+            let num_bits = get_eobrun_bits(self.eobrun);
+            let value = self.eobrun & ((1 << num_bits) - 1);
+
+            self.huffman_encode_value(num_bits, num_bits << 4, value, ac_table)?;
+
+            self.eobrun = 0;
+
+            let mut correction_bits = core::mem::take(&mut self.correction_bits);
+            self.write_correction_bits(&correction_bits)?;
+
+            correction_bits.clear();
+            self.correction_bits = correction_bits;
+        }
+
+        Ok(())
+    }
+
+    fn write_correction_bits(&mut self, bits: &[u8]) -> Result<(), EncodingError> {
+        for &bit in bits {
+            self.write_bits(u32::from(bit), 1)?;
+        }
+        Ok(())
+    }
+
     pub fn write_frame_header(
         &mut self,
         width: u16,
         height: u16,
         components: &[Component],
-        progressive: bool,
+        sof_type: SOFType,
+        precision: u8,
     ) -> Result<(), EncodingError> {
-        if progressive {
-            self.write_marker(Marker::SOF(SOFType::ProgressiveDCT))?;
-        } else {
-            self.write_marker(Marker::SOF(SOFType::BaselineDCT))?;
-        }
+        self.write_frame_segment(Marker::SOF(sof_type), width, height, components, precision)
+    }
+
+    /// Writes the DHP segment, which has the structure of a frame header
+    /// with the final size of the image and no quantization tables
+    pub fn write_hierarchical_progression(
+        &mut self,
+        width: u16,
+        height: u16,
+        components: &[Component],
+        precision: u8,
+    ) -> Result<(), EncodingError> {
+        self.write_frame_segment(Marker::DHP, width, height, components, precision)
+    }
+
+    fn write_frame_segment(
+        &mut self,
+        marker: Marker,
+        width: u16,
+        height: u16,
+        components: &[Component],
+        precision: u8,
+    ) -> Result<(), EncodingError> {
+        self.write_marker(marker)?;
 
         self.write_u16(2 + 1 + 2 + 2 + 1 + (components.len() as u16) * 3)?;
 
-        // Precision
-        self.write_u8(8)?;
+        self.write_u8(precision)?;
 
         self.write_u16(height)?;
         self.write_u16(width)?;
@@ -377,7 +641,11 @@ impl<W: JfifWrite> JfifWriter<W> {
             self.write_u8(
                 (component.horizontal_sampling_factor << 4) | component.vertical_sampling_factor,
             )?;
-            self.write_u8(component.quantization_table)?;
+            self.write_u8(if marker == Marker::DHP {
+                0
+            } else {
+                component.quantization_table
+            })?;
         }
 
         Ok(())
@@ -387,6 +655,7 @@ impl<W: JfifWrite> JfifWriter<W> {
         &mut self,
         components: &[&Component],
         spectral: Option<(u8, u8)>,
+        approximation: (u8, u8),
     ) -> Result<(), EncodingError> {
         self.write_marker(Marker::SOS)?;
 
@@ -407,13 +676,22 @@ impl<W: JfifWrite> JfifWriter<W> {
         // End of spectral selection
         self.write_u8(spectral_end)?;
 
+        let (approximation_high, approximation_low) = approximation;
+
         // Successive approximation bit position high and low
-        self.write_u8(0)?;
+        self.write_u8((approximation_high << 4) | approximation_low)?;
 
         Ok(())
     }
 }
 
+/// Number of additional bits of an end of band run (The `n` in EOBn)
+#[inline]
+pub(crate) fn get_eobrun_bits(eobrun: u16) -> u8 {
+    debug_assert!(eobrun > 0 && eobrun < 0x8000);
+    (15 - eobrun.leading_zeros()) as u8
+}
+
 #[inline]
 pub(crate) fn get_code(value: i16) -> (u8, u16) {
     let temp = value - (value.is_negative() as i16);
@@ -426,7 +704,7 @@ pub(crate) fn get_code(value: i16) -> (u8, u16) {
      */
     let num_bits = 15 - (temp2 << 1 | 1).leading_zeros() as u16;
 
-    let coefficient = temp & ((1 << num_bits as usize) - 1);
+    let coefficient = temp & ((1i32 << num_bits) - 1) as i16;
 
     (num_bits as u8, coefficient as u16)
 }
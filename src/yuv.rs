@@ -0,0 +1,179 @@
+/*
+ * Input of YCbCr images whose chroma components are already subsampled
+ *
+ * The samples of each component are read directly from the buffer, so that the chroma
+ * components are encoded with their resolution and without resampling.
+ */
+
+use crate::SamplingFactor;
+
+/// # Memory layouts of YCbCr images with subsampled chroma
+///
+/// The rows of all planes are tightly packed. Chroma planes have half the width
+/// (and height for 4:2:0), rounded up.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum YuvFormat {
+    /// 4:2:0 with a Y plane followed by a Cb plane and a Cr plane
+    I420,
+
+    /// 4:2:0 with a Y plane followed by a plane with interleaved Cb and Cr samples
+    Nv12,
+
+    /// 4:2:2 with packed samples in the order Y0 Cb Y1 Cr for every two pixels
+    Yuy2,
+}
+
+impl YuvFormat {
+    pub(crate) fn sampling_factor(self) -> SamplingFactor {
+        match self {
+            YuvFormat::I420 | YuvFormat::Nv12 => SamplingFactor::F_2_2,
+            YuvFormat::Yuy2 => SamplingFactor::F_2_1,
+        }
+    }
+
+    /// Returns the size of the chroma planes
+    fn chroma_size(self, width: u16, height: u16) -> (usize, usize) {
+        let width = (usize::from(width) + 1) / 2;
+        let height = usize::from(height);
+
+        match self {
+            YuvFormat::I420 | YuvFormat::Nv12 => (width, (height + 1) / 2),
+            YuvFormat::Yuy2 => (width, height),
+        }
+    }
+
+    pub(crate) fn required_data_len(self, width: u16, height: u16) -> usize {
+        let luma_len = usize::from(width) * usize::from(height);
+        let (chroma_width, chroma_height) = self.chroma_size(width, height);
+
+        match self {
+            YuvFormat::I420 | YuvFormat::Nv12 => luma_len + 2 * chroma_width * chroma_height,
+            YuvFormat::Yuy2 => 4 * chroma_width * chroma_height,
+        }
+    }
+
+    /// Returns the Y, Cb and Cr planes of the image
+    pub(crate) fn planes(self, data: &[u8], width: u16, height: u16) -> [YuvPlane<'_>; 3] {
+        let luma_width = usize::from(width);
+        let luma_height = usize::from(height);
+        let luma_len = luma_width * luma_height;
+
+        let (chroma_width, chroma_height) = self.chroma_size(width, height);
+
+        let plane = |offset, sample_stride, row_stride, width, height| YuvPlane {
+            data,
+            offset,
+            sample_stride,
+            row_stride,
+            width,
+            height,
+        };
+
+        match self {
+            YuvFormat::I420 => [
+                plane(0, 1, luma_width, luma_width, luma_height),
+                plane(luma_len, 1, chroma_width, chroma_width, chroma_height),
+                plane(
+                    luma_len + chroma_width * chroma_height,
+                    1,
+                    chroma_width,
+                    chroma_width,
+                    chroma_height,
+                ),
+            ],
+            YuvFormat::Nv12 => [
+                plane(0, 1, luma_width, luma_width, luma_height),
+                plane(luma_len, 2, 2 * chroma_width, chroma_width, chroma_height),
+                plane(
+                    luma_len + 1,
+                    2,
+                    2 * chroma_width,
+                    chroma_width,
+                    chroma_height,
+                ),
+            ],
+            YuvFormat::Yuy2 => [
+                plane(0, 2, 4 * chroma_width, luma_width, luma_height),
+                plane(1, 4, 4 * chroma_width, chroma_width, chroma_height),
+                plane(3, 4, 4 * chroma_width, chroma_width, chroma_height),
+            ],
+        }
+    }
+}
+
+/// The samples of a single component within the image buffer
+pub(crate) struct YuvPlane<'a> {
+    data: &'a [u8],
+    offset: usize,
+    sample_stride: usize,
+    row_stride: usize,
+    width: usize,
+    height: usize,
+}
+
+impl<'a> YuvPlane<'a> {
+    #[inline]
+    fn get(&self, x: usize, y: usize) -> u8 {
+        let x = x.min(self.width - 1);
+        let y = y.min(self.height - 1);
+
+        self.data[self.offset + y * self.row_stride + x * self.sample_stride]
+    }
+
+    /// Returns the level shifted samples of a block
+    ///
+    /// Blocks exceeding the plane are padded by repeating the last column and row.
+    pub fn get_block(&self, block_x: usize, block_y: usize) -> [i16; 64] {
+        let mut block = [0i16; 64];
+
+        for y in 0..8 {
+            for x in 0..8 {
+                block[y * 8 + x] = i16::from(self.get(block_x * 8 + x, block_y * 8 + y)) - 128;
+            }
+        }
+
+        block
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::YuvFormat;
+
+    #[test]
+    fn test_planes() {
+        // 3x2 pixels with chroma planes of 2x1 (4:2:0) or 2x2 (4:2:2) samples
+        let i420 = [0, 1, 2, 3, 4, 5, 10, 11, 20, 21];
+        let nv12 = [0, 1, 2, 3, 4, 5, 10, 20, 11, 21];
+        let yuy2 = [0, 10, 1, 20, 2, 11, 2, 21, 3, 12, 4, 22, 5, 13, 5, 23];
+
+        assert_eq!(YuvFormat::I420.required_data_len(3, 2), i420.len());
+        assert_eq!(YuvFormat::Nv12.required_data_len(3, 2), nv12.len());
+        assert_eq!(YuvFormat::Yuy2.required_data_len(3, 2), yuy2.len());
+
+        for (format, data) in [
+            (YuvFormat::I420, &i420[..]),
+            (YuvFormat::Nv12, &nv12[..]),
+            (YuvFormat::Yuy2, &yuy2[..]),
+        ] {
+            let planes = format.planes(data, 3, 2);
+
+            let luma: Vec<u8> = (0..6).map(|i| planes[0].get(i % 3, i / 3)).collect();
+            assert_eq!(luma, [0, 1, 2, 3, 4, 5]);
+
+            assert_eq!(planes[1].get(0, 0), 10);
+            assert_eq!(planes[1].get(1, 0), 11);
+            assert_eq!(planes[2].get(0, 0), 20);
+            assert_eq!(planes[2].get(1, 0), 21);
+
+            // Samples outside of the plane repeat the edge
+            assert_eq!(planes[0].get(5, 5), 5);
+        }
+
+        let planes = YuvFormat::Yuy2.planes(&yuy2, 3, 2);
+        assert_eq!(planes[1].get(1, 1), 13);
+        assert_eq!(planes[2].get(0, 1), 22);
+    }
+}